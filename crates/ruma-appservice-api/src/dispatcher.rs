@@ -0,0 +1,507 @@
+//! A framework-agnostic dispatcher for the requests sent by a homeserver to an application service.
+//!
+//! [`Dispatcher::handle()`] takes a plain [`http::Request`], checks that it was sent by the
+//! homeserver, parses it and calls the matching method of an [`AppserviceHandler`], before
+//! converting its result back into an [`http::Response`]. It can be plugged into any HTTP server
+//! framework.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    future::Future,
+    sync::Mutex,
+};
+
+use http::{Method, StatusCode};
+use percent_encoding::percent_decode_str;
+use ruma_common::{
+    api::{
+        error::{MatrixError, MatrixErrorBody},
+        IncomingRequest, OutgoingResponse,
+    },
+    serde::Raw,
+    thirdparty::{Location, Protocol, User},
+    OwnedTransactionId, RoomAliasId, TransactionId, UserId,
+};
+use ruma_events::AnyTimelineEvent;
+use serde_json::json;
+
+use crate::{
+    event::push_events,
+    ping::send_ping,
+    query::{query_room_alias, query_user_id},
+    thirdparty::{
+        get_location_for_protocol, get_location_for_room_alias, get_protocol,
+        get_user_for_protocol, get_user_for_user_id,
+    },
+    Registration,
+};
+
+/// The default number of transaction IDs remembered by a [`Dispatcher`].
+const DEFAULT_TRANSACTION_CAPACITY: usize = 1000;
+
+/// A transaction of events pushed by the homeserver.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Transaction {
+    /// The ID of the transaction.
+    pub txn_id: OwnedTransactionId,
+
+    /// The events of the transaction.
+    pub events: Vec<AnyTimelineEvent>,
+
+    /// The events of the transaction that could not be deserialized.
+    pub invalid_events: Vec<Raw<AnyTimelineEvent>>,
+
+    /// Information on E2E device updates.
+    #[cfg(feature = "unstable-msc3202")]
+    pub device_lists: push_events::v1::DeviceLists,
+
+    /// The number of unclaimed one-time keys currently held on the server for each device of the
+    /// users of the application service, for each algorithm.
+    #[cfg(feature = "unstable-msc3202")]
+    pub device_one_time_keys_count: BTreeMap<
+        ruma_common::OwnedUserId,
+        BTreeMap<
+            ruma_common::OwnedDeviceId,
+            BTreeMap<ruma_common::DeviceKeyAlgorithm, js_int::UInt>,
+        >,
+    >,
+
+    /// The key algorithms for which the server has an unused fallback key for each device of the
+    /// users of the application service.
+    #[cfg(feature = "unstable-msc3202")]
+    pub device_unused_fallback_key_types: BTreeMap<
+        ruma_common::OwnedUserId,
+        BTreeMap<ruma_common::OwnedDeviceId, Vec<ruma_common::DeviceKeyAlgorithm>>,
+    >,
+
+    /// The EDUs of the transaction.
+    #[cfg(feature = "unstable-msc2409")]
+    pub ephemeral: Vec<push_events::v1::Edu>,
+
+    /// The to-device messages of the transaction.
+    #[cfg(feature = "unstable-msc2409")]
+    pub to_device: Vec<Raw<ruma_events::AnyToDeviceEvent>>,
+}
+
+impl Transaction {
+    fn from_request(request: push_events::v1::Request) -> Self {
+        let mut events = Vec::with_capacity(request.events.len());
+        let mut invalid_events = Vec::new();
+
+        for raw in request.events {
+            match raw.deserialize() {
+                Ok(event) => events.push(event),
+                Err(_) => invalid_events.push(raw),
+            }
+        }
+
+        Self {
+            txn_id: request.txn_id,
+            events,
+            invalid_events,
+            #[cfg(feature = "unstable-msc3202")]
+            device_lists: request.device_lists,
+            #[cfg(feature = "unstable-msc3202")]
+            device_one_time_keys_count: request.device_one_time_keys_count,
+            #[cfg(feature = "unstable-msc3202")]
+            device_unused_fallback_key_types: request.device_unused_fallback_key_types,
+            #[cfg(feature = "unstable-msc2409")]
+            ephemeral: request.ephemeral,
+            #[cfg(feature = "unstable-msc2409")]
+            to_device: request.to_device,
+        }
+    }
+}
+
+/// The handler of the requests sent by a homeserver to an application service.
+///
+/// Only [`on_transaction()`](Self::on_transaction) must be implemented. By default, the other
+/// methods report that the queried users, room aliases and third party entities don't exist.
+///
+/// The methods can be implemented with `async fn`. Returning an error sends it back to the
+/// homeserver. For transactions, this means that the homeserver will retry to send it later.
+pub trait AppserviceHandler: Send + Sync {
+    /// Handle a transaction of events pushed by the homeserver.
+    ///
+    /// Transactions that were already handled successfully are not passed again to this method.
+    /// While a transaction is being handled, the same transaction sent again concurrently is
+    /// rejected with a `503 Service Unavailable` error, so the homeserver retries it later.
+    fn on_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> impl Future<Output = Result<(), MatrixError>> + Send;
+
+    /// Whether the given user exists.
+    ///
+    /// If it returns `true`, the application service must have created the user.
+    fn query_user_id(
+        &self,
+        user_id: &UserId,
+    ) -> impl Future<Output = Result<bool, MatrixError>> + Send {
+        let _ = user_id;
+        async { Ok(false) }
+    }
+
+    /// Whether the given room alias exists.
+    ///
+    /// If it returns `true`, the application service must have created the room and associated
+    /// the alias to it.
+    fn query_room_alias(
+        &self,
+        room_alias: &RoomAliasId,
+    ) -> impl Future<Output = Result<bool, MatrixError>> + Send {
+        let _ = room_alias;
+        async { Ok(false) }
+    }
+
+    /// Handle a ping from the homeserver.
+    ///
+    /// The transaction ID is the one sent by the application service when it asked the homeserver
+    /// to ping it, if any.
+    fn ping(
+        &self,
+        transaction_id: Option<&TransactionId>,
+    ) -> impl Future<Output = Result<(), MatrixError>> + Send {
+        let _ = transaction_id;
+        async { Ok(()) }
+    }
+
+    /// Get the metadata of the given third party protocol, if it is supported.
+    fn get_protocol(
+        &self,
+        protocol: &str,
+    ) -> impl Future<Output = Result<Option<Protocol>, MatrixError>> + Send {
+        let _ = protocol;
+        async { Ok(None) }
+    }
+
+    /// Get the Matrix portal rooms matching the given fields of a third party location.
+    fn get_location_for_protocol(
+        &self,
+        protocol: &str,
+        fields: &BTreeMap<String, String>,
+    ) -> impl Future<Output = Result<Vec<Location>, MatrixError>> + Send {
+        let _ = (protocol, fields);
+        async { Ok(Vec::new()) }
+    }
+
+    /// Get the third party locations matching the given room alias.
+    fn get_location_for_room_alias(
+        &self,
+        room_alias: &RoomAliasId,
+    ) -> impl Future<Output = Result<Vec<Location>, MatrixError>> + Send {
+        let _ = room_alias;
+        async { Ok(Vec::new()) }
+    }
+
+    /// Get the third party users matching the given fields.
+    fn get_user_for_protocol(
+        &self,
+        protocol: &str,
+        fields: &BTreeMap<String, String>,
+    ) -> impl Future<Output = Result<Vec<User>, MatrixError>> + Send {
+        let _ = (protocol, fields);
+        async { Ok(Vec::new()) }
+    }
+
+    /// Get the third party users matching the given Matrix user ID.
+    fn get_user_for_user_id(
+        &self,
+        user_id: &UserId,
+    ) -> impl Future<Output = Result<Vec<User>, MatrixError>> + Send {
+        let _ = user_id;
+        async { Ok(Vec::new()) }
+    }
+}
+
+/// A dispatcher for the requests sent by a homeserver to an application service.
+#[derive(Debug)]
+pub struct Dispatcher<H> {
+    handler: H,
+    hs_token: String,
+    transactions: Mutex<TransactionLog>,
+}
+
+impl<H: AppserviceHandler> Dispatcher<H> {
+    /// Creates a new `Dispatcher` for the given registration that calls the given handler.
+    pub fn new(registration: &Registration, handler: H) -> Self {
+        Self {
+            handler,
+            hs_token: registration.hs_token.clone(),
+            transactions: Mutex::new(TransactionLog::new(DEFAULT_TRANSACTION_CAPACITY)),
+        }
+    }
+
+    /// Set the number of transaction IDs that are remembered to detect transactions that are sent
+    /// again by the homeserver.
+    ///
+    /// Defaults to 1000.
+    pub fn transaction_capacity(self, capacity: usize) -> Self {
+        Self { transactions: Mutex::new(TransactionLog::new(capacity)), ..self }
+    }
+
+    /// The handler of this dispatcher.
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Handle the given request sent by the homeserver.
+    ///
+    /// The request must be authenticated with the `hs_token` of the registration, otherwise an
+    /// error response is returned.
+    pub async fn handle<T: AsRef<[u8]>>(
+        &self,
+        request: http::Request<T>,
+    ) -> http::Response<Vec<u8>> {
+        let result = match self.check_hs_token(&request) {
+            Ok(()) => self.dispatch(request).await,
+            Err(error) => Err(error),
+        };
+
+        result.unwrap_or_else(|error| {
+            error.try_into_http_response().expect("MatrixError with a JSON body can be serialized")
+        })
+    }
+
+    /// Check that the request contains the `hs_token`, either in the `Authorization` header or in
+    /// the deprecated `access_token` query parameter.
+    fn check_hs_token<T>(&self, request: &http::Request<T>) -> Result<(), MatrixError> {
+        let from_header = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(ToOwned::to_owned);
+        let token = from_header.or_else(|| {
+            let query = request.uri().query()?;
+            form_urlencoded_token(query)
+        });
+
+        match token {
+            None => Err(matrix_error(
+                StatusCode::UNAUTHORIZED,
+                "M_UNAUTHORIZED",
+                "Missing homeserver token",
+            )),
+            Some(token) if constant_time_eq(token.as_bytes(), self.hs_token.as_bytes()) => Ok(()),
+            Some(_) => {
+                Err(matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "Invalid homeserver token"))
+            }
+        }
+    }
+
+    async fn dispatch<T: AsRef<[u8]>>(
+        &self,
+        request: http::Request<T>,
+    ) -> Result<http::Response<Vec<u8>>, MatrixError> {
+        let path = request.uri().path().to_owned();
+        let segments: Vec<_> = path
+            .strip_prefix("/_matrix/app/v1/")
+            .or_else(|| path.strip_prefix("/_matrix/app/unstable/fi.mau.msc2659/"))
+            .ok_or_else(unrecognized)?
+            .split('/')
+            .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+            .collect();
+        let segments: Vec<_> = segments.iter().map(String::as_str).collect();
+        let method = request.method().clone();
+
+        match (method, segments.as_slice()) {
+            (Method::PUT, ["transactions", txn_id]) => {
+                let request = parse::<push_events::v1::Request, _>(request, &[txn_id])?;
+                self.push_events(request).await?;
+                respond(push_events::v1::Response::new())
+            }
+            (Method::GET, ["users", user_id]) => {
+                let request = parse::<query_user_id::v1::Request, _>(request, &[user_id])?;
+                if self.handler.query_user_id(&request.user_id).await? {
+                    respond(query_user_id::v1::Response::new())
+                } else {
+                    Err(not_found("User does not exist"))
+                }
+            }
+            (Method::GET, ["rooms", room_alias]) => {
+                let request = parse::<query_room_alias::v1::Request, _>(request, &[room_alias])?;
+                if self.handler.query_room_alias(&request.room_alias).await? {
+                    respond(query_room_alias::v1::Response::new())
+                } else {
+                    Err(not_found("Room alias does not exist"))
+                }
+            }
+            (Method::POST, ["ping"]) => {
+                let request = parse::<send_ping::v1::Request, &str>(request, &[])?;
+                self.handler.ping(request.transaction_id.as_deref()).await?;
+                respond(send_ping::v1::Response::new())
+            }
+            (Method::GET, ["thirdparty", "protocol", protocol]) => {
+                let request = parse::<get_protocol::v1::Request, _>(request, &[protocol])?;
+                match self.handler.get_protocol(&request.protocol).await? {
+                    Some(protocol) => respond(get_protocol::v1::Response::new(protocol)),
+                    None => Err(not_found("Protocol is not supported")),
+                }
+            }
+            (Method::GET, ["thirdparty", "location", protocol]) => {
+                let request =
+                    parse::<get_location_for_protocol::v1::Request, _>(request, &[protocol])?;
+                let locations = self
+                    .handler
+                    .get_location_for_protocol(&request.protocol, &request.fields)
+                    .await?;
+                respond(get_location_for_protocol::v1::Response::new(locations))
+            }
+            (Method::GET, ["thirdparty", "location"]) => {
+                let request =
+                    parse::<get_location_for_room_alias::v1::Request, &str>(request, &[])?;
+                let locations = self.handler.get_location_for_room_alias(&request.alias).await?;
+                respond(get_location_for_room_alias::v1::Response::new(locations))
+            }
+            (Method::GET, ["thirdparty", "user", protocol]) => {
+                let request = parse::<get_user_for_protocol::v1::Request, _>(request, &[protocol])?;
+                let users =
+                    self.handler.get_user_for_protocol(&request.protocol, &request.fields).await?;
+                respond(get_user_for_protocol::v1::Response::new(users))
+            }
+            (Method::GET, ["thirdparty", "user"]) => {
+                let request = parse::<get_user_for_user_id::v1::Request, &str>(request, &[])?;
+                let users = self.handler.get_user_for_user_id(&request.userid).await?;
+                respond(get_user_for_user_id::v1::Response::new(users))
+            }
+            _ => Err(unrecognized()),
+        }
+    }
+
+    /// Pass the transaction to the handler, unless it was already handled or is being handled.
+    async fn push_events(&self, request: push_events::v1::Request) -> Result<(), MatrixError> {
+        let guard = {
+            let mut transactions =
+                self.transactions.lock().expect("transactions mutex was poisoned");
+            if transactions.contains(&request.txn_id) {
+                return Ok(());
+            }
+            if !transactions.in_flight.insert(request.txn_id.clone()) {
+                return Err(matrix_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "M_UNKNOWN",
+                    "The transaction is already being handled",
+                ));
+            }
+
+            InFlightTransaction { transactions: &self.transactions, txn_id: request.txn_id.clone() }
+        };
+
+        self.handler.on_transaction(Transaction::from_request(request)).await?;
+
+        // Only remember transactions that were handled successfully, so the homeserver retries
+        // the others.
+        guard.complete();
+        Ok(())
+    }
+}
+
+/// The IDs of the latest transactions that were handled successfully, and of the transactions
+/// that are being handled.
+#[derive(Debug)]
+struct TransactionLog {
+    capacity: usize,
+    ids: BTreeSet<OwnedTransactionId>,
+    order: VecDeque<OwnedTransactionId>,
+    in_flight: BTreeSet<OwnedTransactionId>,
+}
+
+impl TransactionLog {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, ids: BTreeSet::new(), order: VecDeque::new(), in_flight: BTreeSet::new() }
+    }
+
+    fn contains(&self, txn_id: &TransactionId) -> bool {
+        self.ids.contains(txn_id)
+    }
+
+    fn insert(&mut self, txn_id: OwnedTransactionId) {
+        if self.capacity == 0 || !self.ids.insert(txn_id.clone()) {
+            return;
+        }
+
+        self.order.push_back(txn_id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// A transaction that is being handled.
+///
+/// It is removed from the in-flight transactions when dropped, which also happens if the handler
+/// fails or if the future handling the request is cancelled.
+struct InFlightTransaction<'a> {
+    transactions: &'a Mutex<TransactionLog>,
+    txn_id: OwnedTransactionId,
+}
+
+impl InFlightTransaction<'_> {
+    /// Remember that the transaction was handled successfully.
+    fn complete(self) {
+        self.transactions
+            .lock()
+            .expect("transactions mutex was poisoned")
+            .insert(self.txn_id.clone());
+    }
+}
+
+impl Drop for InFlightTransaction<'_> {
+    fn drop(&mut self) {
+        // Don't panic while unwinding if the mutex was poisoned.
+        if let Ok(mut transactions) = self.transactions.lock() {
+            transactions.in_flight.remove(&self.txn_id);
+        }
+    }
+}
+
+/// Parse an incoming request with the given path arguments, which must be percent-decoded.
+fn parse<R: IncomingRequest, S: AsRef<str>>(
+    request: http::Request<impl AsRef<[u8]>>,
+    path_args: &[S],
+) -> Result<R, MatrixError> {
+    R::try_from_http_request(request, path_args)
+        .map_err(|error| matrix_error(StatusCode::BAD_REQUEST, "M_BAD_JSON", &error.to_string()))
+}
+
+/// Convert a response to an `http::Response`.
+fn respond(response: impl OutgoingResponse) -> Result<http::Response<Vec<u8>>, MatrixError> {
+    response.try_into_http_response().map_err(|error| {
+        matrix_error(StatusCode::INTERNAL_SERVER_ERROR, "M_UNKNOWN", &error.to_string())
+    })
+}
+
+/// Extract the `access_token` from the given query string.
+fn form_urlencoded_token(query: &str) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct Query {
+        access_token: Option<String>,
+    }
+
+    serde_html_form::from_str::<Query>(query).ok()?.access_token
+}
+
+/// Compare the given byte strings in a time that only depends on their length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Construct a `MatrixError` with the given status code, error code and message.
+fn matrix_error(status_code: StatusCode, errcode: &str, error: &str) -> MatrixError {
+    MatrixError {
+        status_code,
+        body: MatrixErrorBody::Json(json!({ "errcode": errcode, "error": error })),
+    }
+}
+
+fn not_found(error: &str) -> MatrixError {
+    matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", error)
+}
+
+fn unrecognized() -> MatrixError {
+    matrix_error(StatusCode::NOT_FOUND, "M_UNRECOGNIZED", "Unrecognized request")
+}
@@ -9,11 +9,18 @@
 
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "dispatcher")]
+pub mod dispatcher;
 pub mod event;
 pub mod ping;
 pub mod query;
+#[cfg(feature = "regex")]
+mod registration;
 pub mod thirdparty;
 
+#[cfg(feature = "regex")]
+pub use self::registration::{CompiledRegistration, RegistrationError};
+
 /// A namespace defined by an application service.
 ///
 /// Used for [appservice registration](https://spec.matrix.org/latest/application-service-api/#registration).
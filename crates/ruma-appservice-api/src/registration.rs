@@ -0,0 +1,173 @@
+//! Evaluation and validation of the namespaces of a [`Registration`].
+
+use regex::Regex;
+use ruma_common::{RoomAliasId, RoomId, UserId};
+
+use crate::{Namespace, Registration};
+
+impl Namespace {
+    /// Whether the given value is included in this namespace.
+    ///
+    /// The regex is not implicitly anchored, so it must start with `^` and end with `$` to only
+    /// match full identifiers. An invalid regex doesn't match anything; use
+    /// [`Registration::validate()`] to detect invalid regexes.
+    ///
+    /// The regex is compiled every time this method is called. Use a [`CompiledRegistration`] to
+    /// check many values.
+    pub fn is_match(&self, value: &str) -> bool {
+        Regex::new(&self.regex).is_ok_and(|regex| regex.is_match(value))
+    }
+}
+
+impl Registration {
+    /// Deserialize a registration from the contents of a YAML registration file, and validate it.
+    ///
+    /// Returns an error if the YAML is invalid, or if any of the namespaces contains an invalid
+    /// regex.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(yaml: &str) -> Result<Self, RegistrationError> {
+        let registration: Self = serde_yaml::from_str(yaml)?;
+        registration.validate()?;
+        Ok(registration)
+    }
+
+    /// Check that the regexes of all the namespaces of this registration are valid.
+    ///
+    /// Returns an error for the first invalid regex.
+    pub fn validate(&self) -> Result<(), RegistrationError> {
+        CompiledNamespaces::new(self).map(|_| ())
+    }
+}
+
+/// A [`Registration`] with the regexes of its namespaces compiled.
+///
+/// This can be used to efficiently check whether users, room aliases or room IDs are included in
+/// the namespaces of the application service. Since the regexes are compiled when this type is
+/// constructed, changing the namespaces requires to construct a new `CompiledRegistration`.
+#[derive(Clone, Debug)]
+pub struct CompiledRegistration {
+    registration: Registration,
+    namespaces: CompiledNamespaces,
+}
+
+impl CompiledRegistration {
+    /// Compile the regexes of the namespaces of the given registration.
+    ///
+    /// Returns an error for the first invalid regex.
+    pub fn new(registration: Registration) -> Result<Self, RegistrationError> {
+        let namespaces = CompiledNamespaces::new(&registration)?;
+        Ok(Self { registration, namespaces })
+    }
+
+    /// The registration.
+    pub fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    /// Get the registration back.
+    pub fn into_registration(self) -> Registration {
+        self.registration
+    }
+
+    /// Whether the given user ID is included in the user namespaces of this application service.
+    pub fn is_user_in_namespace(&self, user_id: &UserId) -> bool {
+        any_match(&self.namespaces.users, user_id.as_str(), false)
+    }
+
+    /// Whether the given user ID is included in an exclusive user namespace of this application
+    /// service.
+    pub fn is_user_in_exclusive_namespace(&self, user_id: &UserId) -> bool {
+        any_match(&self.namespaces.users, user_id.as_str(), true)
+    }
+
+    /// Whether the given room alias is included in the alias namespaces of this application
+    /// service.
+    pub fn is_room_alias_in_namespace(&self, room_alias: &RoomAliasId) -> bool {
+        any_match(&self.namespaces.aliases, room_alias.as_str(), false)
+    }
+
+    /// Whether the given room alias is included in an exclusive alias namespace of this
+    /// application service.
+    pub fn is_room_alias_in_exclusive_namespace(&self, room_alias: &RoomAliasId) -> bool {
+        any_match(&self.namespaces.aliases, room_alias.as_str(), true)
+    }
+
+    /// Whether the given room ID is included in the room namespaces of this application service.
+    pub fn is_room_id_in_namespace(&self, room_id: &RoomId) -> bool {
+        any_match(&self.namespaces.rooms, room_id.as_str(), false)
+    }
+
+    /// Whether the given room ID is included in an exclusive room namespace of this application
+    /// service.
+    pub fn is_room_id_in_exclusive_namespace(&self, room_id: &RoomId) -> bool {
+        any_match(&self.namespaces.rooms, room_id.as_str(), true)
+    }
+}
+
+/// The compiled regexes of the namespaces of a [`Registration`], with their exclusivity.
+#[derive(Clone, Debug)]
+struct CompiledNamespaces {
+    users: Vec<(bool, Regex)>,
+    aliases: Vec<(bool, Regex)>,
+    rooms: Vec<(bool, Regex)>,
+}
+
+impl CompiledNamespaces {
+    fn new(registration: &Registration) -> Result<Self, RegistrationError> {
+        let compile = |kind: &'static str, namespaces: &[Namespace]| {
+            namespaces
+                .iter()
+                .map(|namespace| {
+                    let regex = Regex::new(&namespace.regex).map_err(|source| {
+                        RegistrationError::InvalidRegex {
+                            namespace: kind,
+                            regex: namespace.regex.clone(),
+                            source,
+                        }
+                    })?;
+                    Ok((namespace.exclusive, regex))
+                })
+                .collect::<Result<Vec<_>, RegistrationError>>()
+        };
+
+        let namespaces = &registration.namespaces;
+        Ok(Self {
+            users: compile("users", &namespaces.users)?,
+            aliases: compile("aliases", &namespaces.aliases)?,
+            rooms: compile("rooms", &namespaces.rooms)?,
+        })
+    }
+}
+
+/// Whether any of the given namespaces includes the given value.
+///
+/// If `exclusive_only` is `true`, only the exclusive namespaces are checked.
+fn any_match(namespaces: &[(bool, Regex)], value: &str, exclusive_only: bool) -> bool {
+    namespaces
+        .iter()
+        .filter(|(exclusive, _)| !exclusive_only || *exclusive)
+        .any(|(_, regex)| regex.is_match(value))
+}
+
+/// An error encountered when loading or validating a [`Registration`].
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum RegistrationError {
+    /// The registration file is not valid YAML, or doesn't match the format of a registration.
+    #[cfg(feature = "yaml")]
+    #[error("invalid registration file: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    /// The regex of a namespace is invalid.
+    #[error("invalid regex `{regex}` in the {namespace} namespaces: {source}")]
+    InvalidRegex {
+        /// The kind of namespace containing the regex: `users`, `aliases` or `rooms`.
+        namespace: &'static str,
+
+        /// The invalid regex.
+        regex: String,
+
+        /// The error returned when compiling the regex.
+        source: regex::Error,
+    },
+}
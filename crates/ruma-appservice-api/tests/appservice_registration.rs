@@ -61,3 +61,114 @@ fn config_with_optional_url() {
     assert_matches!(serde_yaml::from_str(registration_config).unwrap(), Registration { url, .. });
     assert_eq!(url, None);
 }
+
+#[cfg(feature = "regex")]
+fn bridge_registration() -> Registration {
+    serde_yaml::from_str(
+        r##"
+        id: "IRC Bridge"
+        url: "http://127.0.0.1:1234"
+        as_token: "as_token"
+        hs_token: "hs_token"
+        sender_localpart: "_irc_bot"
+        namespaces:
+          users:
+            - exclusive: true
+              regex: "^@_irc_bridge_.*:example\\.org$"
+            - exclusive: false
+              regex: "^@irc_friend:example\\.org$"
+          aliases:
+            - exclusive: false
+              regex: "^#_irc_bridge_.*$"
+          rooms:
+            - exclusive: true
+              regex: "^!bridged:example\\.org$"
+        "##,
+    )
+    .unwrap()
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn namespace_matching() {
+    use ruma_appservice_api::CompiledRegistration;
+    use ruma_common::{room_alias_id, room_id, user_id};
+
+    let registration = CompiledRegistration::new(bridge_registration()).unwrap();
+
+    assert!(registration.is_user_in_namespace(user_id!("@_irc_bridge_alice:example.org")));
+    assert!(registration.is_user_in_exclusive_namespace(user_id!("@_irc_bridge_alice:example.org")));
+    assert!(registration.is_user_in_namespace(user_id!("@irc_friend:example.org")));
+    assert!(!registration.is_user_in_exclusive_namespace(user_id!("@irc_friend:example.org")));
+    assert!(!registration.is_user_in_namespace(user_id!("@_irc_bridge_alice:example.com")));
+    assert!(!registration.is_user_in_namespace(user_id!("@alice:example.org")));
+
+    assert!(
+        registration.is_room_alias_in_namespace(room_alias_id!("#_irc_bridge_ruma:example.org"))
+    );
+    assert!(!registration
+        .is_room_alias_in_exclusive_namespace(room_alias_id!("#_irc_bridge_ruma:example.org")));
+    assert!(!registration.is_room_alias_in_namespace(room_alias_id!("#ruma:example.org")));
+
+    assert!(registration.is_room_id_in_exclusive_namespace(room_id!("!bridged:example.org")));
+    assert!(!registration.is_room_id_in_namespace(room_id!("!other:example.org")));
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn namespace_regex_change() {
+    use ruma_appservice_api::Namespace;
+
+    let mut namespace = Namespace::new(true, "^@alice:.*$".to_owned());
+    assert!(namespace.is_match("@alice:example.org"));
+
+    namespace.regex = "^@bob:.*$".to_owned();
+    assert!(!namespace.is_match("@alice:example.org"));
+    assert!(namespace.is_match("@bob:example.org"));
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn invalid_namespace_regex() {
+    use ruma_appservice_api::{CompiledRegistration, Namespace, RegistrationError};
+
+    let mut registration = bridge_registration();
+    registration.validate().unwrap();
+    registration.namespaces.aliases.push(Namespace::new(false, "#_irc_(.*".to_owned()));
+
+    let error = registration.validate().unwrap_err();
+    assert!(error.to_string().starts_with("invalid regex `#_irc_(.*` in the aliases namespaces"));
+    assert!(!registration.namespaces.aliases[1].is_match("#_irc_(ruma:example.org"));
+    assert_matches!(
+        CompiledRegistration::new(registration),
+        Err(RegistrationError::InvalidRegex { namespace: "aliases", .. })
+    );
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn registration_from_yaml() {
+    use ruma_appservice_api::RegistrationError;
+
+    let registration_config = r#"
+        id: "IRC Bridge"
+        url: null
+        as_token: "as_token"
+        hs_token: "hs_token"
+        sender_localpart: "_irc_bot"
+        namespaces:
+          users:
+            - exclusive: true
+              regex: "@_irc_bridge_["
+        "#;
+    assert_matches!(
+        Registration::from_yaml(registration_config),
+        Err(RegistrationError::InvalidRegex { namespace: "users", regex, .. })
+    );
+    assert_eq!(regex, "@_irc_bridge_[");
+
+    assert_matches!(Registration::from_yaml("id: 3"), Err(RegistrationError::Yaml(_)));
+
+    let registration = Registration::from_yaml(&registration_config.replace('[', ".*")).unwrap();
+    assert!(registration.namespaces.users[0].is_match("@_irc_bridge_alice:example.org"));
+}
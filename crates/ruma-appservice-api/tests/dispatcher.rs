@@ -0,0 +1,266 @@
+#![cfg(feature = "dispatcher")]
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use http::{Method, StatusCode};
+use ruma_appservice_api::{
+    dispatcher::{AppserviceHandler, Dispatcher, Transaction},
+    Namespace, Registration, RegistrationInit,
+};
+use ruma_common::{
+    api::error::{MatrixError, MatrixErrorBody},
+    user_id, OwnedTransactionId, UserId,
+};
+use serde_json::{json, Value as JsonValue};
+
+#[derive(Default)]
+struct Bridge {
+    transactions: Mutex<Vec<(OwnedTransactionId, usize, usize)>>,
+    fail_next_transaction: AtomicBool,
+}
+
+impl AppserviceHandler for Bridge {
+    async fn on_transaction(&self, transaction: Transaction) -> Result<(), MatrixError> {
+        // Let concurrent requests run while the transaction is being handled.
+        tokio::task::yield_now().await;
+
+        if self.fail_next_transaction.swap(false, Ordering::SeqCst) {
+            return Err(MatrixError {
+                status_code: StatusCode::SERVICE_UNAVAILABLE,
+                body: MatrixErrorBody::Json(json!({ "errcode": "M_UNKNOWN", "error": "Busy" })),
+            });
+        }
+
+        self.transactions.lock().unwrap().push((
+            transaction.txn_id,
+            transaction.events.len(),
+            transaction.invalid_events.len(),
+        ));
+        Ok(())
+    }
+
+    async fn query_user_id(&self, user_id: &UserId) -> Result<bool, MatrixError> {
+        Ok(user_id == user_id!("@_bridge_alice:example.org"))
+    }
+}
+
+fn dispatcher() -> Dispatcher<Bridge> {
+    let mut registration: Registration = RegistrationInit {
+        id: "bridge".to_owned(),
+        url: None,
+        as_token: "as_token".to_owned(),
+        hs_token: "hs_token".to_owned(),
+        sender_localpart: "_bridge".to_owned(),
+        namespaces: Default::default(),
+        rate_limited: None,
+        protocols: None,
+    }
+    .into();
+    registration.namespaces.users.push(Namespace::new(true, "^@_bridge_.*$".to_owned()));
+
+    Dispatcher::new(&registration, Bridge::default())
+}
+
+fn request(
+    method: Method,
+    uri: &str,
+    token: Option<&str>,
+    body: JsonValue,
+) -> http::Request<Vec<u8>> {
+    let mut builder = http::Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        builder = builder.header(http::header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    builder.body(serde_json::to_vec(&body).unwrap()).unwrap()
+}
+
+fn transaction(txn_id: &str) -> http::Request<Vec<u8>> {
+    request(
+        Method::PUT,
+        &format!("/_matrix/app/v1/transactions/{txn_id}"),
+        Some("hs_token"),
+        json!({
+            "events": [
+                {
+                    "type": "m.room.message",
+                    "event_id": "$message",
+                    "room_id": "!room:example.org",
+                    "sender": "@alice:example.org",
+                    "origin_server_ts": 1,
+                    "content": { "msgtype": "m.text", "body": "Hello" },
+                },
+                { "type": "m.room.message" },
+            ],
+        }),
+    )
+}
+
+fn errcode(response: &http::Response<Vec<u8>>) -> String {
+    let body: JsonValue = serde_json::from_slice(response.body()).unwrap();
+    body["errcode"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn hs_token_validation() {
+    let dispatcher = dispatcher();
+
+    let response = dispatcher
+        .handle(request(Method::GET, "/_matrix/app/v1/users/@a:example.org", None, json!({})))
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(errcode(&response), "M_UNAUTHORIZED");
+
+    let response = dispatcher
+        .handle(request(
+            Method::GET,
+            "/_matrix/app/v1/users/@a:example.org",
+            Some("wrong"),
+            json!({}),
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(errcode(&response), "M_FORBIDDEN");
+
+    // The deprecated query parameter is still accepted.
+    let response = dispatcher
+        .handle(request(
+            Method::GET,
+            "/_matrix/app/v1/users/%40_bridge_alice%3Aexample.org?access_token=hs_token",
+            None,
+            json!({}),
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn transaction_deduplication() {
+    let dispatcher = dispatcher();
+
+    dispatcher.handler().fail_next_transaction.store(true, Ordering::SeqCst);
+    let response = dispatcher.handle(transaction("1")).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(dispatcher.handler().transactions.lock().unwrap().is_empty());
+
+    // The homeserver retries the failed transaction, then sends it again.
+    for _ in 0..2 {
+        let response = dispatcher.handle(transaction("1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), b"{}");
+    }
+    let response = dispatcher.handle(transaction("2")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let transactions = dispatcher.handler().transactions.lock().unwrap();
+    assert_eq!(
+        *transactions,
+        [(OwnedTransactionId::from("1"), 1, 1), (OwnedTransactionId::from("2"), 1, 1)]
+    );
+}
+
+#[tokio::test]
+async fn concurrent_transaction_retries() {
+    let dispatcher = dispatcher();
+
+    let (first, second) =
+        tokio::join!(dispatcher.handle(transaction("1")), dispatcher.handle(transaction("1")));
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(dispatcher.handler().transactions.lock().unwrap().len(), 1);
+
+    // The transaction was handled, so it is not passed to the handler again.
+    let response = dispatcher.handle(transaction("1")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(dispatcher.handler().transactions.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn cancelled_transaction() {
+    let dispatcher = dispatcher();
+
+    // The request is cancelled while the transaction is being handled.
+    tokio::select! {
+        biased;
+        _ = dispatcher.handle(transaction("1")) => panic!("the transaction should be pending"),
+        _ = async {} => {}
+    }
+    assert!(dispatcher.handler().transactions.lock().unwrap().is_empty());
+
+    // The homeserver retries it.
+    let response = dispatcher.handle(transaction("1")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(dispatcher.handler().transactions.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn transaction_capacity() {
+    let dispatcher = dispatcher().transaction_capacity(1);
+
+    for txn_id in ["1", "2", "1"] {
+        dispatcher.handle(transaction(txn_id)).await;
+    }
+
+    assert_eq!(dispatcher.handler().transactions.lock().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn queries_and_defaults() {
+    let dispatcher = dispatcher();
+
+    let response = dispatcher
+        .handle(request(
+            Method::GET,
+            "/_matrix/app/v1/users/@_bridge_bob:example.org",
+            Some("hs_token"),
+            json!({}),
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(errcode(&response), "M_NOT_FOUND");
+
+    let response = dispatcher
+        .handle(request(
+            Method::GET,
+            "/_matrix/app/v1/rooms/%23room:example.org",
+            Some("hs_token"),
+            json!({}),
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = dispatcher
+        .handle(request(
+            Method::POST,
+            "/_matrix/app/v1/ping",
+            Some("hs_token"),
+            json!({ "transaction_id": "ping" }),
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = dispatcher
+        .handle(request(
+            Method::GET,
+            "/_matrix/app/v1/thirdparty/user?userid=@_bridge_alice:example.org",
+            Some("hs_token"),
+            json!({}),
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.body(), b"[]");
+
+    let response = dispatcher
+        .handle(request(Method::GET, "/_matrix/app/v1/unknown", Some("hs_token"), json!({})))
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(errcode(&response), "M_UNRECOGNIZED");
+
+    let response = dispatcher
+        .handle(request(Method::PUT, "/_matrix/app/v1/transactions/1", Some("hs_token"), json!(3)))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(errcode(&response), "M_BAD_JSON");
+}
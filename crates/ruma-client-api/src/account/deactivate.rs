@@ -14,7 +14,7 @@ pub mod v3 {
 
     use crate::{
         account::ThirdPartyIdRemovalStatus,
-        uiaa::{AuthData, UiaaResponse},
+        uiaa::{AuthData, UiaaRequest, UiaaResponse},
     };
 
     const METADATA: Metadata = metadata! {
@@ -55,6 +55,12 @@ pub mod v3 {
         }
     }
 
+    impl UiaaRequest for Request {
+        fn set_auth(&mut self, auth: Option<AuthData>) {
+            self.auth = auth;
+        }
+    }
+
     impl Response {
         /// Creates a new `Response` with the given unbind result.
         pub fn new(id_server_unbind_result: ThirdPartyIdRemovalStatus) -> Self {
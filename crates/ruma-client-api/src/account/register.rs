@@ -17,7 +17,7 @@ pub mod v3 {
     };
 
     use super::{LoginType, RegistrationKind};
-    use crate::uiaa::{AuthData, UiaaResponse};
+    use crate::uiaa::{AuthData, UiaaRequest, UiaaResponse};
 
     const METADATA: Metadata = metadata! {
         method: POST,
@@ -154,6 +154,12 @@ pub mod v3 {
         }
     }
 
+    impl UiaaRequest for Request {
+        fn set_auth(&mut self, auth: Option<AuthData>) {
+            self.auth = auth;
+        }
+    }
+
     impl Response {
         /// Creates a new `Response` with the given user ID.
         pub fn new(user_id: OwnedUserId) -> Self {
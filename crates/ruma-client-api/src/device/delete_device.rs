@@ -12,7 +12,7 @@ pub mod v3 {
         metadata, OwnedDeviceId,
     };
 
-    use crate::uiaa::{AuthData, UiaaResponse};
+    use crate::uiaa::{AuthData, UiaaRequest, UiaaResponse};
 
     const METADATA: Metadata = metadata! {
         method: DELETE,
@@ -48,6 +48,12 @@ pub mod v3 {
         }
     }
 
+    impl UiaaRequest for Request {
+        fn set_auth(&mut self, auth: Option<AuthData>) {
+            self.auth = auth;
+        }
+    }
+
     impl Response {
         /// Creates an empty `Response`.
         pub fn new() -> Self {
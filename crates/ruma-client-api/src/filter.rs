@@ -3,6 +3,7 @@
 pub mod create_filter;
 pub mod get_filter;
 
+mod evaluate;
 mod lazy_load;
 mod url;
 
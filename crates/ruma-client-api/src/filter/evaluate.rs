@@ -0,0 +1,437 @@
+//! Evaluation of filters against events and sync responses.
+
+use js_int::UInt;
+use ruma_common::{serde::Raw, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde::{de::IgnoredAny, Deserialize};
+
+use super::{Filter, FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter, UrlFilter};
+use crate::sync::sync_events::v3;
+
+impl RoomEventFilter {
+    /// Whether the given event matches this filter.
+    ///
+    /// The `rooms` and `not_rooms` fields are only checked if the event has a `room_id` field.
+    /// Use [`RoomEventFilter::matches_in_room()`] for events that don't have one, like events in
+    /// sync responses. The `limit` and `lazy_load_options` fields don't apply to single events.
+    pub fn matches<T>(&self, event: &Raw<T>) -> bool {
+        let fields = EventFields::from_raw(event);
+        self.matches_fields(&fields, fields.room_id.as_deref())
+    }
+
+    /// Whether the given event in the given room matches this filter.
+    ///
+    /// The `limit` and `lazy_load_options` fields don't apply to single events.
+    pub fn matches_in_room<T>(&self, event: &Raw<T>, room_id: &RoomId) -> bool {
+        self.matches_fields(&EventFields::from_raw(event), Some(room_id))
+    }
+
+    fn matches_fields(&self, fields: &EventFields, room_id: Option<&RoomId>) -> bool {
+        if let Some(room_id) = room_id {
+            if !matches_allow_deny(room_id, self.rooms.as_deref(), &self.not_rooms) {
+                return false;
+            }
+        }
+
+        let has_url = fields.content.as_ref().is_some_and(|content| content.url.is_some());
+        match self.url_filter {
+            Some(UrlFilter::EventsWithUrl) if !has_url => return false,
+            Some(UrlFilter::EventsWithoutUrl) if has_url => return false,
+            _ => {}
+        }
+
+        matches_types_and_senders(
+            fields,
+            self.types.as_deref(),
+            &self.not_types,
+            self.senders.as_deref(),
+            &self.not_senders,
+        )
+    }
+
+    /// Only keep the events of the given room that match this filter, up to the limit.
+    ///
+    /// Returns `true` if events were removed because of the limit.
+    fn retain_in_room<T>(&self, events: &mut Vec<Raw<T>>, room_id: &RoomId) -> bool {
+        events.retain(|event| self.matches_in_room(event, room_id));
+        truncate_to_limit(events, self.limit)
+    }
+}
+
+impl RoomFilter {
+    /// Whether the given room matches the `rooms` and `not_rooms` fields of this filter.
+    pub fn matches_room(&self, room_id: &RoomId) -> bool {
+        matches_allow_deny(room_id, self.rooms.as_deref(), &self.not_rooms)
+    }
+}
+
+impl Filter {
+    /// Whether the given event matches this filter.
+    ///
+    /// The `limit` field doesn't apply to single events.
+    pub fn matches<T>(&self, event: &Raw<T>) -> bool {
+        matches_types_and_senders(
+            &EventFields::from_raw(event),
+            self.types.as_deref(),
+            &self.not_types,
+            self.senders.as_deref(),
+            &self.not_senders,
+        )
+    }
+
+    /// Only keep the events that match this filter, up to the limit.
+    fn retain<T>(&self, events: &mut Vec<Raw<T>>) {
+        events.retain(|event| self.matches(event));
+        truncate_to_limit(events, self.limit);
+    }
+}
+
+impl FilterDefinition {
+    /// Apply this filter to the given response of the [`sync_events`] endpoint.
+    ///
+    /// This removes the rooms and events that don't match the filters, which is useful for
+    /// homeservers that compute the full response first, or for clients that sync with a broad
+    /// filter and serve narrower views from a cache.
+    ///
+    /// Filtering is stateless, so:
+    ///
+    /// * When the `limit` of the timeline filter is reached, the most recent events are kept and
+    ///   `limited` is set, but `prev_batch` is not updated.
+    /// * When lazy-loading of members is enabled, the membership events in the `state` are only
+    ///   kept for the senders of the events in the `timeline`, but redundant members are always
+    ///   included, regardless of `include_redundant_members`.
+    /// * When `unread_thread_notifications` is disabled in the timeline filter, the counts per
+    ///   thread are removed, but they are not added to the counts of the room.
+    ///
+    /// The `event_fields` and `event_format` fields are ignored.
+    ///
+    /// [`sync_events`]: crate::sync::sync_events
+    pub fn apply(&self, response: &mut v3::Response) {
+        self.presence.retain(&mut response.presence.events);
+        self.account_data.retain(&mut response.account_data.events);
+
+        let room_filter = &self.room;
+        let rooms = &mut response.rooms;
+
+        rooms.join.retain(|room_id, _| room_filter.matches_room(room_id));
+        rooms.invite.retain(|room_id, _| room_filter.matches_room(room_id));
+        rooms.knock.retain(|room_id, _| room_filter.matches_room(room_id));
+        if room_filter.include_leave {
+            rooms.leave.retain(|room_id, _| room_filter.matches_room(room_id));
+        } else {
+            rooms.leave.clear();
+        }
+
+        for (room_id, room) in &mut rooms.join {
+            room_filter.apply_to_timeline_and_state(room_id, &mut room.timeline, &mut room.state);
+            room_filter.account_data.retain_in_room(&mut room.account_data.events, room_id);
+            room_filter.ephemeral.retain_in_room(&mut room.ephemeral.events, room_id);
+
+            if !room_filter.timeline.unread_thread_notifications {
+                room.unread_thread_notifications.clear();
+            }
+        }
+
+        for (room_id, room) in &mut rooms.leave {
+            room_filter.apply_to_timeline_and_state(room_id, &mut room.timeline, &mut room.state);
+            room_filter.account_data.retain_in_room(&mut room.account_data.events, room_id);
+        }
+    }
+}
+
+impl RoomFilter {
+    fn apply_to_timeline_and_state(
+        &self,
+        room_id: &RoomId,
+        timeline: &mut v3::Timeline,
+        state: &mut v3::State,
+    ) {
+        if self.timeline.retain_in_room(&mut timeline.events, room_id) {
+            timeline.limited = true;
+        }
+
+        state.events.retain(|event| self.state.matches_in_room(event, room_id));
+
+        if matches!(self.state.lazy_load_options, LazyLoadOptions::Enabled { .. }) {
+            let senders: Vec<_> = timeline
+                .events
+                .iter()
+                .filter_map(|event| EventFields::from_raw(event).sender)
+                .collect();
+
+            state.events.retain(|event| {
+                let fields = EventFields::from_raw(event);
+                fields.event_type.as_deref() != Some("m.room.member")
+                    || fields.state_key.is_some_and(|state_key| {
+                        senders.iter().any(|sender| sender.as_str() == state_key)
+                    })
+            });
+        }
+
+        truncate_to_limit(&mut state.events, self.state.limit);
+    }
+}
+
+/// The fields of an event that are used by filters.
+#[derive(Default, Deserialize)]
+struct EventFields {
+    #[serde(rename = "type")]
+    event_type: Option<String>,
+    sender: Option<OwnedUserId>,
+    room_id: Option<OwnedRoomId>,
+    state_key: Option<String>,
+    content: Option<ContentFields>,
+}
+
+impl EventFields {
+    /// Get the fields of the given event.
+    ///
+    /// If the event can't be deserialized, all the fields are missing.
+    fn from_raw<T>(event: &Raw<T>) -> Self {
+        event.deserialize_as().unwrap_or_default()
+    }
+}
+
+/// The fields of the content of an event that are used by filters.
+#[derive(Deserialize)]
+struct ContentFields {
+    url: Option<IgnoredAny>,
+}
+
+/// Whether the given event fields match the given lists of types and senders.
+fn matches_types_and_senders(
+    fields: &EventFields,
+    types: Option<&[String]>,
+    not_types: &[String],
+    senders: Option<&[OwnedUserId]>,
+    not_senders: &[OwnedUserId],
+) -> bool {
+    let event_type = fields.event_type.as_deref().unwrap_or_default();
+    if not_types.iter().any(|pattern| matches_wildcard(pattern, event_type)) {
+        return false;
+    }
+    if types.is_some_and(|types| !types.iter().any(|pattern| matches_wildcard(pattern, event_type)))
+    {
+        return false;
+    }
+
+    match fields.sender.as_deref() {
+        Some(sender) => matches_allow_deny::<UserId, _>(sender, senders, not_senders),
+        None => senders.is_none(),
+    }
+}
+
+/// Whether the given value is not in `denied`, and is in `allowed` if it is set.
+fn matches_allow_deny<T, U>(value: &T, allowed: Option<&[U]>, denied: &[U]) -> bool
+where
+    T: PartialEq + ?Sized,
+    U: AsRef<T>,
+{
+    !denied.iter().any(|denied| denied.as_ref() == value)
+        && allowed.map_or(true, |allowed| allowed.iter().any(|allowed| allowed.as_ref() == value))
+}
+
+/// Whether the given string matches the given pattern, where `*` matches any sequence of
+/// characters.
+fn matches_wildcard(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+
+    // There is always at least one part.
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // The last part must match the end of the string.
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    // There was no wildcard, so the whole string must match.
+    rest.is_empty()
+}
+
+/// Keep only the last `limit` events.
+///
+/// Returns `true` if events were removed.
+fn truncate_to_limit<T>(events: &mut Vec<T>, limit: Option<UInt>) -> bool {
+    let Some(limit) = limit.and_then(|limit| usize::try_from(limit).ok()) else {
+        return false;
+    };
+
+    if events.len() <= limit {
+        return false;
+    }
+
+    events.drain(..events.len() - limit);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use js_int::uint;
+    use ruma_common::{owned_room_id, owned_user_id, room_id, serde::Raw};
+    use serde_json::{from_value as from_json_value, json, Value as JsonValue};
+
+    use super::matches_wildcard;
+    use crate::{
+        filter::{FilterDefinition, LazyLoadOptions, RoomEventFilter, UrlFilter},
+        sync::sync_events::v3,
+    };
+
+    fn raw<T>(json: JsonValue) -> Raw<T> {
+        from_json_value(json).unwrap()
+    }
+
+    fn message(sender: &str, body: &str) -> JsonValue {
+        json!({
+            "type": "m.room.message",
+            "event_id": format!("${body}"),
+            "sender": sender,
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": body },
+        })
+    }
+
+    fn member(user_id: &str) -> JsonValue {
+        json!({
+            "type": "m.room.member",
+            "event_id": format!("${user_id}"),
+            "sender": user_id,
+            "state_key": user_id,
+            "origin_server_ts": 1,
+            "content": { "membership": "join" },
+        })
+    }
+
+    #[test]
+    fn wildcard() {
+        assert!(matches_wildcard("m.room.message", "m.room.message"));
+        assert!(!matches_wildcard("m.room.message", "m.room.message.extra"));
+        assert!(!matches_wildcard("m.room.message", "m.room"));
+
+        assert!(matches_wildcard("m.room.*", "m.room.message"));
+        assert!(matches_wildcard("m.room.*", "m.room."));
+        assert!(!matches_wildcard("m.room.*", "m.call.invite"));
+
+        assert!(matches_wildcard("*", "anything"));
+        assert!(matches_wildcard("*.member", "m.room.member"));
+        assert!(!matches_wildcard("*.member", "m.room.members"));
+        assert!(matches_wildcard("m.*.mem*r", "m.room.member"));
+        assert!(!matches_wildcard("m.*.mem*r", "m.room.members"));
+    }
+
+    #[test]
+    fn room_event_filter_matches() {
+        let image: Raw<()> = raw(json!({
+            "type": "m.room.message",
+            "sender": "@alice:example.org",
+            "room_id": "!room:example.org",
+            "content": { "msgtype": "m.image", "body": "image.png", "url": "mxc://example.org/abc" },
+        }));
+
+        assert!(RoomEventFilter::empty().matches(&image));
+        assert!(!RoomEventFilter::ignore_all().matches(&image));
+
+        let mut filter = RoomEventFilter::empty();
+        filter.types = Some(vec!["m.room.*".to_owned()]);
+        filter.not_types = vec!["m.room.member".to_owned()];
+        filter.senders = Some(vec![owned_user_id!("@alice:example.org")]);
+        filter.url_filter = Some(UrlFilter::EventsWithUrl);
+        assert!(filter.matches(&image));
+
+        filter.not_rooms = vec![owned_room_id!("!room:example.org")];
+        assert!(!filter.matches(&image));
+        assert!(filter.matches_in_room(&image, room_id!("!other:example.org")));
+
+        filter.url_filter = Some(UrlFilter::EventsWithoutUrl);
+        assert!(!filter.matches_in_room(&image, room_id!("!other:example.org")));
+
+        filter.url_filter = None;
+        filter.not_senders = vec![owned_user_id!("@alice:example.org")];
+        assert!(!filter.matches_in_room(&image, room_id!("!other:example.org")));
+    }
+
+    #[test]
+    fn apply_to_sync_response() {
+        let mut response = v3::Response::new("s1".to_owned());
+        response.presence = from_json_value(json!({
+            "events": [
+                {
+                    "type": "m.presence",
+                    "sender": "@bob:example.org",
+                    "content": { "presence": "online" },
+                },
+            ],
+        }))
+        .unwrap();
+        response.rooms = from_json_value(json!({
+            "join": {
+                "!room:example.org": {
+                    "timeline": {
+                        "events": [
+                            message("@alice:example.org", "one"),
+                            message("@bob:example.org", "two"),
+                            message("@carl:example.org", "three"),
+                        ],
+                        "limited": false,
+                    },
+                    "state": {
+                        "events": [
+                            member("@alice:example.org"),
+                            member("@bob:example.org"),
+                            member("@carl:example.org"),
+                            member("@dan:example.org"),
+                        ],
+                    },
+                    "unread_thread_notifications": {
+                        "$thread": { "notification_count": 1 },
+                    },
+                },
+                "!other:example.org": {},
+            },
+            "leave": {
+                "!left:example.org": {},
+            },
+        }))
+        .unwrap();
+
+        let mut filter = FilterDefinition::empty();
+        filter.presence.not_types = vec!["m.*".to_owned()];
+        filter.room.not_rooms = vec![owned_room_id!("!other:example.org")];
+        filter.room.timeline.limit = Some(uint!(2));
+        filter.room.state.lazy_load_options =
+            LazyLoadOptions::Enabled { include_redundant_members: false };
+        filter.apply(&mut response);
+
+        assert!(response.presence.events.is_empty());
+        assert!(response.rooms.leave.is_empty());
+        assert_eq!(response.rooms.join.len(), 1);
+
+        let room = &response.rooms.join[room_id!("!room:example.org")];
+        assert!(room.timeline.limited);
+        let bodies: Vec<_> = room
+            .timeline
+            .events
+            .iter()
+            .map(|event| event.get_field::<JsonValue>("content").unwrap().unwrap()["body"].clone())
+            .collect();
+        assert_eq!(bodies, ["two", "three"]);
+
+        let members: Vec<_> = room
+            .state
+            .events
+            .iter()
+            .map(|event| event.get_field::<String>("state_key").unwrap().unwrap())
+            .collect();
+        assert_eq!(members, ["@bob:example.org", "@carl:example.org"]);
+        assert!(room.unread_thread_notifications.is_empty());
+    }
+}
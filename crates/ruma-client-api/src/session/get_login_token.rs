@@ -14,7 +14,7 @@ pub mod v1 {
         metadata,
     };
 
-    use crate::uiaa::{AuthData, UiaaResponse};
+    use crate::uiaa::{AuthData, UiaaRequest, UiaaResponse};
 
     const METADATA: Metadata = metadata! {
         method: POST,
@@ -56,6 +56,12 @@ pub mod v1 {
         }
     }
 
+    impl UiaaRequest for Request {
+        fn set_auth(&mut self, auth: Option<AuthData>) {
+            self.auth = auth;
+        }
+    }
+
     impl Response {
         /// Creates a new `Response` with the given expiration duration and login token.
         pub fn new(expires_in: Duration, login_token: String) -> Self {
@@ -4,6 +4,7 @@
 
 use js_int::UInt;
 use ruma_common::OwnedUserId;
+use ruma_events::push_rules::UnreadCounts;
 use serde::{self, Deserialize, Serialize};
 
 pub mod v3;
@@ -36,6 +37,15 @@ impl UnreadNotificationsCount {
     }
 }
 
+impl From<UnreadCounts> for UnreadNotificationsCount {
+    fn from(counts: UnreadCounts) -> Self {
+        Self {
+            highlight_count: Some(counts.highlights),
+            notification_count: Some(counts.notifications),
+        }
+    }
+}
+
 /// Information on E2E device updates.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
@@ -25,8 +25,11 @@ use crate::{
 };
 
 pub mod get_uiaa_fallback_page;
+mod session;
 mod user_serde;
 
+pub use self::session::{NoCompletableFlowError, UiaaSession};
+
 /// Information for one authentication stage.
 #[derive(Clone, Serialize)]
 #[non_exhaustive]
@@ -630,6 +633,15 @@ impl AuthFlow {
     }
 }
 
+/// A request to an endpoint protected by the User-Interactive Authentication API.
+///
+/// This allows to set the authentication data of the request generically, to retry it after each
+/// stage of a [`UiaaSession`].
+pub trait UiaaRequest {
+    /// Set the `auth` field of this request.
+    fn set_auth(&mut self, auth: Option<AuthData>);
+}
+
 /// Contains either a User-Interactive Authentication API response body or a Matrix error.
 #[derive(Clone, Debug)]
 #[allow(clippy::exhaustive_enums)]
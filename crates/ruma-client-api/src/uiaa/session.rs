@@ -0,0 +1,242 @@
+//! Helper to walk through the stages of a User-Interactive Authentication API flow.
+
+use std::fmt;
+
+use ruma_common::serde::JsonObject;
+use serde_json::value::RawValue as RawJsonValue;
+
+use super::{AuthData, AuthFlow, AuthType, UiaaInfo};
+use crate::error::StandardErrorBody;
+
+/// The state of a User-Interactive Authentication API session.
+///
+/// Given the [`UiaaInfo`] returned by the homeserver and the stages the application is able to
+/// complete, it chooses a flow that can be completed and keeps track of the completed stages and
+/// of the session key, to build the [`AuthData`] of the next stage.
+///
+/// After each stage, the homeserver responds with a new `UiaaInfo` that must be passed to
+/// [`UiaaSession::update()`], until the request succeeds.
+#[derive(Clone, Debug)]
+pub struct UiaaSession {
+    /// The latest information returned by the homeserver.
+    info: UiaaInfo,
+
+    /// The stages that the application can complete.
+    supported_stages: Vec<AuthType>,
+
+    /// The index of the chosen flow in `info.flows`.
+    flow: usize,
+}
+
+impl UiaaSession {
+    /// Creates a new `UiaaSession` from the given `UiaaInfo` and the stages that the application
+    /// can complete.
+    ///
+    /// The first flow advertised by the homeserver that contains all the stages that were already
+    /// completed and whose remaining stages are all supported is chosen.
+    ///
+    /// Returns an error if none of the flows can be completed.
+    pub fn new(
+        info: UiaaInfo,
+        supported_stages: impl IntoIterator<Item = AuthType>,
+    ) -> Result<Self, NoCompletableFlowError> {
+        let supported_stages: Vec<_> = supported_stages.into_iter().collect();
+        let flow = choose_flow(&info, &supported_stages)?;
+        Ok(Self { info, supported_stages, flow })
+    }
+
+    /// Update this session with the `UiaaInfo` returned by the homeserver after a stage was
+    /// attempted.
+    ///
+    /// If the chosen flow can't be completed anymore, another flow is chosen. Returns an error if
+    /// none of the flows can be completed, in which case the session is left unchanged.
+    pub fn update(&mut self, info: UiaaInfo) -> Result<(), NoCompletableFlowError> {
+        let current_flow = &self.info.flows[self.flow];
+        let flow = match info.flows.iter().position(|flow| flow.stages == current_flow.stages) {
+            Some(idx) if is_completable(&info.flows[idx], &info, &self.supported_stages) => idx,
+            _ => choose_flow(&info, &self.supported_stages)?,
+        };
+
+        self.info = info;
+        self.flow = flow;
+        Ok(())
+    }
+
+    /// The latest `UiaaInfo` returned by the homeserver.
+    pub fn info(&self) -> &UiaaInfo {
+        &self.info
+    }
+
+    /// The flow that was chosen for this session.
+    pub fn flow(&self) -> &AuthFlow {
+        &self.info.flows[self.flow]
+    }
+
+    /// The session key that must be sent back to the homeserver, if any.
+    pub fn session(&self) -> Option<&str> {
+        self.info.session.as_deref()
+    }
+
+    /// The stages that were already completed.
+    pub fn completed(&self) -> &[AuthType] {
+        &self.info.completed
+    }
+
+    /// The parameters that the homeserver provided to complete the stages.
+    pub fn params(&self) -> &RawJsonValue {
+        &self.info.params
+    }
+
+    /// The error returned by the homeserver for the last attempted stage, if any.
+    pub fn auth_error(&self) -> Option<&StandardErrorBody> {
+        self.info.auth_error.as_ref()
+    }
+
+    /// The next stage of the chosen flow that must be completed.
+    ///
+    /// Returns `None` if all the stages of the flow were completed.
+    pub fn next_stage(&self) -> Option<&AuthType> {
+        self.flow().stages.iter().find(|stage| !self.info.completed.contains(stage))
+    }
+
+    /// Whether all the stages of the chosen flow were completed.
+    pub fn is_complete(&self) -> bool {
+        self.next_stage().is_none()
+    }
+
+    /// Creates the `AuthData` to complete a stage of the given type with the given data.
+    ///
+    /// The session key of this session is added to the `AuthData`. `data` must not contain the
+    /// `type` and `session` fields.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the `auth_type` is known and `data` doesn't match the data of the
+    /// corresponding `AuthData` variant.
+    pub fn auth_data(
+        &self,
+        auth_type: &AuthType,
+        data: JsonObject,
+    ) -> serde_json::Result<AuthData> {
+        AuthData::new(auth_type.as_str(), self.info.session.clone(), data)
+    }
+
+    /// Creates the `AuthData` to acknowledge that a stage was completed with the fallback page.
+    ///
+    /// Returns `None` if the homeserver didn't provide a session key.
+    pub fn fallback_acknowledgement(&self) -> Option<AuthData> {
+        self.info.session.clone().map(AuthData::fallback_acknowledgement)
+    }
+}
+
+/// Whether the given flow contains all the completed stages of `info`, and all its remaining
+/// stages are supported.
+fn is_completable(flow: &AuthFlow, info: &UiaaInfo, supported_stages: &[AuthType]) -> bool {
+    info.completed.iter().all(|stage| flow.stages.contains(stage))
+        && flow
+            .stages
+            .iter()
+            .filter(|stage| !info.completed.contains(stage))
+            .all(|stage| supported_stages.contains(stage))
+}
+
+/// Get the index of the first completable flow of `info`.
+fn choose_flow(
+    info: &UiaaInfo,
+    supported_stages: &[AuthType],
+) -> Result<usize, NoCompletableFlowError> {
+    info.flows
+        .iter()
+        .position(|flow| is_completable(flow, info, supported_stages))
+        .ok_or(NoCompletableFlowError)
+}
+
+/// None of the flows advertised by the homeserver can be completed with the supported stages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct NoCompletableFlowError;
+
+impl fmt::Display for NoCompletableFlowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("none of the authentication flows can be completed with the supported stages")
+    }
+}
+
+impl std::error::Error for NoCompletableFlowError {}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use serde_json::{from_value as from_json_value, json};
+
+    use super::{NoCompletableFlowError, UiaaSession};
+    use crate::uiaa::{AuthData, AuthType, UiaaInfo};
+
+    fn uiaa_info(completed: &[&str], session: Option<&str>) -> UiaaInfo {
+        from_json_value(json!({
+            "flows": [
+                { "stages": ["m.login.recaptcha", "m.login.dummy"] },
+                { "stages": ["m.login.password"] },
+                { "stages": ["m.login.email.identity", "m.login.dummy"] },
+            ],
+            "completed": completed,
+            "params": {},
+            "session": session,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn choose_supported_flow() {
+        let session =
+            UiaaSession::new(uiaa_info(&[], None), [AuthType::Dummy, AuthType::EmailIdentity])
+                .unwrap();
+
+        assert_eq!(session.flow().stages, [AuthType::EmailIdentity, AuthType::Dummy]);
+        assert_eq!(session.next_stage(), Some(&AuthType::EmailIdentity));
+        assert!(!session.is_complete());
+    }
+
+    #[test]
+    fn no_completable_flow() {
+        assert_matches!(
+            UiaaSession::new(uiaa_info(&[], None), [AuthType::Dummy]),
+            Err(NoCompletableFlowError)
+        );
+    }
+
+    #[test]
+    fn walk_flow() {
+        let mut session = UiaaSession::new(
+            uiaa_info(&[], Some("abcdef")),
+            [AuthType::ReCaptcha, AuthType::Dummy, AuthType::Password],
+        )
+        .unwrap();
+        assert_eq!(session.next_stage(), Some(&AuthType::ReCaptcha));
+
+        session.update(uiaa_info(&["m.login.recaptcha"], Some("abcdef"))).unwrap();
+        assert_eq!(session.completed(), [AuthType::ReCaptcha]);
+        assert_eq!(session.next_stage(), Some(&AuthType::Dummy));
+
+        let auth_data = session.auth_data(&AuthType::Dummy, Default::default()).unwrap();
+        assert_eq!(auth_data.session(), Some("abcdef"));
+        assert_matches!(auth_data, AuthData::Dummy(_));
+
+        session.update(uiaa_info(&["m.login.recaptcha", "m.login.dummy"], Some("abcdef"))).unwrap();
+        assert_eq!(session.next_stage(), None);
+        assert!(session.is_complete());
+    }
+
+    #[test]
+    fn switch_flow_when_completed_stage_differs() {
+        let mut session =
+            UiaaSession::new(uiaa_info(&[], Some("abcdef")), [AuthType::Password, AuthType::Dummy])
+                .unwrap();
+        assert_eq!(session.flow().stages, [AuthType::Password]);
+
+        // The server accepted a stage from another flow.
+        session.update(uiaa_info(&["m.login.recaptcha"], Some("abcdef"))).unwrap();
+        assert_eq!(session.flow().stages, [AuthType::ReCaptcha, AuthType::Dummy]);
+        assert_eq!(session.next_stage(), Some(&AuthType::Dummy));
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    future::Future,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -6,25 +7,41 @@ use std::{
 use assign::assign;
 use async_stream::try_stream;
 use futures_core::stream::Stream;
+use futures_timer::Delay;
 use ruma_client_api::{
     account::register::{self, RegistrationKind},
-    session::login::{self, v3::LoginInfo},
+    session::{
+        login::{self, v3::LoginInfo},
+        refresh_token,
+    },
     sync::sync_events,
-    uiaa::UserIdentifier,
+    uiaa::{AuthData, AuthType, UiaaRequest, UiaaResponse, UiaaSession, UserIdentifier},
 };
 use ruma_common::{
-    api::{MatrixVersion, OutgoingRequest, SendAccessToken},
+    api::{
+        error::FromHttpResponseError, AuthScheme, MatrixVersion, OutgoingRequest, SendAccessToken,
+    },
     presence::PresenceState,
     DeviceId, UserId,
 };
+use tracing::{warn, Instrument};
 
 use crate::{
-    add_user_id_to_query, send_customized_request, Error, HttpClient, ResponseError, ResponseResult,
+    add_user_id_to_query, deserialize_response,
+    retry::{RetryAttempt, RetryCause, RetryPolicy},
+    send_customized_request, send_span, serialize_request, Error, HttpClient, ResponseError,
+    ResponseResult,
 };
 
 mod builder;
+mod session;
+#[cfg(feature = "unstable-msc3575")]
+mod sliding_sync;
 
-pub use self::builder::ClientBuilder;
+use self::session::{is_soft_logout, SessionCallback};
+#[cfg(feature = "unstable-msc3575")]
+pub use self::sliding_sync::{SlidingSync, SlidingSyncError};
+pub use self::{builder::ClientBuilder, session::Session};
 
 /// A client for the Matrix client-server API.
 #[derive(Clone, Debug)]
@@ -42,8 +59,20 @@ struct ClientData<C> {
     /// The access token, if logged in.
     access_token: Mutex<Option<String>>,
 
+    /// The session, if logged in with a device.
+    session: Mutex<Option<Session>>,
+
+    /// Lock held while the access token is being refreshed.
+    refresh_lock: futures_util::lock::Mutex<()>,
+
+    /// The callback to call when the session changes, if any.
+    session_callback: Option<SessionCallback>,
+
     /// The (known) Matrix versions the homeserver supports.
     supported_matrix_versions: Vec<MatrixVersion>,
+
+    /// The policy used to retry failed requests, if any.
+    retry_policy: Option<Box<dyn RetryPolicy>>,
 }
 
 impl Client<()> {
@@ -60,6 +89,32 @@ impl<C> Client<C> {
     pub fn access_token(&self) -> Option<String> {
         self.0.access_token.lock().expect("session mutex was poisoned").clone()
     }
+
+    /// Get a copy of the current session, if any.
+    ///
+    /// The session is only available if the client was built with a session, or after logging in
+    /// or registering with this client.
+    pub fn session(&self) -> Option<Session> {
+        self.0.session.lock().expect("session mutex was poisoned").clone()
+    }
+
+    /// Replace the current session and notify the session callback.
+    fn set_session(&self, session: Session) {
+        *self.0.access_token.lock().expect("session mutex was poisoned") =
+            Some(session.access_token.clone());
+
+        *self.0.session.lock().expect("session mutex was poisoned") = Some(session.clone());
+
+        if let Some(callback) = &self.0.session_callback {
+            (callback.0)(&session);
+        }
+    }
+
+    /// Replace the current access token, forgetting the current session.
+    fn set_access_token(&self, access_token: Option<String>) {
+        *self.0.session.lock().expect("session mutex was poisoned") = None;
+        *self.0.access_token.lock().expect("session mutex was poisoned") = access_token;
+    }
 }
 
 impl<C: HttpClient> Client<C> {
@@ -69,30 +124,139 @@ impl<C: HttpClient> Client<C> {
     }
 
     /// Makes a request to a Matrix API endpoint including additional URL parameters.
+    ///
+    /// `customize` is called again every time the request is sent again, which happens if the
+    /// request is retried according to the [`RetryPolicy`] set when building this client, or if
+    /// the access token expired and was refreshed.
     pub async fn send_customized_request<R, F>(
         &self,
         request: R,
-        customize: F,
+        mut customize: F,
     ) -> ResponseResult<C, R>
     where
         R: OutgoingRequest,
-        F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
+        F: FnMut(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
     {
+        let method = R::METADATA.method;
+        let uses_access_token = R::METADATA.authentication == AuthScheme::AccessToken;
+
+        if uses_access_token && self.session().is_some_and(|session| session.should_refresh()) {
+            let access_token = self.access_token();
+            if let Err(error) = self.refresh_session(access_token.as_deref()).await {
+                warn!(error_kind = ?error.error_kind(), "Failed to refresh expiring access token");
+            }
+        }
+
+        let mut attempt = 1;
+        let mut refreshed = false;
+        loop {
+            let access_token = self.access_token();
+            let send_access_token = match access_token.as_deref() {
+                Some(at) => SendAccessToken::IfRequired(at),
+                None => SendAccessToken::None,
+            };
+
+            let http_req = serialize_request::<C, R, _>(
+                &self.0.homeserver_url,
+                send_access_token,
+                &self.0.supported_matrix_versions,
+                request.clone(),
+                &mut customize,
+            )?;
+
+            let http_res = self
+                .0
+                .http_client
+                .send_http_request(http_req)
+                .instrument(send_span::<C, R>(&self.0.homeserver_url))
+                .await;
+
+            if uses_access_token && !refreshed && http_res.as_ref().is_ok_and(is_soft_logout) {
+                // Only try to refresh the access token once per request.
+                refreshed = true;
+
+                match self.refresh_session(access_token.as_deref()).await {
+                    Ok(()) => continue,
+                    Err(error) => {
+                        warn!(error_kind = ?error.error_kind(), "Failed to refresh expired access token");
+                    }
+                }
+            }
+
+            if let Some(retry_policy) = &self.0.retry_policy {
+                let cause = match &http_res {
+                    Ok(res) => RetryCause::from_response(res),
+                    Err(_) => Some(RetryCause::Network),
+                };
+
+                if let Some(cause) = cause {
+                    let retry_attempt = RetryAttempt::new(attempt, &method, cause);
+
+                    if let Some(delay) = retry_policy.retry_delay(&retry_attempt) {
+                        warn!(
+                            attempt,
+                            cause = ?retry_attempt.cause,
+                            ?delay,
+                            "Request failed, retrying",
+                        );
+
+                        Delay::new(delay).await;
+                        attempt += 1;
+                        continue;
+                    }
+                }
+            }
+
+            return deserialize_response::<C, R>(http_res.map_err(Error::Response)?);
+        }
+    }
+
+    /// Refresh the access token of the current session.
+    ///
+    /// This is done automatically when the access token is about to expire or when the homeserver
+    /// reports that it has expired, so this method generally doesn't need to be called manually.
+    ///
+    /// Returns [`Error::AuthenticationRequired`] if there is no session or it doesn't have a
+    /// refresh token.
+    pub async fn refresh_access_token(
+        &self,
+    ) -> Result<(), Error<C::Error, ruma_client_api::Error>> {
         let access_token = self.access_token();
-        let send_access_token = match access_token.as_deref() {
-            Some(at) => SendAccessToken::IfRequired(at),
-            None => SendAccessToken::None,
+        self.refresh_session(access_token.as_deref()).await
+    }
+
+    /// Refresh the access token of the current session, unless it is no longer `stale_token`
+    /// because it was refreshed concurrently.
+    async fn refresh_session(
+        &self,
+        stale_token: Option<&str>,
+    ) -> Result<(), Error<C::Error, ruma_client_api::Error>> {
+        let _guard = self.0.refresh_lock.lock().await;
+
+        let Some(mut session) = self.session() else {
+            return Err(Error::AuthenticationRequired);
+        };
+        if stale_token != Some(session.access_token.as_str()) {
+            return Ok(());
+        }
+        let Some(refresh_token) = session.refresh_token.clone() else {
+            return Err(Error::AuthenticationRequired);
         };
 
-        send_customized_request(
+        let response = send_customized_request(
             &self.0.http_client,
             &self.0.homeserver_url,
-            send_access_token,
+            SendAccessToken::None,
             &self.0.supported_matrix_versions,
-            request,
-            customize,
+            refresh_token::v3::Request::new(refresh_token),
+            |_| Ok(()),
         )
-        .await
+        .await?;
+
+        session.apply_refresh_response(response);
+        self.set_session(session);
+
+        Ok(())
     }
 
     /// Makes a request to a Matrix API endpoint as a virtual user.
@@ -107,10 +271,90 @@ impl<C: HttpClient> Client<C> {
         self.send_customized_request(request, add_user_id_to_query::<C, R>(user_id)).await
     }
 
+    /// Makes a request to a Matrix API endpoint protected by the User-Interactive Authentication
+    /// API.
+    ///
+    /// Every time the homeserver responds that authentication is required, a flow that only uses
+    /// `supported_stages` is chosen and `authenticate` is called with the current state of the
+    /// [`UiaaSession`]. The request is then sent again with the returned [`AuthData`], which would
+    /// usually complete [`UiaaSession::next_stage()`]. If `authenticate` returns `None`, or if none
+    /// of the flows can be completed, the error of the homeserver is returned.
+    ///
+    /// # Example:
+    ///
+    /// ```no_run
+    /// use ruma_client_api::{
+    ///     account::deactivate,
+    ///     uiaa::{AuthData, AuthType, Password, UserIdentifier},
+    /// };
+    ///
+    /// # let homeserver_url = "https://example.com".to_owned();
+    /// # async {
+    /// # let client = ruma_client::Client::builder()
+    /// #     .homeserver_url(homeserver_url)
+    /// #     .build::<ruma_client::http_client::Dummy>()
+    /// #     .await
+    /// #     .unwrap();
+    /// let response = client
+    ///     .send_uiaa_request(deactivate::v3::Request::new(), [AuthType::Password], |session| {
+    ///         let mut password = Password::new(
+    ///             UserIdentifier::UserIdOrLocalpart("alice".to_owned()),
+    ///             "hunter2".to_owned(),
+    ///         );
+    ///         password.session = session.session().map(ToOwned::to_owned);
+    ///         async move { Some(AuthData::Password(password)) }
+    ///     })
+    ///     .await?;
+    /// # Result::<(), ruma_client::Error<_, ruma_client_api::uiaa::UiaaResponse>>::Ok(())
+    /// # };
+    /// ```
+    pub async fn send_uiaa_request<R, F, Fut>(
+        &self,
+        mut request: R,
+        supported_stages: impl IntoIterator<Item = AuthType>,
+        mut authenticate: F,
+    ) -> ResponseResult<C, R>
+    where
+        R: OutgoingRequest<EndpointError = UiaaResponse> + UiaaRequest,
+        F: FnMut(&UiaaSession) -> Fut,
+        Fut: Future<Output = Option<AuthData>>,
+    {
+        let supported_stages: Vec<_> = supported_stages.into_iter().collect();
+        let mut session: Option<UiaaSession> = None;
+
+        loop {
+            let info = match self.send_request(request.clone()).await {
+                Err(Error::FromHttpResponse(FromHttpResponseError::Server(
+                    UiaaResponse::AuthResponse(info),
+                ))) => info,
+                result => return result,
+            };
+            let uiaa_error = |info| {
+                Error::FromHttpResponse(FromHttpResponseError::Server(UiaaResponse::AuthResponse(
+                    info,
+                )))
+            };
+
+            let updated = match session.take() {
+                Some(mut session) => session.update(info.clone()).map(|()| session),
+                None => UiaaSession::new(info.clone(), supported_stages.iter().cloned()),
+            };
+            let Ok(updated) = updated else {
+                return Err(uiaa_error(info));
+            };
+
+            match authenticate(session.insert(updated)).await {
+                Some(auth) => request.set_auth(Some(auth)),
+                None => return Err(uiaa_error(info)),
+            }
+        }
+    }
+
     /// Log in with a username and password.
     ///
-    /// In contrast to [`send_request`][Self::send_request], this method stores the access token
-    /// returned by the endpoint in this client, in addition to returning it.
+    /// In contrast to [`send_request`][Self::send_request], this method stores the session
+    /// returned by the endpoint in this client, in addition to returning it. A refresh token is
+    /// requested, so the access token can be refreshed automatically when it expires.
     pub async fn log_in(
         &self,
         user: &str,
@@ -126,33 +370,34 @@ impl<C: HttpClient> Client<C> {
             .send_request(assign!(login::v3::Request::new(login_info), {
                 device_id: device_id.map(ToOwned::to_owned),
                 initial_device_display_name: initial_device_display_name.map(ToOwned::to_owned),
+                refresh_token: true,
             }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = Some(response.access_token.clone());
+        self.set_session(Session::from(&response));
 
         Ok(response)
     }
 
     /// Register as a guest.
     ///
-    /// In contrast to [`send_request`][Self::send_request], this method stores the access token
+    /// In contrast to [`send_request`][Self::send_request], this method stores the session
     /// returned by the endpoint in this client, in addition to returning it.
     pub async fn register_guest(
         &self,
-    ) -> Result<register::v3::Response, Error<C::Error, ruma_client_api::uiaa::UiaaResponse>> {
+    ) -> Result<register::v3::Response, Error<C::Error, UiaaResponse>> {
         let response = self
             .send_request(assign!(register::v3::Request::new(), { kind: RegistrationKind::Guest }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = response.access_token.clone();
+        self.store_register_response(&response);
 
         Ok(response)
     }
 
     /// Register as a new user on this server.
     ///
-    /// In contrast to [`send_request`][Self::send_request], this method stores the access token
+    /// In contrast to [`send_request`][Self::send_request], this method stores the session
     /// returned by the endpoint in this client, in addition to returning it.
     ///
     /// The username is the local part of the returned user_id. If it is omitted from this request,
@@ -161,7 +406,7 @@ impl<C: HttpClient> Client<C> {
         &self,
         username: Option<&str>,
         password: &str,
-    ) -> Result<register::v3::Response, Error<C::Error, ruma_client_api::uiaa::UiaaResponse>> {
+    ) -> Result<register::v3::Response, Error<C::Error, UiaaResponse>> {
         let response = self
             .send_request(assign!(register::v3::Request::new(), {
                 username: username.map(ToOwned::to_owned),
@@ -169,11 +414,18 @@ impl<C: HttpClient> Client<C> {
             }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = response.access_token.clone();
+        self.store_register_response(&response);
 
         Ok(response)
     }
 
+    fn store_register_response(&self, response: &register::v3::Response) {
+        match Session::from_register_response(response) {
+            Some(session) => self.set_session(session),
+            None => self.set_access_token(response.access_token.clone()),
+        }
+    }
+
     /// Convenience method that represents repeated calls to the sync_events endpoint as a stream.
     ///
     /// # Example:
@@ -226,4 +478,72 @@ impl<C: HttpClient> Client<C> {
             }
         }
     }
+
+    /// Convenience method that represents repeated calls to the sliding sync endpoint
+    /// ([MSC3575]) as a stream.
+    ///
+    /// The `pos` token, the rooms of each list and the to-device `since` token are tracked in the
+    /// given [`SlidingSync`], which can be used to change the lists, room subscriptions and
+    /// extensions while the stream is running. If the server doesn't know the `pos` token anymore,
+    /// a new connection is started transparently. If a response contains an invalid list update,
+    /// the stream returns an [`Error::SlidingSync`] and the state of `sliding_sync` is not updated.
+    ///
+    /// # Example:
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// # use js_int::uint;
+    /// # use ruma_client_api::sync::sync_events::v4;
+    /// # use tokio_stream::{StreamExt as _};
+    /// # let homeserver_url = "https://example.com".to_owned();
+    /// # async {
+    /// # let client = ruma_client::Client::builder()
+    /// #     .homeserver_url(homeserver_url)
+    /// #     .build::<ruma_client::http_client::Dummy>()
+    /// #     .await?;
+    /// let sliding_sync = ruma_client::SlidingSync::new();
+    /// sliding_sync.set_timeout(Some(Duration::from_secs(30)));
+    ///
+    /// let mut list = v4::SyncRequestList::default();
+    /// list.ranges = vec![(uint!(0), uint!(19))];
+    /// sliding_sync.add_list("all_rooms", list);
+    ///
+    /// let mut sync_stream = Box::pin(client.sliding_sync(sliding_sync.clone()));
+    /// while let Some(response) = sync_stream.try_next().await? {
+    ///     let rooms = sliding_sync.list_rooms("all_rooms");
+    ///     // Do something with the data in the response...
+    /// }
+    /// # Result::<(), ruma_client::Error<_, _>>::Ok(())
+    /// # };
+    /// ```
+    ///
+    /// [MSC3575]: https://github.com/matrix-org/matrix-spec-proposals/pull/3575
+    #[cfg(feature = "unstable-msc3575")]
+    pub fn sliding_sync(
+        &self,
+        sliding_sync: SlidingSync,
+    ) -> impl Stream<Item = Result<sync_events::v4::Response, Error<C::Error, ruma_client_api::Error>>>
+           + '_ {
+        use ruma_client_api::error::ErrorKind;
+
+        try_stream! {
+            loop {
+                let request = sliding_sync.build_request();
+                let response = match self.send_request(request.clone()).await {
+                    Ok(response) => response,
+                    Err(error)
+                        if error.error_kind() == Some(&ErrorKind::UnknownPos)
+                            && sliding_sync.reset() =>
+                    {
+                        continue;
+                    }
+                    Err(error) => Err(error)?,
+                };
+
+                sliding_sync.apply_response(&request, &response).map_err(Error::SlidingSync)?;
+                yield response;
+            }
+        }
+    }
 }
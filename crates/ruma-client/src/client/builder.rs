@@ -3,8 +3,8 @@ use std::sync::{Arc, Mutex};
 use ruma_client_api::discovery::get_supported_versions;
 use ruma_common::api::{MatrixVersion, SendAccessToken};
 
-use super::{Client, ClientData};
-use crate::{DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt};
+use super::{session::SessionCallback, Client, ClientData, Session};
+use crate::{retry::RetryPolicy, DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt};
 
 /// A [`Client`] builder.
 ///
@@ -12,12 +12,22 @@ use crate::{DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt};
 pub struct ClientBuilder {
     homeserver_url: Option<String>,
     access_token: Option<String>,
+    session: Option<Session>,
+    session_callback: Option<SessionCallback>,
     supported_matrix_versions: Option<Vec<MatrixVersion>>,
+    retry_policy: Option<Box<dyn RetryPolicy>>,
 }
 
 impl ClientBuilder {
     pub(super) fn new() -> Self {
-        Self { homeserver_url: None, access_token: None, supported_matrix_versions: None }
+        Self {
+            homeserver_url: None,
+            access_token: None,
+            session: None,
+            session_callback: None,
+            supported_matrix_versions: None,
+            retry_policy: None,
+        }
     }
 
     /// Set the homeserver URL.
@@ -33,6 +43,24 @@ impl ClientBuilder {
         Self { access_token, ..self }
     }
 
+    /// Set the session to restore.
+    ///
+    /// This takes precedence over [`access_token()`][Self::access_token]. Unlike a bare access
+    /// token, a session with a refresh token allows the client to refresh its access token
+    /// automatically when it expires.
+    pub fn session(self, session: Option<Session>) -> Self {
+        Self { session, ..self }
+    }
+
+    /// Set a callback to call every time the session of the client changes.
+    ///
+    /// This happens when logging in, when registering and when the access token is refreshed. The
+    /// callback should persist the session, so that it can be restored with
+    /// [`session()`][Self::session] after a restart.
+    pub fn on_session_change(self, callback: impl Fn(&Session) + Send + Sync + 'static) -> Self {
+        Self { session_callback: Some(SessionCallback(Box::new(callback))), ..self }
+    }
+
     /// Set the supported Matrix versions.
     ///
     /// This method generally *shouldn't* be called. The [`build()`][Self::build] or
@@ -42,6 +70,17 @@ impl ClientBuilder {
         Self { supported_matrix_versions: Some(versions), ..self }
     }
 
+    /// Set the policy used to retry failed requests.
+    ///
+    /// By default, failed requests are never retried and errors are returned to the caller
+    /// directly. Use [`ExponentialBackoff`] for a policy that retries rate-limited requests and
+    /// idempotent requests that failed due to transient errors.
+    ///
+    /// [`ExponentialBackoff`]: crate::retry::ExponentialBackoff
+    pub fn retry_policy(self, policy: impl RetryPolicy + 'static) -> Self {
+        Self { retry_policy: Some(Box::new(policy)), ..self }
+    }
+
     /// Finish building the [`Client`].
     ///
     /// Uses [`DefaultConstructibleHttpClient::default()`] to create an HTTP client instance.
@@ -88,8 +127,17 @@ impl ClientBuilder {
         Ok(Client(Arc::new(ClientData {
             homeserver_url,
             http_client,
-            access_token: Mutex::new(self.access_token),
+            access_token: Mutex::new(
+                self.session
+                    .as_ref()
+                    .map(|session| session.access_token.clone())
+                    .or(self.access_token),
+            ),
+            session: Mutex::new(self.session),
+            refresh_lock: Default::default(),
+            session_callback: self.session_callback,
             supported_matrix_versions,
+            retry_policy: self.retry_policy,
         })))
     }
 }
@@ -0,0 +1,200 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use ruma_client_api::{
+    account::register,
+    session::{login, refresh_token},
+};
+use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+
+/// How long before its expiry an access token is refreshed.
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// The session of a logged-in device.
+///
+/// This type can be serialized to persist the session, so that it can be restored later with
+/// [`ClientBuilder::session()`](crate::ClientBuilder::session). Since the access token and refresh
+/// token can change during the lifetime of a [`Client`](crate::Client), the session should be
+/// persisted every time the callback set with
+/// [`ClientBuilder::on_session_change()`](crate::ClientBuilder::on_session_change) is called.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Session {
+    /// The ID of the logged-in user.
+    pub user_id: OwnedUserId,
+
+    /// The ID of the logged-in device.
+    pub device_id: OwnedDeviceId,
+
+    /// The access token used to authenticate requests.
+    pub access_token: String,
+
+    /// The token used to obtain a new access token when the current one expires, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+
+    /// The time at which the access token expires.
+    ///
+    /// If this is `None`, the access token does not expire.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl Session {
+    /// Creates a new `Session` with the given user ID, device ID and access token, that never
+    /// expires.
+    pub fn new(user_id: OwnedUserId, device_id: OwnedDeviceId, access_token: String) -> Self {
+        Self { user_id, device_id, access_token, refresh_token: None, expires_at: None }
+    }
+
+    /// Creates a new `Session` from the response to a [`register`] request.
+    ///
+    /// Returns `None` if the response doesn't contain an access token and a device ID, which is
+    /// the case if `inhibit_login` was set in the request.
+    pub fn from_register_response(response: &register::v3::Response) -> Option<Self> {
+        Some(Self {
+            user_id: response.user_id.clone(),
+            device_id: response.device_id.clone()?,
+            access_token: response.access_token.clone()?,
+            refresh_token: response.refresh_token.clone(),
+            expires_at: response.expires_in.and_then(expires_at),
+        })
+    }
+
+    /// Whether the access token should be refreshed before being used.
+    ///
+    /// This is the case if the access token expires soon and a refresh token is available.
+    pub fn should_refresh(&self) -> bool {
+        self.refresh_token.is_some()
+            && self
+                .expires_at
+                .and_then(|expires_at| expires_at.to_system_time())
+                .and_then(|expires_at| expires_at.checked_sub(REFRESH_MARGIN))
+                .is_some_and(|refresh_at| refresh_at <= SystemTime::now())
+    }
+
+    /// Update this session with the response to a [`refresh_token`] request.
+    pub(crate) fn apply_refresh_response(&mut self, response: refresh_token::v3::Response) {
+        self.access_token = response.access_token;
+        if let Some(refresh_token) = response.refresh_token {
+            self.refresh_token = Some(refresh_token);
+        }
+        self.expires_at = response.expires_in_ms.and_then(expires_at);
+    }
+}
+
+impl From<&login::v3::Response> for Session {
+    fn from(response: &login::v3::Response) -> Self {
+        Self {
+            user_id: response.user_id.clone(),
+            device_id: response.device_id.clone(),
+            access_token: response.access_token.clone(),
+            refresh_token: response.refresh_token.clone(),
+            expires_at: response.expires_in.and_then(expires_at),
+        }
+    }
+}
+
+/// Compute the expiry time of an access token valid for the given duration from now.
+fn expires_at(expires_in: Duration) -> Option<MilliSecondsSinceUnixEpoch> {
+    MilliSecondsSinceUnixEpoch::from_system_time(SystemTime::now() + expires_in)
+}
+
+/// Whether the given response is an `M_UNKNOWN_TOKEN` error with `soft_logout` set to `true`.
+///
+/// This is the error returned by homeservers when the access token has expired.
+pub(crate) fn is_soft_logout<T: AsRef<[u8]>>(response: &http::Response<T>) -> bool {
+    #[derive(Deserialize)]
+    struct UnknownTokenBody<'a> {
+        errcode: &'a str,
+        #[serde(default)]
+        soft_logout: bool,
+    }
+
+    response.status() == http::StatusCode::UNAUTHORIZED
+        && serde_json::from_slice::<UnknownTokenBody<'_>>(response.body().as_ref())
+            .is_ok_and(|body| body.errcode == "M_UNKNOWN_TOKEN" && body.soft_logout)
+}
+
+/// A callback called every time the session of a [`Client`](crate::Client) changes.
+pub(crate) struct SessionCallback(pub(crate) Box<dyn Fn(&Session) + Send + Sync>);
+
+impl fmt::Debug for SessionCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCallback").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use ruma_client_api::session::refresh_token;
+    use ruma_common::{owned_device_id, owned_user_id, MilliSecondsSinceUnixEpoch};
+
+    use super::{is_soft_logout, Session};
+
+    fn session() -> Session {
+        Session::new(
+            owned_user_id!("@alice:example.com"),
+            owned_device_id!("ABCDEF"),
+            "access".to_owned(),
+        )
+    }
+
+    #[test]
+    fn should_refresh() {
+        let mut session = session();
+        assert!(!session.should_refresh());
+
+        session.expires_at = MilliSecondsSinceUnixEpoch::from_system_time(
+            SystemTime::now() + Duration::from_secs(10),
+        );
+        assert!(!session.should_refresh());
+
+        session.refresh_token = Some("refresh".to_owned());
+        assert!(session.should_refresh());
+
+        session.expires_at = MilliSecondsSinceUnixEpoch::from_system_time(
+            SystemTime::now() + Duration::from_secs(3600),
+        );
+        assert!(!session.should_refresh());
+    }
+
+    #[test]
+    fn apply_refresh_response() {
+        let mut session = session();
+        session.refresh_token = Some("refresh".to_owned());
+
+        let mut response = refresh_token::v3::Response::new("new_access".to_owned());
+        response.expires_in_ms = Some(Duration::from_secs(60));
+        session.apply_refresh_response(response);
+
+        assert_eq!(session.access_token, "new_access");
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh"));
+        assert!(session.expires_at.is_some());
+    }
+
+    #[test]
+    fn soft_logout() {
+        let response = |status, body: &str| {
+            http::Response::builder().status(status).body(body.as_bytes().to_owned()).unwrap()
+        };
+
+        assert!(is_soft_logout(&response(
+            http::StatusCode::UNAUTHORIZED,
+            r#"{ "errcode": "M_UNKNOWN_TOKEN", "error": "Expired", "soft_logout": true }"#
+        )));
+        assert!(!is_soft_logout(&response(
+            http::StatusCode::UNAUTHORIZED,
+            r#"{ "errcode": "M_UNKNOWN_TOKEN", "error": "Logged out" }"#
+        )));
+        assert!(!is_soft_logout(&response(
+            http::StatusCode::FORBIDDEN,
+            r#"{ "errcode": "M_FORBIDDEN", "error": "Nope" }"#
+        )));
+    }
+}
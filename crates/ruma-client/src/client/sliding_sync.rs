@@ -0,0 +1,586 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use js_int::UInt;
+use ruma_client_api::sync::sync_events::v4;
+use ruma_common::{OwnedRoomId, RoomId};
+
+/// The maximum number of rooms in a list that is accepted from the server.
+///
+/// Counts and indices above this limit are rejected, so a malformed response can't make the client
+/// allocate an arbitrary amount of memory.
+const MAX_LIST_LEN: u64 = 1_000_000;
+
+/// The configuration and state of a sliding sync connection ([MSC3575]).
+///
+/// This type keeps track of the `pos` token, the rooms in each list and the to-device `since`
+/// token between requests made by [`Client::sliding_sync()`](crate::Client::sliding_sync). It is
+/// cheap to clone, and all clones share the same state, so the lists, room subscriptions and
+/// extensions can be changed while the sync loop is running. Changes are sent to the homeserver
+/// with the next request.
+///
+/// [MSC3575]: https://github.com/matrix-org/matrix-spec-proposals/pull/3575
+#[derive(Clone, Debug, Default)]
+pub struct SlidingSync(Arc<Mutex<SlidingSyncState>>);
+
+#[derive(Debug, Default)]
+struct SlidingSyncState {
+    /// The `pos` token of the last response.
+    pos: Option<String>,
+
+    /// The connection ID sent with every request.
+    conn_id: Option<String>,
+
+    /// The maximum time to poll before responding to a request.
+    timeout: Option<Duration>,
+
+    /// The lists, by name.
+    lists: BTreeMap<String, SlidingSyncList>,
+
+    /// The active room subscriptions.
+    room_subscriptions: BTreeMap<OwnedRoomId, v4::RoomSubscription>,
+
+    /// The rooms to unsubscribe from.
+    ///
+    /// They are sent with every request until a response is received for one of them.
+    unsubscribe_rooms: BTreeSet<OwnedRoomId>,
+
+    /// The extensions configuration.
+    extensions: v4::ExtensionsConfig,
+
+    /// The `next_batch` token of the last to-device extension response.
+    to_device_since: Option<String>,
+}
+
+#[derive(Debug)]
+struct SlidingSyncList {
+    /// The configuration of the list sent to the server.
+    config: v4::SyncRequestList,
+
+    /// The known rooms of the list, by index.
+    rooms: Vec<Option<OwnedRoomId>>,
+
+    /// The total number of rooms matching the list's filters.
+    count: UInt,
+}
+
+impl SlidingSync {
+    /// Creates a new `SlidingSync` without any lists, room subscriptions or extensions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the connection ID to send with every request.
+    ///
+    /// This is necessary to use several sliding sync connections concurrently.
+    pub fn set_conn_id(&self, conn_id: Option<String>) {
+        self.state().conn_id = conn_id;
+    }
+
+    /// Set the maximum time to poll before the server responds to a request.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.state().timeout = timeout;
+    }
+
+    /// The `pos` token that will be sent with the next request, if any.
+    pub fn pos(&self) -> Option<String> {
+        self.state().pos.clone()
+    }
+
+    /// Add a list with the given name and configuration.
+    ///
+    /// If a list with the same name already exists, its configuration is replaced but the known
+    /// rooms are kept.
+    pub fn add_list(&self, name: impl Into<String>, config: v4::SyncRequestList) {
+        let mut state = self.state();
+        match state.lists.entry(name.into()) {
+            Entry::Vacant(entry) => {
+                entry.insert(SlidingSyncList { config, rooms: Vec::new(), count: UInt::MIN });
+            }
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().config = config;
+            }
+        }
+    }
+
+    /// Remove the list with the given name.
+    ///
+    /// Returns `true` if the list existed.
+    pub fn remove_list(&self, name: &str) -> bool {
+        self.state().lists.remove(name).is_some()
+    }
+
+    /// Set the ranges of the list with the given name.
+    ///
+    /// Returns `false` if there is no list with this name.
+    pub fn set_list_ranges(&self, name: &str, ranges: Vec<(UInt, UInt)>) -> bool {
+        match self.state().lists.get_mut(name) {
+            Some(list) => {
+                list.config.ranges = ranges;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The known rooms of the list with the given name, by index.
+    ///
+    /// Entries outside of the requested ranges, or that were invalidated by the server, are
+    /// `None`. Returns `None` if there is no list with this name.
+    pub fn list_rooms(&self, name: &str) -> Option<Vec<Option<OwnedRoomId>>> {
+        self.state().lists.get(name).map(|list| list.rooms.clone())
+    }
+
+    /// The total number of rooms in the list with the given name, as reported by the server.
+    ///
+    /// Returns `None` if there is no list with this name.
+    pub fn list_count(&self, name: &str) -> Option<UInt> {
+        self.state().lists.get(name).map(|list| list.count)
+    }
+
+    /// Subscribe to the room with the given ID.
+    pub fn subscribe_room(&self, room_id: OwnedRoomId, subscription: v4::RoomSubscription) {
+        let mut state = self.state();
+        state.unsubscribe_rooms.remove(&room_id);
+        state.room_subscriptions.insert(room_id, subscription);
+    }
+
+    /// Unsubscribe from the room with the given ID.
+    pub fn unsubscribe_room(&self, room_id: &RoomId) {
+        let mut state = self.state();
+        if let Some((room_id, _)) = state.room_subscriptions.remove_entry(room_id) {
+            state.unsubscribe_rooms.insert(room_id);
+        }
+    }
+
+    /// Set the extensions configuration.
+    ///
+    /// The `since` token of the to-device extension is managed automatically and overrides the
+    /// one set here once a response with to-device messages was received.
+    pub fn set_extensions(&self, extensions: v4::ExtensionsConfig) {
+        let mut state = self.state();
+        state.to_device_since = extensions.to_device.since.clone();
+        state.extensions = extensions;
+    }
+
+    /// Build the next request.
+    pub(crate) fn build_request(&self) -> v4::Request {
+        let state = self.state();
+
+        let mut extensions = state.extensions.clone();
+        if state.to_device_since.is_some() {
+            extensions.to_device.since = state.to_device_since.clone();
+        }
+
+        let mut request = v4::Request::new();
+        request.pos = state.pos.clone();
+        request.conn_id = state.conn_id.clone();
+        request.timeout = state.timeout;
+        request.lists =
+            state.lists.iter().map(|(name, list)| (name.clone(), list.config.clone())).collect();
+        request.room_subscriptions = state.room_subscriptions.clone();
+        request.unsubscribe_rooms = state.unsubscribe_rooms.iter().cloned().collect();
+        request.extensions = extensions;
+
+        request
+    }
+
+    /// Update the state with the given response to the given request.
+    ///
+    /// If the response contains an invalid list update, the state is not modified.
+    pub(crate) fn apply_response(
+        &self,
+        request: &v4::Request,
+        response: &v4::Response,
+    ) -> Result<(), SlidingSyncError> {
+        for (name, sync_list) in &response.lists {
+            validate_list_update(name, sync_list)?;
+        }
+
+        let mut state = self.state();
+
+        state.pos = Some(response.pos.clone());
+
+        // The server received the unsubscriptions, they don't need to be sent again.
+        for room_id in &request.unsubscribe_rooms {
+            state.unsubscribe_rooms.remove(room_id);
+        }
+
+        if let Some(to_device) = &response.extensions.to_device {
+            state.to_device_since = Some(to_device.next_batch.clone());
+        }
+
+        for (name, sync_list) in &response.lists {
+            if let Some(list) = state.lists.get_mut(name) {
+                list.apply_update(sync_list);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forget the `pos` token and the known rooms of all lists, to start a new connection.
+    ///
+    /// Returns `false` if there was no `pos` token.
+    pub(crate) fn reset(&self) -> bool {
+        let mut state = self.state();
+
+        for list in state.lists.values_mut() {
+            list.rooms.clear();
+            list.count = UInt::MIN;
+        }
+
+        state.pos.take().is_some()
+    }
+
+    fn state(&self) -> MutexGuard<'_, SlidingSyncState> {
+        self.0.lock().expect("sliding sync mutex was poisoned")
+    }
+}
+
+impl SlidingSyncList {
+    /// Apply the operations of the given list update to the known rooms.
+    ///
+    /// The update must have been checked with [`validate_list_update()`].
+    fn apply_update(&mut self, update: &v4::SyncList) {
+        for op in &update.ops {
+            match op.op {
+                v4::SlidingOp::Sync => {
+                    let Some((start, end)) = op.range else { continue };
+                    let (start, end) = (index(start), index(end));
+                    self.ensure_len(end.saturating_add(1));
+
+                    for (slot, room_id) in
+                        self.rooms[start..=end].iter_mut().zip(op.room_ids.iter().cloned())
+                    {
+                        *slot = Some(room_id);
+                    }
+                }
+                v4::SlidingOp::Insert => {
+                    let (Some(idx), Some(room_id)) = (op.index, &op.room_id) else { continue };
+                    let idx = index(idx);
+                    self.ensure_len(idx);
+                    self.rooms.insert(idx, Some(room_id.clone()));
+                }
+                v4::SlidingOp::Delete => {
+                    let Some(idx) = op.index else { continue };
+                    let idx = index(idx);
+                    if idx < self.rooms.len() {
+                        self.rooms.remove(idx);
+                    }
+                }
+                v4::SlidingOp::Invalidate => {
+                    let Some((start, end)) = op.range else { continue };
+                    let end = index(end).min(self.rooms.len().saturating_sub(1));
+                    for slot in self.rooms.iter_mut().take(end + 1).skip(index(start)) {
+                        *slot = None;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.count = update.count;
+        self.rooms.resize(index(update.count), None);
+    }
+
+    fn ensure_len(&mut self, len: usize) {
+        if self.rooms.len() < len {
+            self.rooms.resize(len, None);
+        }
+    }
+}
+
+/// Check that the count, ranges and indices of the given list update are valid.
+fn validate_list_update(name: &str, update: &v4::SyncList) -> Result<(), SlidingSyncError> {
+    let check_len = |value: UInt| {
+        if u64::from(value) > MAX_LIST_LEN {
+            Err(SlidingSyncError::TooLarge { list: name.to_owned(), value })
+        } else {
+            Ok(())
+        }
+    };
+
+    check_len(update.count)?;
+
+    for op in &update.ops {
+        match op.op {
+            v4::SlidingOp::Sync | v4::SlidingOp::Invalidate => {
+                let Some((start, end)) = op.range else { continue };
+                if start > end {
+                    return Err(SlidingSyncError::InvertedRange {
+                        list: name.to_owned(),
+                        start,
+                        end,
+                    });
+                }
+                check_len(end)?;
+            }
+            v4::SlidingOp::Insert | v4::SlidingOp::Delete => {
+                let Some(idx) = op.index else { continue };
+                check_len(idx)?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// An error in a sliding sync response.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SlidingSyncError {
+    /// An operation on a list has a range whose start is after its end.
+    InvertedRange {
+        /// The name of the list.
+        list: String,
+
+        /// The start of the range.
+        start: UInt,
+
+        /// The end of the range.
+        end: UInt,
+    },
+
+    /// The count of a list, or an index or range of one of its operations, is too large.
+    TooLarge {
+        /// The name of the list.
+        list: String,
+
+        /// The value that is too large.
+        value: UInt,
+    },
+}
+
+impl fmt::Display for SlidingSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvertedRange { list, start, end } => {
+                write!(f, "invalid range [{start}, {end}] in sliding sync list `{list}`")
+            }
+            Self::TooLarge { list, value } => {
+                write!(f, "too large count or index {value} in sliding sync list `{list}`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SlidingSyncError {}
+
+/// Convert the given list index to a `usize`.
+fn index(value: UInt) -> usize {
+    u64::from(value).try_into().unwrap_or(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use js_int::uint;
+    use ruma_client_api::sync::sync_events::v4;
+    use ruma_common::owned_room_id;
+    use serde_json::{from_value as from_json_value, json};
+
+    use super::{SlidingSync, SlidingSyncError};
+
+    fn response(lists: serde_json::Value) -> v4::Response {
+        let mut response = v4::Response::new("pos".to_owned());
+        response.lists = from_json_value(lists).unwrap();
+        response
+    }
+
+    #[test]
+    fn list_operations() {
+        let sliding_sync = SlidingSync::new();
+        sliding_sync.add_list("all", v4::SyncRequestList::default());
+
+        sliding_sync
+            .apply_response(
+                &v4::Request::new(),
+                &response(json!({
+                    "all": {
+                        "count": 4,
+                        "ops": [{
+                            "op": "SYNC",
+                            "range": [0, 2],
+                            "room_ids": ["!a:localhost", "!b:localhost", "!c:localhost"],
+                        }],
+                    },
+                })),
+            )
+            .unwrap();
+        assert_eq!(sliding_sync.pos().as_deref(), Some("pos"));
+        assert_eq!(sliding_sync.list_count("all"), Some(uint!(4)));
+        assert_eq!(
+            sliding_sync.list_rooms("all").unwrap(),
+            [
+                Some(owned_room_id!("!a:localhost")),
+                Some(owned_room_id!("!b:localhost")),
+                Some(owned_room_id!("!c:localhost")),
+                None
+            ]
+        );
+
+        // Room c moves to the top.
+        sliding_sync
+            .apply_response(
+                &v4::Request::new(),
+                &response(json!({
+                    "all": {
+                        "count": 4,
+                        "ops": [
+                            { "op": "DELETE", "index": 2 },
+                            { "op": "INSERT", "index": 0, "room_id": "!c:localhost" },
+                        ],
+                    },
+                })),
+            )
+            .unwrap();
+        assert_eq!(
+            sliding_sync.list_rooms("all").unwrap(),
+            [
+                Some(owned_room_id!("!c:localhost")),
+                Some(owned_room_id!("!a:localhost")),
+                Some(owned_room_id!("!b:localhost")),
+                None
+            ]
+        );
+
+        sliding_sync
+            .apply_response(
+                &v4::Request::new(),
+                &response(json!({
+                    "all": {
+                        "count": 3,
+                        "ops": [{ "op": "INVALIDATE", "range": [1, 2] }],
+                    },
+                })),
+            )
+            .unwrap();
+        assert_eq!(
+            sliding_sync.list_rooms("all").unwrap(),
+            [Some(owned_room_id!("!c:localhost")), None, None]
+        );
+
+        assert!(sliding_sync.reset());
+        assert_eq!(sliding_sync.pos(), None);
+        assert_eq!(sliding_sync.list_rooms("all").unwrap(), []);
+        assert!(!sliding_sync.reset());
+    }
+
+    #[test]
+    fn invalid_list_updates() {
+        let sliding_sync = SlidingSync::new();
+        sliding_sync.add_list("all", v4::SyncRequestList::default());
+
+        let error = sliding_sync
+            .apply_response(
+                &v4::Request::new(),
+                &response(json!({
+                    "all": {
+                        "count": 4,
+                        "ops": [{
+                            "op": "SYNC",
+                            "range": [3, 1],
+                            "room_ids": ["!a:localhost", "!b:localhost"],
+                        }],
+                    },
+                })),
+            )
+            .unwrap_err();
+        assert_matches!(error, SlidingSyncError::InvertedRange { list, start, end });
+        assert_eq!(list, "all");
+        assert_eq!(start, uint!(3));
+        assert_eq!(end, uint!(1));
+
+        let error = sliding_sync
+            .apply_response(
+                &v4::Request::new(),
+                &response(json!({
+                    "all": { "count": 9_007_199_254_740_991_u64, "ops": [] },
+                })),
+            )
+            .unwrap_err();
+        assert_matches!(error, SlidingSyncError::TooLarge { .. });
+
+        let error = sliding_sync
+            .apply_response(&v4::Request::new(), &response(json!({
+                "all": {
+                    "count": 1,
+                    "ops": [{ "op": "INSERT", "index": 4_000_000_000_u64, "room_id": "!a:localhost" }],
+                },
+            })))
+            .unwrap_err();
+        assert_matches!(error, SlidingSyncError::TooLarge { .. });
+
+        // The state was not modified.
+        assert_eq!(sliding_sync.pos(), None);
+        assert_eq!(sliding_sync.list_count("all"), Some(uint!(0)));
+        assert_eq!(sliding_sync.list_rooms("all").unwrap(), []);
+    }
+
+    #[test]
+    fn request_bookkeeping() {
+        let sliding_sync = SlidingSync::new();
+        sliding_sync.add_list("all", v4::SyncRequestList::default());
+        assert!(sliding_sync.set_list_ranges("all", vec![(uint!(0), uint!(9))]));
+        assert!(!sliding_sync.set_list_ranges("unknown", vec![]));
+        sliding_sync.subscribe_room(owned_room_id!("!a:localhost"), Default::default());
+        sliding_sync.subscribe_room(owned_room_id!("!b:localhost"), Default::default());
+
+        let mut extensions = v4::ExtensionsConfig::default();
+        extensions.to_device.enabled = Some(true);
+        sliding_sync.set_extensions(extensions);
+
+        let request = sliding_sync.build_request();
+        assert_eq!(request.pos, None);
+        assert_eq!(request.lists["all"].ranges, [(uint!(0), uint!(9))]);
+        assert_eq!(request.room_subscriptions.len(), 2);
+        assert!(request.unsubscribe_rooms.is_empty());
+        assert_eq!(request.extensions.to_device.since, None);
+
+        sliding_sync.unsubscribe_room(&owned_room_id!("!a:localhost"));
+        let mut response = response(json!({}));
+        response.extensions.to_device =
+            Some(from_json_value(json!({ "next_batch": "to_device_token" })).unwrap());
+        sliding_sync.apply_response(&request, &response).unwrap();
+
+        let request = sliding_sync.build_request();
+        assert_eq!(request.pos.as_deref(), Some("pos"));
+        assert_eq!(request.room_subscriptions.len(), 1);
+        assert_eq!(request.unsubscribe_rooms, [owned_room_id!("!a:localhost")]);
+        assert_eq!(request.extensions.to_device.enabled, Some(true));
+        assert_eq!(request.extensions.to_device.since.as_deref(), Some("to_device_token"));
+
+        // Unsubscriptions are not sent again once a response was received.
+        sliding_sync.apply_response(&request, &response).unwrap();
+        let request = sliding_sync.build_request();
+        assert!(request.unsubscribe_rooms.is_empty());
+    }
+
+    #[test]
+    fn unsubscriptions_after_failed_request() {
+        let sliding_sync = SlidingSync::new();
+        sliding_sync.subscribe_room(owned_room_id!("!a:localhost"), Default::default());
+        sliding_sync.unsubscribe_room(&owned_room_id!("!a:localhost"));
+
+        // No response is received for the first request.
+        let request = sliding_sync.build_request();
+        assert_eq!(request.unsubscribe_rooms, [owned_room_id!("!a:localhost")]);
+
+        // An invalid response is not a response to the unsubscription either.
+        let invalid_response = response(json!({ "all": { "count": 1_000_001, "ops": [] } }));
+        sliding_sync.apply_response(&request, &invalid_response).unwrap_err();
+
+        let request = sliding_sync.build_request();
+        assert_eq!(request.unsubscribe_rooms, [owned_room_id!("!a:localhost")]);
+
+        sliding_sync.apply_response(&request, &response(json!({}))).unwrap();
+        let request = sliding_sync.build_request();
+        assert!(request.unsubscribe_rooms.is_empty());
+    }
+}
@@ -22,6 +22,10 @@ pub enum Error<E, F> {
 
     /// Converting the HTTP response to one of ruma's types failed.
     FromHttpResponse(FromHttpResponseError<F>),
+
+    /// A sliding sync response contains an invalid list update.
+    #[cfg(feature = "unstable-msc3575")]
+    SlidingSync(crate::SlidingSyncError),
 }
 
 #[cfg(feature = "client-api")]
@@ -46,6 +50,8 @@ impl<E: Display, F: Display> Display for Error<E, F> {
             Self::Url(err) => write!(f, "Invalid URL: {err}"),
             Self::Response(err) => write!(f, "Couldn't obtain a response: {err}"),
             Self::FromHttpResponse(err) => write!(f, "HTTP response conversion failed: {err}"),
+            #[cfg(feature = "unstable-msc3575")]
+            Self::SlidingSync(err) => write!(f, "Invalid sliding sync response: {err}"),
         }
     }
 }
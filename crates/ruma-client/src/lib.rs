@@ -23,12 +23,15 @@
 //!     .log_in("@alice:example.com", "secret", None, None)
 //!     .await?;
 //!
-//! // You're now logged in! Write the session to a file if you want to restore it later.
-//! // Then start using the API!
+//! // You're now logged in! Write `client.session()` to a file if you want to restore it later
+//! // with `ClientBuilder::session`. Then start using the API!
 //! # Result::<(), ruma_client::Error<_, _>>::Ok(())
 //! # };
 //! ```
 //!
+//! Since access tokens can be refreshed while the client is running, use
+//! `ClientBuilder::on_session_change` to persist the updated session every time it changes.
+//!
 //! You can also pass an existing access token to the `Client` constructor to restore a previous
 //! session rather than calling `log_in`. This can also be used to create a session for an
 //! application service that does not need to log in, but uses the access_token directly:
@@ -105,15 +108,21 @@ use ruma_common::{
     api::{MatrixVersion, OutgoingRequest, SendAccessToken},
     UserId,
 };
-use tracing::{info_span, Instrument};
+use tracing::{info_span, Instrument, Span};
 
 #[cfg(feature = "client-api")]
 mod client;
 mod error;
 pub mod http_client;
+#[cfg(feature = "client-api")]
+pub mod retry;
+#[cfg(feature = "room-state")]
+pub mod room_state;
 
 #[cfg(feature = "client-api")]
-pub use self::client::{Client, ClientBuilder};
+pub use self::client::{Client, ClientBuilder, Session};
+#[cfg(feature = "unstable-msc3575")]
+pub use self::client::{SlidingSync, SlidingSyncError};
 pub use self::{
     error::Error,
     http_client::{DefaultConstructibleHttpClient, HttpClient, HttpClientExt},
@@ -140,23 +149,14 @@ where
     R: OutgoingRequest,
     F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
 {
-    let http_req =
-        info_span!("serialize_request", request_type = type_name::<R>()).in_scope(move || {
-            request
-                .try_into_http_request(homeserver_url, send_access_token, for_versions)
-                .map_err(ResponseError::<C, R>::from)
-                .and_then(|mut req| {
-                    customize(&mut req)?;
-                    Ok(req)
-                })
-        });
-
-    let send_span = info_span!(
-        "send_request",
-        request_type = type_name::<R>(),
-        http_client = type_name::<C>(),
+    let http_req = serialize_request::<C, R, F>(
         homeserver_url,
+        send_access_token,
+        for_versions,
+        request,
+        customize,
     );
+    let send_span = send_span::<C, R>(homeserver_url);
 
     async move {
         let http_res = http_client
@@ -165,19 +165,57 @@ where
             .await
             .map_err(Error::Response)?;
 
-        let res =
-            info_span!("deserialize_response", response_type = type_name::<R::IncomingResponse>())
-                .in_scope(move || {
-                    ruma_common::api::IncomingResponse::try_from_http_response(http_res)
-                })?;
-
-        Ok(res)
+        deserialize_response::<C, R>(http_res)
     }
 }
 
+fn serialize_request<C, R, F>(
+    homeserver_url: &str,
+    send_access_token: SendAccessToken<'_>,
+    for_versions: &[MatrixVersion],
+    request: R,
+    customize: F,
+) -> Result<http::Request<C::RequestBody>, ResponseError<C, R>>
+where
+    C: HttpClient + ?Sized,
+    R: OutgoingRequest,
+    F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
+{
+    info_span!("serialize_request", request_type = type_name::<R>()).in_scope(move || {
+        request
+            .try_into_http_request(homeserver_url, send_access_token, for_versions)
+            .map_err(ResponseError::<C, R>::from)
+            .and_then(|mut req| {
+                customize(&mut req)?;
+                Ok(req)
+            })
+    })
+}
+
+fn send_span<C: HttpClient + ?Sized, R: OutgoingRequest>(homeserver_url: &str) -> Span {
+    info_span!(
+        "send_request",
+        request_type = type_name::<R>(),
+        http_client = type_name::<C>(),
+        homeserver_url,
+    )
+}
+
+fn deserialize_response<C: HttpClient + ?Sized, R: OutgoingRequest>(
+    http_res: http::Response<C::ResponseBody>,
+) -> ResponseResult<C, R> {
+    let res =
+        info_span!("deserialize_response", response_type = type_name::<R::IncomingResponse>())
+            .in_scope(move || {
+                ruma_common::api::IncomingResponse::try_from_http_response(http_res)
+            })?;
+
+    Ok(res)
+}
+
 fn add_user_id_to_query<C: HttpClient + ?Sized, R: OutgoingRequest>(
     user_id: &UserId,
-) -> impl FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>> + '_ {
+) -> impl Fn(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>> + '_ {
     use assign::assign;
     use http::uri::Uri;
 
@@ -0,0 +1,381 @@
+//! Policies for automatically retrying failed requests.
+//!
+//! A [`RetryPolicy`] can be set on a [`Client`](crate::Client) through
+//! [`ClientBuilder::retry_policy()`](crate::ClientBuilder::retry_policy). Whenever a request fails
+//! because the homeserver rate-limited it, was temporarily unavailable or couldn't be reached at
+//! all, the policy is asked whether and after how long the request should be sent again.
+
+use std::{
+    fmt::Debug,
+    time::{Duration, SystemTime},
+};
+
+use http::{header::RETRY_AFTER, Method, StatusCode};
+use rand::Rng;
+use serde::Deserialize;
+
+/// A policy deciding whether and when a failed request should be sent again.
+pub trait RetryPolicy: Debug + Send + Sync {
+    /// Returns the delay after which the request described by `attempt` should be sent again, or
+    /// `None` if the error should be returned to the caller.
+    fn retry_delay(&self, attempt: &RetryAttempt<'_>) -> Option<Duration>;
+}
+
+/// Information about a failed attempt to send a request.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RetryAttempt<'a> {
+    /// The number of times the request has been sent so far, including the failed attempt.
+    ///
+    /// This is `1` after the first failure.
+    pub attempt: u32,
+
+    /// The HTTP method of the request.
+    pub method: &'a Method,
+
+    /// Why the attempt failed.
+    pub cause: RetryCause,
+}
+
+impl<'a> RetryAttempt<'a> {
+    /// Creates a new `RetryAttempt` with the given attempt number, HTTP method and cause.
+    pub fn new(attempt: u32, method: &'a Method, cause: RetryCause) -> Self {
+        Self { attempt, method, cause }
+    }
+
+    /// Whether the request can be sent several times without changing its effect.
+    ///
+    /// This is the case for the `GET`, `HEAD`, `OPTIONS`, `PUT` and `DELETE` methods. Matrix
+    /// endpoints that use `PUT` to create a resource include a transaction ID in their path for
+    /// this reason.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            *self.method,
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+        )
+    }
+}
+
+/// The reason why an attempt to send a request failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RetryCause {
+    /// The homeserver responded with `429 Too Many Requests`.
+    ///
+    /// The request was not processed by the homeserver, so it is always safe to send it again.
+    RateLimited {
+        /// The time to wait before sending the request again, as indicated by the
+        /// `Retry-After` header or the `retry_after_ms` field of an `M_LIMIT_EXCEEDED` error.
+        retry_after: Option<Duration>,
+    },
+
+    /// The homeserver, or a proxy in front of it, responded with `502 Bad Gateway`,
+    /// `503 Service Unavailable` or `504 Gateway Timeout`.
+    Unavailable {
+        /// The HTTP status code of the response.
+        status: StatusCode,
+
+        /// The time to wait before sending the request again, as indicated by the
+        /// `Retry-After` header.
+        retry_after: Option<Duration>,
+    },
+
+    /// The HTTP client couldn't obtain a response, e.g. due to network or DNS issues.
+    Network,
+}
+
+impl RetryCause {
+    /// Get the `RetryCause` for the given HTTP response, if it is a response that can be retried.
+    pub(crate) fn from_response<T: AsRef<[u8]>>(response: &http::Response<T>) -> Option<Self> {
+        let retry_after = || {
+            response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, SystemTime::now()))
+        };
+
+        match response.status() {
+            StatusCode::TOO_MANY_REQUESTS => {
+                #[derive(Deserialize)]
+                struct RateLimitedBody {
+                    retry_after_ms: Option<u64>,
+                }
+
+                let retry_after = retry_after().or_else(|| {
+                    serde_json::from_slice::<RateLimitedBody>(response.body().as_ref())
+                        .ok()?
+                        .retry_after_ms
+                        .map(Duration::from_millis)
+                });
+
+                Some(Self::RateLimited { retry_after })
+            }
+            status @ (StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT) => {
+                Some(Self::Unavailable { status, retry_after: retry_after() })
+            }
+            _ => None,
+        }
+    }
+
+    /// The time to wait before retrying, as requested by the homeserver.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after } | Self::Unavailable { retry_after, .. } => {
+                *retry_after
+            }
+            Self::Network => None,
+        }
+    }
+}
+
+/// Parse the value of a `Retry-After` header, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// A [`RetryPolicy`] using exponential backoff with jitter.
+///
+/// Requests that were rate-limited are always retried, after the delay requested by the
+/// homeserver if there is one. Requests that failed because of a network error or because the
+/// homeserver was unavailable are only retried if they are [idempotent].
+///
+/// [idempotent]: RetryAttempt::is_idempotent
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ExponentialBackoff {
+    /// The maximum number of times a request is sent, including the first attempt.
+    ///
+    /// Defaults to `5`.
+    pub max_attempts: u32,
+
+    /// The delay before the first retry.
+    ///
+    /// Defaults to 500 milliseconds.
+    pub initial_delay: Duration,
+
+    /// The maximum delay between two attempts.
+    ///
+    /// If the homeserver asks to wait longer than this, the error is returned to the caller.
+    ///
+    /// Defaults to 60 seconds.
+    pub max_delay: Duration,
+
+    /// The factor by which the delay is multiplied after each attempt.
+    ///
+    /// Defaults to `2`.
+    pub multiplier: u32,
+
+    /// Whether to randomize the computed delays.
+    ///
+    /// If this is `true`, a delay between half of and the full computed delay is used, so that
+    /// many clients failing at the same time don't retry in lockstep. Delays requested by the
+    /// homeserver are never randomized.
+    ///
+    /// Defaults to `true`.
+    pub jitter: bool,
+}
+
+impl ExponentialBackoff {
+    /// Creates a new `ExponentialBackoff` with the default settings.
+    pub fn new() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2,
+            jitter: true,
+        }
+    }
+
+    /// The delay computed by this policy for the given attempt, without jitter.
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn retry_delay(&self, attempt: &RetryAttempt<'_>) -> Option<Duration> {
+        if attempt.attempt >= self.max_attempts {
+            return None;
+        }
+
+        let retry_safe =
+            matches!(attempt.cause, RetryCause::RateLimited { .. }) || attempt.is_idempotent();
+        if !retry_safe {
+            return None;
+        }
+
+        if let Some(retry_after) = attempt.cause.retry_after() {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+
+        let delay = self.backoff_delay(attempt.attempt);
+        if self.jitter && !delay.is_zero() {
+            Some(rand::thread_rng().gen_range(delay / 2..=delay))
+        } else {
+            Some(delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use assign::assign;
+    use http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::{parse_retry_after, ExponentialBackoff, RetryAttempt, RetryCause, RetryPolicy};
+
+    fn response(
+        status: StatusCode,
+        retry_after: Option<&str>,
+        body: &[u8],
+    ) -> http::Response<Vec<u8>> {
+        let mut builder = http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            builder = builder.header(http::header::RETRY_AFTER, retry_after);
+        }
+        builder.body(body.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn cause_from_rate_limited_response() {
+        let body = serde_json::to_vec(&json!({
+            "errcode": "M_LIMIT_EXCEEDED",
+            "error": "Too many requests",
+            "retry_after_ms": 2000,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            RetryCause::from_response(&response(StatusCode::TOO_MANY_REQUESTS, None, &body)),
+            Some(RetryCause::RateLimited { retry_after: Some(Duration::from_secs(2)) })
+        );
+        // The header takes precedence over the body.
+        assert_eq!(
+            RetryCause::from_response(&response(StatusCode::TOO_MANY_REQUESTS, Some("5"), &body)),
+            Some(RetryCause::RateLimited { retry_after: Some(Duration::from_secs(5)) })
+        );
+        assert_eq!(
+            RetryCause::from_response(&response(StatusCode::TOO_MANY_REQUESTS, None, b"")),
+            Some(RetryCause::RateLimited { retry_after: None })
+        );
+    }
+
+    #[test]
+    fn cause_from_other_responses() {
+        assert_eq!(
+            RetryCause::from_response(&response(StatusCode::SERVICE_UNAVAILABLE, Some("1"), b"")),
+            Some(RetryCause::Unavailable {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                retry_after: Some(Duration::from_secs(1)),
+            })
+        );
+        assert_eq!(RetryCause::from_response(&response(StatusCode::OK, None, b"{}")), None);
+        assert_eq!(
+            RetryCause::from_response(&response(StatusCode::INTERNAL_SERVER_ERROR, None, b"{}")),
+            None
+        );
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_767);
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:17 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn exponential_backoff_delays() {
+        let policy = assign!(ExponentialBackoff::new(), { jitter: false });
+
+        let delays: Vec<_> = (1..=5)
+            .map(|attempt| {
+                policy.retry_delay(&RetryAttempt::new(attempt, &Method::GET, RetryCause::Network))
+            })
+            .collect();
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::from_millis(500)),
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(4)),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn exponential_backoff_jitter() {
+        let policy = ExponentialBackoff::new();
+
+        for _ in 0..20 {
+            let delay = policy
+                .retry_delay(&RetryAttempt::new(3, &Method::GET, RetryCause::Network))
+                .unwrap();
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn exponential_backoff_idempotency() {
+        let policy = ExponentialBackoff::new();
+
+        assert_eq!(
+            policy.retry_delay(&RetryAttempt::new(1, &Method::POST, RetryCause::Network)),
+            None
+        );
+        assert_eq!(
+            policy.retry_delay(&RetryAttempt::new(
+                1,
+                &Method::POST,
+                RetryCause::Unavailable { status: StatusCode::BAD_GATEWAY, retry_after: None }
+            )),
+            None
+        );
+        assert_eq!(
+            policy.retry_delay(&RetryAttempt::new(
+                1,
+                &Method::POST,
+                RetryCause::RateLimited { retry_after: Some(Duration::from_secs(3)) }
+            )),
+            Some(Duration::from_secs(3))
+        );
+    }
+
+    #[test]
+    fn exponential_backoff_retry_after_too_long() {
+        let policy = ExponentialBackoff::new();
+
+        assert_eq!(
+            policy.retry_delay(&RetryAttempt::new(
+                1,
+                &Method::GET,
+                RetryCause::RateLimited { retry_after: Some(Duration::from_secs(3600)) }
+            )),
+            None
+        );
+    }
+}
@@ -0,0 +1,947 @@
+//! An in-memory model of the state of the rooms of a user, built from sync responses.
+//!
+//! The main entry point is [`RoomStore`], which keeps a [`RoomState`] for every room the
+//! user is in, has been invited to, has knocked on or has left, and is updated by passing it every
+//! [`sync_events::v3::Response`] received from the homeserver.
+
+use std::{collections::BTreeMap, fmt};
+
+use js_int::{uint, UInt};
+use ruma_client_api::sync::sync_events::{self, UnreadNotificationsCount};
+use ruma_common::{
+    serde::Raw, EventId, OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use ruma_events::{
+    receipt::{Receipt, ReceiptEventContent, ReceiptType},
+    room::member::{MembershipChange, MembershipState, RoomMemberEventContent},
+    AnyRoomAccountDataEvent, AnyStrippedStateEvent, AnySyncEphemeralRoomEvent, AnySyncStateEvent,
+    RoomAccountDataEventType, StateEventType, SyncStateEvent,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+/// The maximum number of heroes used to calculate the display name of a room when the
+/// homeserver didn't provide any.
+const MAX_HEROES: usize = 5;
+
+/// The state of all the rooms of a user, built from sync responses.
+///
+/// Every response to a [`sync_events::v3`] request should be passed to
+/// [`RoomStore::apply_sync_response()`], in the order in which they were received.
+#[derive(Clone, Debug)]
+pub struct RoomStore {
+    own_user_id: OwnedUserId,
+    rooms: BTreeMap<OwnedRoomId, RoomState>,
+}
+
+impl RoomStore {
+    /// Creates an empty `RoomStore` for the user with the given ID.
+    pub fn new(own_user_id: OwnedUserId) -> Self {
+        Self { own_user_id, rooms: BTreeMap::new() }
+    }
+
+    /// The ID of the user whose rooms are tracked by this store.
+    pub fn own_user_id(&self) -> &UserId {
+        &self.own_user_id
+    }
+
+    /// Get the state of the room with the given ID, if it is known.
+    pub fn get(&self, room_id: &RoomId) -> Option<&RoomState> {
+        self.rooms.get(room_id)
+    }
+
+    /// Iterate over the state of all the known rooms.
+    pub fn rooms(&self) -> impl Iterator<Item = &RoomState> {
+        self.rooms.values()
+    }
+
+    /// Iterate over the state of the known rooms in which the user has the given membership.
+    pub fn rooms_with_membership<'a>(
+        &'a self,
+        membership: &'a MembershipState,
+    ) -> impl Iterator<Item = &'a RoomState> {
+        self.rooms().filter(move |room| room.membership() == membership)
+    }
+
+    /// Update the state of the rooms with the given sync response.
+    pub fn apply_sync_response(&mut self, response: &sync_events::v3::Response) {
+        let rooms = &response.rooms;
+
+        for (room_id, room) in &rooms.join {
+            let state = self.room_mut(room_id, MembershipState::Join);
+            state.apply_state_events(&room.state.events);
+            state.apply_timeline(&room.timeline);
+            state.apply_summary(&room.summary);
+            state.apply_account_data(&room.account_data.events);
+            state.apply_ephemeral(&room.ephemeral.events);
+
+            state.unread_notifications = room.unread_notifications.clone();
+            for (thread_id, counts) in &room.unread_thread_notifications {
+                if is_zero(counts) {
+                    state.unread_thread_notifications.remove(thread_id);
+                } else {
+                    state.unread_thread_notifications.insert(thread_id.clone(), counts.clone());
+                }
+            }
+        }
+
+        for (room_id, room) in &rooms.invite {
+            let state = self.room_mut(room_id, MembershipState::Invite);
+            state.apply_stripped_state_events(&room.invite_state.events);
+        }
+
+        for (room_id, room) in &rooms.knock {
+            let state = self.room_mut(room_id, MembershipState::Knock);
+            state.apply_stripped_state_events(&room.knock_state.events);
+        }
+
+        for (room_id, room) in &rooms.leave {
+            let state = self.room_mut(room_id, MembershipState::Leave);
+            state.apply_state_events(&room.state.events);
+            state.apply_timeline(&room.timeline);
+            state.apply_account_data(&room.account_data.events);
+
+            state.unread_notifications = UnreadNotificationsCount::new();
+            state.unread_thread_notifications.clear();
+            state.typing.clear();
+        }
+    }
+
+    /// Get the state of the room with the given ID, creating it if necessary, and update the
+    /// membership of the user in it.
+    fn room_mut(&mut self, room_id: &RoomId, membership: MembershipState) -> &mut RoomState {
+        let state = self
+            .rooms
+            .entry(room_id.to_owned())
+            .or_insert_with(|| RoomState::new(room_id.to_owned(), self.own_user_id.clone()));
+
+        // The stripped state of invites and knocks is superseded by the full state once the room
+        // is joined or left.
+        if matches!(state.membership, MembershipState::Invite | MembershipState::Knock)
+            && !matches!(membership, MembershipState::Invite | MembershipState::Knock)
+        {
+            *state = RoomState::new(room_id.to_owned(), self.own_user_id.clone());
+        }
+
+        state.membership = membership;
+        state
+    }
+}
+
+/// The state of a single room, as seen by the user.
+#[derive(Clone, Debug)]
+pub struct RoomState {
+    room_id: OwnedRoomId,
+    own_user_id: OwnedUserId,
+    membership: MembershipState,
+    state: BTreeMap<(StateEventType, String), Raw<AnySyncStateEvent>>,
+    stripped_state: BTreeMap<(StateEventType, String), Raw<AnyStrippedStateEvent>>,
+    members: BTreeMap<OwnedUserId, RoomMemberEventContent>,
+    name: Option<String>,
+    canonical_alias: Option<OwnedRoomAliasId>,
+    heroes: Vec<OwnedUserId>,
+    joined_member_count: Option<UInt>,
+    invited_member_count: Option<UInt>,
+    unread_notifications: UnreadNotificationsCount,
+    unread_thread_notifications: BTreeMap<OwnedEventId, UnreadNotificationsCount>,
+    account_data: BTreeMap<RoomAccountDataEventType, Raw<AnyRoomAccountDataEvent>>,
+    typing: Vec<OwnedUserId>,
+    receipts: BTreeMap<ReceiptType, BTreeMap<OwnedUserId, (OwnedEventId, Receipt)>>,
+    prev_batch: Option<String>,
+}
+
+impl RoomState {
+    /// Creates an empty `RoomState` for the room with the given ID, as seen by the user with the
+    /// given ID.
+    pub fn new(room_id: OwnedRoomId, own_user_id: OwnedUserId) -> Self {
+        Self {
+            room_id,
+            own_user_id,
+            membership: MembershipState::Leave,
+            state: BTreeMap::new(),
+            stripped_state: BTreeMap::new(),
+            members: BTreeMap::new(),
+            name: None,
+            canonical_alias: None,
+            heroes: Vec::new(),
+            joined_member_count: None,
+            invited_member_count: None,
+            unread_notifications: UnreadNotificationsCount::new(),
+            unread_thread_notifications: BTreeMap::new(),
+            account_data: BTreeMap::new(),
+            typing: Vec::new(),
+            receipts: BTreeMap::new(),
+            prev_batch: None,
+        }
+    }
+
+    /// The ID of the room.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// The membership of the user in the room.
+    pub fn membership(&self) -> &MembershipState {
+        &self.membership
+    }
+
+    /// Get the state event with the given type and state key, if any.
+    pub fn state_event(
+        &self,
+        event_type: &StateEventType,
+        state_key: &str,
+    ) -> Option<&Raw<AnySyncStateEvent>> {
+        self.state.get(&(event_type.clone(), state_key.to_owned()))
+    }
+
+    /// Iterate over all the state events of the room.
+    pub fn state_events(&self) -> impl Iterator<Item = &Raw<AnySyncStateEvent>> {
+        self.state.values()
+    }
+
+    /// Get the stripped state event with the given type and state key, if any.
+    ///
+    /// Stripped state is only available for rooms the user was invited to or knocked on.
+    pub fn stripped_state_event(
+        &self,
+        event_type: &StateEventType,
+        state_key: &str,
+    ) -> Option<&Raw<AnyStrippedStateEvent>> {
+        self.stripped_state.get(&(event_type.clone(), state_key.to_owned()))
+    }
+
+    /// The content of the latest `m.room.member` event of the given user, if any.
+    ///
+    /// If the event was redacted, only its `membership` is set.
+    pub fn member(&self, user_id: &UserId) -> Option<&RoomMemberEventContent> {
+        self.members.get(user_id)
+    }
+
+    /// Iterate over the IDs of the users with the given membership.
+    pub fn members_with_membership<'a>(
+        &'a self,
+        membership: &'a MembershipState,
+    ) -> impl Iterator<Item = &'a UserId> {
+        self.members
+            .iter()
+            .filter(move |(_, content)| content.membership == *membership)
+            .map(|(user_id, _)| &**user_id)
+    }
+
+    /// Iterate over the IDs of the users that joined the room.
+    pub fn joined_members(&self) -> impl Iterator<Item = &UserId> {
+        self.members_with_membership(&MembershipState::Join)
+    }
+
+    /// Iterate over the IDs of the users that were invited to the room.
+    pub fn invited_members(&self) -> impl Iterator<Item = &UserId> {
+        self.members_with_membership(&MembershipState::Invite)
+    }
+
+    /// Iterate over the IDs of the users that left the room or were banned from it.
+    pub fn left_members(&self) -> impl Iterator<Item = &UserId> {
+        self.members
+            .iter()
+            .filter(|(_, content)| {
+                matches!(content.membership, MembershipState::Leave | MembershipState::Ban)
+            })
+            .map(|(user_id, _)| &**user_id)
+    }
+
+    /// The number of users that joined the room.
+    ///
+    /// Uses the count from the room summary sent by the homeserver if available, since the
+    /// members known locally may be incomplete when lazy-loading members.
+    pub fn joined_member_count(&self) -> u64 {
+        self.joined_member_count
+            .map(Into::into)
+            .unwrap_or_else(|| self.joined_members().count() as u64)
+    }
+
+    /// The number of users that were invited to the room.
+    ///
+    /// Uses the count from the room summary sent by the homeserver if available, since the
+    /// members known locally may be incomplete when lazy-loading members.
+    pub fn invited_member_count(&self) -> u64 {
+        self.invited_member_count
+            .map(Into::into)
+            .unwrap_or_else(|| self.invited_members().count() as u64)
+    }
+
+    /// The display name of the given user in the room.
+    ///
+    /// This follows the [disambiguation rules] of the spec: the display name of the user is used
+    /// if it is unique among the joined and invited members of the room, otherwise their user ID
+    /// is appended to it. If they don't have a display name, their user ID is used.
+    ///
+    /// [disambiguation rules]: https://spec.matrix.org/latest/client-server-api/#calculating-the-display-name-for-a-user
+    pub fn member_display_name(&self, user_id: &UserId) -> String {
+        let Some(displayname) = self
+            .members
+            .get(user_id)
+            .and_then(|content| content.displayname.as_deref())
+            .filter(|displayname| !displayname.is_empty())
+        else {
+            return user_id.to_string();
+        };
+
+        let is_ambiguous = self.members.iter().any(|(other_user_id, content)| {
+            other_user_id != user_id
+                && matches!(content.membership, MembershipState::Join | MembershipState::Invite)
+                && content.displayname.as_deref() == Some(displayname)
+        });
+
+        if is_ambiguous {
+            format!("{displayname} ({user_id})")
+        } else {
+            displayname.to_owned()
+        }
+    }
+
+    /// The display name of the room.
+    ///
+    /// This follows the [room name calculation] of the spec: the `m.room.name` is used if it is
+    /// set, then the `m.room.canonical_alias`, and finally the name is computed from the heroes of
+    /// the room.
+    ///
+    /// [room name calculation]: https://spec.matrix.org/latest/client-server-api/#calculating-the-display-name-for-a-room
+    pub fn display_name(&self) -> RoomDisplayName {
+        if let Some(name) = self.name.as_ref().filter(|name| !name.is_empty()) {
+            return RoomDisplayName::Named(name.clone());
+        }
+
+        if let Some(alias) = &self.canonical_alias {
+            return RoomDisplayName::Aliased(alias.clone());
+        }
+
+        let heroes = self.heroes();
+        let hero_names: Vec<_> =
+            heroes.iter().map(|user_id| self.member_display_name(user_id)).collect();
+        let member_count = self.joined_member_count() + self.invited_member_count();
+
+        if member_count <= 1 {
+            return RoomDisplayName::Empty { heroes: hero_names };
+        }
+
+        let others = (member_count - 1).saturating_sub(hero_names.len() as u64);
+        RoomDisplayName::Calculated { heroes: hero_names, others }
+    }
+
+    /// The users used to compute the display name of the room.
+    ///
+    /// Uses the heroes from the room summary sent by the homeserver if available, otherwise the
+    /// first joined or invited members of the room, or the first members that left if there are
+    /// none.
+    fn heroes(&self) -> Vec<&UserId> {
+        if !self.heroes.is_empty() {
+            return self.heroes.iter().map(|user_id| &**user_id).collect();
+        }
+
+        let own_user_id = &*self.own_user_id;
+        let heroes: Vec<_> = self
+            .joined_members()
+            .chain(self.invited_members())
+            .filter(|user_id| *user_id != own_user_id)
+            .take(MAX_HEROES)
+            .collect();
+
+        if !heroes.is_empty() {
+            return heroes;
+        }
+
+        self.left_members().filter(|user_id| *user_id != own_user_id).take(MAX_HEROES).collect()
+    }
+
+    /// The unread notification counts of the room.
+    pub fn unread_notifications(&self) -> &UnreadNotificationsCount {
+        &self.unread_notifications
+    }
+
+    /// The unread notification counts of the threads of the room, keyed by thread root.
+    ///
+    /// Only contains threads with unread notifications.
+    pub fn unread_thread_notifications(&self) -> &BTreeMap<OwnedEventId, UnreadNotificationsCount> {
+        &self.unread_thread_notifications
+    }
+
+    /// Get the room account data event of the given type, if any.
+    pub fn account_data(
+        &self,
+        event_type: &RoomAccountDataEventType,
+    ) -> Option<&Raw<AnyRoomAccountDataEvent>> {
+        self.account_data.get(event_type)
+    }
+
+    /// The IDs of the users currently typing in the room.
+    pub fn typing_users(&self) -> &[OwnedUserId] {
+        &self.typing
+    }
+
+    /// Get the latest receipt of the given type sent by the given user, if any.
+    pub fn user_receipt(
+        &self,
+        receipt_type: &ReceiptType,
+        user_id: &UserId,
+    ) -> Option<(&EventId, &Receipt)> {
+        self.receipts
+            .get(receipt_type)?
+            .get(user_id)
+            .map(|(event_id, receipt)| (&**event_id, receipt))
+    }
+
+    /// The token to paginate backwards from the start of the latest timeline received in a sync
+    /// response, if any.
+    pub fn prev_batch(&self) -> Option<&str> {
+        self.prev_batch.as_deref()
+    }
+
+    /// Update the room with the given state event.
+    ///
+    /// State events that can't be deserialized are ignored.
+    pub fn apply_state_event(&mut self, raw: &Raw<AnySyncStateEvent>) {
+        let event = match raw.deserialize() {
+            Ok(event) => event,
+            Err(error) => {
+                warn!(room_id = %self.room_id, %error, "Ignoring invalid state event");
+                return;
+            }
+        };
+
+        match &event {
+            AnySyncStateEvent::RoomMember(member) => {
+                let content = match member {
+                    SyncStateEvent::Original(ev) => ev.content.clone(),
+                    SyncStateEvent::Redacted(ev) => {
+                        RoomMemberEventContent::new(ev.content.membership.clone())
+                    }
+                };
+                self.apply_member(content, member.sender(), member.state_key());
+            }
+            AnySyncStateEvent::RoomName(name) => {
+                self.name = name.as_original().map(|ev| ev.content.name.clone());
+            }
+            AnySyncStateEvent::RoomCanonicalAlias(alias) => {
+                self.canonical_alias = alias.as_original().and_then(|ev| ev.content.alias.clone());
+            }
+            _ => {}
+        }
+
+        self.state.insert((event.event_type(), event.state_key().to_owned()), raw.clone());
+    }
+
+    /// Update the room with the given stripped state event.
+    ///
+    /// Stripped state events that can't be deserialized are ignored.
+    pub fn apply_stripped_state_event(&mut self, raw: &Raw<AnyStrippedStateEvent>) {
+        let event = match raw.deserialize() {
+            Ok(event) => event,
+            Err(error) => {
+                warn!(room_id = %self.room_id, %error, "Ignoring invalid stripped state event");
+                return;
+            }
+        };
+
+        match &event {
+            AnyStrippedStateEvent::RoomMember(member) => {
+                self.apply_member(member.content.clone(), &member.sender, &member.state_key);
+            }
+            AnyStrippedStateEvent::RoomName(name) => {
+                self.name = name.content.name.clone();
+            }
+            AnyStrippedStateEvent::RoomCanonicalAlias(alias) => {
+                self.canonical_alias = alias.content.alias.clone();
+            }
+            _ => {}
+        }
+
+        self.stripped_state.insert((event.event_type(), event.state_key().to_owned()), raw.clone());
+    }
+
+    fn apply_state_events(&mut self, events: &[Raw<AnySyncStateEvent>]) {
+        for raw in events {
+            self.apply_state_event(raw);
+        }
+    }
+
+    fn apply_stripped_state_events(&mut self, events: &[Raw<AnyStrippedStateEvent>]) {
+        for raw in events {
+            self.apply_stripped_state_event(raw);
+        }
+    }
+
+    fn apply_timeline(&mut self, timeline: &sync_events::v3::Timeline) {
+        #[derive(Deserialize)]
+        struct StateKey {
+            #[allow(dead_code)]
+            state_key: String,
+        }
+
+        for raw in &timeline.events {
+            // Only state events have a state key.
+            if raw.deserialize_as::<StateKey>().is_ok() {
+                self.apply_state_event(raw.cast_ref());
+            }
+        }
+
+        if timeline.prev_batch.is_some() {
+            self.prev_batch = timeline.prev_batch.clone();
+        }
+    }
+
+    fn apply_summary(&mut self, summary: &sync_events::v3::RoomSummary) {
+        // The fields of the summary are only sent when they changed.
+        if !summary.heroes.is_empty() {
+            self.heroes =
+                summary.heroes.iter().filter_map(|hero| hero.as_str().try_into().ok()).collect();
+        }
+        if summary.joined_member_count.is_some() {
+            self.joined_member_count = summary.joined_member_count;
+        }
+        if summary.invited_member_count.is_some() {
+            self.invited_member_count = summary.invited_member_count;
+        }
+    }
+
+    fn apply_account_data(&mut self, events: &[Raw<AnyRoomAccountDataEvent>]) {
+        #[derive(Deserialize)]
+        struct EventType {
+            #[serde(rename = "type")]
+            event_type: RoomAccountDataEventType,
+        }
+
+        for raw in events {
+            match raw.deserialize_as::<EventType>() {
+                Ok(EventType { event_type }) => {
+                    self.account_data.insert(event_type, raw.clone());
+                }
+                Err(error) => {
+                    warn!(room_id = %self.room_id, %error, "Ignoring invalid account data event");
+                }
+            }
+        }
+    }
+
+    fn apply_ephemeral(&mut self, events: &[Raw<AnySyncEphemeralRoomEvent>]) {
+        for raw in events {
+            match raw.deserialize() {
+                Ok(AnySyncEphemeralRoomEvent::Typing(event)) => {
+                    self.typing = event.content.user_ids;
+                }
+                Ok(AnySyncEphemeralRoomEvent::Receipt(event)) => self.apply_receipts(event.content),
+                Ok(_) => {}
+                Err(error) => {
+                    warn!(room_id = %self.room_id, %error, "Ignoring invalid ephemeral event");
+                }
+            }
+        }
+    }
+
+    fn apply_receipts(&mut self, content: ReceiptEventContent) {
+        for (event_id, receipts) in content.0 {
+            for (receipt_type, user_receipts) in receipts {
+                let latest = self.receipts.entry(receipt_type).or_default();
+                for (user_id, receipt) in user_receipts {
+                    latest.insert(user_id, (event_id.clone(), receipt));
+                }
+            }
+        }
+    }
+
+    fn apply_member(
+        &mut self,
+        content: RoomMemberEventContent,
+        sender: &UserId,
+        state_key: &UserId,
+    ) {
+        let prev_content = self.members.get(state_key);
+        let change =
+            content.membership_change(prev_content.map(|c| c.details()), sender, state_key);
+
+        // Gappy syncs can skip intermediate membership events, so the state sent by the server is
+        // authoritative even if the transition looks invalid.
+        if let MembershipChange::Error = change {
+            warn!(
+                room_id = %self.room_id,
+                user_id = %state_key,
+                membership = %content.membership,
+                "Applying unexpected membership transition"
+            );
+        }
+
+        self.members.insert(state_key.to_owned(), content);
+    }
+}
+
+/// The display name of a room, computed by [`RoomState::display_name()`].
+///
+/// Its `Display` implementation produces the English strings suggested by the spec.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_enums)]
+pub enum RoomDisplayName {
+    /// The room has an `m.room.name`.
+    Named(String),
+
+    /// The room has no name but has an `m.room.canonical_alias`.
+    Aliased(OwnedRoomAliasId),
+
+    /// The name is calculated from the heroes of the room.
+    Calculated {
+        /// The display names of the heroes of the room.
+        heroes: Vec<String>,
+
+        /// The number of other members of the room that are not part of the heroes.
+        others: u64,
+    },
+
+    /// The user is alone in the room.
+    Empty {
+        /// The display names of the former members of the room, if any.
+        heroes: Vec<String>,
+    },
+}
+
+impl fmt::Display for RoomDisplayName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Named(name) => f.write_str(name),
+            Self::Aliased(alias) => f.write_str(alias.as_str()),
+            Self::Calculated { heroes, others } => write_heroes(f, heroes, *others),
+            Self::Empty { heroes } if heroes.is_empty() => f.write_str("Empty Room"),
+            Self::Empty { heroes } => {
+                f.write_str("Empty Room (was ")?;
+                write_heroes(f, heroes, 0)?;
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+/// Write the given list of names, followed by the count of other users, in English.
+fn write_heroes(f: &mut fmt::Formatter<'_>, heroes: &[String], others: u64) -> fmt::Result {
+    let others = match others {
+        0 => None,
+        1 => Some("1 other".to_owned()),
+        n => Some(format!("{n} others")),
+    };
+    let names: Vec<&str> = heroes.iter().map(String::as_str).chain(others.as_deref()).collect();
+
+    match names.as_slice() {
+        [] => Ok(()),
+        [name] => f.write_str(name),
+        [names @ .., last] => write!(f, "{} and {last}", names.join(", ")),
+    }
+}
+
+/// Whether the given notification counts are both zero or absent.
+fn is_zero(counts: &UnreadNotificationsCount) -> bool {
+    counts.highlight_count.unwrap_or(uint!(0)) == uint!(0)
+        && counts.notification_count.unwrap_or(uint!(0)) == uint!(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use js_int::uint;
+    use ruma_client_api::sync::sync_events::v3::{
+        InvitedRoom, JoinedRoom, LeftRoom, Response, RoomSummary,
+    };
+    use ruma_common::{owned_room_id, owned_user_id, room_id, serde::Raw, user_id, OwnedRoomId};
+    use ruma_events::{
+        receipt::ReceiptType, room::member::MembershipState, RoomAccountDataEventType,
+        StateEventType,
+    };
+    use serde_json::{json, Value as JsonValue};
+
+    use super::{RoomDisplayName, RoomStore};
+
+    fn raw<T>(json: JsonValue) -> Raw<T> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn member(user_id: &str, membership: &str, displayname: Option<&str>) -> JsonValue {
+        json!({
+            "type": "m.room.member",
+            "event_id": format!("${user_id}-{membership}"),
+            "sender": user_id,
+            "state_key": user_id,
+            "origin_server_ts": 1,
+            "content": { "membership": membership, "displayname": displayname },
+        })
+    }
+
+    fn store() -> RoomStore {
+        RoomStore::new(owned_user_id!("@me:example.org"))
+    }
+
+    fn joined(room_id: OwnedRoomId, room: JoinedRoom) -> Response {
+        let mut response = Response::new("next".to_owned());
+        response.rooms.join.insert(room_id, room);
+        response
+    }
+
+    #[test]
+    fn state_timeline_and_members() {
+        let mut room = JoinedRoom::new();
+        room.state.events = vec![
+            raw(member("@me:example.org", "join", Some("Me"))),
+            raw(member("@alice:example.org", "join", Some("Alice"))),
+        ];
+        room.timeline.events = vec![
+            raw(member("@bob:example.org", "join", Some("Alice"))),
+            raw(json!({
+                "type": "m.room.message",
+                "event_id": "$message",
+                "sender": "@bob:example.org",
+                "origin_server_ts": 2,
+                "content": { "msgtype": "m.text", "body": "Hello" },
+            })),
+            raw(member("@alice:example.org", "leave", Some("Alice"))),
+        ];
+        room.timeline.prev_batch = Some("prev".to_owned());
+        room.unread_notifications.notification_count = Some(uint!(3));
+
+        let mut store = store();
+        store.apply_sync_response(&joined(owned_room_id!("!room:example.org"), room));
+
+        let state = store.get(room_id!("!room:example.org")).unwrap();
+        assert_eq!(*state.membership(), MembershipState::Join);
+        assert!(state.state_event(&StateEventType::RoomMember, "@bob:example.org").is_some());
+        assert_eq!(state.state_events().count(), 3);
+        assert_eq!(
+            state.joined_members().collect::<Vec<_>>(),
+            [user_id!("@bob:example.org"), user_id!("@me:example.org")]
+        );
+        assert_eq!(state.left_members().collect::<Vec<_>>(), [user_id!("@alice:example.org")]);
+        assert_eq!(state.prev_batch(), Some("prev"));
+        assert_eq!(state.unread_notifications().notification_count, Some(uint!(3)));
+
+        // Alice left, so Bob's display name is not ambiguous anymore.
+        assert_eq!(state.member_display_name(user_id!("@bob:example.org")), "Alice");
+        assert_eq!(state.member_display_name(user_id!("@carol:example.org")), "@carol:example.org");
+    }
+
+    #[test]
+    fn member_display_name_disambiguation() {
+        let mut room = JoinedRoom::new();
+        room.state.events = vec![
+            raw(member("@alice:example.org", "join", Some("Alice"))),
+            raw(member("@fake:example.org", "invite", Some("Alice"))),
+            raw(member("@bob:example.org", "join", None)),
+        ];
+
+        let mut store = store();
+        store.apply_sync_response(&joined(owned_room_id!("!room:example.org"), room));
+        let state = store.get(room_id!("!room:example.org")).unwrap();
+
+        assert_eq!(
+            state.member_display_name(user_id!("@alice:example.org")),
+            "Alice (@alice:example.org)"
+        );
+        assert_eq!(
+            state.member_display_name(user_id!("@fake:example.org")),
+            "Alice (@fake:example.org)"
+        );
+        assert_eq!(state.member_display_name(user_id!("@bob:example.org")), "@bob:example.org");
+    }
+
+    #[test]
+    fn room_display_name() {
+        let room_id = owned_room_id!("!room:example.org");
+        let mut store = store();
+
+        let mut room = JoinedRoom::new();
+        room.state.events = vec![
+            raw(member("@me:example.org", "join", None)),
+            raw(member("@alice:example.org", "join", Some("Alice"))),
+            raw(member("@bob:example.org", "invite", Some("Bob"))),
+        ];
+        store.apply_sync_response(&joined(room_id.clone(), room));
+        let name = store.get(&room_id).unwrap().display_name();
+        assert_eq!(
+            name,
+            RoomDisplayName::Calculated {
+                heroes: vec!["Alice".to_owned(), "Bob".to_owned()],
+                others: 0
+            }
+        );
+        assert_eq!(name.to_string(), "Alice and Bob");
+
+        // The summary of the homeserver takes precedence.
+        let mut room = JoinedRoom::new();
+        room.summary = RoomSummary::new();
+        room.summary.heroes = vec!["@alice:example.org".to_owned()];
+        room.summary.joined_member_count = Some(uint!(40));
+        room.summary.invited_member_count = Some(uint!(2));
+        store.apply_sync_response(&joined(room_id.clone(), room));
+        assert_eq!(store.get(&room_id).unwrap().display_name().to_string(), "Alice and 40 others");
+
+        let mut room = JoinedRoom::new();
+        room.timeline.events = vec![raw(json!({
+            "type": "m.room.canonical_alias",
+            "event_id": "$alias",
+            "sender": "@alice:example.org",
+            "state_key": "",
+            "origin_server_ts": 3,
+            "content": { "alias": "#room:example.org" },
+        }))];
+        store.apply_sync_response(&joined(room_id.clone(), room));
+        assert_eq!(store.get(&room_id).unwrap().display_name().to_string(), "#room:example.org");
+
+        let mut room = JoinedRoom::new();
+        room.timeline.events = vec![raw(json!({
+            "type": "m.room.name",
+            "event_id": "$name",
+            "sender": "@alice:example.org",
+            "state_key": "",
+            "origin_server_ts": 4,
+            "content": { "name": "Ruma" },
+        }))];
+        store.apply_sync_response(&joined(room_id.clone(), room));
+        assert_eq!(store.get(&room_id).unwrap().display_name().to_string(), "Ruma");
+    }
+
+    #[test]
+    fn empty_room_display_name() {
+        let room_id = owned_room_id!("!room:example.org");
+        let mut store = store();
+
+        let mut room = JoinedRoom::new();
+        room.state.events = vec![raw(member("@me:example.org", "join", None))];
+        store.apply_sync_response(&joined(room_id.clone(), room));
+        assert_eq!(store.get(&room_id).unwrap().display_name().to_string(), "Empty Room");
+
+        let mut room = JoinedRoom::new();
+        room.state.events = vec![
+            raw(member("@alice:example.org", "leave", Some("Alice"))),
+            raw(member("@bob:example.org", "leave", Some("Bob"))),
+            raw(member("@carol:example.org", "leave", None)),
+        ];
+        store.apply_sync_response(&joined(room_id.clone(), room));
+        assert_eq!(
+            store.get(&room_id).unwrap().display_name().to_string(),
+            "Empty Room (was Alice, Bob and @carol:example.org)"
+        );
+    }
+
+    #[test]
+    fn gappy_sync_membership_transition() {
+        let room_id = owned_room_id!("!room:example.org");
+        let mut store = store();
+
+        let mut room = JoinedRoom::new();
+        room.state.events = vec![
+            raw(member("@me:example.org", "join", None)),
+            raw(member("@alice:example.org", "join", Some("Alice"))),
+        ];
+        room.timeline.events = vec![raw(json!({
+            "type": "m.room.member",
+            "event_id": "$ban",
+            "sender": "@me:example.org",
+            "state_key": "@alice:example.org",
+            "origin_server_ts": 2,
+            "content": { "membership": "ban" },
+        }))];
+        store.apply_sync_response(&joined(room_id.clone(), room));
+        let state = store.get(&room_id).unwrap();
+        assert_eq!(
+            state.member(user_id!("@alice:example.org")).unwrap().membership,
+            MembershipState::Ban
+        );
+
+        // The unban was skipped by a gappy sync.
+        let mut room = JoinedRoom::new();
+        room.timeline.limited = true;
+        room.timeline.events = vec![raw(member("@alice:example.org", "join", Some("Alice")))];
+        store.apply_sync_response(&joined(room_id.clone(), room));
+
+        let state = store.get(&room_id).unwrap();
+        assert_eq!(
+            state.member(user_id!("@alice:example.org")).unwrap().membership,
+            MembershipState::Join
+        );
+        assert_eq!(
+            state
+                .state_event(&StateEventType::RoomMember, "@alice:example.org")
+                .unwrap()
+                .get_field::<String>("event_id")
+                .unwrap()
+                .as_deref(),
+            Some("$@alice:example.org-join")
+        );
+        assert_eq!(
+            state.joined_members().collect::<Vec<_>>(),
+            [user_id!("@alice:example.org"), user_id!("@me:example.org")]
+        );
+        assert_eq!(state.member_display_name(user_id!("@alice:example.org")), "Alice");
+    }
+
+    #[test]
+    fn invite_then_join_then_leave() {
+        let room_id = owned_room_id!("!room:example.org");
+        let mut store = store();
+
+        let mut invite = InvitedRoom::new();
+        invite.invite_state.events = vec![
+            raw(json!({
+                "type": "m.room.name",
+                "sender": "@alice:example.org",
+                "state_key": "",
+                "content": { "name": "Invite" },
+            })),
+            raw(json!({
+                "type": "m.room.member",
+                "sender": "@alice:example.org",
+                "state_key": "@me:example.org",
+                "content": { "membership": "invite" },
+            })),
+        ];
+        let mut response = Response::new("next".to_owned());
+        response.rooms.invite.insert(room_id.clone(), invite);
+        store.apply_sync_response(&response);
+
+        let state = store.get(&room_id).unwrap();
+        assert_eq!(*state.membership(), MembershipState::Invite);
+        assert_eq!(state.display_name().to_string(), "Invite");
+        assert!(state.stripped_state_event(&StateEventType::RoomName, "").is_some());
+        assert_eq!(store.rooms_with_membership(&MembershipState::Invite).count(), 1);
+
+        let mut room = JoinedRoom::new();
+        room.state.events = vec![raw(member("@alice:example.org", "join", Some("Alice")))];
+        room.timeline.events = vec![raw(member("@me:example.org", "join", None))];
+        room.account_data.events =
+            vec![raw(json!({ "type": "m.tag", "content": { "tags": { "u.work": {} } } }))];
+        room.ephemeral.events = vec![
+            raw(json!({
+                "type": "m.typing",
+                "content": { "user_ids": ["@alice:example.org"] },
+            })),
+            raw(json!({
+                "type": "m.receipt",
+                "content": {
+                    "$event": { "m.read": { "@alice:example.org": { "ts": 1 } } },
+                },
+            })),
+        ];
+        store.apply_sync_response(&joined(room_id.clone(), room));
+
+        let state = store.get(&room_id).unwrap();
+        assert_eq!(*state.membership(), MembershipState::Join);
+        assert!(state.stripped_state_event(&StateEventType::RoomName, "").is_none());
+        assert_eq!(state.display_name().to_string(), "Alice");
+        assert!(state.account_data(&RoomAccountDataEventType::Tag).is_some());
+        assert_eq!(state.typing_users(), [owned_user_id!("@alice:example.org")]);
+        let (event_id, _) =
+            state.user_receipt(&ReceiptType::Read, user_id!("@alice:example.org")).unwrap();
+        assert_eq!(event_id, "$event");
+
+        let mut room = LeftRoom::new();
+        room.timeline.events = vec![raw(member("@me:example.org", "leave", None))];
+        let mut response = Response::new("next".to_owned());
+        response.rooms.leave.insert(room_id.clone(), room);
+        store.apply_sync_response(&response);
+
+        let state = store.get(&room_id).unwrap();
+        assert_eq!(*state.membership(), MembershipState::Leave);
+        assert!(state.typing_users().is_empty());
+        assert_eq!(state.left_members().collect::<Vec<_>>(), [user_id!("@me:example.org")]);
+    }
+}
@@ -0,0 +1,8 @@
+#![cfg(feature = "client-api")]
+
+mod mock;
+mod retry;
+mod session;
+#[cfg(feature = "unstable-msc3575")]
+mod sliding_sync;
+mod uiaa;
@@ -0,0 +1,50 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use http::StatusCode;
+use ruma_client::HttpClient;
+
+/// An `HttpClient` that returns canned responses in order.
+#[derive(Debug, Default)]
+pub(crate) struct MockClient {
+    responses: Mutex<VecDeque<(StatusCode, &'static str)>>,
+    pub(crate) requests: Arc<AtomicUsize>,
+    pub(crate) authorization: Arc<Mutex<Vec<Option<String>>>>,
+    pub(crate) uris: Arc<Mutex<Vec<http::Uri>>>,
+    pub(crate) bodies: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl MockClient {
+    pub(crate) fn new(responses: impl IntoIterator<Item = (StatusCode, &'static str)>) -> Self {
+        Self { responses: Mutex::new(responses.into_iter().collect()), ..Default::default() }
+    }
+}
+
+impl HttpClient for MockClient {
+    type RequestBody = Vec<u8>;
+    type ResponseBody = Vec<u8>;
+    type Error = ();
+
+    async fn send_http_request(
+        &self,
+        req: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<Vec<u8>>, ()> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        self.authorization.lock().unwrap().push(
+            req.headers()
+                .get(http::header::AUTHORIZATION)
+                .map(|value| value.to_str().unwrap().to_owned()),
+        );
+        self.uris.lock().unwrap().push(req.uri().clone());
+        self.bodies.lock().unwrap().push(req.body().clone());
+
+        let (status, body) =
+            self.responses.lock().unwrap().pop_front().expect("unexpected request");
+        Ok(http::Response::builder().status(status).body(body.as_bytes().to_owned()).unwrap())
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use assign::assign;
+use http::StatusCode;
+use ruma_client::{
+    retry::{ExponentialBackoff, RetryAttempt, RetryPolicy},
+    Client,
+};
+use ruma_client_api::{alias::get_alias, error::ErrorKind, session::logout};
+use ruma_common::{api::MatrixVersion, owned_room_alias_id, room_id};
+
+use crate::mock::MockClient;
+
+/// A policy that retries everything immediately, up to 3 attempts.
+#[derive(Debug)]
+struct Immediately;
+
+impl RetryPolicy for Immediately {
+    fn retry_delay(&self, attempt: &RetryAttempt<'_>) -> Option<Duration> {
+        (attempt.attempt < 3).then_some(Duration::ZERO)
+    }
+}
+
+async fn client(
+    http_client: MockClient,
+    policy: impl RetryPolicy + 'static,
+) -> (Client<MockClient>, Arc<AtomicUsize>) {
+    let requests = http_client.requests.clone();
+    let client = Client::builder()
+        .homeserver_url("https://example.com".to_owned())
+        .access_token(Some("token".to_owned()))
+        .supported_matrix_versions(vec![MatrixVersion::V1_0])
+        .retry_policy(policy)
+        .http_client(http_client)
+        .await
+        .unwrap();
+
+    (client, requests)
+}
+
+const RATE_LIMITED: (StatusCode, &str) = (
+    StatusCode::TOO_MANY_REQUESTS,
+    r#"{ "errcode": "M_LIMIT_EXCEEDED", "error": "Slow down", "retry_after_ms": 10 }"#,
+);
+const ALIAS: (StatusCode, &str) =
+    (StatusCode::OK, r#"{ "room_id": "!room:example.com", "servers": [] }"#);
+
+#[tokio::test]
+async fn retries_rate_limited_request() {
+    let (client, requests) =
+        client(MockClient::new([RATE_LIMITED, RATE_LIMITED, ALIAS]), Immediately).await;
+
+    let response = client
+        .send_request(get_alias::v3::Request::new(owned_room_alias_id!("#room:example.com")))
+        .await
+        .unwrap();
+
+    assert_eq!(response.room_id, room_id!("!room:example.com"));
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn returns_error_when_giving_up() {
+    let (client, requests) =
+        client(MockClient::new([RATE_LIMITED, RATE_LIMITED, RATE_LIMITED]), Immediately).await;
+
+    let error = client
+        .send_request(get_alias::v3::Request::new(owned_room_alias_id!("#room:example.com")))
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        error.error_kind(),
+        Some(&ErrorKind::LimitExceeded { retry_after_ms: Some(Duration::from_millis(10)) })
+    );
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn exponential_backoff_honors_retry_after() {
+    let policy = assign!(ExponentialBackoff::new(), { initial_delay: Duration::ZERO });
+    let (client, requests) = client(MockClient::new([RATE_LIMITED, ALIAS]), policy).await;
+
+    client
+        .send_request(get_alias::v3::Request::new(owned_room_alias_id!("#room:example.com")))
+        .await
+        .unwrap();
+
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn exponential_backoff_does_not_replay_unsafe_request() {
+    let policy = assign!(ExponentialBackoff::new(), { initial_delay: Duration::ZERO });
+    let (client, requests) =
+        client(MockClient::new([(StatusCode::BAD_GATEWAY, "Bad Gateway")]), policy).await;
+
+    client.send_request(logout::v3::Request::new()).await.unwrap_err();
+
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,120 @@
+use std::sync::{Arc, Mutex};
+
+use http::StatusCode;
+use ruma_client::{Client, Session};
+use ruma_client_api::{account::whoami, error::ErrorKind};
+use ruma_common::{api::MatrixVersion, owned_device_id, owned_user_id, MilliSecondsSinceUnixEpoch};
+
+use crate::mock::MockClient;
+
+const SOFT_LOGOUT: (StatusCode, &str) = (
+    StatusCode::UNAUTHORIZED,
+    r#"{ "errcode": "M_UNKNOWN_TOKEN", "error": "Token expired", "soft_logout": true }"#,
+);
+const REFRESHED: (StatusCode, &str) = (
+    StatusCode::OK,
+    r#"{ "access_token": "new_access", "refresh_token": "new_refresh", "expires_in_ms": 60000 }"#,
+);
+const WHOAMI: (StatusCode, &str) = (StatusCode::OK, r#"{ "user_id": "@alice:example.com" }"#);
+
+fn session() -> Session {
+    let mut session = Session::new(
+        owned_user_id!("@alice:example.com"),
+        owned_device_id!("ABCDEF"),
+        "old_access".to_owned(),
+    );
+    session.refresh_token = Some("old_refresh".to_owned());
+    session
+}
+
+async fn client(
+    http_client: MockClient,
+    session: Session,
+) -> (Client<MockClient>, Arc<Mutex<Vec<Session>>>) {
+    let persisted = Arc::new(Mutex::new(Vec::new()));
+    let persisted_clone = persisted.clone();
+
+    let client = Client::builder()
+        .homeserver_url("https://example.com".to_owned())
+        .session(Some(session))
+        .on_session_change(move |session| persisted_clone.lock().unwrap().push(session.clone()))
+        .supported_matrix_versions(vec![MatrixVersion::V1_3])
+        .http_client(http_client)
+        .await
+        .unwrap();
+
+    (client, persisted)
+}
+
+#[tokio::test]
+async fn refresh_on_soft_logout() {
+    let http_client = MockClient::new([SOFT_LOGOUT, REFRESHED, WHOAMI]);
+    let authorization = http_client.authorization.clone();
+    let (client, persisted) = client(http_client, session()).await;
+
+    client.send_request(whoami::v3::Request::new()).await.unwrap();
+
+    assert_eq!(
+        *authorization.lock().unwrap(),
+        [Some("Bearer old_access".to_owned()), None, Some("Bearer new_access".to_owned())]
+    );
+
+    let session = client.session().unwrap();
+    assert_eq!(session.access_token, "new_access");
+    assert_eq!(session.refresh_token.as_deref(), Some("new_refresh"));
+    assert!(session.expires_at.is_some());
+    assert_eq!(client.access_token().as_deref(), Some("new_access"));
+
+    let persisted = persisted.lock().unwrap();
+    assert_eq!(persisted.len(), 1);
+    assert_eq!(persisted[0].access_token, "new_access");
+}
+
+#[tokio::test]
+async fn refresh_before_expiry() {
+    let mut session = session();
+    session.expires_at = Some(MilliSecondsSinceUnixEpoch::now());
+
+    let http_client = MockClient::new([REFRESHED, WHOAMI]);
+    let authorization = http_client.authorization.clone();
+    let (client, persisted) = client(http_client, session).await;
+
+    client.send_request(whoami::v3::Request::new()).await.unwrap();
+
+    assert_eq!(*authorization.lock().unwrap(), [None, Some("Bearer new_access".to_owned())]);
+    assert_eq!(persisted.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn no_refresh_without_refresh_token() {
+    let mut session = session();
+    session.refresh_token = None;
+
+    let (client, persisted) = client(MockClient::new([SOFT_LOGOUT]), session).await;
+
+    let error = client.send_request(whoami::v3::Request::new()).await.unwrap_err();
+
+    assert_eq!(error.error_kind(), Some(&ErrorKind::UnknownToken { soft_logout: true }));
+    assert!(persisted.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn failed_refresh_returns_original_error() {
+    let (client, persisted) = client(
+        MockClient::new([
+            SOFT_LOGOUT,
+            (
+                StatusCode::UNAUTHORIZED,
+                r#"{ "errcode": "M_UNKNOWN_TOKEN", "error": "Refresh token revoked" }"#,
+            ),
+        ]),
+        session(),
+    )
+    .await;
+
+    let error = client.send_request(whoami::v3::Request::new()).await.unwrap_err();
+
+    assert_eq!(error.error_kind(), Some(&ErrorKind::UnknownToken { soft_logout: true }));
+    assert_eq!(client.session().unwrap().access_token, "old_access");
+    assert!(persisted.lock().unwrap().is_empty());
+}
@@ -0,0 +1,63 @@
+use http::StatusCode;
+use ruma_client::{Client, SlidingSync};
+use ruma_client_api::sync::sync_events::v4;
+use ruma_common::{api::MatrixVersion, owned_room_id};
+use tokio_stream::StreamExt as _;
+
+use crate::mock::MockClient;
+
+#[tokio::test]
+async fn restart_on_unknown_pos() {
+    let http_client = MockClient::new([
+        (
+            StatusCode::OK,
+            r#"{
+                "pos": "1",
+                "lists": {
+                    "all": {
+                        "count": 1,
+                        "ops": [{ "op": "SYNC", "range": [0, 0], "room_ids": ["!a:localhost"] }]
+                    }
+                }
+            }"#,
+        ),
+        (StatusCode::BAD_REQUEST, r#"{ "errcode": "M_UNKNOWN_POS", "error": "Unknown position" }"#),
+        (
+            StatusCode::OK,
+            r#"{
+                "pos": "2",
+                "lists": {
+                    "all": {
+                        "count": 1,
+                        "ops": [{ "op": "SYNC", "range": [0, 0], "room_ids": ["!b:localhost"] }]
+                    }
+                }
+            }"#,
+        ),
+    ]);
+    let uris = http_client.uris.clone();
+
+    let client = Client::builder()
+        .homeserver_url("https://example.com".to_owned())
+        .access_token(Some("token".to_owned()))
+        .supported_matrix_versions(vec![MatrixVersion::V1_0])
+        .http_client(http_client)
+        .await
+        .unwrap();
+
+    let sliding_sync = SlidingSync::new();
+    sliding_sync.add_list("all", v4::SyncRequestList::default());
+    let mut stream = Box::pin(client.sliding_sync(sliding_sync.clone()));
+
+    let response = stream.try_next().await.unwrap().unwrap();
+    assert_eq!(response.pos, "1");
+    assert_eq!(sliding_sync.list_rooms("all").unwrap(), [Some(owned_room_id!("!a:localhost"))]);
+
+    let response = stream.try_next().await.unwrap().unwrap();
+    assert_eq!(response.pos, "2");
+    assert_eq!(sliding_sync.list_rooms("all").unwrap(), [Some(owned_room_id!("!b:localhost"))]);
+
+    let queries: Vec<_> =
+        uris.lock().unwrap().iter().map(|uri| uri.query().map(ToOwned::to_owned)).collect();
+    assert_eq!(queries, [None, Some("pos=1".to_owned()), None]);
+}
@@ -0,0 +1,130 @@
+use std::sync::atomic::Ordering;
+
+use http::StatusCode;
+use ruma_client::{Client, Error};
+use ruma_client_api::{
+    account::deactivate,
+    uiaa::{AuthType, UiaaResponse},
+};
+use ruma_common::api::{error::FromHttpResponseError, MatrixVersion};
+use serde_json::{json, Value as JsonValue};
+
+use crate::mock::MockClient;
+
+const AUTH_REQUIRED: (StatusCode, &str) = (
+    StatusCode::UNAUTHORIZED,
+    r#"{
+        "flows": [
+            { "stages": ["m.login.recaptcha"] },
+            { "stages": ["m.login.password", "m.login.dummy"] }
+        ],
+        "params": {},
+        "session": "abcdef"
+    }"#,
+);
+const PASSWORD_COMPLETED: (StatusCode, &str) = (
+    StatusCode::UNAUTHORIZED,
+    r#"{
+        "flows": [
+            { "stages": ["m.login.recaptcha"] },
+            { "stages": ["m.login.password", "m.login.dummy"] }
+        ],
+        "completed": ["m.login.password"],
+        "params": {},
+        "session": "abcdef"
+    }"#,
+);
+const DEACTIVATED: (StatusCode, &str) =
+    (StatusCode::OK, r#"{ "id_server_unbind_result": "success" }"#);
+
+async fn client(http_client: MockClient) -> Client<MockClient> {
+    Client::builder()
+        .homeserver_url("https://example.com".to_owned())
+        .access_token(Some("token".to_owned()))
+        .supported_matrix_versions(vec![MatrixVersion::V1_0])
+        .http_client(http_client)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn completes_all_stages() {
+    let http_client = MockClient::new([AUTH_REQUIRED, PASSWORD_COMPLETED, DEACTIVATED]);
+    let bodies = http_client.bodies.clone();
+    let client = client(http_client).await;
+
+    let mut stages = Vec::new();
+    client
+        .send_uiaa_request(
+            deactivate::v3::Request::new(),
+            [AuthType::Password, AuthType::Dummy],
+            |session| {
+                let stage = session.next_stage().unwrap().clone();
+                stages.push(stage.clone());
+
+                let data = match stage {
+                    AuthType::Password => json!({
+                        "identifier": { "type": "m.id.user", "user": "alice" },
+                        "password": "hunter2",
+                    }),
+                    _ => json!({}),
+                };
+                let auth_data = session.auth_data(&stage, serde_json::from_value(data).unwrap());
+                async move { Some(auth_data.unwrap()) }
+            },
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(stages, [AuthType::Password, AuthType::Dummy]);
+
+    let auth: Vec<JsonValue> = bodies
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|body| serde_json::from_slice::<JsonValue>(body).unwrap()["auth"].clone())
+        .collect();
+    assert_eq!(auth[0], JsonValue::Null);
+    assert_eq!(auth[1]["type"], "m.login.password");
+    assert_eq!(auth[1]["session"], "abcdef");
+    assert_eq!(auth[2], json!({ "type": "m.login.dummy", "session": "abcdef" }));
+}
+
+#[tokio::test]
+async fn returns_error_without_completable_flow() {
+    let http_client = MockClient::new([AUTH_REQUIRED]);
+    let requests = http_client.requests.clone();
+    let client = client(http_client).await;
+
+    let mut called = false;
+    let error = client
+        .send_uiaa_request(deactivate::v3::Request::new(), [AuthType::Dummy], |_| {
+            called = true;
+            async { None }
+        })
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        error,
+        Error::FromHttpResponse(FromHttpResponseError::Server(UiaaResponse::AuthResponse(_)))
+    ));
+    assert!(!called);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn returns_error_when_cancelled() {
+    let http_client = MockClient::new([AUTH_REQUIRED]);
+    let client = client(http_client).await;
+
+    let error = client
+        .send_uiaa_request(deactivate::v3::Request::new(), [AuthType::Password], |_| async { None })
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        error,
+        Error::FromHttpResponse(FromHttpResponseError::Server(UiaaResponse::AuthResponse(_)))
+    ));
+}
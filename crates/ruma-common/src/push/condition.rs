@@ -250,6 +250,40 @@ pub struct PushConditionRoomCtx {
     pub supported_features: Vec<RoomVersionFeature>,
 }
 
+impl PushConditionRoomCtx {
+    /// Creates a new `PushConditionRoomCtx` with the given room ID, member count, user ID and
+    /// display name.
+    ///
+    /// The power levels are not set, and the room doesn't support any feature.
+    pub fn new(
+        room_id: OwnedRoomId,
+        member_count: UInt,
+        user_id: OwnedUserId,
+        user_display_name: String,
+    ) -> Self {
+        Self {
+            room_id,
+            member_count,
+            user_id,
+            user_display_name,
+            power_levels: None,
+            #[cfg(feature = "unstable-msc3931")]
+            supported_features: Vec::new(),
+        }
+    }
+
+    /// Set the power levels context of this `PushConditionRoomCtx`.
+    pub fn with_power_levels(self, power_levels: PushConditionPowerLevelsCtx) -> Self {
+        Self { power_levels: Some(power_levels), ..self }
+    }
+
+    /// Set the features supported by the room of this `PushConditionRoomCtx`.
+    #[cfg(feature = "unstable-msc3931")]
+    pub fn with_supported_features(self, supported_features: Vec<RoomVersionFeature>) -> Self {
+        Self { supported_features, ..self }
+    }
+}
+
 /// The room power levels context to be able to test the corresponding push conditions.
 #[derive(Clone, Debug)]
 #[allow(clippy::exhaustive_structs)]
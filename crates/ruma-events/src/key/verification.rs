@@ -20,6 +20,8 @@ pub mod key;
 pub mod mac;
 pub mod ready;
 pub mod request;
+#[cfg(feature = "sas-verification")]
+pub mod sas;
 pub mod start;
 
 // For these two constants, see <https://spec.matrix.org/latest/client-server-api/#key-verification-framework>
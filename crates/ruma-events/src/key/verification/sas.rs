@@ -0,0 +1,1144 @@
+//! A state machine for the [Short Authentication String (SAS) verification] method.
+//!
+//! [`SasVerification`] implements the `m.sas.v1` method, regardless of whether the verification
+//! happens with to-device messages or in a room. The events of the verification are converted to
+//! [`VerificationMessage`]s, and the [`VerificationMessage`]s that must be sent to the other party
+//! can be converted back to the event content of the transport of the verification.
+//!
+//! The verification must be started after the `m.key.verification.request` was accepted with
+//! `m.key.verification.ready`, or directly with to-device messages.
+//!
+//! [Short Authentication String (SAS) verification]: https://spec.matrix.org/latest/client-server-api/#short-authentication-string-sas-verification
+
+use std::collections::BTreeMap;
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::thread_rng;
+use ruma_common::{
+    canonical_json::to_canonical_value,
+    serde::{base64::Standard, Base64},
+    OwnedDeviceId, OwnedEventId, OwnedTransactionId, OwnedUserId, UserId,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+use super::{
+    accept::{
+        self, AcceptMethod, KeyVerificationAcceptEventContent,
+        ToDeviceKeyVerificationAcceptEventContent,
+    },
+    cancel::{
+        CancelCode, KeyVerificationCancelEventContent, ToDeviceKeyVerificationCancelEventContent,
+    },
+    done::{KeyVerificationDoneEventContent, ToDeviceKeyVerificationDoneEventContent},
+    key::{KeyVerificationKeyEventContent, ToDeviceKeyVerificationKeyEventContent},
+    mac::{KeyVerificationMacEventContent, ToDeviceKeyVerificationMacEventContent},
+    start::{
+        self, KeyVerificationStartEventContent, StartMethod,
+        ToDeviceKeyVerificationStartEventContent,
+    },
+    HashAlgorithm, KeyAgreementProtocol, MessageAuthenticationCode, ShortAuthenticationString,
+};
+use crate::{relation::Reference, AnyMessageLikeEventContent, AnyToDeviceEventContent};
+
+/// The length of a Curve25519 public key, in bytes.
+const PUBLIC_KEY_LENGTH: usize = 32;
+
+/// The key ID used to compute the MAC of the list of key IDs.
+const KEY_IDS_MAC_KEY_ID: &str = "KEY_IDS";
+
+/// The emoji used for the emoji method, with their description, as defined in the spec.
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "Dog"),
+    ("🐱", "Cat"),
+    ("🦁", "Lion"),
+    ("🐎", "Horse"),
+    ("🦄", "Unicorn"),
+    ("🐷", "Pig"),
+    ("🐘", "Elephant"),
+    ("🐰", "Rabbit"),
+    ("🐼", "Panda"),
+    ("🐓", "Rooster"),
+    ("🐧", "Penguin"),
+    ("🐢", "Turtle"),
+    ("🐟", "Fish"),
+    ("🐙", "Octopus"),
+    ("🦋", "Butterfly"),
+    ("🌷", "Flower"),
+    ("🌳", "Tree"),
+    ("🌵", "Cactus"),
+    ("🍄", "Mushroom"),
+    ("🌏", "Globe"),
+    ("🌙", "Moon"),
+    ("☁️", "Cloud"),
+    ("🔥", "Fire"),
+    ("🍌", "Banana"),
+    ("🍎", "Apple"),
+    ("🍓", "Strawberry"),
+    ("🌽", "Corn"),
+    ("🍕", "Pizza"),
+    ("🎂", "Cake"),
+    ("❤️", "Heart"),
+    ("😀", "Smiley"),
+    ("🤖", "Robot"),
+    ("🎩", "Hat"),
+    ("👓", "Glasses"),
+    ("🔧", "Spanner"),
+    ("🎅", "Santa"),
+    ("👍", "Thumbs Up"),
+    ("☂️", "Umbrella"),
+    ("⌛", "Hourglass"),
+    ("⏰", "Clock"),
+    ("🎁", "Gift"),
+    ("💡", "Light Bulb"),
+    ("📕", "Book"),
+    ("✏️", "Pencil"),
+    ("📎", "Paperclip"),
+    ("✂️", "Scissors"),
+    ("🔒", "Lock"),
+    ("🔑", "Key"),
+    ("🔨", "Hammer"),
+    ("☎️", "Telephone"),
+    ("🏁", "Flag"),
+    ("🚂", "Train"),
+    ("🚲", "Bicycle"),
+    ("✈️", "Aeroplane"),
+    ("🚀", "Rocket"),
+    ("🏆", "Trophy"),
+    ("⚽", "Ball"),
+    ("🎸", "Guitar"),
+    ("🎺", "Trumpet"),
+    ("🔔", "Bell"),
+    ("⚓", "Anchor"),
+    ("🎧", "Headphones"),
+    ("📁", "Folder"),
+    ("📌", "Pin"),
+];
+
+/// The identifier of a verification flow.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum FlowId {
+    /// A verification with to-device messages, identified by its transaction ID.
+    ToDevice(OwnedTransactionId),
+
+    /// A verification in a room, identified by the ID of the `m.key.verification.request` event.
+    InRoom(OwnedEventId),
+}
+
+impl FlowId {
+    /// The string representation of this `FlowId`, used as the transaction ID of the
+    /// verification.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::ToDevice(transaction_id) => transaction_id.as_str(),
+            Self::InRoom(event_id) => event_id.as_str(),
+        }
+    }
+}
+
+/// The content of a message of a SAS verification, independent of its transport.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum VerificationContent {
+    /// The content of an `m.key.verification.start` event.
+    Start {
+        /// The device ID which is initiating the process.
+        from_device: OwnedDeviceId,
+
+        /// The verification method and method-specific fields.
+        method: StartMethod,
+    },
+
+    /// The content of an `m.key.verification.accept` event.
+    Accept(AcceptMethod),
+
+    /// The content of an `m.key.verification.key` event, the Curve25519 public key of the device.
+    Key(Base64),
+
+    /// The content of an `m.key.verification.mac` event.
+    Mac {
+        /// A map of the key ID to the MAC of the key, using the algorithm in the verification
+        /// process.
+        mac: BTreeMap<String, Base64>,
+
+        /// The MAC of the comma-separated, sorted, list of key IDs given in the `mac` property.
+        keys: Base64,
+    },
+
+    /// The content of an `m.key.verification.done` event.
+    Done,
+
+    /// The content of an `m.key.verification.cancel` event.
+    Cancel {
+        /// The error code for why the process / request was cancelled by the user.
+        code: CancelCode,
+
+        /// A human readable description of the `code`.
+        reason: String,
+    },
+}
+
+/// A message of a SAS verification.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct VerificationMessage {
+    /// The verification flow of the message.
+    pub flow_id: FlowId,
+
+    /// The content of the message.
+    pub content: VerificationContent,
+}
+
+impl VerificationMessage {
+    /// Creates a new `VerificationMessage` with the given flow ID and content.
+    pub fn new(flow_id: FlowId, content: VerificationContent) -> Self {
+        Self { flow_id, content }
+    }
+
+    /// Convert this message to the content of the event to send, depending on the transport of
+    /// its flow.
+    pub fn into_event_content(self) -> VerificationEventContent {
+        match self.flow_id {
+            FlowId::ToDevice(transaction_id) => {
+                VerificationEventContent::ToDevice(match self.content {
+                    VerificationContent::Start { from_device, method } => {
+                        ToDeviceKeyVerificationStartEventContent::new(
+                            from_device,
+                            transaction_id,
+                            method,
+                        )
+                        .into()
+                    }
+                    VerificationContent::Accept(method) => {
+                        ToDeviceKeyVerificationAcceptEventContent::new(transaction_id, method)
+                            .into()
+                    }
+                    VerificationContent::Key(key) => {
+                        ToDeviceKeyVerificationKeyEventContent::new(transaction_id, key).into()
+                    }
+                    VerificationContent::Mac { mac, keys } => {
+                        ToDeviceKeyVerificationMacEventContent::new(transaction_id, mac, keys)
+                            .into()
+                    }
+                    VerificationContent::Done => {
+                        ToDeviceKeyVerificationDoneEventContent::new(transaction_id).into()
+                    }
+                    VerificationContent::Cancel { code, reason } => {
+                        ToDeviceKeyVerificationCancelEventContent::new(transaction_id, reason, code)
+                            .into()
+                    }
+                })
+            }
+            FlowId::InRoom(event_id) => {
+                let relates_to = Reference::new(event_id);
+                VerificationEventContent::MessageLike(match self.content {
+                    VerificationContent::Start { from_device, method } => {
+                        KeyVerificationStartEventContent::new(from_device, method, relates_to)
+                            .into()
+                    }
+                    VerificationContent::Accept(method) => {
+                        KeyVerificationAcceptEventContent::new(method, relates_to).into()
+                    }
+                    VerificationContent::Key(key) => {
+                        KeyVerificationKeyEventContent::new(key, relates_to).into()
+                    }
+                    VerificationContent::Mac { mac, keys } => {
+                        KeyVerificationMacEventContent::new(mac, keys, relates_to).into()
+                    }
+                    VerificationContent::Done => {
+                        KeyVerificationDoneEventContent::new(relates_to).into()
+                    }
+                    VerificationContent::Cancel { code, reason } => {
+                        KeyVerificationCancelEventContent::new(reason, code, relates_to).into()
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl From<ToDeviceKeyVerificationStartEventContent> for VerificationMessage {
+    fn from(content: ToDeviceKeyVerificationStartEventContent) -> Self {
+        let ToDeviceKeyVerificationStartEventContent { from_device, transaction_id, method } =
+            content;
+        Self::new(
+            FlowId::ToDevice(transaction_id),
+            VerificationContent::Start { from_device, method },
+        )
+    }
+}
+
+impl From<KeyVerificationStartEventContent> for VerificationMessage {
+    fn from(content: KeyVerificationStartEventContent) -> Self {
+        let KeyVerificationStartEventContent { from_device, method, relates_to } = content;
+        Self::new(
+            FlowId::InRoom(relates_to.event_id),
+            VerificationContent::Start { from_device, method },
+        )
+    }
+}
+
+impl From<ToDeviceKeyVerificationAcceptEventContent> for VerificationMessage {
+    fn from(content: ToDeviceKeyVerificationAcceptEventContent) -> Self {
+        Self::new(
+            FlowId::ToDevice(content.transaction_id),
+            VerificationContent::Accept(content.method),
+        )
+    }
+}
+
+impl From<KeyVerificationAcceptEventContent> for VerificationMessage {
+    fn from(content: KeyVerificationAcceptEventContent) -> Self {
+        Self::new(
+            FlowId::InRoom(content.relates_to.event_id),
+            VerificationContent::Accept(content.method),
+        )
+    }
+}
+
+impl From<ToDeviceKeyVerificationKeyEventContent> for VerificationMessage {
+    fn from(content: ToDeviceKeyVerificationKeyEventContent) -> Self {
+        Self::new(FlowId::ToDevice(content.transaction_id), VerificationContent::Key(content.key))
+    }
+}
+
+impl From<KeyVerificationKeyEventContent> for VerificationMessage {
+    fn from(content: KeyVerificationKeyEventContent) -> Self {
+        Self::new(
+            FlowId::InRoom(content.relates_to.event_id),
+            VerificationContent::Key(content.key),
+        )
+    }
+}
+
+impl From<ToDeviceKeyVerificationMacEventContent> for VerificationMessage {
+    fn from(content: ToDeviceKeyVerificationMacEventContent) -> Self {
+        let ToDeviceKeyVerificationMacEventContent { transaction_id, mac, keys } = content;
+        Self::new(FlowId::ToDevice(transaction_id), VerificationContent::Mac { mac, keys })
+    }
+}
+
+impl From<KeyVerificationMacEventContent> for VerificationMessage {
+    fn from(content: KeyVerificationMacEventContent) -> Self {
+        let KeyVerificationMacEventContent { mac, keys, relates_to } = content;
+        Self::new(FlowId::InRoom(relates_to.event_id), VerificationContent::Mac { mac, keys })
+    }
+}
+
+impl From<ToDeviceKeyVerificationDoneEventContent> for VerificationMessage {
+    fn from(content: ToDeviceKeyVerificationDoneEventContent) -> Self {
+        Self::new(FlowId::ToDevice(content.transaction_id), VerificationContent::Done)
+    }
+}
+
+impl From<KeyVerificationDoneEventContent> for VerificationMessage {
+    fn from(content: KeyVerificationDoneEventContent) -> Self {
+        Self::new(FlowId::InRoom(content.relates_to.event_id), VerificationContent::Done)
+    }
+}
+
+impl From<ToDeviceKeyVerificationCancelEventContent> for VerificationMessage {
+    fn from(content: ToDeviceKeyVerificationCancelEventContent) -> Self {
+        let ToDeviceKeyVerificationCancelEventContent { transaction_id, reason, code } = content;
+        Self::new(FlowId::ToDevice(transaction_id), VerificationContent::Cancel { code, reason })
+    }
+}
+
+impl From<KeyVerificationCancelEventContent> for VerificationMessage {
+    fn from(content: KeyVerificationCancelEventContent) -> Self {
+        let KeyVerificationCancelEventContent { reason, code, relates_to } = content;
+        Self::new(FlowId::InRoom(relates_to.event_id), VerificationContent::Cancel { code, reason })
+    }
+}
+
+/// The content of an event of a SAS verification, depending on its transport.
+#[derive(Clone, Debug)]
+#[allow(clippy::exhaustive_enums)]
+pub enum VerificationEventContent {
+    /// The content of a to-device event.
+    ToDevice(AnyToDeviceEventContent),
+
+    /// The content of a message-like event, to send in a room.
+    MessageLike(AnyMessageLikeEventContent),
+}
+
+/// The identity of a party of a SAS verification.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct SasIdentity {
+    /// The ID of the user.
+    pub user_id: OwnedUserId,
+
+    /// The ID of the device.
+    pub device_id: OwnedDeviceId,
+
+    /// The Ed25519 public keys that are verified, encoded in unpadded base64, by key ID.
+    ///
+    /// These are usually the key of the device, with the `ed25519:{device_id}` key ID, and the
+    /// master cross-signing key of the user, with the `ed25519:{public_key}` key ID.
+    pub keys: BTreeMap<String, String>,
+}
+
+impl SasIdentity {
+    /// Creates a new `SasIdentity` with the given user ID, device ID and keys.
+    pub fn new(
+        user_id: OwnedUserId,
+        device_id: OwnedDeviceId,
+        keys: BTreeMap<String, String>,
+    ) -> Self {
+        Self { user_id, device_id, keys }
+    }
+}
+
+/// The state of a [`SasVerification`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum SasState {
+    /// The verification was not started yet.
+    Created,
+
+    /// We started the verification and are waiting for the other party to accept it.
+    Started,
+
+    /// The other party started the verification and is waiting for us to accept it.
+    StartReceived,
+
+    /// The verification was accepted and the parties are exchanging their public keys.
+    Accepted,
+
+    /// The public keys were exchanged, the short authentication string can be presented to the
+    /// user.
+    KeysExchanged,
+
+    /// The user confirmed that the short authentication strings match, and we are waiting for the
+    /// MAC of the other party.
+    Confirmed,
+
+    /// The keys of the other party were verified, and we are waiting for the other party to
+    /// verify our keys.
+    Verified,
+
+    /// The verification is complete.
+    Done,
+
+    /// The verification was cancelled.
+    Cancelled(SasCancellation),
+}
+
+/// Information about the cancellation of a [`SasVerification`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct SasCancellation {
+    /// The code of the cancellation.
+    pub code: CancelCode,
+
+    /// The human-readable reason of the cancellation.
+    pub reason: String,
+
+    /// Whether we cancelled the verification, or the other party did.
+    pub cancelled_by_us: bool,
+}
+
+/// An emoji of the emoji method of the short authentication string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct SasEmoji {
+    /// The emoji.
+    pub symbol: &'static str,
+
+    /// The English description of the emoji.
+    pub description: &'static str,
+}
+
+/// The short authentication string of a SAS verification, to compare with the other party.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct ShortAuthString {
+    /// The three numbers of the decimal method.
+    pub decimal: [u16; 3],
+
+    /// The seven emoji of the emoji method, if both parties support it.
+    pub emoji: Option<[SasEmoji; 7]>,
+}
+
+/// The parameters of the verification, chosen by the party that accepted it.
+#[derive(Clone, Debug)]
+struct AcceptedParameters {
+    /// The key agreement protocol.
+    key_agreement_protocol: KeyAgreementProtocol,
+
+    /// The short authentication string methods.
+    short_authentication_string: Vec<ShortAuthenticationString>,
+
+    /// The commitment of the party that accepted the verification.
+    commitment: Base64,
+}
+
+/// A SAS verification between our device and a device of another user, or another device of our
+/// own user.
+///
+/// The verification supports the `curve25519-hkdf-sha256` and `curve25519` key agreement
+/// protocols, the `sha256` hash, the `hkdf-hmac-sha256.v2` MAC, and the `decimal` and `emoji` short
+/// authentication string methods.
+///
+/// Messages that are not part of the verification flow are cancelled with
+/// [`CancelCode::UnknownTransaction`], messages received in the wrong order cancel the
+/// verification with [`CancelCode::UnexpectedMessage`], and invalid messages cancel it with the
+/// corresponding [`CancelCode`]. The current state of the verification can be checked with
+/// [`SasVerification::state()`].
+pub struct SasVerification {
+    /// Our identity.
+    own: SasIdentity,
+
+    /// The identity of the other party.
+    other: SasIdentity,
+
+    /// The ID of the verification flow.
+    flow_id: FlowId,
+
+    /// The state of the verification.
+    state: SasState,
+
+    /// Our ephemeral Curve25519 secret key.
+    secret_key: StaticSecret,
+
+    /// Our ephemeral Curve25519 public key.
+    public_key: PublicKey,
+
+    /// Whether we started the verification.
+    we_started: bool,
+
+    /// The canonical JSON of the content of the `m.key.verification.start` event.
+    start_content: Option<String>,
+
+    /// The parameters of the verification chosen by the party that accepted it.
+    parameters: Option<AcceptedParameters>,
+
+    /// The ephemeral Curve25519 public key of the other party.
+    their_public_key: Option<PublicKey>,
+
+    /// The shared secret computed from the ephemeral keys.
+    shared_secret: Option<SharedSecret>,
+
+    /// The MAC received from the other party before the user confirmed the verification.
+    their_mac: Option<(BTreeMap<String, Base64>, Base64)>,
+}
+
+impl SasVerification {
+    /// Creates a new `SasVerification` between the given parties, for the given flow.
+    ///
+    /// A random ephemeral Curve25519 key pair is generated for the verification.
+    pub fn new(own: SasIdentity, other: SasIdentity, flow_id: FlowId) -> Self {
+        let secret_key = StaticSecret::random_from_rng(thread_rng());
+        let public_key = PublicKey::from(&secret_key);
+
+        Self {
+            own,
+            other,
+            flow_id,
+            state: SasState::Created,
+            secret_key,
+            public_key,
+            we_started: false,
+            start_content: None,
+            parameters: None,
+            their_public_key: None,
+            shared_secret: None,
+            their_mac: None,
+        }
+    }
+
+    /// Use the given ephemeral Curve25519 secret key for the verification, instead of a random
+    /// one.
+    ///
+    /// This should only be used for testing, the key must never be reused.
+    pub fn with_secret_key(self, secret_key: [u8; 32]) -> Self {
+        let secret_key = StaticSecret::from(secret_key);
+        let public_key = PublicKey::from(&secret_key);
+        Self { secret_key, public_key, ..self }
+    }
+
+    /// The ID of the verification flow.
+    pub fn flow_id(&self) -> &FlowId {
+        &self.flow_id
+    }
+
+    /// The current state of the verification.
+    pub fn state(&self) -> &SasState {
+        &self.state
+    }
+
+    /// Whether we started the verification.
+    pub fn we_started(&self) -> bool {
+        self.we_started
+    }
+
+    /// Our ephemeral Curve25519 public key.
+    pub fn public_key(&self) -> Base64 {
+        Base64::new(self.public_key.as_bytes().to_vec())
+    }
+
+    /// Start the verification.
+    ///
+    /// Returns the `m.key.verification.start` message to send, or `None` if the verification was
+    /// already started.
+    pub fn start(&mut self) -> Option<VerificationMessage> {
+        if self.state != SasState::Created {
+            return None;
+        }
+
+        let method = StartMethod::SasV1(
+            start::SasV1ContentInit {
+                key_agreement_protocols: vec![
+                    KeyAgreementProtocol::Curve25519HkdfSha256,
+                    KeyAgreementProtocol::Curve25519,
+                ],
+                hashes: vec![HashAlgorithm::Sha256],
+                message_authentication_codes: vec![MessageAuthenticationCode::HkdfHmacSha256V2],
+                short_authentication_string: vec![
+                    ShortAuthenticationString::Decimal,
+                    ShortAuthenticationString::Emoji,
+                ],
+            }
+            .into(),
+        );
+        let from_device = self.own.device_id.clone();
+
+        self.start_content = Some(self.start_content_json(from_device.clone(), method.clone()));
+        self.we_started = true;
+        self.state = SasState::Started;
+
+        Some(self.message(VerificationContent::Start { from_device, method }))
+    }
+
+    /// Accept the verification started by the other party.
+    ///
+    /// Returns the `m.key.verification.accept` message to send, or `None` if the other party
+    /// didn't start the verification or if it was already accepted.
+    pub fn accept(&mut self) -> Option<VerificationMessage> {
+        if self.state != SasState::StartReceived {
+            return None;
+        }
+
+        let parameters = self.parameters.as_ref()?;
+        let content = AcceptMethod::SasV1(
+            accept::SasV1ContentInit {
+                key_agreement_protocol: parameters.key_agreement_protocol.clone(),
+                hash: HashAlgorithm::Sha256,
+                message_authentication_code: MessageAuthenticationCode::HkdfHmacSha256V2,
+                short_authentication_string: parameters.short_authentication_string.clone(),
+                commitment: parameters.commitment.clone(),
+            }
+            .into(),
+        );
+
+        self.state = SasState::Accepted;
+        Some(self.message(VerificationContent::Accept(content)))
+    }
+
+    /// The short authentication string to present to the user, once the public keys were
+    /// exchanged.
+    pub fn short_auth_string(&self) -> Option<ShortAuthString> {
+        let shared_secret = self.shared_secret.as_ref()?;
+        let parameters = self.parameters.as_ref()?;
+        let their_public_key = self.their_public_key.as_ref()?;
+
+        let (initiator, initiator_key, acceptor, acceptor_key) = if self.we_started {
+            (&self.own, &self.public_key, &self.other, their_public_key)
+        } else {
+            (&self.other, their_public_key, &self.own, &self.public_key)
+        };
+
+        let info = match parameters.key_agreement_protocol {
+            KeyAgreementProtocol::Curve25519HkdfSha256 => format!(
+                "MATRIX_KEY_VERIFICATION_SAS|{}|{}|{}|{}|{}|{}|{}",
+                initiator.user_id,
+                initiator.device_id,
+                encode_key(initiator_key),
+                acceptor.user_id,
+                acceptor.device_id,
+                encode_key(acceptor_key),
+                self.flow_id.as_str(),
+            ),
+            _ => format!(
+                "MATRIX_KEY_VERIFICATION_SAS{}{}{}{}{}",
+                initiator.user_id,
+                initiator.device_id,
+                acceptor.user_id,
+                acceptor.device_id,
+                self.flow_id.as_str(),
+            ),
+        };
+
+        let mut bytes = [0; 6];
+        Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+            .expand(info.as_bytes(), &mut bytes)
+            .expect("6 bytes is a valid length for HKDF-SHA256");
+
+        let emoji = parameters
+            .short_authentication_string
+            .contains(&ShortAuthenticationString::Emoji)
+            .then(|| emoji_from_bytes(&bytes));
+
+        Some(ShortAuthString { decimal: decimal_from_bytes(&bytes), emoji })
+    }
+
+    /// Confirm that the short authentication strings of both parties match.
+    ///
+    /// Returns the messages to send: the `m.key.verification.mac` message, followed by the
+    /// `m.key.verification.done` message if the MAC of the other party was already received and
+    /// verified. If the MAC of the other party is invalid, the verification is cancelled and the
+    /// `m.key.verification.cancel` message is returned instead.
+    ///
+    /// Returns an empty list if the short authentication string is not available.
+    pub fn confirm(&mut self) -> Vec<VerificationMessage> {
+        if self.state != SasState::KeysExchanged {
+            return Vec::new();
+        }
+
+        if let Some((mac, keys)) = self.their_mac.take() {
+            if let Err(code) = self.verify_mac(&mac, &keys) {
+                return vec![self.cancel_with_code(code)];
+            }
+
+            self.state = SasState::Verified;
+            vec![self.own_mac(), self.message(VerificationContent::Done)]
+        } else {
+            self.state = SasState::Confirmed;
+            vec![self.own_mac()]
+        }
+    }
+
+    /// Cancel the verification because the short authentication strings don't match.
+    ///
+    /// Returns the `m.key.verification.cancel` message to send, or `None` if the verification is
+    /// already over.
+    pub fn mismatch(&mut self) -> Option<VerificationMessage> {
+        self.cancel(CancelCode::MismatchedSas)
+    }
+
+    /// Cancel the verification with the given code.
+    ///
+    /// Returns the `m.key.verification.cancel` message to send, or `None` if the verification is
+    /// already over.
+    pub fn cancel(&mut self, code: CancelCode) -> Option<VerificationMessage> {
+        if matches!(self.state, SasState::Done | SasState::Cancelled(_)) {
+            return None;
+        }
+
+        Some(self.cancel_with_code(code))
+    }
+
+    /// Handle the given message sent by the given user.
+    ///
+    /// Returns the messages to send in response, if any.
+    ///
+    /// Messages of other users are ignored, as well as the messages received once the verification
+    /// is over.
+    pub fn receive(
+        &mut self,
+        sender: &UserId,
+        message: VerificationMessage,
+    ) -> Vec<VerificationMessage> {
+        if sender != self.other.user_id
+            || matches!(self.state, SasState::Done | SasState::Cancelled(_))
+        {
+            return Vec::new();
+        }
+
+        if message.flow_id != self.flow_id {
+            if matches!(message.content, VerificationContent::Cancel { .. }) {
+                return Vec::new();
+            }
+
+            let code = CancelCode::UnknownTransaction;
+            let content =
+                VerificationContent::Cancel { reason: cancel_reason(&code).to_owned(), code };
+            return vec![VerificationMessage::new(message.flow_id, content)];
+        }
+
+        let result = match message.content {
+            VerificationContent::Start { from_device, method } => {
+                self.receive_start(from_device, method).map(|_| Vec::new())
+            }
+            VerificationContent::Accept(method) => self.receive_accept(method),
+            VerificationContent::Key(key) => self.receive_key(key),
+            VerificationContent::Mac { mac, keys } => self.receive_mac(mac, keys),
+            VerificationContent::Done => self.receive_done().map(|_| Vec::new()),
+            VerificationContent::Cancel { code, reason } => {
+                self.state =
+                    SasState::Cancelled(SasCancellation { code, reason, cancelled_by_us: false });
+                Ok(Vec::new())
+            }
+        };
+
+        match result {
+            Ok(contents) => contents.into_iter().map(|content| self.message(content)).collect(),
+            Err(code) => vec![self.cancel_with_code(code)],
+        }
+    }
+
+    /// Handle an `m.key.verification.start` message.
+    fn receive_start(
+        &mut self,
+        from_device: OwnedDeviceId,
+        method: StartMethod,
+    ) -> Result<(), CancelCode> {
+        match self.state {
+            SasState::Created => {}
+            SasState::Started => {
+                // Both parties started the verification, the start with the lowest user ID, then
+                // the lowest device ID, is used.
+                if (&self.other.user_id, &self.other.device_id)
+                    > (&self.own.user_id, &self.own.device_id)
+                {
+                    return Ok(());
+                }
+            }
+            _ => return Err(CancelCode::UnexpectedMessage),
+        }
+
+        if from_device != self.other.device_id {
+            return Err(CancelCode::UnexpectedMessage);
+        }
+
+        let StartMethod::SasV1(content) = &method else {
+            return Err(CancelCode::UnknownMethod);
+        };
+
+        let key_agreement_protocol =
+            [KeyAgreementProtocol::Curve25519HkdfSha256, KeyAgreementProtocol::Curve25519]
+                .into_iter()
+                .find(|protocol| content.key_agreement_protocols.contains(protocol))
+                .ok_or(CancelCode::UnknownMethod)?;
+
+        if !content.hashes.contains(&HashAlgorithm::Sha256)
+            || !content
+                .message_authentication_codes
+                .contains(&MessageAuthenticationCode::HkdfHmacSha256V2)
+            || !content.short_authentication_string.contains(&ShortAuthenticationString::Decimal)
+        {
+            return Err(CancelCode::UnknownMethod);
+        }
+
+        let short_authentication_string =
+            [ShortAuthenticationString::Decimal, ShortAuthenticationString::Emoji]
+                .into_iter()
+                .filter(|method| content.short_authentication_string.contains(method))
+                .collect();
+
+        let start_content = self.start_content_json(from_device, method);
+        let commitment = commitment(&self.public_key, &start_content);
+
+        self.start_content = Some(start_content);
+        self.parameters = Some(AcceptedParameters {
+            key_agreement_protocol,
+            short_authentication_string,
+            commitment,
+        });
+        self.we_started = false;
+        self.state = SasState::StartReceived;
+
+        Ok(())
+    }
+
+    /// Handle an `m.key.verification.accept` message.
+    fn receive_accept(
+        &mut self,
+        method: AcceptMethod,
+    ) -> Result<Vec<VerificationContent>, CancelCode> {
+        if self.state != SasState::Started {
+            return Err(CancelCode::UnexpectedMessage);
+        }
+
+        let AcceptMethod::SasV1(content) = method else {
+            return Err(CancelCode::UnknownMethod);
+        };
+
+        if !matches!(
+            content.key_agreement_protocol,
+            KeyAgreementProtocol::Curve25519HkdfSha256 | KeyAgreementProtocol::Curve25519
+        ) || content.hash != HashAlgorithm::Sha256
+            || content.message_authentication_code != MessageAuthenticationCode::HkdfHmacSha256V2
+            || !content.short_authentication_string.contains(&ShortAuthenticationString::Decimal)
+        {
+            return Err(CancelCode::UnknownMethod);
+        }
+
+        self.parameters = Some(AcceptedParameters {
+            key_agreement_protocol: content.key_agreement_protocol,
+            short_authentication_string: content
+                .short_authentication_string
+                .into_iter()
+                .filter(|method| {
+                    matches!(
+                        method,
+                        ShortAuthenticationString::Decimal | ShortAuthenticationString::Emoji
+                    )
+                })
+                .collect(),
+            commitment: content.commitment,
+        });
+        self.state = SasState::Accepted;
+
+        Ok(vec![VerificationContent::Key(self.public_key())])
+    }
+
+    /// Handle an `m.key.verification.key` message.
+    fn receive_key(&mut self, key: Base64) -> Result<Vec<VerificationContent>, CancelCode> {
+        if self.state != SasState::Accepted {
+            return Err(CancelCode::UnexpectedMessage);
+        }
+
+        let key: [u8; PUBLIC_KEY_LENGTH] =
+            key.as_bytes().try_into().map_err(|_| CancelCode::InvalidMessage)?;
+        let their_public_key = PublicKey::from(key);
+
+        let response = if self.we_started {
+            // The commitment of the party that accepted the verification must match its key.
+            let parameters = self.parameters.as_ref().ok_or(CancelCode::UnexpectedMessage)?;
+            let start_content = self.start_content.as_ref().ok_or(CancelCode::UnexpectedMessage)?;
+            if commitment(&their_public_key, start_content).as_bytes()
+                != parameters.commitment.as_bytes()
+            {
+                return Err(CancelCode::MismatchedCommitment);
+            }
+
+            Vec::new()
+        } else {
+            vec![VerificationContent::Key(self.public_key())]
+        };
+
+        self.shared_secret = Some(self.secret_key.diffie_hellman(&their_public_key));
+        self.their_public_key = Some(their_public_key);
+        self.state = SasState::KeysExchanged;
+
+        Ok(response)
+    }
+
+    /// Handle an `m.key.verification.mac` message.
+    fn receive_mac(
+        &mut self,
+        mac: BTreeMap<String, Base64>,
+        keys: Base64,
+    ) -> Result<Vec<VerificationContent>, CancelCode> {
+        match self.state {
+            SasState::KeysExchanged if self.their_mac.is_none() => {
+                // The MAC can only be verified once the user confirmed the verification.
+                self.their_mac = Some((mac, keys));
+                Ok(Vec::new())
+            }
+            SasState::Confirmed => {
+                self.verify_mac(&mac, &keys)?;
+                self.state = SasState::Verified;
+                Ok(vec![VerificationContent::Done])
+            }
+            _ => Err(CancelCode::UnexpectedMessage),
+        }
+    }
+
+    /// Handle an `m.key.verification.done` message.
+    fn receive_done(&mut self) -> Result<(), CancelCode> {
+        if self.state != SasState::Verified {
+            return Err(CancelCode::UnexpectedMessage);
+        }
+
+        self.state = SasState::Done;
+        Ok(())
+    }
+
+    /// Verify the MAC of the keys of the other party.
+    ///
+    /// The MACs of keys that we don't know are ignored, but at least one of the known keys must be
+    /// verified.
+    fn verify_mac(&self, mac: &BTreeMap<String, Base64>, keys: &Base64) -> Result<(), CancelCode> {
+        let key_ids = mac.keys().map(String::as_str).collect::<Vec<_>>().join(",");
+        if !self.check_mac(&self.other, &self.own, KEY_IDS_MAC_KEY_ID, &key_ids, keys) {
+            return Err(CancelCode::KeyMismatch);
+        }
+
+        let mut verified_keys = 0;
+        for (key_id, key_mac) in mac {
+            let Some(key) = self.other.keys.get(key_id) else {
+                continue;
+            };
+
+            if !self.check_mac(&self.other, &self.own, key_id, key, key_mac) {
+                return Err(CancelCode::KeyMismatch);
+            }
+
+            verified_keys += 1;
+        }
+
+        if verified_keys == 0 {
+            return Err(CancelCode::KeyMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Compute our `m.key.verification.mac` message.
+    fn own_mac(&self) -> VerificationMessage {
+        let mac = self
+            .own
+            .keys
+            .iter()
+            .map(|(key_id, key)| {
+                let mac = self.mac(&self.own, &self.other, key_id).chain_update(key);
+                (key_id.clone(), Base64::new(mac.finalize().into_bytes().to_vec()))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let key_ids = mac.keys().map(String::as_str).collect::<Vec<_>>().join(",");
+        let keys = self.mac(&self.own, &self.other, KEY_IDS_MAC_KEY_ID).chain_update(key_ids);
+
+        self.message(VerificationContent::Mac {
+            mac,
+            keys: Base64::new(keys.finalize().into_bytes().to_vec()),
+        })
+    }
+
+    /// Check the MAC of the given input sent by the given party.
+    fn check_mac(
+        &self,
+        sender: &SasIdentity,
+        receiver: &SasIdentity,
+        key_id: &str,
+        input: &str,
+        mac: &Base64,
+    ) -> bool {
+        self.mac(sender, receiver, key_id).chain_update(input).verify_slice(mac.as_bytes()).is_ok()
+    }
+
+    /// Construct the HMAC-SHA256 of a key sent by the given party, according to the
+    /// `hkdf-hmac-sha256.v2` method.
+    fn mac(&self, sender: &SasIdentity, receiver: &SasIdentity, key_id: &str) -> Hmac<Sha256> {
+        let shared_secret =
+            self.shared_secret.as_ref().expect("the shared secret should be computed");
+        let info = format!(
+            "MATRIX_KEY_VERIFICATION_MAC{}{}{}{}{}{key_id}",
+            sender.user_id,
+            sender.device_id,
+            receiver.user_id,
+            receiver.device_id,
+            self.flow_id.as_str(),
+        );
+
+        let mut mac_key = [0; 32];
+        Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+            .expand(info.as_bytes(), &mut mac_key)
+            .expect("32 bytes is a valid length for HKDF-SHA256");
+
+        Hmac::<Sha256>::new_from_slice(&mac_key).expect("HMAC can take a key of any size")
+    }
+
+    /// Cancel the verification with the given code and get the message to send.
+    fn cancel_with_code(&mut self, code: CancelCode) -> VerificationMessage {
+        let reason = cancel_reason(&code).to_owned();
+        self.state = SasState::Cancelled(SasCancellation {
+            code: code.clone(),
+            reason: reason.clone(),
+            cancelled_by_us: true,
+        });
+
+        self.message(VerificationContent::Cancel { code, reason })
+    }
+
+    /// Construct a message for this verification flow.
+    fn message(&self, content: VerificationContent) -> VerificationMessage {
+        VerificationMessage::new(self.flow_id.clone(), content)
+    }
+
+    /// Get the canonical JSON of the content of the `m.key.verification.start` event with the
+    /// given data, to compute the commitment.
+    fn start_content_json(&self, from_device: OwnedDeviceId, method: StartMethod) -> String {
+        match &self.flow_id {
+            FlowId::ToDevice(transaction_id) => {
+                canonical_json(&ToDeviceKeyVerificationStartEventContent::new(
+                    from_device,
+                    transaction_id.clone(),
+                    method,
+                ))
+            }
+            FlowId::InRoom(event_id) => canonical_json(&KeyVerificationStartEventContent::new(
+                from_device,
+                method,
+                Reference::new(event_id.clone()),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Debug for SasVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SasVerification")
+            .field("own", &self.own)
+            .field("other", &self.other)
+            .field("flow_id", &self.flow_id)
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Serialize the given value to canonical JSON.
+fn canonical_json(value: &impl Serialize) -> String {
+    to_canonical_value(value)
+        .expect("verification event content should be canonical JSON")
+        .to_string()
+}
+
+/// Compute the commitment of the given public key to the given content of the start event.
+fn commitment(public_key: &PublicKey, start_content: &str) -> Base64 {
+    let hash =
+        Sha256::new().chain_update(encode_key(public_key)).chain_update(start_content).finalize();
+
+    Base64::new(hash.to_vec())
+}
+
+/// Encode the given public key to unpadded base64.
+fn encode_key(public_key: &PublicKey) -> String {
+    Base64::<Standard, _>::new(public_key.as_bytes()).encode()
+}
+
+/// Compute the three numbers of the decimal method from the given bytes.
+fn decimal_from_bytes(bytes: &[u8; 6]) -> [u16; 3] {
+    let [b0, b1, b2, b3, b4, _] = bytes.map(u16::from);
+
+    [
+        ((b0 << 5) | (b1 >> 3)) + 1000,
+        (((b1 & 0x7) << 10) | (b2 << 2) | (b3 >> 6)) + 1000,
+        (((b3 & 0x3f) << 7) | (b4 >> 1)) + 1000,
+    ]
+}
+
+/// Compute the seven emoji of the emoji method from the given bytes.
+fn emoji_from_bytes(bytes: &[u8; 6]) -> [SasEmoji; 7] {
+    let bits = bytes.iter().fold(0_u64, |bits, byte| (bits << 8) | u64::from(*byte));
+
+    std::array::from_fn(|i| {
+        let index = (bits >> (42 - 6 * i)) & 0x3f;
+        let (symbol, description) = EMOJI[index as usize];
+        SasEmoji { symbol, description }
+    })
+}
+
+/// The reason to send for a cancellation with the given code.
+fn cancel_reason(code: &CancelCode) -> &'static str {
+    match code {
+        CancelCode::User => "The user cancelled the verification",
+        CancelCode::Timeout => "The verification timed out",
+        CancelCode::UnknownTransaction => "Unknown verification transaction",
+        CancelCode::UnknownMethod => "Unsupported verification method",
+        CancelCode::UnexpectedMessage => "Unexpected verification message",
+        CancelCode::KeyMismatch => "The MAC of the keys doesn't match",
+        CancelCode::UserMismatch => "The user doesn't match",
+        CancelCode::InvalidMessage => "Invalid verification message",
+        CancelCode::Accepted => "The verification was accepted by another device",
+        CancelCode::MismatchedCommitment => "The commitment doesn't match the key",
+        CancelCode::MismatchedSas => "The short authentication strings don't match",
+        _ => "The verification was cancelled",
+    }
+}
@@ -6,6 +6,10 @@ use ruma_common::push::Ruleset;
 use ruma_macros::EventContent;
 use serde::{Deserialize, Serialize};
 
+mod evaluator;
+
+pub use self::evaluator::{PushEvaluator, RoomUnreadCounts, UnreadCounts};
+
 /// The content of an `m.push_rules` event.
 ///
 /// Describes all push rules for a user.
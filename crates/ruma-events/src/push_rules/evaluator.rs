@@ -0,0 +1,338 @@
+//! Evaluation of push rules for the events of a room.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use js_int::{int, uint, UInt};
+#[cfg(feature = "unstable-msc3931")]
+use ruma_common::push::RoomVersionFeature;
+use ruma_common::{
+    push::{Action, PushConditionPowerLevelsCtx, PushConditionRoomCtx, Ruleset},
+    serde::Raw,
+    OwnedEventId, OwnedRoomId, OwnedUserId, RoomVersionId,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    relation::RelationType,
+    room::{
+        create::SyncRoomCreateEvent,
+        member::{MembershipState, SyncRoomMemberEvent},
+        power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent, SyncRoomPowerLevelsEvent},
+    },
+    StateEventType,
+};
+
+/// Evaluates the push rules of a user for the events of a room.
+///
+/// This builds the [`PushConditionRoomCtx`] needed by [`Ruleset::get_actions()`] from the state
+/// of the room, and keeps it up-to-date when evaluating state events, so it can be used by
+/// homeservers to evaluate the events of a room in order, and to compute the notification counts
+/// of the room.
+#[derive(Clone, Debug)]
+pub struct PushEvaluator<'a> {
+    /// The push rules of the user.
+    ruleset: &'a Ruleset,
+
+    /// The context of the room, built from the state of the room.
+    context: PushConditionRoomCtx,
+
+    /// The users that are joined to the room.
+    joined_members: BTreeSet<OwnedUserId>,
+
+    /// The display name of the user in the room, if any.
+    display_name: Option<String>,
+
+    /// The creator of the room, if the `m.room.create` event was encountered.
+    creator: Option<OwnedUserId>,
+
+    /// Whether an `m.room.power_levels` event was encountered.
+    has_power_levels_event: bool,
+}
+
+impl<'a> PushEvaluator<'a> {
+    /// Creates a new `PushEvaluator` for the given user and room, with the given state events.
+    ///
+    /// The state events can be any state event in the room, the events that are not relevant to
+    /// the evaluation of push rules are ignored. The following events are used to build the
+    /// context of the room:
+    ///
+    /// * `m.room.member` to compute the number of joined members and the display name of the user.
+    ///   If the user doesn't have a display name, the localpart of their user ID is used.
+    /// * `m.room.power_levels` to compute the power levels of the room. If it is missing, the
+    ///   default power levels of the room are computed from the `m.room.create` event.
+    /// * `m.room.create` to compute the features supported by the room version.
+    ///
+    /// The state events that can't be deserialized are ignored.
+    pub fn from_room_state<'b, T: 'b>(
+        ruleset: &'a Ruleset,
+        room_id: OwnedRoomId,
+        user_id: OwnedUserId,
+        state: impl IntoIterator<Item = &'b Raw<T>>,
+    ) -> Self {
+        let display_name = user_id.localpart().to_owned();
+        let mut evaluator = Self {
+            ruleset,
+            context: PushConditionRoomCtx::new(room_id, uint!(0), user_id, display_name),
+            joined_members: BTreeSet::new(),
+            display_name: None,
+            creator: None,
+            has_power_levels_event: false,
+        };
+
+        for event in state {
+            evaluator.apply_state_event(event);
+        }
+
+        evaluator
+    }
+
+    /// The context of the room used to evaluate the push rules.
+    pub fn context(&self) -> &PushConditionRoomCtx {
+        &self.context
+    }
+
+    /// Update the context of the room with the given state event.
+    ///
+    /// Events that are not state events or that are not relevant to the evaluation of push rules
+    /// are ignored.
+    pub fn apply_state_event<T>(&mut self, event: &Raw<T>) {
+        let Ok(Some(event_type)) = event.get_field::<StateEventType>("type") else {
+            return;
+        };
+        if !matches!(event.get_field::<String>("state_key"), Ok(Some(_))) {
+            return;
+        }
+
+        match event_type {
+            StateEventType::RoomMember => {
+                let event = match event.deserialize_as::<SyncRoomMemberEvent>() {
+                    Ok(event) => event,
+                    Err(error) => {
+                        warn!("Failed to deserialize m.room.member event: {error}");
+                        return;
+                    }
+                };
+                let member_id = event.state_key();
+
+                if *event.membership() == MembershipState::Join {
+                    self.joined_members.insert(member_id.clone());
+                } else {
+                    self.joined_members.remove(member_id);
+                }
+
+                if *member_id == self.context.user_id {
+                    self.display_name =
+                        event.as_original().and_then(|event| event.content.displayname.clone());
+                }
+            }
+            StateEventType::RoomPowerLevels => {
+                let event = match event.deserialize_as::<SyncRoomPowerLevelsEvent>() {
+                    Ok(event) => event,
+                    Err(error) => {
+                        warn!("Failed to deserialize m.room.power_levels event: {error}");
+                        return;
+                    }
+                };
+
+                self.has_power_levels_event = true;
+                self.context.power_levels = Some(event.power_levels().into());
+            }
+            StateEventType::RoomCreate => {
+                let event = match event.deserialize_as::<SyncRoomCreateEvent>() {
+                    Ok(event) => event,
+                    Err(error) => {
+                        warn!("Failed to deserialize m.room.create event: {error}");
+                        return;
+                    }
+                };
+
+                self.creator = Some(event.sender().to_owned());
+                self.set_room_version(match &event {
+                    SyncRoomCreateEvent::Original(event) => &event.content.room_version,
+                    SyncRoomCreateEvent::Redacted(event) => &event.content.room_version,
+                });
+            }
+            _ => return,
+        }
+
+        self.update_context();
+    }
+
+    /// Get the push actions that apply to the given event, with the current context of the room.
+    ///
+    /// Returns an empty slice if no push rule applies.
+    pub fn actions<T>(&self, event: &Raw<T>) -> &'a [Action] {
+        self.ruleset.get_actions(event, &self.context)
+    }
+
+    /// Get the push actions that apply to each of the given events.
+    ///
+    /// The events must be in the order of the timeline of the room. Each event is evaluated with
+    /// the state of the room before the event, and the state events in the batch are applied to
+    /// the context of the room after they were evaluated.
+    pub fn evaluate_batch<'b, T: 'b>(
+        &mut self,
+        events: impl IntoIterator<Item = &'b Raw<T>>,
+    ) -> Vec<&'a [Action]> {
+        events
+            .into_iter()
+            .map(|event| {
+                let actions = self.actions(event);
+                self.apply_state_event(event);
+                actions
+            })
+            .collect()
+    }
+
+    /// Compute the unread notification counts of the given events.
+    ///
+    /// The events are evaluated like with [`PushEvaluator::evaluate_batch()`]. They should be the
+    /// events of the room that were not read by the user.
+    ///
+    /// An event counts as a notification if one of its push actions is [`Action::Notify`]. A
+    /// notification is also counted as a highlight if its actions include a highlight tweak.
+    pub fn unread_counts<'b, T: 'b>(
+        &mut self,
+        events: impl IntoIterator<Item = &'b Raw<T>>,
+    ) -> RoomUnreadCounts {
+        let mut counts = RoomUnreadCounts::default();
+
+        for event in events {
+            let actions = self.actions(event);
+            self.apply_state_event(event);
+
+            if !actions.iter().any(Action::should_notify) {
+                continue;
+            }
+
+            let thread_counts = match thread_root(event) {
+                Some(thread_root) => counts.threads.entry(thread_root).or_default(),
+                None => &mut counts.main,
+            };
+
+            thread_counts.notifications += uint!(1);
+            if actions.iter().any(Action::is_highlight) {
+                thread_counts.highlights += uint!(1);
+            }
+        }
+
+        counts
+    }
+
+    /// Update the context of the room with the data collected from the state events.
+    fn update_context(&mut self) {
+        self.context.member_count = UInt::new_saturating(self.joined_members.len() as u64);
+        self.context.user_display_name = self
+            .display_name
+            .clone()
+            .unwrap_or_else(|| self.context.user_id.localpart().to_owned());
+
+        if !self.has_power_levels_event {
+            // Use the default power levels, as described in the spec.
+            self.context.power_levels = self.creator.clone().map(|creator| {
+                let mut content = RoomPowerLevelsEventContent::new();
+                content.users.insert(creator, int!(100));
+                PushConditionPowerLevelsCtx::from(RoomPowerLevels::from(content))
+            });
+        }
+    }
+
+    /// Update the features supported by the room with the given room version.
+    #[cfg(feature = "unstable-msc3931")]
+    fn set_room_version(&mut self, room_version: &RoomVersionId) {
+        self.context.supported_features = RoomVersionFeature::list_for_room_version(room_version);
+    }
+
+    /// Update the features supported by the room with the given room version.
+    #[cfg(not(feature = "unstable-msc3931"))]
+    fn set_room_version(&mut self, _room_version: &RoomVersionId) {}
+}
+
+/// Get the ID of the root of the thread the given event belongs to, if any.
+fn thread_root<T>(event: &Raw<T>) -> Option<OwnedEventId> {
+    #[derive(Deserialize)]
+    struct EventContent {
+        #[serde(rename = "m.relates_to")]
+        relates_to: Option<Relation>,
+    }
+
+    #[derive(Deserialize)]
+    struct Relation {
+        rel_type: Option<RelationType>,
+        event_id: Option<OwnedEventId>,
+    }
+
+    let relation = event.get_field::<EventContent>("content").ok()??.relates_to?;
+    (relation.rel_type? == RelationType::Thread).then_some(relation.event_id?)
+}
+
+/// Counts of unread notifications.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct UnreadCounts {
+    /// The number of unread notifications.
+    pub notifications: UInt,
+
+    /// The number of unread notifications with the highlight flag set.
+    pub highlights: UInt,
+}
+
+impl UnreadCounts {
+    /// Creates empty `UnreadCounts`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether there are no unread notifications.
+    pub fn is_empty(&self) -> bool {
+        self.notifications == uint!(0)
+    }
+}
+
+impl std::ops::Add for UnreadCounts {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            notifications: self.notifications.saturating_add(rhs.notifications),
+            highlights: self.highlights.saturating_add(rhs.highlights),
+        }
+    }
+}
+
+impl std::ops::AddAssign for UnreadCounts {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+/// Counts of unread notifications in a room, split between the main timeline and the threads.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct RoomUnreadCounts {
+    /// The unread notifications in the main timeline of the room.
+    ///
+    /// This includes the roots of the threads.
+    pub main: UnreadCounts,
+
+    /// The unread notifications in the threads of the room.
+    ///
+    /// This is a map from thread root ID to unread notifications in the thread.
+    pub threads: BTreeMap<OwnedEventId, UnreadCounts>,
+}
+
+impl RoomUnreadCounts {
+    /// Creates empty `RoomUnreadCounts`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The unread notifications in the whole room, including the threads.
+    ///
+    /// This should be used when the client didn't request separate notification counts for
+    /// threads.
+    pub fn total(&self) -> UnreadCounts {
+        self.threads.values().fold(self.main, |total, counts| total + *counts)
+    }
+}
@@ -14,8 +14,11 @@ use serde::{Deserialize, Serialize};
 use super::AnyMessageLikeEvent;
 use crate::PrivOwnedStr;
 
+mod aggregation;
 mod rel_serde;
 
+pub use self::aggregation::{AnnotationGroup, AnnotationSender, ThreadSummary, TimelineAggregator};
+
 /// Information about the event a [rich reply] is replying to.
 ///
 /// [rich reply]: https://spec.matrix.org/latest/client-server-api/#rich-replies
@@ -0,0 +1,317 @@
+//! Aggregation of the relations of the events of a room timeline.
+
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+
+use js_int::UInt;
+use ruma_common::{
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, RoomVersionId, UserId,
+};
+
+use crate::{
+    room::encrypted::Relation, AnySyncMessageLikeEvent, AnySyncTimelineEvent, TimelineEventType,
+};
+
+/// Aggregates the relations of the events of a room timeline.
+///
+/// The events can be added in any order, for example when paginating backwards after a sync. The
+/// aggregations are computed when they are requested, so they always take into account all the
+/// events that were added, including the redactions:
+///
+/// * The latest valid replacement of an event, with [`TimelineAggregator::latest_edit()`]. A
+///   replacement is only valid if it has the same sender and type as the original event, and if the
+///   original event is not a state event or a replacement itself. The replacements of redacted
+///   events are ignored.
+/// * The annotations of an event, like reactions, grouped by key, with
+///   [`TimelineAggregator::annotations()`].
+/// * The summary of the thread of a thread root, with [`TimelineAggregator::thread()`].
+///
+/// Redacted events are ignored in all the aggregations.
+#[derive(Clone, Debug)]
+pub struct TimelineAggregator {
+    /// The version of the room, used to get the target of redactions.
+    room_version: RoomVersionId,
+
+    /// Data about the events that were added, by event ID.
+    events: BTreeMap<OwnedEventId, EventData>,
+
+    /// The replacement events, by event ID.
+    replacements: BTreeMap<OwnedEventId, AnySyncMessageLikeEvent>,
+
+    /// The IDs of the events that relate to an event, by ID of the related event.
+    relations: BTreeMap<OwnedEventId, BTreeSet<OwnedEventId>>,
+
+    /// The IDs of the events that were redacted.
+    redacted: BTreeSet<OwnedEventId>,
+}
+
+impl TimelineAggregator {
+    /// Creates an empty `TimelineAggregator` for a room with the given version.
+    pub fn new(room_version: RoomVersionId) -> Self {
+        Self {
+            room_version,
+            events: BTreeMap::new(),
+            replacements: BTreeMap::new(),
+            relations: BTreeMap::new(),
+            redacted: BTreeSet::new(),
+        }
+    }
+
+    /// Add the given event.
+    ///
+    /// If an event with the same ID was already added, only its redaction is taken into account.
+    pub fn add_event(&mut self, event: AnySyncTimelineEvent) {
+        let event_id = event.event_id().to_owned();
+
+        if let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomRedaction(
+            redaction,
+        )) = &event
+        {
+            if let Some(redacts) = redaction.redacts(&self.room_version) {
+                self.redacted.insert(redacts.to_owned());
+            }
+        }
+
+        let is_redacted = match &event {
+            AnySyncTimelineEvent::MessageLike(event) => event.original_content().is_none(),
+            AnySyncTimelineEvent::State(event) => event.original_content().is_none(),
+        };
+        if is_redacted {
+            self.redacted.insert(event_id.clone());
+        }
+
+        if self.events.contains_key(&event_id) {
+            return;
+        }
+
+        let relation = match &event {
+            AnySyncTimelineEvent::MessageLike(event) => {
+                event.original_content().and_then(|content| content.relation())
+            }
+            AnySyncTimelineEvent::State(_) => None,
+        };
+        let related_event_id = relation.as_ref().and_then(related_event_id);
+
+        if let Some(related_event_id) = related_event_id {
+            self.relations.entry(related_event_id.to_owned()).or_default().insert(event_id.clone());
+        }
+
+        let data = EventData {
+            sender: event.sender().to_owned(),
+            event_type: event.event_type(),
+            origin_server_ts: event.origin_server_ts(),
+            is_state: matches!(event, AnySyncTimelineEvent::State(_)),
+            relation,
+        };
+
+        if let (Some(Relation::Replacement(_)), AnySyncTimelineEvent::MessageLike(event)) =
+            (&data.relation, event)
+        {
+            self.replacements.insert(event_id.clone(), event);
+        }
+
+        self.events.insert(event_id, data);
+    }
+
+    /// Add the given events.
+    pub fn add_events(&mut self, events: impl IntoIterator<Item = AnySyncTimelineEvent>) {
+        for event in events {
+            self.add_event(event);
+        }
+    }
+
+    /// Whether the event with the given ID was redacted.
+    pub fn is_redacted(&self, event_id: &EventId) -> bool {
+        self.redacted.contains(event_id)
+    }
+
+    /// The latest valid replacement of the event with the given ID, if any.
+    ///
+    /// The latest replacement is the one with the highest `origin_server_ts`, or the one with the
+    /// lexicographically largest event ID if they have the same `origin_server_ts`.
+    ///
+    /// Returns `None` if the original event was not added yet or was redacted.
+    pub fn latest_edit(&self, event_id: &EventId) -> Option<&AnySyncMessageLikeEvent> {
+        if self.is_redacted(event_id) {
+            return None;
+        }
+
+        let original = self.events.get(event_id)?;
+        if original.is_state || matches!(original.relation, Some(Relation::Replacement(_))) {
+            return None;
+        }
+
+        self.valid_relations(event_id)
+            .filter(|(_, data)| {
+                matches!(data.relation, Some(Relation::Replacement(_)))
+                    && data.sender == original.sender
+                    && data.event_type == original.event_type
+            })
+            .max_by(|(a_id, a), (b_id, b)| {
+                (a.origin_server_ts, a_id).cmp(&(b.origin_server_ts, b_id))
+            })
+            .and_then(|(replacement_id, _)| self.replacements.get(replacement_id))
+    }
+
+    /// The annotations of the event with the given ID, by key.
+    ///
+    /// Only one annotation per key is counted for each sender.
+    pub fn annotations(&self, event_id: &EventId) -> BTreeMap<String, AnnotationGroup> {
+        let mut annotations = BTreeMap::<String, AnnotationGroup>::new();
+
+        for (annotation_id, data) in self.valid_relations(event_id) {
+            let Some(Relation::Annotation(annotation)) = &data.relation else {
+                continue;
+            };
+
+            let group = annotations.entry(annotation.key.clone()).or_default();
+            let sender = AnnotationSender {
+                event_id: annotation_id.to_owned(),
+                origin_server_ts: data.origin_server_ts,
+            };
+
+            // Keep the first annotation of each sender, so the result doesn't depend on the order
+            // of the events.
+            match group.senders.entry(data.sender.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(sender);
+                }
+                Entry::Occupied(mut entry) => {
+                    let existing = entry.get();
+                    if (sender.origin_server_ts, &sender.event_id)
+                        < (existing.origin_server_ts, &existing.event_id)
+                    {
+                        entry.insert(sender);
+                    }
+                }
+            }
+        }
+
+        annotations
+    }
+
+    /// The summary of the thread of the event with the given ID, if it is a thread root.
+    ///
+    /// Returns `None` if the event doesn't have any thread reply, or if the event is known and
+    /// can't be a thread root because it has a relation type itself.
+    pub fn thread(&self, event_id: &EventId) -> Option<ThreadSummary> {
+        let root = self.events.get(event_id);
+        if root.is_some_and(|root| root.relation.as_ref().and_then(Relation::rel_type).is_some()) {
+            return None;
+        }
+
+        let mut latest_event: Option<(MilliSecondsSinceUnixEpoch, &EventId)> = None;
+        let mut count = 0_u64;
+        let mut participants =
+            root.map(|root| root.sender.clone()).into_iter().collect::<BTreeSet<_>>();
+
+        for (reply_id, data) in self.valid_relations(event_id) {
+            if !matches!(data.relation, Some(Relation::Thread(_))) {
+                continue;
+            }
+
+            count += 1;
+            participants.insert(data.sender.clone());
+            if latest_event.map_or(true, |latest| (data.origin_server_ts, reply_id) > latest) {
+                latest_event = Some((data.origin_server_ts, reply_id));
+            }
+        }
+
+        let (_, latest_event) = latest_event?;
+        Some(ThreadSummary {
+            latest_event: latest_event.to_owned(),
+            count: UInt::new_saturating(count),
+            participants,
+        })
+    }
+
+    /// The events that relate to the event with the given ID and are not redacted.
+    fn valid_relations<'a>(
+        &'a self,
+        event_id: &EventId,
+    ) -> impl Iterator<Item = (&'a EventId, &'a EventData)> {
+        self.relations
+            .get(event_id)
+            .into_iter()
+            .flatten()
+            .filter(|relation_id| !self.is_redacted(relation_id))
+            .filter_map(|relation_id| Some((&**relation_id, self.events.get(relation_id)?)))
+    }
+}
+
+/// Get the ID of the event that is related with the given relation.
+fn related_event_id(relation: &Relation) -> Option<&EventId> {
+    match relation {
+        Relation::Replacement(replacement) => Some(&replacement.event_id),
+        Relation::Annotation(annotation) => Some(&annotation.event_id),
+        Relation::Thread(thread) => Some(&thread.event_id),
+        _ => None,
+    }
+}
+
+/// Data about an event of the timeline.
+#[derive(Clone, Debug)]
+struct EventData {
+    /// The sender of the event.
+    sender: OwnedUserId,
+
+    /// The type of the event.
+    event_type: TimelineEventType,
+
+    /// The timestamp of the event.
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+
+    /// Whether this is a state event.
+    is_state: bool,
+
+    /// The relation of the event, if any.
+    relation: Option<Relation>,
+}
+
+/// The annotations with the same key on an event.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct AnnotationGroup {
+    /// The senders of the annotations, with their annotation.
+    pub senders: BTreeMap<OwnedUserId, AnnotationSender>,
+}
+
+impl AnnotationGroup {
+    /// The number of annotations with this key.
+    pub fn count(&self) -> usize {
+        self.senders.len()
+    }
+
+    /// Whether the given user sent an annotation with this key.
+    pub fn has_sender(&self, user_id: &UserId) -> bool {
+        self.senders.contains_key(user_id)
+    }
+}
+
+/// An annotation sent by a user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct AnnotationSender {
+    /// The ID of the annotation event.
+    ///
+    /// This can be used to redact the annotation.
+    pub event_id: OwnedEventId,
+
+    /// The timestamp of the annotation event.
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+}
+
+/// A summary of a thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct ThreadSummary {
+    /// The ID of the latest event of the thread.
+    pub latest_event: OwnedEventId,
+
+    /// The number of events in the thread, excluding the root.
+    pub count: UInt,
+
+    /// The users that sent the root or an event of the thread.
+    ///
+    /// The sender of the root is only included if the root was added.
+    pub participants: BTreeSet<OwnedUserId>,
+}
@@ -12,6 +12,8 @@ use ruma_common::{
 use serde::{de, Deserialize, Serialize};
 
 pub mod aliases;
+#[cfg(feature = "attachment-encryption")]
+mod attachment_encryption;
 pub mod avatar;
 pub mod canonical_alias;
 pub mod create;
@@ -32,6 +34,11 @@ mod thumbnail_source_serde;
 pub mod tombstone;
 pub mod topic;
 
+#[cfg(feature = "attachment-encryption")]
+pub use self::attachment_encryption::{
+    AttachmentDecryptionError, AttachmentDecryptor, AttachmentEncryptor,
+};
+
 /// The source of a media file.
 #[derive(Clone, Debug, Serialize)]
 #[allow(clippy::exhaustive_enums)]
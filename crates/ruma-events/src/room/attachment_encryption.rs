@@ -0,0 +1,312 @@
+//! Encryption and decryption of [attachments] in encrypted rooms.
+//!
+//! [attachments]: https://spec.matrix.org/latest/client-server-api/#sending-encrypted-attachments
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use aes::{
+    cipher::{KeyIvInit, StreamCipher},
+    Aes256,
+};
+use futures_io::AsyncRead;
+use rand::{thread_rng, RngCore};
+use ruma_common::{
+    serde::{base64::UrlSafe, Base64},
+    OwnedMxcUri,
+};
+use sha2::{Digest, Sha256};
+
+use super::{EncryptedFile, EncryptedFileInit, JsonWebKey, JsonWebKeyInit};
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+/// The length of the AES key, in bytes.
+const KEY_LENGTH: usize = 32;
+
+/// The length of the AES-CTR IV, in bytes.
+const IV_LENGTH: usize = 16;
+
+/// The version of the encrypted attachments protocol produced by [`AttachmentEncryptor`].
+const VERSION: &str = "v2";
+
+/// The key of the SHA-256 hash in [`EncryptedFile::hashes`].
+const SHA256_HASH_KEY: &str = "sha256";
+
+/// A reader that encrypts the data of another reader, to upload it as an encrypted attachment.
+///
+/// A random key and IV are generated when this is constructed. The data can be read with [`Read`]
+/// or [`AsyncRead`], depending on the implementation of the inner reader. Once all the data was
+/// read and uploaded, the [`EncryptedFile`] to send in the event can be obtained with
+/// [`AttachmentEncryptor::finish()`].
+///
+/// # Example
+///
+/// ```no_run
+/// # fn upload(_data: Vec<u8>) -> ruma_common::OwnedMxcUri { unimplemented!() }
+/// use std::io::Read;
+///
+/// use ruma_events::room::{AttachmentEncryptor, MediaSource};
+///
+/// let data = b"Hello, world!";
+/// let mut encryptor = AttachmentEncryptor::new(&data[..]);
+///
+/// let mut encrypted = Vec::new();
+/// encryptor.read_to_end(&mut encrypted)?;
+///
+/// let url = upload(encrypted);
+/// let source = MediaSource::Encrypted(Box::new(encryptor.finish(url)));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct AttachmentEncryptor<R> {
+    /// The reader of the plain data.
+    inner: R,
+
+    /// The key used to encrypt the data.
+    key: [u8; KEY_LENGTH],
+
+    /// The IV used to encrypt the data.
+    iv: [u8; IV_LENGTH],
+
+    /// The cipher used to encrypt the data.
+    cipher: Aes256Ctr,
+
+    /// The hasher of the encrypted data.
+    sha256: Sha256,
+}
+
+impl<R> AttachmentEncryptor<R> {
+    /// Creates a new `AttachmentEncryptor` that encrypts the data of the given reader with a
+    /// random key and IV.
+    pub fn new(inner: R) -> Self {
+        let mut rng = thread_rng();
+
+        let mut key = [0; KEY_LENGTH];
+        rng.fill_bytes(&mut key);
+
+        // Only the first 64 bits of the IV are random, so the counter doesn't wrap around.
+        let mut iv = [0; IV_LENGTH];
+        rng.fill_bytes(&mut iv[..8]);
+
+        let cipher = Aes256Ctr::new(&key.into(), &iv.into());
+
+        Self { inner, key, iv, cipher, sha256: Sha256::new() }
+    }
+
+    /// Consume this `AttachmentEncryptor` and get the `EncryptedFile` to decrypt the data that was
+    /// read, once it is available at the given URL.
+    ///
+    /// This should only be called once all the data of the inner reader was read.
+    pub fn finish(self, url: OwnedMxcUri) -> EncryptedFile {
+        let key = JsonWebKeyInit {
+            kty: "oct".to_owned(),
+            key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+            alg: "A256CTR".to_owned(),
+            k: Base64::<UrlSafe>::new(self.key.to_vec()),
+            ext: true,
+        };
+
+        let hashes = BTreeMap::from([(
+            SHA256_HASH_KEY.to_owned(),
+            Base64::new(self.sha256.finalize().to_vec()),
+        )]);
+
+        EncryptedFileInit {
+            url,
+            key: key.into(),
+            iv: Base64::new(self.iv.to_vec()),
+            hashes,
+            v: VERSION.to_owned(),
+        }
+        .into()
+    }
+
+    /// Encrypt the given data that was read from the inner reader.
+    fn encrypt(&mut self, data: &mut [u8]) {
+        self.cipher.apply_keystream(data);
+        self.sha256.update(data);
+    }
+}
+
+impl<R: Read> Read for AttachmentEncryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.encrypt(&mut buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AttachmentEncryptor<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let read = match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(read)) => read,
+            poll => return poll,
+        };
+
+        this.encrypt(&mut buf[..read]);
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<R> std::fmt::Debug for AttachmentEncryptor<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttachmentEncryptor").finish_non_exhaustive()
+    }
+}
+
+/// A reader that decrypts the data of another reader, downloaded from an encrypted attachment.
+///
+/// The SHA-256 hash of the encrypted data is checked when the end of the inner reader is reached.
+/// If it doesn't match, reading returns an error of kind [`io::ErrorKind::InvalidData`] wrapping
+/// an [`AttachmentDecryptionError::HashMismatch`].
+///
+/// Since the data is decrypted as it is read, it must not be trusted until the end of the data
+/// was reached without error.
+pub struct AttachmentDecryptor<R> {
+    /// The reader of the encrypted data.
+    inner: R,
+
+    /// The cipher used to decrypt the data.
+    cipher: Aes256Ctr,
+
+    /// The hasher of the encrypted data.
+    sha256: Sha256,
+
+    /// The expected SHA-256 hash of the encrypted data.
+    expected_hash: Vec<u8>,
+
+    /// Whether the hash of the encrypted data was checked.
+    hash_checked: bool,
+}
+
+impl<R> AttachmentDecryptor<R> {
+    /// Creates a new `AttachmentDecryptor` that decrypts the data of the given reader with the
+    /// key and IV of the given `EncryptedFile`.
+    ///
+    /// Returns an error if the version of the encrypted attachments protocol or the key algorithm
+    /// is not supported, or if the key, IV or SHA-256 hash are missing or invalid.
+    pub fn new(inner: R, file: &EncryptedFile) -> Result<Self, AttachmentDecryptionError> {
+        if !matches!(file.v.as_str(), "v1" | "v2") {
+            return Err(AttachmentDecryptionError::UnsupportedVersion(file.v.clone()));
+        }
+
+        let JsonWebKey { kty, alg, k, .. } = &file.key;
+        if kty != "oct" || alg != "A256CTR" {
+            return Err(AttachmentDecryptionError::UnsupportedAlgorithm);
+        }
+
+        let key: [u8; KEY_LENGTH] =
+            k.as_bytes().try_into().map_err(|_| AttachmentDecryptionError::InvalidKeyLength)?;
+        let iv: [u8; IV_LENGTH] = file
+            .iv
+            .as_bytes()
+            .try_into()
+            .map_err(|_| AttachmentDecryptionError::InvalidIvLength)?;
+        let expected_hash = file
+            .hashes
+            .get(SHA256_HASH_KEY)
+            .ok_or(AttachmentDecryptionError::MissingHash)?
+            .as_bytes()
+            .to_owned();
+
+        let cipher = Aes256Ctr::new(&key.into(), &iv.into());
+
+        Ok(Self { inner, cipher, sha256: Sha256::new(), expected_hash, hash_checked: false })
+    }
+
+    /// Decrypt the given data that was read from the inner reader.
+    ///
+    /// If no data was read while the buffer was not empty, the end of the inner reader was
+    /// reached and the hash of the data is checked.
+    fn decrypt(&mut self, data: &mut [u8], is_eof: bool) -> io::Result<()> {
+        if is_eof {
+            if self.hash_checked {
+                return Ok(());
+            }
+
+            self.hash_checked = true;
+            let hash = self.sha256.finalize_reset();
+            if hash.as_slice() != self.expected_hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    AttachmentDecryptionError::HashMismatch,
+                ));
+            }
+        } else {
+            self.sha256.update(&*data);
+            self.cipher.apply_keystream(data);
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for AttachmentDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let is_eof = read == 0 && !buf.is_empty();
+        self.decrypt(&mut buf[..read], is_eof)?;
+        Ok(read)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AttachmentDecryptor<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let read = match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(read)) => read,
+            poll => return poll,
+        };
+
+        let is_eof = read == 0 && !buf.is_empty();
+        Poll::Ready(this.decrypt(&mut buf[..read], is_eof).map(|_| read))
+    }
+}
+
+impl<R> std::fmt::Debug for AttachmentDecryptor<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttachmentDecryptor").finish_non_exhaustive()
+    }
+}
+
+/// An error encountered when decrypting an attachment.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum AttachmentDecryptionError {
+    /// The version of the encrypted attachments protocol is not supported.
+    #[error("unsupported encrypted attachments version: {0}")]
+    UnsupportedVersion(String),
+
+    /// The key type or algorithm is not supported.
+    #[error("unsupported key type or algorithm")]
+    UnsupportedAlgorithm,
+
+    /// The key doesn't have the length of an AES-256 key.
+    #[error("invalid key length")]
+    InvalidKeyLength,
+
+    /// The IV doesn't have the length of an AES-CTR IV.
+    #[error("invalid IV length")]
+    InvalidIvLength,
+
+    /// The SHA-256 hash of the encrypted data is missing.
+    #[error("missing SHA-256 hash")]
+    MissingHash,
+
+    /// The SHA-256 hash of the encrypted data doesn't match the expected hash.
+    #[error("SHA-256 hash mismatch")]
+    HashMismatch,
+}
@@ -9,7 +9,7 @@ use ruma_common::{
     OwnedEventId, RoomId,
 };
 #[cfg(feature = "html")]
-use ruma_html::{sanitize_html, HtmlSanitizerMode, RemoveReplyFallback};
+use ruma_html::{sanitize_html, Html, HtmlSanitizerMode, RemoveReplyFallback};
 use ruma_macros::EventContent;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value as JsonValue;
@@ -875,6 +875,68 @@ impl FormattedBody {
             self.body = sanitize_html(&self.body, mode, remove_reply_fallback);
         }
     }
+
+    /// Compute the [mentions] in this `FormattedBody` if its format is `MessageFormat::Html`.
+    ///
+    /// The users linked to with a `matrix.to` or `matrix:` URI are mentioned, and the whole room
+    /// is mentioned if the text contains `@room` as a standalone word. Links in the [rich reply
+    /// fallback] are ignored.
+    ///
+    /// The result can be used with [`RoomMessageEventContent::set_mentions()`] or
+    /// [`RoomMessageEventContent::add_mentions()`].
+    ///
+    /// Returns an empty `Mentions` if the format is not `MessageFormat::Html`.
+    ///
+    /// [mentions]: https://spec.matrix.org/latest/client-server-api/#user-and-room-mentions
+    /// [rich reply fallback]: https://spec.matrix.org/latest/client-server-api/#fallbacks-for-rich-replies
+    #[cfg(feature = "html")]
+    pub fn mentions(&self) -> Mentions {
+        use ruma_common::matrix_uri::MatrixId;
+
+        let mut mentions = Mentions::new();
+        if self.format != MessageFormat::Html {
+            return mentions;
+        }
+
+        let html = Html::parse(&self.body);
+        mentions.user_ids = html
+            .matrix_links()
+            .into_iter()
+            .filter_map(|link| match link.id {
+                MatrixId::User(user_id) => Some(user_id),
+                _ => None,
+            })
+            .collect();
+        mentions.room = contains_room_mention(&html.to_plain_text());
+
+        mentions
+    }
+}
+
+/// Whether the given text contains `@room` as a standalone word.
+///
+/// `@room` is not a room mention if it is part of another word or of a user ID like
+/// `@room:example.org`.
+#[cfg(feature = "html")]
+fn contains_room_mention(text: &str) -> bool {
+    fn is_word_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+
+    text.match_indices("@room").any(|(start, word)| {
+        let before = text[..start].chars().next_back();
+        let mut after = text[start + word.len()..].chars();
+
+        let word_starts = !before.is_some_and(|c| is_word_char(c) || c == '@');
+        let word_ends = match after.next() {
+            None => true,
+            // `@room:` followed by a server name is a user ID.
+            Some(':') => !after.next().is_some_and(|c| is_word_char(c) || c == '['),
+            Some(c) => !is_word_char(c),
+        };
+
+        word_starts && word_ends
+    })
 }
 
 /// The payload for a custom message event.
@@ -1,5 +1,10 @@
 //! Module for events in the `m.secret_storage` namespace.
 
 pub mod default_key;
+#[cfg(feature = "secret-storage-encryption")]
+mod encryption;
 pub mod key;
 pub mod secret;
+
+#[cfg(feature = "secret-storage-encryption")]
+pub use self::encryption::{SecretStorageError, SecretStorageKey};
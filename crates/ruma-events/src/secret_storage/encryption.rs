@@ -0,0 +1,376 @@
+//! Encryption and decryption of secrets with the `m.secret_storage.v1.aes-hmac-sha2` algorithm.
+//!
+//! See the [spec](https://spec.matrix.org/latest/client-server-api/#msecret_storagev1aes-hmac-sha2)
+//! for the details of the algorithm.
+
+use std::fmt;
+
+use aes::{
+    cipher::{KeyIvInit, StreamCipher},
+    Aes256,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use ruma_common::{serde::Base64, KeyDerivationAlgorithm};
+use sha2::{Sha256, Sha512};
+use zeroize::{Zeroize, Zeroizing};
+
+use super::{
+    key::{
+        PassPhrase, SecretStorageEncryptionAlgorithm, SecretStorageKeyEventContent,
+        SecretStorageV1AesHmacSha2Properties,
+    },
+    secret::SecretEncryptedData,
+};
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// The length of a secret storage key, in bytes.
+const KEY_LENGTH: usize = 32;
+
+/// The length of the AES-CTR IV, in bytes.
+const IV_LENGTH: usize = 16;
+
+/// The prefix of a recovery key, before it is encoded in base58.
+const RECOVERY_KEY_PREFIX: [u8; 2] = [0x8b, 0x01];
+
+/// The length of a recovery key, before it is encoded in base58.
+const RECOVERY_KEY_LENGTH: usize = RECOVERY_KEY_PREFIX.len() + KEY_LENGTH + 1;
+
+/// A key to encrypt and decrypt secrets with the `m.secret_storage.v1.aes-hmac-sha2` algorithm.
+///
+/// The `Debug` implementation of this type doesn't print the key, and the key is zeroized when this
+/// type is dropped.
+#[derive(Clone)]
+pub struct SecretStorageKey {
+    /// The ID of the key.
+    key_id: String,
+
+    /// The bytes of the key.
+    key: [u8; KEY_LENGTH],
+}
+
+impl SecretStorageKey {
+    /// Creates a new random `SecretStorageKey` with the given ID.
+    pub fn new(key_id: String) -> Self {
+        let mut key = [0; KEY_LENGTH];
+        thread_rng().fill_bytes(&mut key);
+
+        Self { key_id, key }
+    }
+
+    /// Creates a `SecretStorageKey` with the given ID and bytes.
+    pub fn from_bytes(key_id: String, key: [u8; KEY_LENGTH]) -> Self {
+        Self { key_id, key }
+    }
+
+    /// Derive a `SecretStorageKey` with the given ID from the given passphrase, with the
+    /// parameters of the given `PassPhrase`.
+    ///
+    /// Returns an error if the key derivation algorithm is not `m.pbkdf2` or if the number of bits
+    /// to generate is not 256.
+    pub fn from_passphrase(
+        key_id: String,
+        passphrase: &str,
+        params: &PassPhrase,
+    ) -> Result<Self, SecretStorageError> {
+        if params.algorithm != KeyDerivationAlgorithm::Pbkfd2
+            || usize::try_from(params.bits).ok() != Some(KEY_LENGTH * 8)
+        {
+            return Err(SecretStorageError::UnsupportedKeyDerivation);
+        }
+
+        let iterations = u32::try_from(params.iterations)
+            .map_err(|_| SecretStorageError::UnsupportedKeyDerivation)?;
+
+        let mut key = [0; KEY_LENGTH];
+        pbkdf2::pbkdf2_hmac::<Sha512>(
+            passphrase.as_bytes(),
+            params.salt.as_bytes(),
+            iterations,
+            &mut key,
+        );
+
+        Ok(Self { key_id, key })
+    }
+
+    /// Decode a `SecretStorageKey` with the given ID from the given recovery key.
+    ///
+    /// The whitespace in the recovery key is ignored.
+    ///
+    /// Returns an error if the recovery key is not valid base58, or if its prefix, length or
+    /// parity byte are invalid.
+    pub fn from_recovery_key(
+        key_id: String,
+        recovery_key: &str,
+    ) -> Result<Self, SecretStorageError> {
+        let recovery_key: String = recovery_key.split_whitespace().collect();
+        let bytes = Zeroizing::new(
+            bs58::decode(recovery_key)
+                .with_alphabet(bs58::Alphabet::BITCOIN)
+                .into_vec()
+                .map_err(|_| SecretStorageError::InvalidRecoveryKey)?,
+        );
+
+        if bytes.len() != RECOVERY_KEY_LENGTH
+            || bytes[..RECOVERY_KEY_PREFIX.len()] != RECOVERY_KEY_PREFIX
+            || bytes.iter().fold(0, |parity, byte| parity ^ byte) != 0
+        {
+            return Err(SecretStorageError::InvalidRecoveryKey);
+        }
+
+        let key = bytes[RECOVERY_KEY_PREFIX.len()..RECOVERY_KEY_LENGTH - 1]
+            .try_into()
+            .expect("recovery key should have the length of a key");
+
+        Ok(Self { key_id, key })
+    }
+
+    /// The ID of this key.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The bytes of this key.
+    pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+        &self.key
+    }
+
+    /// Encode this key as a recovery key.
+    ///
+    /// The recovery key is encoded in base58, in groups of 4 characters separated by spaces.
+    pub fn to_recovery_key(&self) -> String {
+        let mut bytes = Vec::with_capacity(RECOVERY_KEY_LENGTH);
+        bytes.extend_from_slice(&RECOVERY_KEY_PREFIX);
+        bytes.extend_from_slice(&self.key);
+        bytes.push(bytes.iter().fold(0, |parity, byte| parity ^ byte));
+
+        let encoded = bs58::encode(bytes).with_alphabet(bs58::Alphabet::BITCOIN).into_string();
+        encoded
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| std::str::from_utf8(chunk).expect("base58 should be ASCII"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Creates the content of the `m.secret_storage.key.*` event describing this key.
+    ///
+    /// The content includes a new `iv` and `mac` to check that a key matches the description. The
+    /// passphrase that was used to derive this key, if any, must be set separately.
+    pub fn event_content(&self) -> SecretStorageKeyEventContent {
+        let (iv, _, mac) = self.encrypt(&[0; KEY_LENGTH], "");
+        let properties = SecretStorageV1AesHmacSha2Properties::new(Some(iv), Some(mac));
+
+        SecretStorageKeyEventContent::new(
+            self.key_id.clone(),
+            SecretStorageEncryptionAlgorithm::V1AesHmacSha2(properties),
+        )
+    }
+
+    /// Check that this key matches the given key description.
+    ///
+    /// The `iv` and `mac` of the description are used to check the key. If they are missing, the
+    /// key can't be checked and is assumed to be correct.
+    ///
+    /// Returns an error if the algorithm of the description is not
+    /// `m.secret_storage.v1.aes-hmac-sha2` or if the key doesn't match.
+    pub fn check(
+        &self,
+        description: &SecretStorageKeyEventContent,
+    ) -> Result<(), SecretStorageError> {
+        let SecretStorageEncryptionAlgorithm::V1AesHmacSha2(properties) = &description.algorithm
+        else {
+            return Err(SecretStorageError::UnsupportedAlgorithm(
+                description.algorithm.algorithm().to_owned(),
+            ));
+        };
+
+        let (Some(iv), Some(mac)) = (&properties.iv, &properties.mac) else {
+            return Ok(());
+        };
+
+        let iv: [u8; IV_LENGTH] =
+            iv.as_bytes().try_into().map_err(|_| SecretStorageError::InvalidIvLength)?;
+        let (aes_key, mac_key) = self.derive_keys("");
+
+        let mut ciphertext = [0; KEY_LENGTH];
+        Aes256Ctr::new(&(*aes_key).into(), &iv.into()).apply_keystream(&mut ciphertext);
+
+        hmac(&mac_key, &ciphertext)
+            .verify_slice(mac.as_bytes())
+            .map_err(|_| SecretStorageError::KeyMismatch)
+    }
+
+    /// Encrypt the given secret with the given name.
+    ///
+    /// The name of the secret is the type of the event where the secret is stored in the account
+    /// data, like `m.cross_signing.master`. The returned data must be added to the `encrypted` map
+    /// of the event content under the ID of this key.
+    pub fn encrypt_secret(&self, secret: &str, secret_name: &str) -> SecretEncryptedData {
+        let (iv, ciphertext, mac) = self.encrypt(secret.as_bytes(), secret_name);
+        SecretEncryptedData::AesHmacSha2EncryptedData { iv, ciphertext, mac }
+    }
+
+    /// Decrypt the given secret with the given name.
+    ///
+    /// Returns an error if the IV is invalid, if the MAC doesn't match or if the decrypted secret
+    /// is not valid UTF-8.
+    pub fn decrypt_secret(
+        &self,
+        data: &SecretEncryptedData,
+        secret_name: &str,
+    ) -> Result<String, SecretStorageError> {
+        let SecretEncryptedData::AesHmacSha2EncryptedData { iv, ciphertext, mac } = data;
+
+        let iv: [u8; IV_LENGTH] =
+            iv.as_bytes().try_into().map_err(|_| SecretStorageError::InvalidIvLength)?;
+        let (aes_key, mac_key) = self.derive_keys(secret_name);
+
+        hmac(&mac_key, ciphertext.as_bytes())
+            .verify_slice(mac.as_bytes())
+            .map_err(|_| SecretStorageError::MacMismatch)?;
+
+        let mut plaintext = ciphertext.as_bytes().to_owned();
+        Aes256Ctr::new(&(*aes_key).into(), &iv.into()).apply_keystream(&mut plaintext);
+
+        String::from_utf8(plaintext).map_err(|error| {
+            error.into_bytes().zeroize();
+            SecretStorageError::InvalidUtf8
+        })
+    }
+
+    /// Derive the AES key and the MAC key to encrypt the secret with the given name.
+    fn derive_keys(
+        &self,
+        secret_name: &str,
+    ) -> (Zeroizing<[u8; KEY_LENGTH]>, Zeroizing<[u8; KEY_LENGTH]>) {
+        let hkdf = Hkdf::<Sha256>::new(Some(&[0; 32]), &self.key);
+
+        let mut keys = Zeroizing::new([0; KEY_LENGTH * 2]);
+        hkdf.expand(secret_name.as_bytes(), &mut *keys).expect("64 bytes should be a valid length");
+
+        let (aes_key, mac_key) = keys.split_at(KEY_LENGTH);
+        (
+            Zeroizing::new(aes_key.try_into().expect("AES key should have the right length")),
+            Zeroizing::new(mac_key.try_into().expect("MAC key should have the right length")),
+        )
+    }
+
+    /// Encrypt the given data with the given name and a random IV.
+    ///
+    /// Returns the IV, the ciphertext and the MAC.
+    fn encrypt(&self, data: &[u8], name: &str) -> (Base64, Base64, Base64) {
+        let mut iv = [0; IV_LENGTH];
+        thread_rng().fill_bytes(&mut iv);
+        // Clear bit 63 of the IV to avoid issues with some implementations of AES-CTR.
+        iv[8] &= 0x7f;
+
+        let (ciphertext, mac) = self.encrypt_with_iv(data, name, iv);
+        (Base64::new(iv.to_vec()), ciphertext, mac)
+    }
+
+    /// Encrypt the given data with the given name and IV.
+    ///
+    /// Returns the ciphertext and the MAC.
+    fn encrypt_with_iv(&self, data: &[u8], name: &str, iv: [u8; IV_LENGTH]) -> (Base64, Base64) {
+        let (aes_key, mac_key) = self.derive_keys(name);
+
+        let mut ciphertext = data.to_owned();
+        Aes256Ctr::new(&(*aes_key).into(), &iv.into()).apply_keystream(&mut ciphertext);
+
+        let mac = hmac(&mac_key, &ciphertext).finalize().into_bytes().to_vec();
+
+        (Base64::new(ciphertext), Base64::new(mac))
+    }
+}
+
+impl Drop for SecretStorageKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Compute the HMAC-SHA-256 of the given data with the given key.
+fn hmac(mac_key: &[u8; KEY_LENGTH], data: &[u8]) -> HmacSha256 {
+    let mut hmac = HmacSha256::new_from_slice(mac_key).expect("HMAC should accept any key");
+    hmac.update(data);
+    hmac
+}
+
+impl fmt::Debug for SecretStorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretStorageKey").field("key_id", &self.key_id).finish_non_exhaustive()
+    }
+}
+
+impl SecretStorageKeyEventContent {
+    /// Derive the key described by this content from the given passphrase, and check that it
+    /// matches this description.
+    ///
+    /// Returns an error if this content doesn't have a `passphrase`, if the key can't be derived
+    /// or if it doesn't match this description.
+    pub fn key_from_passphrase(
+        &self,
+        passphrase: &str,
+    ) -> Result<SecretStorageKey, SecretStorageError> {
+        let params = self.passphrase.as_ref().ok_or(SecretStorageError::MissingPassPhrase)?;
+        let key = SecretStorageKey::from_passphrase(self.key_id.clone(), passphrase, params)?;
+        key.check(self)?;
+
+        Ok(key)
+    }
+
+    /// Decode the key described by this content from the given recovery key, and check that it
+    /// matches this description.
+    ///
+    /// Returns an error if the recovery key is invalid or if it doesn't match this description.
+    pub fn key_from_recovery_key(
+        &self,
+        recovery_key: &str,
+    ) -> Result<SecretStorageKey, SecretStorageError> {
+        let key = SecretStorageKey::from_recovery_key(self.key_id.clone(), recovery_key)?;
+        key.check(self)?;
+
+        Ok(key)
+    }
+}
+
+/// An error encountered when using a [`SecretStorageKey`].
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum SecretStorageError {
+    /// The algorithm of the key is not supported.
+    #[error("unsupported secret storage algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+
+    /// The key description doesn't have a passphrase.
+    #[error("the key can't be derived from a passphrase")]
+    MissingPassPhrase,
+
+    /// The key derivation algorithm or its parameters are not supported.
+    #[error("unsupported key derivation algorithm or parameters")]
+    UnsupportedKeyDerivation,
+
+    /// The recovery key is invalid.
+    #[error("invalid recovery key")]
+    InvalidRecoveryKey,
+
+    /// The key doesn't match the key description.
+    #[error("the key doesn't match the key description")]
+    KeyMismatch,
+
+    /// The IV doesn't have the length of an AES-CTR IV.
+    #[error("invalid IV length")]
+    InvalidIvLength,
+
+    /// The MAC of the encrypted secret doesn't match.
+    #[error("MAC mismatch")]
+    MacMismatch,
+
+    /// The decrypted secret is not valid UTF-8.
+    #[error("the decrypted secret is not valid UTF-8")]
+    InvalidUtf8,
+}
@@ -0,0 +1,284 @@
+use assert_matches2::assert_matches;
+use js_int::uint;
+use ruma_common::{event_id, owned_event_id, user_id, RoomVersionId};
+use ruma_events::{
+    relation::{Replacement, TimelineAggregator},
+    room::message::{
+        MessageType, Relation, RoomMessageEventContentWithoutRelation, SyncRoomMessageEvent,
+    },
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent,
+};
+use serde_json::{from_value as from_json_value, json, Value as JsonValue};
+
+fn event(json: JsonValue) -> AnySyncTimelineEvent {
+    from_json_value(json).unwrap()
+}
+
+fn message(event_id: &str, sender: &str, ts: u64, body: &str) -> AnySyncTimelineEvent {
+    event(json!({
+        "type": "m.room.message",
+        "event_id": event_id,
+        "sender": sender,
+        "origin_server_ts": ts,
+        "content": { "msgtype": "m.text", "body": body },
+    }))
+}
+
+fn edit(event_id: &str, sender: &str, ts: u64, original: &str, body: &str) -> AnySyncTimelineEvent {
+    event(json!({
+        "type": "m.room.message",
+        "event_id": event_id,
+        "sender": sender,
+        "origin_server_ts": ts,
+        "content": {
+            "msgtype": "m.text",
+            "body": format!("* {body}"),
+            "m.new_content": { "msgtype": "m.text", "body": body },
+            "m.relates_to": { "rel_type": "m.replace", "event_id": original },
+        },
+    }))
+}
+
+fn reaction(
+    event_id: &str,
+    sender: &str,
+    ts: u64,
+    target: &str,
+    key: &str,
+) -> AnySyncTimelineEvent {
+    event(json!({
+        "type": "m.reaction",
+        "event_id": event_id,
+        "sender": sender,
+        "origin_server_ts": ts,
+        "content": {
+            "m.relates_to": { "rel_type": "m.annotation", "event_id": target, "key": key },
+        },
+    }))
+}
+
+fn thread_reply(event_id: &str, sender: &str, ts: u64, root: &str) -> AnySyncTimelineEvent {
+    event(json!({
+        "type": "m.room.message",
+        "event_id": event_id,
+        "sender": sender,
+        "origin_server_ts": ts,
+        "content": {
+            "msgtype": "m.text",
+            "body": "In a thread",
+            "m.relates_to": {
+                "rel_type": "m.thread",
+                "event_id": root,
+                "is_falling_back": true,
+                "m.in_reply_to": { "event_id": root },
+            },
+        },
+    }))
+}
+
+fn redaction(event_id: &str, sender: &str, ts: u64, redacts: &str) -> AnySyncTimelineEvent {
+    event(json!({
+        "type": "m.room.redaction",
+        "event_id": event_id,
+        "sender": sender,
+        "origin_server_ts": ts,
+        "content": { "redacts": redacts },
+    }))
+}
+
+fn edited_body(event: &AnySyncMessageLikeEvent) -> String {
+    assert_matches!(
+        event,
+        AnySyncMessageLikeEvent::RoomMessage(SyncRoomMessageEvent::Original(event))
+    );
+    assert_matches!(
+        event.content.relates_to.clone(),
+        Some(Relation::Replacement(Replacement { new_content, .. }))
+    );
+    let RoomMessageEventContentWithoutRelation { msgtype, .. } = new_content;
+    assert_matches!(msgtype, MessageType::Text(text));
+    text.body
+}
+
+#[test]
+fn latest_edit_in_any_order() {
+    let mut aggregator = TimelineAggregator::new(RoomVersionId::V11);
+
+    // The edits are received before the original event.
+    aggregator.add_events([
+        edit("$edit2:localhost", "@alice:localhost", 3, "$original:localhost", "Second edit"),
+        edit("$edit1:localhost", "@alice:localhost", 2, "$original:localhost", "First edit"),
+    ]);
+    assert!(aggregator.latest_edit(event_id!("$original:localhost")).is_none());
+
+    aggregator.add_event(message("$original:localhost", "@alice:localhost", 1, "Hello"));
+    let latest_edit = aggregator.latest_edit(event_id!("$original:localhost")).unwrap();
+    assert_eq!(latest_edit.event_id(), "$edit2:localhost");
+    assert_eq!(edited_body(latest_edit), "Second edit");
+
+    // Redacting the latest edit falls back to the previous one.
+    aggregator.add_event(redaction(
+        "$redaction:localhost",
+        "@alice:localhost",
+        4,
+        "$edit2:localhost",
+    ));
+    let latest_edit = aggregator.latest_edit(event_id!("$original:localhost")).unwrap();
+    assert_eq!(latest_edit.event_id(), "$edit1:localhost");
+
+    // Redacting the original event ignores all the edits.
+    aggregator.add_event(redaction(
+        "$redaction2:localhost",
+        "@alice:localhost",
+        5,
+        "$original:localhost",
+    ));
+    assert!(aggregator.is_redacted(event_id!("$original:localhost")));
+    assert!(aggregator.latest_edit(event_id!("$original:localhost")).is_none());
+}
+
+#[test]
+fn invalid_edits() {
+    let mut aggregator = TimelineAggregator::new(RoomVersionId::V11);
+    aggregator.add_events([
+        message("$original:localhost", "@alice:localhost", 1, "Hello"),
+        // Another sender.
+        edit("$edit1:localhost", "@bob:localhost", 2, "$original:localhost", "Hijacked"),
+        // Another type.
+        event(json!({
+            "type": "m.sticker",
+            "event_id": "$edit2:localhost",
+            "sender": "@alice:localhost",
+            "origin_server_ts": 3,
+            "content": {
+                "body": "sticker",
+                "info": {},
+                "url": "mxc://localhost/sticker",
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$original:localhost" },
+            },
+        })),
+        // An edit of an edit.
+        edit("$edit3:localhost", "@alice:localhost", 4, "$original:localhost", "Valid"),
+        edit("$edit4:localhost", "@alice:localhost", 5, "$edit3:localhost", "Edit of edit"),
+    ]);
+
+    let latest_edit = aggregator.latest_edit(event_id!("$original:localhost")).unwrap();
+    assert_eq!(latest_edit.event_id(), "$edit3:localhost");
+    assert!(aggregator.latest_edit(event_id!("$edit3:localhost")).is_none());
+}
+
+#[test]
+fn latest_edit_same_timestamp() {
+    let mut aggregator = TimelineAggregator::new(RoomVersionId::V11);
+    aggregator.add_events([
+        message("$original:localhost", "@alice:localhost", 1, "Hello"),
+        edit("$b:localhost", "@alice:localhost", 2, "$original:localhost", "B"),
+        edit("$a:localhost", "@alice:localhost", 2, "$original:localhost", "A"),
+    ]);
+
+    let latest_edit = aggregator.latest_edit(event_id!("$original:localhost")).unwrap();
+    assert_eq!(latest_edit.event_id(), "$b:localhost");
+}
+
+#[test]
+fn reactions() {
+    let mut aggregator = TimelineAggregator::new(RoomVersionId::V11);
+    aggregator.add_events([
+        reaction("$r1:localhost", "@alice:localhost", 2, "$target:localhost", "👍"),
+        reaction("$r2:localhost", "@bob:localhost", 3, "$target:localhost", "👍"),
+        reaction("$r3:localhost", "@bob:localhost", 4, "$target:localhost", "👍"),
+        reaction("$r4:localhost", "@bob:localhost", 5, "$target:localhost", "🎉"),
+        reaction("$r5:localhost", "@carl:localhost", 6, "$target:localhost", "🎉"),
+        redaction("$redaction:localhost", "@carl:localhost", 7, "$r5:localhost"),
+        message("$target:localhost", "@alice:localhost", 1, "Hello"),
+    ]);
+
+    let annotations = aggregator.annotations(event_id!("$target:localhost"));
+    assert_eq!(annotations.len(), 2);
+
+    let thumbs_up = &annotations["👍"];
+    assert_eq!(thumbs_up.count(), 2);
+    assert!(thumbs_up.has_sender(user_id!("@alice:localhost")));
+    // The first reaction of a sender is kept.
+    assert_eq!(thumbs_up.senders[user_id!("@bob:localhost")].event_id, "$r2:localhost");
+
+    let party = &annotations["🎉"];
+    assert_eq!(party.count(), 1);
+    assert!(!party.has_sender(user_id!("@carl:localhost")));
+
+    assert!(aggregator.annotations(event_id!("$unknown:localhost")).is_empty());
+}
+
+#[test]
+fn threads() {
+    let mut aggregator = TimelineAggregator::new(RoomVersionId::V11);
+    aggregator.add_events([
+        thread_reply("$reply2:localhost", "@carl:localhost", 3, "$root:localhost"),
+        thread_reply("$reply1:localhost", "@bob:localhost", 2, "$root:localhost"),
+        thread_reply("$reply3:localhost", "@bob:localhost", 4, "$root:localhost"),
+        message("$root:localhost", "@alice:localhost", 1, "Hello"),
+        message("$other:localhost", "@alice:localhost", 5, "Not in a thread"),
+    ]);
+
+    let thread = aggregator.thread(event_id!("$root:localhost")).unwrap();
+    assert_eq!(thread.count, uint!(3));
+    assert_eq!(thread.latest_event, "$reply3:localhost");
+    assert_eq!(thread.participants.len(), 3);
+    assert!(thread.participants.contains(user_id!("@alice:localhost")));
+
+    // Redacted replies are not counted.
+    aggregator.add_event(redaction(
+        "$redaction:localhost",
+        "@bob:localhost",
+        6,
+        "$reply3:localhost",
+    ));
+    let thread = aggregator.thread(event_id!("$root:localhost")).unwrap();
+    assert_eq!(thread.count, uint!(2));
+    assert_eq!(thread.latest_event, "$reply2:localhost");
+
+    assert_eq!(aggregator.thread(event_id!("$other:localhost")), None);
+    // A thread reply can't be a thread root.
+    aggregator.add_event(thread_reply(
+        "$nested:localhost",
+        "@bob:localhost",
+        7,
+        "$reply1:localhost",
+    ));
+    assert_eq!(aggregator.thread(event_id!("$reply1:localhost")), None);
+}
+
+#[test]
+fn redacted_events() {
+    let mut aggregator = TimelineAggregator::new(RoomVersionId::V11);
+    aggregator.add_event(message("$original:localhost", "@alice:localhost", 1, "Hello"));
+    aggregator.add_event(edit(
+        "$edit:localhost",
+        "@alice:localhost",
+        2,
+        "$original:localhost",
+        "Hi",
+    ));
+
+    // The redacted version of the edit is received later.
+    aggregator.add_event(event(json!({
+        "type": "m.room.message",
+        "event_id": "$edit:localhost",
+        "sender": "@alice:localhost",
+        "origin_server_ts": 2,
+        "content": {},
+        "unsigned": {
+            "redacted_because": {
+                "type": "m.room.redaction",
+                "event_id": "$redaction:localhost",
+                "sender": "@alice:localhost",
+                "origin_server_ts": 3,
+                "redacts": "$edit:localhost",
+                "content": { "redacts": "$edit:localhost" },
+            },
+        },
+    })));
+
+    assert!(aggregator.is_redacted(event_id!("$edit:localhost")));
+    assert!(aggregator.latest_edit(&owned_event_id!("$original:localhost")).is_none());
+}
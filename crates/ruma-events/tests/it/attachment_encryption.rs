@@ -0,0 +1,106 @@
+use std::io::{ErrorKind, Read};
+
+use assert_matches2::assert_matches;
+use ruma_common::{
+    mxc_uri,
+    serde::{base64::Standard, Base64},
+};
+use ruma_events::room::{
+    AttachmentDecryptionError, AttachmentDecryptor, AttachmentEncryptor, EncryptedFile,
+};
+use serde_json::{from_value as from_json_value, json};
+
+fn encrypted_file() -> EncryptedFile {
+    from_json_value(json!({
+        "url": "mxc://localhost/encryptedfile",
+        "key": {
+            "kty": "oct",
+            "key_ops": ["encrypt", "decrypt"],
+            "alg": "A256CTR",
+            "k": "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8",
+            "ext": true,
+        },
+        "iv": "AQIDBAUGBwgAAAAAAAAAAA",
+        "hashes": {
+            "sha256": "AyE9lTUf4shRS0qmTU89aGrHgCChE/slFzIj4N7zrYc",
+        },
+        "v": "v2",
+    }))
+    .unwrap()
+}
+
+const CIPHERTEXT: &str = "LQcXvUjYdQThmqKJ8atI0MTWLM49Q1s";
+
+#[test]
+fn decrypt_known_attachment() {
+    let ciphertext = Base64::<Standard>::parse(CIPHERTEXT).unwrap().into_inner();
+    let mut decryptor = AttachmentDecryptor::new(&ciphertext[..], &encrypted_file()).unwrap();
+
+    let mut plaintext = Vec::new();
+    decryptor.read_to_end(&mut plaintext).unwrap();
+    assert_eq!(plaintext, b"Hello, encrypted world!");
+}
+
+#[test]
+fn encrypt_decrypt_roundtrip() {
+    let data = vec![42; 100_000];
+    let mut encryptor = AttachmentEncryptor::new(&data[..]);
+
+    let mut ciphertext = Vec::new();
+    encryptor.read_to_end(&mut ciphertext).unwrap();
+    assert_eq!(ciphertext.len(), data.len());
+    assert_ne!(ciphertext, data);
+
+    let file = encryptor.finish(mxc_uri!("mxc://localhost/encryptedfile").to_owned());
+    assert_eq!(file.v, "v2");
+    assert_eq!(file.key.alg, "A256CTR");
+    assert_eq!(file.key.k.as_bytes().len(), 32);
+    // The counter part of the IV starts at zero.
+    assert_eq!(&file.iv.as_bytes()[8..], [0; 8]);
+    assert!(file.hashes.contains_key("sha256"));
+
+    let mut decryptor = AttachmentDecryptor::new(&ciphertext[..], &file).unwrap();
+    let mut plaintext = Vec::new();
+    decryptor.read_to_end(&mut plaintext).unwrap();
+    assert_eq!(plaintext, data);
+}
+
+#[test]
+fn decrypt_hash_mismatch() {
+    let mut ciphertext = Base64::<Standard>::parse(CIPHERTEXT).unwrap().into_inner();
+    ciphertext[0] ^= 1;
+    let mut decryptor = AttachmentDecryptor::new(&ciphertext[..], &encrypted_file()).unwrap();
+
+    let error = decryptor.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    let error = error.into_inner().unwrap();
+    assert_matches!(
+        error.downcast_ref::<AttachmentDecryptionError>(),
+        Some(AttachmentDecryptionError::HashMismatch)
+    );
+}
+
+#[test]
+fn decrypt_invalid_file() {
+    let mut file = encrypted_file();
+    file.v = "v3".to_owned();
+    assert_matches!(
+        AttachmentDecryptor::new(&b""[..], &file),
+        Err(AttachmentDecryptionError::UnsupportedVersion(version))
+    );
+    assert_eq!(version, "v3");
+
+    let mut file = encrypted_file();
+    file.hashes.clear();
+    assert_matches!(
+        AttachmentDecryptor::new(&b""[..], &file),
+        Err(AttachmentDecryptionError::MissingHash)
+    );
+
+    let mut file = encrypted_file();
+    file.iv = Base64::new(vec![0; 8]);
+    assert_matches!(
+        AttachmentDecryptor::new(&b""[..], &file),
+        Err(AttachmentDecryptionError::InvalidIvLength)
+    );
+}
@@ -1,3 +1,6 @@
+mod aggregation;
+#[cfg(feature = "attachment-encryption")]
+mod attachment_encryption;
 mod audio;
 mod call;
 mod encrypted;
@@ -13,10 +16,15 @@ mod location;
 mod message;
 mod pdu;
 mod poll;
+mod push_evaluator;
 mod redacted;
 mod redaction;
 mod relations;
 mod room_message;
+#[cfg(feature = "sas-verification")]
+mod sas_verification;
+#[cfg(feature = "secret-storage-encryption")]
+mod secret_storage;
 mod state_event;
 mod sticker;
 mod stripped;
@@ -0,0 +1,175 @@
+use js_int::{int, uint};
+use ruma_common::{owned_event_id, owned_room_id, owned_user_id, push::Ruleset, serde::Raw};
+use ruma_events::push_rules::PushEvaluator;
+use serde_json::{json, Value as JsonValue};
+
+fn raw(json: JsonValue) -> Raw<JsonValue> {
+    Raw::new(&json).unwrap()
+}
+
+fn member(user_id: &str, membership: &str, displayname: Option<&str>) -> Raw<JsonValue> {
+    raw(json!({
+        "type": "m.room.member",
+        "event_id": format!("$member_{membership}_{}", &user_id[1..]),
+        "sender": user_id,
+        "state_key": user_id,
+        "origin_server_ts": 1,
+        "content": {
+            "membership": membership,
+            "displayname": displayname,
+        },
+    }))
+}
+
+fn message(event_id: &str, sender: &str, body: &str, thread_root: Option<&str>) -> Raw<JsonValue> {
+    let mut content = json!({ "msgtype": "m.text", "body": body });
+    if let Some(thread_root) = thread_root {
+        content["m.relates_to"] = json!({ "rel_type": "m.thread", "event_id": thread_root });
+    }
+
+    raw(json!({
+        "type": "m.room.message",
+        "event_id": event_id,
+        "sender": sender,
+        "origin_server_ts": 1,
+        "content": content,
+    }))
+}
+
+fn room_state() -> Vec<Raw<JsonValue>> {
+    vec![
+        raw(json!({
+            "type": "m.room.create",
+            "event_id": "$create:localhost",
+            "sender": "@alice:localhost",
+            "state_key": "",
+            "origin_server_ts": 1,
+            "content": { "room_version": "11" },
+        })),
+        member("@alice:localhost", "join", Some("Alice")),
+        member("@bob:localhost", "join", None),
+        member("@carl:localhost", "join", Some("Carl")),
+        member("@carl:localhost", "leave", Some("Carl")),
+        member("@dora:localhost", "invite", None),
+    ]
+}
+
+#[test]
+fn context_from_room_state() {
+    let ruleset = Ruleset::server_default(&owned_user_id!("@alice:localhost"));
+    let evaluator = PushEvaluator::from_room_state(
+        &ruleset,
+        owned_room_id!("!room:localhost"),
+        owned_user_id!("@alice:localhost"),
+        &room_state(),
+    );
+
+    let context = evaluator.context();
+    assert_eq!(context.member_count, uint!(2));
+    assert_eq!(context.user_display_name, "Alice");
+
+    // The creator gets the default power level of 100 without `m.room.power_levels`.
+    let power_levels = context.power_levels.as_ref().unwrap();
+    assert_eq!(power_levels.users.get(&owned_user_id!("@alice:localhost")), Some(&int!(100)));
+    assert_eq!(power_levels.users_default, int!(0));
+
+    // Without display name, the localpart is used.
+    let evaluator = PushEvaluator::from_room_state(
+        &ruleset,
+        owned_room_id!("!room:localhost"),
+        owned_user_id!("@bob:localhost"),
+        &room_state(),
+    );
+    assert_eq!(evaluator.context().user_display_name, "bob");
+}
+
+#[test]
+fn power_levels_from_room_state() {
+    let ruleset = Ruleset::server_default(&owned_user_id!("@alice:localhost"));
+    let mut state = room_state();
+    state.push(raw(json!({
+        "type": "m.room.power_levels",
+        "event_id": "$power_levels:localhost",
+        "sender": "@alice:localhost",
+        "state_key": "",
+        "origin_server_ts": 1,
+        "content": {
+            "users": { "@bob:localhost": 50 },
+            "notifications": { "room": 40 },
+        },
+    })));
+
+    let evaluator = PushEvaluator::from_room_state(
+        &ruleset,
+        owned_room_id!("!room:localhost"),
+        owned_user_id!("@alice:localhost"),
+        &state,
+    );
+
+    let power_levels = evaluator.context().power_levels.as_ref().unwrap();
+    assert_eq!(power_levels.users.get(&owned_user_id!("@alice:localhost")), None);
+    assert_eq!(power_levels.users.get(&owned_user_id!("@bob:localhost")), Some(&int!(50)));
+    assert_eq!(power_levels.notifications.room, int!(40));
+}
+
+#[test]
+fn evaluate_batch_updates_context() {
+    let ruleset = Ruleset::server_default(&owned_user_id!("@alice:localhost"));
+    let mut evaluator = PushEvaluator::from_room_state(
+        &ruleset,
+        owned_room_id!("!room:localhost"),
+        owned_user_id!("@alice:localhost"),
+        &room_state(),
+    );
+
+    let events = [
+        message("$1:localhost", "@bob:localhost", "Hello", None),
+        member("@dora:localhost", "join", None),
+        message("$2:localhost", "@bob:localhost", "Hello again", None),
+        message("$3:localhost", "@alice:localhost", "Hi", None),
+    ];
+    let actions = evaluator.evaluate_batch(&events);
+
+    assert_eq!(actions.len(), 4);
+    // In a room with 2 members, messages match `.m.rule.room_one_to_one`, which plays a sound.
+    assert!(actions[0].iter().any(|action| action.sound().is_some()));
+    // In a room with 3 members, messages match `.m.rule.message`.
+    assert!(actions[2].iter().any(|action| action.should_notify()));
+    assert!(!actions[2].iter().any(|action| action.sound().is_some()));
+    // The events of the user are ignored.
+    assert!(actions[3].is_empty());
+
+    assert_eq!(evaluator.context().member_count, uint!(3));
+}
+
+#[test]
+fn unread_counts() {
+    let ruleset = Ruleset::server_default(&owned_user_id!("@alice:localhost"));
+    let mut evaluator = PushEvaluator::from_room_state(
+        &ruleset,
+        owned_room_id!("!room:localhost"),
+        owned_user_id!("@alice:localhost"),
+        &room_state(),
+    );
+
+    let events = [
+        message("$1:localhost", "@bob:localhost", "Hello", None),
+        message("$2:localhost", "@bob:localhost", "Hello Alice", None),
+        message("$3:localhost", "@alice:localhost", "Hi Bob", None),
+        message("$4:localhost", "@bob:localhost", "In a thread", Some("$1:localhost")),
+        message("$5:localhost", "@bob:localhost", "Still in a thread, Alice", Some("$1:localhost")),
+    ];
+    let counts = evaluator.unread_counts(&events);
+
+    assert_eq!(counts.main.notifications, uint!(2));
+    assert_eq!(counts.main.highlights, uint!(1));
+
+    assert_eq!(counts.threads.len(), 1);
+    let thread_counts = counts.threads[&owned_event_id!("$1:localhost")];
+    assert_eq!(thread_counts.notifications, uint!(2));
+    assert_eq!(thread_counts.highlights, uint!(1));
+
+    let total = counts.total();
+    assert_eq!(total.notifications, uint!(4));
+    assert_eq!(total.highlights, uint!(2));
+}
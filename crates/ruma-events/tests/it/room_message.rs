@@ -1258,3 +1258,44 @@ fn invalid_replacement() {
     assert_matches!(&data, Cow::Borrowed(_)); // data is stored in JSON form because it's invalid
     assert_eq!(JsonValue::Object(data.into_owned()), relation);
 }
+
+#[test]
+#[cfg(feature = "html")]
+fn formatted_body_mentions() {
+    use ruma_events::room::message::FormattedBody;
+
+    let formatted = FormattedBody::html(
+        "<mx-reply><blockquote>\
+            <a href=\"https://matrix.to/#/@carl:example.org\">@carl:example.org</a> said\
+        </blockquote></mx-reply>\
+        Hey <a href=\"https://matrix.to/#/@alice:example.org\">Alice</a> and \
+        <a href=\"matrix:u/bob:example.org\">Bob</a>, look at \
+        <a href=\"https://matrix.to/#/#room:example.org\">this room</a>",
+    );
+    let mentions = formatted.mentions();
+    assert_eq!(
+        mentions.user_ids,
+        BTreeSet::from([owned_user_id!("@alice:example.org"), owned_user_id!("@bob:example.org")])
+    );
+    assert!(!mentions.room);
+
+    let formatted = FormattedBody::html("<p>Hey @room, look!</p>");
+    let mentions = formatted.mentions();
+    assert!(mentions.user_ids.is_empty());
+    assert!(mentions.room);
+
+    let formatted = FormattedBody::html("<p>Not a @roommate</p>");
+    assert!(!formatted.mentions().room);
+
+    let formatted = FormattedBody::html("<p>Ask @room:example.org</p>");
+    assert!(!formatted.mentions().room);
+
+    let formatted = FormattedBody::html("<p>Contact support@room.example.org</p>");
+    assert!(!formatted.mentions().room);
+
+    let formatted = FormattedBody::html("<p>(@room) @room: look!</p>");
+    assert!(formatted.mentions().room);
+
+    let formatted = FormattedBody::html("<p>Hey @room:example.org and @room</p>");
+    assert!(formatted.mentions().room);
+}
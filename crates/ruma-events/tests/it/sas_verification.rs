@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+
+use assert_matches2::assert_matches;
+use ruma_common::{owned_event_id, serde::Base64, user_id, OwnedTransactionId};
+use ruma_events::{
+    key::verification::{
+        accept::AcceptMethod,
+        cancel::CancelCode,
+        sas::{
+            FlowId, SasIdentity, SasState, SasVerification, VerificationContent,
+            VerificationEventContent, VerificationMessage,
+        },
+        start::{ReciprocateV1Content, SasV1ContentInit, StartMethod},
+    },
+    AnyMessageLikeEventContent,
+};
+
+const ALICE_KEY: &str = "aliceKey";
+const BOB_KEY: &str = "bobKey";
+
+fn alice() -> SasIdentity {
+    SasIdentity::new(
+        user_id!("@alice:localhost").to_owned(),
+        "ALICEDEVICE".into(),
+        BTreeMap::from([("ed25519:ALICEDEVICE".to_owned(), ALICE_KEY.to_owned())]),
+    )
+}
+
+fn bob() -> SasIdentity {
+    SasIdentity::new(
+        user_id!("@bob:localhost").to_owned(),
+        "BOBDEVICE".into(),
+        BTreeMap::from([("ed25519:BOBDEVICE".to_owned(), BOB_KEY.to_owned())]),
+    )
+}
+
+fn flow_id() -> FlowId {
+    FlowId::ToDevice(OwnedTransactionId::from("txn"))
+}
+
+/// Create the verifications of Alice and Bob, with fixed keys.
+fn verifications(flow_id: FlowId) -> (SasVerification, SasVerification) {
+    let alice_sas = SasVerification::new(alice(), bob(), flow_id.clone()).with_secret_key([1; 32]);
+    let bob_sas = SasVerification::new(bob(), alice(), flow_id).with_secret_key([2; 32]);
+    (alice_sas, bob_sas)
+}
+
+/// Exchange the messages until the short authentication string is available.
+fn exchange_keys(alice_sas: &mut SasVerification, bob_sas: &mut SasVerification) {
+    let start = alice_sas.start().unwrap();
+    assert!(bob_sas.receive(user_id!("@alice:localhost"), start).is_empty());
+    assert_eq!(*bob_sas.state(), SasState::StartReceived);
+
+    let accept = bob_sas.accept().unwrap();
+    let mut messages = alice_sas.receive(user_id!("@bob:localhost"), accept);
+    assert_eq!(messages.len(), 1);
+
+    let mut messages = bob_sas.receive(user_id!("@alice:localhost"), messages.remove(0));
+    assert_eq!(messages.len(), 1);
+
+    assert!(alice_sas.receive(user_id!("@bob:localhost"), messages.remove(0)).is_empty());
+    assert_eq!(*alice_sas.state(), SasState::KeysExchanged);
+    assert_eq!(*bob_sas.state(), SasState::KeysExchanged);
+}
+
+#[test]
+fn to_device_verification() {
+    let (mut alice_sas, mut bob_sas) = verifications(flow_id());
+    assert_eq!(alice_sas.public_key().encode(), "pOCSkrZRwni5dyxWn1+puxPZBrRqtoyd+dwrRAn4ogk");
+    assert_eq!(bob_sas.public_key().encode(), "zo060cy2M+x7cMF4FKXHbs0CloUFDTRHRboFhw5YfVk");
+
+    exchange_keys(&mut alice_sas, &mut bob_sas);
+
+    let sas = alice_sas.short_auth_string().unwrap();
+    assert_eq!(sas, bob_sas.short_auth_string().unwrap());
+    assert_eq!(sas.decimal, [1400, 3468, 4700]);
+    let emoji = sas.emoji.unwrap().map(|emoji| emoji.description);
+    assert_eq!(emoji, ["Horse", "Panda", "Rooster", "Light Bulb", "Rabbit", "Butterfly", "Hat"]);
+
+    // Bob confirms first, Alice stores the MAC until she confirms.
+    let mut bob_messages = bob_sas.confirm();
+    assert_eq!(bob_messages.len(), 1);
+    assert_eq!(*bob_sas.state(), SasState::Confirmed);
+    assert!(alice_sas.receive(user_id!("@bob:localhost"), bob_messages.remove(0)).is_empty());
+
+    let mut alice_messages = alice_sas.confirm();
+    assert_eq!(alice_messages.len(), 2);
+    assert_eq!(*alice_sas.state(), SasState::Verified);
+
+    let mac_message = alice_messages.remove(0);
+    assert_matches!(&mac_message.content, VerificationContent::Mac { mac, keys });
+    assert_eq!(mac["ed25519:ALICEDEVICE"].encode(), "dot4ShOVRri/wOsRUMP6sTZUFVga9ZXBQhOlmogZzwY");
+    assert_eq!(keys.encode(), "iFu+ZshMArw3psUTkgnc/KZTU6as0Mhxc7idV55d/jI");
+
+    let mut bob_messages = bob_sas.receive(user_id!("@alice:localhost"), mac_message);
+    assert_eq!(bob_messages.len(), 1);
+    assert_eq!(*bob_sas.state(), SasState::Verified);
+
+    assert!(bob_sas.receive(user_id!("@alice:localhost"), alice_messages.remove(0)).is_empty());
+    assert!(alice_sas.receive(user_id!("@bob:localhost"), bob_messages.remove(0)).is_empty());
+    assert_eq!(*alice_sas.state(), SasState::Done);
+    assert_eq!(*bob_sas.state(), SasState::Done);
+}
+
+#[test]
+fn in_room_verification() {
+    let flow_id = FlowId::InRoom(owned_event_id!("$request:localhost"));
+    let (mut alice_sas, mut bob_sas) = verifications(flow_id.clone());
+
+    // Send the messages through their event content.
+    let through_room = |message: VerificationMessage| -> VerificationMessage {
+        assert_matches!(
+            message.into_event_content(),
+            VerificationEventContent::MessageLike(content)
+        );
+        match content {
+            AnyMessageLikeEventContent::KeyVerificationStart(content) => content.into(),
+            AnyMessageLikeEventContent::KeyVerificationAccept(content) => content.into(),
+            AnyMessageLikeEventContent::KeyVerificationKey(content) => content.into(),
+            AnyMessageLikeEventContent::KeyVerificationMac(content) => content.into(),
+            AnyMessageLikeEventContent::KeyVerificationDone(content) => content.into(),
+            content => panic!("unexpected content: {content:?}"),
+        }
+    };
+
+    let start = through_room(alice_sas.start().unwrap());
+    assert_eq!(start.flow_id, flow_id);
+    bob_sas.receive(user_id!("@alice:localhost"), start);
+    let accept = through_room(bob_sas.accept().unwrap());
+    let key = through_room(alice_sas.receive(user_id!("@bob:localhost"), accept).remove(0));
+    let key = through_room(bob_sas.receive(user_id!("@alice:localhost"), key).remove(0));
+    alice_sas.receive(user_id!("@bob:localhost"), key);
+
+    assert_eq!(alice_sas.short_auth_string().unwrap(), bob_sas.short_auth_string().unwrap());
+
+    let mac = through_room(alice_sas.confirm().remove(0));
+    bob_sas.receive(user_id!("@alice:localhost"), mac);
+    let messages = bob_sas.confirm();
+    assert_eq!(messages.len(), 2);
+
+    for message in messages {
+        alice_sas.receive(user_id!("@bob:localhost"), through_room(message));
+    }
+    assert_eq!(*alice_sas.state(), SasState::Done);
+}
+
+#[test]
+fn mismatched_commitment() {
+    let (mut alice_sas, mut bob_sas) = verifications(flow_id());
+
+    bob_sas.receive(user_id!("@alice:localhost"), alice_sas.start().unwrap());
+    let mut accept = bob_sas.accept().unwrap();
+    assert_matches!(&mut accept.content, VerificationContent::Accept(AcceptMethod::SasV1(content)));
+    content.commitment = Base64::new(vec![0; 32]);
+
+    let key = alice_sas.receive(user_id!("@bob:localhost"), accept).remove(0);
+    let key = bob_sas.receive(user_id!("@alice:localhost"), key).remove(0);
+
+    let messages = alice_sas.receive(user_id!("@bob:localhost"), key);
+    assert_matches!(
+        &messages[0].content,
+        VerificationContent::Cancel { code: CancelCode::MismatchedCommitment, .. }
+    );
+    assert_matches!(alice_sas.state(), SasState::Cancelled(cancellation));
+    assert!(cancellation.cancelled_by_us);
+}
+
+#[test]
+fn mismatched_keys() {
+    let flow_id = flow_id();
+    let mut other_bob = bob();
+    other_bob.keys.insert("ed25519:BOBDEVICE".to_owned(), "otherKey".to_owned());
+
+    let mut alice_sas =
+        SasVerification::new(alice(), other_bob, flow_id.clone()).with_secret_key([1; 32]);
+    let mut bob_sas = SasVerification::new(bob(), alice(), flow_id).with_secret_key([2; 32]);
+    exchange_keys(&mut alice_sas, &mut bob_sas);
+
+    let mac = bob_sas.confirm().remove(0);
+    alice_sas.receive(user_id!("@bob:localhost"), mac);
+
+    let messages = alice_sas.confirm();
+    assert_eq!(messages.len(), 1);
+    assert_matches!(
+        &messages[0].content,
+        VerificationContent::Cancel { code: CancelCode::KeyMismatch, .. }
+    );
+
+    // The cancellation is forwarded to the other party.
+    bob_sas.receive(user_id!("@alice:localhost"), messages[0].clone());
+    assert_matches!(bob_sas.state(), SasState::Cancelled(cancellation));
+    assert_eq!(cancellation.code, CancelCode::KeyMismatch);
+    assert!(!cancellation.cancelled_by_us);
+}
+
+#[test]
+fn unexpected_messages() {
+    let (mut alice_sas, mut bob_sas) = verifications(flow_id());
+
+    // The key is sent before the verification was accepted.
+    bob_sas.receive(user_id!("@alice:localhost"), alice_sas.start().unwrap());
+    let key = VerificationMessage::new(flow_id(), VerificationContent::Key(alice_sas.public_key()));
+    let messages = bob_sas.receive(user_id!("@alice:localhost"), key);
+    assert_matches!(
+        &messages[0].content,
+        VerificationContent::Cancel { code: CancelCode::UnexpectedMessage, .. }
+    );
+
+    // Messages are ignored once the verification is cancelled.
+    assert!(bob_sas.accept().is_none());
+    let done = VerificationMessage::new(flow_id(), VerificationContent::Done);
+    assert!(bob_sas.receive(user_id!("@alice:localhost"), done).is_empty());
+}
+
+#[test]
+fn unknown_transaction() {
+    let (mut alice_sas, mut bob_sas) = verifications(flow_id());
+    let start = alice_sas.start().unwrap();
+    let other_flow_id = FlowId::ToDevice("other_txn".into());
+
+    let messages = bob_sas.receive(
+        user_id!("@alice:localhost"),
+        VerificationMessage::new(other_flow_id.clone(), start.content),
+    );
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].flow_id, other_flow_id);
+    assert_matches!(
+        &messages[0].content,
+        VerificationContent::Cancel { code: CancelCode::UnknownTransaction, .. }
+    );
+    assert_eq!(*bob_sas.state(), SasState::Created);
+
+    // Messages of other users are ignored.
+    let start = VerificationMessage::new(
+        flow_id(),
+        VerificationContent::Start {
+            from_device: "ALICEDEVICE".into(),
+            method: StartMethod::SasV1(
+                SasV1ContentInit {
+                    key_agreement_protocols: vec![],
+                    hashes: vec![],
+                    message_authentication_codes: vec![],
+                    short_authentication_string: vec![],
+                }
+                .into(),
+            ),
+        },
+    );
+    assert!(bob_sas.receive(user_id!("@mallory:localhost"), start).is_empty());
+    assert_eq!(*bob_sas.state(), SasState::Created);
+}
+
+#[test]
+fn unknown_method() {
+    let (_, mut bob_sas) = verifications(flow_id());
+
+    let start = VerificationMessage::new(
+        flow_id(),
+        VerificationContent::Start {
+            from_device: "ALICEDEVICE".into(),
+            method: StartMethod::ReciprocateV1(ReciprocateV1Content::new(Base64::new(vec![0; 32]))),
+        },
+    );
+    let messages = bob_sas.receive(user_id!("@alice:localhost"), start);
+    assert_matches!(
+        &messages[0].content,
+        VerificationContent::Cancel { code: CancelCode::UnknownMethod, .. }
+    );
+}
+
+#[test]
+fn simultaneous_start() {
+    let (mut alice_sas, mut bob_sas) = verifications(flow_id());
+    let alice_start = alice_sas.start().unwrap();
+    let bob_start = bob_sas.start().unwrap();
+
+    // The start of Alice is used, because her user ID is lower.
+    assert!(alice_sas.receive(user_id!("@bob:localhost"), bob_start).is_empty());
+    assert_eq!(*alice_sas.state(), SasState::Started);
+    assert!(alice_sas.we_started());
+
+    assert!(bob_sas.receive(user_id!("@alice:localhost"), alice_start).is_empty());
+    assert_eq!(*bob_sas.state(), SasState::StartReceived);
+    assert!(!bob_sas.we_started());
+
+    let accept = bob_sas.accept().unwrap();
+    assert_eq!(alice_sas.receive(user_id!("@bob:localhost"), accept).len(), 1);
+    assert_eq!(*alice_sas.state(), SasState::Accepted);
+}
+
+#[test]
+fn mismatched_sas() {
+    let (mut alice_sas, mut bob_sas) = verifications(flow_id());
+    exchange_keys(&mut alice_sas, &mut bob_sas);
+
+    let cancel = alice_sas.mismatch().unwrap();
+    assert_matches!(
+        &cancel.content,
+        VerificationContent::Cancel { code: CancelCode::MismatchedSas, .. }
+    );
+    assert!(alice_sas.short_auth_string().is_some());
+    assert!(alice_sas.confirm().is_empty());
+    assert!(alice_sas.mismatch().is_none());
+}
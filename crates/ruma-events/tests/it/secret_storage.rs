@@ -0,0 +1,130 @@
+use assert_matches2::assert_matches;
+use js_int::uint;
+use ruma_common::serde::Base64;
+use ruma_events::secret_storage::{
+    key::{
+        PassPhrase, SecretStorageEncryptionAlgorithm, SecretStorageKeyEventContent,
+        SecretStorageV1AesHmacSha2Properties,
+    },
+    secret::SecretEncryptedData,
+    SecretStorageError, SecretStorageKey,
+};
+
+const RECOVERY_KEY: &str = "EsSz ykH7 LCZx 7Cae cmKD wcmY JRXi Ybtu 8iQ3 t8Ez nRwK pUY1";
+
+fn key_bytes() -> [u8; 32] {
+    std::array::from_fn(|i| i as u8)
+}
+
+fn key_description(iv: &str, mac: &str) -> SecretStorageKeyEventContent {
+    SecretStorageKeyEventContent::new(
+        "my_key".to_owned(),
+        SecretStorageEncryptionAlgorithm::V1AesHmacSha2(SecretStorageV1AesHmacSha2Properties::new(
+            Some(Base64::parse(iv).unwrap()),
+            Some(Base64::parse(mac).unwrap()),
+        )),
+    )
+}
+
+#[test]
+fn recovery_key_roundtrip() {
+    let key = SecretStorageKey::from_bytes("my_key".to_owned(), key_bytes());
+    assert_eq!(key.to_recovery_key(), RECOVERY_KEY);
+
+    let key = SecretStorageKey::from_recovery_key("my_key".to_owned(), RECOVERY_KEY).unwrap();
+    assert_eq!(key.as_bytes(), &key_bytes());
+
+    // Whitespace is ignored.
+    let key =
+        SecretStorageKey::from_recovery_key("my_key".to_owned(), &RECOVERY_KEY.replace(' ', ""))
+            .unwrap();
+    assert_eq!(key.as_bytes(), &key_bytes());
+}
+
+#[test]
+fn invalid_recovery_key() {
+    // Invalid parity byte.
+    let recovery_key = RECOVERY_KEY.replace("pUY1", "pUY2");
+    assert_matches!(
+        SecretStorageKey::from_recovery_key("my_key".to_owned(), &recovery_key),
+        Err(SecretStorageError::InvalidRecoveryKey)
+    );
+
+    // Invalid base58.
+    assert_matches!(
+        SecretStorageKey::from_recovery_key("my_key".to_owned(), "EsSz 0OIl"),
+        Err(SecretStorageError::InvalidRecoveryKey)
+    );
+}
+
+#[test]
+fn check_key() {
+    let description =
+        key_description("AAECAwQFBgcICQoLDA0ODw", "ONrOSgDDUXMzIvXsfYBi1m8m075MdjPldfXCxIpU7IY");
+
+    let key = description.key_from_recovery_key(RECOVERY_KEY).unwrap();
+    assert_eq!(key.key_id(), "my_key");
+
+    let other_key = SecretStorageKey::new("my_key".to_owned());
+    assert_matches!(other_key.check(&description), Err(SecretStorageError::KeyMismatch));
+
+    // A new key description can be used to check the key.
+    let description = other_key.event_content();
+    other_key.check(&description).unwrap();
+    assert_matches!(key.check(&description), Err(SecretStorageError::KeyMismatch));
+}
+
+#[test]
+fn key_from_passphrase() {
+    let mut description =
+        key_description("AAECAwQFBgcICQoLDA0ODw", "npA9FbLS3+fxyv+p7xLuSfd2ymbVUd0sYOCCtGigaiI");
+
+    assert_matches!(
+        description.key_from_passphrase("correct horse battery staple"),
+        Err(SecretStorageError::MissingPassPhrase)
+    );
+
+    description.passphrase = Some(PassPhrase::new("saltysalt".to_owned(), uint!(10)));
+    description.key_from_passphrase("correct horse battery staple").unwrap();
+
+    assert_matches!(
+        description.key_from_passphrase("incorrect horse battery staple"),
+        Err(SecretStorageError::KeyMismatch)
+    );
+}
+
+#[test]
+fn decrypt_known_secret() {
+    let key = SecretStorageKey::from_bytes("my_key".to_owned(), key_bytes());
+    let data = SecretEncryptedData::AesHmacSha2EncryptedData {
+        iv: Base64::parse("AAECAwQFBgcICQoLDA0ODw").unwrap(),
+        ciphertext: Base64::parse("JamlhFZ/0Ikx").unwrap(),
+        mac: Base64::parse("qhRqMRMSgD2rhTTPQH1v+etfA32hQM2gz6Fwdrewsgo").unwrap(),
+    };
+
+    assert_eq!(key.decrypt_secret(&data, "m.cross_signing.master").unwrap(), "my secret");
+
+    // The name of the secret is used to derive the keys.
+    assert_matches!(
+        key.decrypt_secret(&data, "m.cross_signing.self_signing"),
+        Err(SecretStorageError::MacMismatch)
+    );
+}
+
+#[test]
+fn encrypt_decrypt_secret() {
+    let key = SecretStorageKey::new("my_key".to_owned());
+
+    let data = key.encrypt_secret("my secret", "m.megolm_backup.v1");
+    assert_matches!(&data, SecretEncryptedData::AesHmacSha2EncryptedData { iv, .. });
+    // Bit 63 of the IV is cleared.
+    assert_eq!(iv.as_bytes()[8] & 0x80, 0);
+
+    assert_eq!(key.decrypt_secret(&data, "m.megolm_backup.v1").unwrap(), "my secret");
+
+    let other_key = SecretStorageKey::new("my_key".to_owned());
+    assert_matches!(
+        other_key.decrypt_secret(&data, "m.megolm_backup.v1"),
+        Err(SecretStorageError::MacMismatch)
+    );
+}
@@ -0,0 +1,535 @@
+//! Conversion of HTML to plain text and Markdown.
+
+use crate::{html::NodeData, Html};
+
+impl Html {
+    /// Convert this HTML to plain text.
+    ///
+    /// This is meant to generate the plain text `body` of a message from its `formatted_body`, or
+    /// to render it on networks that don't support HTML:
+    ///
+    /// * Paragraphs, headings, code blocks and other block elements are separated by blank lines,
+    /// * List items are prefixed with `- `, or with their number for ordered lists,
+    /// * Lines of blockquotes are prefixed with `> `,
+    /// * Links are rendered as `text <url>`, or only as the URL if it is the same as the text,
+    /// * The [rich reply fallback] is removed.
+    ///
+    /// [rich reply fallback]: https://spec.matrix.org/latest/client-server-api/#fallbacks-for-rich-replies
+    pub fn to_plain_text(&self) -> String {
+        TextWriter::new(TextFormat::PlainText).write_html(self)
+    }
+
+    /// Convert this HTML to [CommonMark] Markdown.
+    ///
+    /// Inline formatting, links, images, headings, lists, blockquotes, code blocks and tables are
+    /// converted to their Markdown syntax, and characters that have a special meaning in Markdown
+    /// are escaped in text. Other elements are rendered like with [`Html::to_plain_text()`].
+    ///
+    /// The [rich reply fallback] is removed.
+    ///
+    /// [CommonMark]: https://commonmark.org/
+    /// [rich reply fallback]: https://spec.matrix.org/latest/client-server-api/#fallbacks-for-rich-replies
+    pub fn to_markdown(&self) -> String {
+        TextWriter::new(TextFormat::Markdown).write_html(self)
+    }
+}
+
+/// The text format to convert HTML to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TextFormat {
+    /// Plain text.
+    PlainText,
+
+    /// CommonMark Markdown.
+    Markdown,
+}
+
+/// Helper to write the text representation of an `Html`.
+struct TextWriter {
+    /// The format of the text.
+    format: TextFormat,
+
+    /// The text written so far.
+    out: String,
+
+    /// The prefixes of the current lines, for blockquotes and list items.
+    prefixes: Vec<String>,
+
+    /// The number of newlines to write before the next text.
+    pending_newlines: usize,
+
+    /// Whether a space should be written before the next text.
+    pending_space: bool,
+
+    /// Whether nothing was written on the current line yet.
+    at_line_start: bool,
+
+    /// Whether a list item marker was just written, in which case block separators are ignored
+    /// until the content of the list item is written.
+    after_marker: bool,
+
+    /// The depth of the current list.
+    list_depth: usize,
+
+    /// The number of rows of the current table that were written.
+    table_rows: usize,
+}
+
+impl TextWriter {
+    fn new(format: TextFormat) -> Self {
+        Self {
+            format,
+            out: String::new(),
+            prefixes: Vec::new(),
+            pending_newlines: 0,
+            pending_space: false,
+            at_line_start: true,
+            after_marker: false,
+            list_depth: 0,
+            table_rows: 0,
+        }
+    }
+
+    fn is_markdown(&self) -> bool {
+        self.format == TextFormat::Markdown
+    }
+
+    /// Write the given `Html` and return the resulting text.
+    fn write_html(mut self, html: &Html) -> String {
+        if let Some(root) = html.nodes[0].first_child {
+            self.write_children(html, root);
+        }
+
+        self.out
+    }
+
+    /// Write the given text, flushing the pending newlines, prefixes and space first.
+    ///
+    /// `text` must not contain newlines.
+    fn write_str(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+
+        if self.pending_newlines > 0 {
+            if !self.out.is_empty() {
+                for i in 0..self.pending_newlines {
+                    self.out.push('\n');
+
+                    if i + 1 < self.pending_newlines {
+                        let prefix = self.prefixes.concat();
+                        self.out.push_str(prefix.trim_end());
+                    }
+                }
+                self.at_line_start = true;
+            }
+
+            self.pending_newlines = 0;
+        }
+
+        if self.at_line_start {
+            self.out.extend(self.prefixes.iter().map(String::as_str));
+            self.at_line_start = false;
+        } else if self.pending_space {
+            self.out.push(' ');
+        }
+
+        self.pending_space = false;
+        self.after_marker = false;
+        self.out.push_str(text);
+    }
+
+    /// Write the given text, keeping the pending space for the next text.
+    ///
+    /// This is used for closing delimiters of inline elements.
+    fn write_closing_str(&mut self, text: &str) {
+        let pending_space = self.pending_space;
+        self.pending_space = false;
+        self.write_str(text);
+        self.pending_space = pending_space;
+    }
+
+    /// Write a space before the next text, unless it is at the start of a line.
+    fn space(&mut self) {
+        if !self.at_line_start && !self.after_marker && self.pending_newlines == 0 {
+            self.pending_space = true;
+        }
+    }
+
+    /// Start a new line.
+    fn newline(&mut self) {
+        self.pending_newlines += 1;
+        self.pending_space = false;
+    }
+
+    /// Separate the next text from the previous block with the given number of newlines.
+    fn block_break(&mut self, newlines: usize) {
+        if self.out.is_empty() || self.after_marker {
+            return;
+        }
+
+        self.pending_newlines = self.pending_newlines.max(newlines);
+        self.pending_space = false;
+    }
+
+    /// Write a list item marker and indent the following lines accordingly.
+    fn start_list_item(&mut self, marker: &str) {
+        self.block_break(1);
+        self.write_str(marker);
+        self.prefixes.push(" ".repeat(marker.len() + 1));
+        self.pending_space = true;
+        self.after_marker = true;
+    }
+
+    /// Write preformatted text, keeping all whitespace.
+    fn write_preformatted(&mut self, text: &str) {
+        let mut lines = text.strip_suffix('\n').unwrap_or(text).split('\n');
+
+        if let Some(line) = lines.next() {
+            self.write_str(line);
+        }
+        for line in lines {
+            self.newline();
+            self.write_str(line);
+        }
+    }
+
+    /// Whether the next text will be the first text of a line or of a list item.
+    fn at_block_start(&self) -> bool {
+        self.at_line_start || self.after_marker || self.pending_newlines > 0
+    }
+
+    /// Write a text node, collapsing whitespace.
+    fn write_text(&mut self, text: &str) {
+        if text.starts_with(|c: char| c.is_ascii_whitespace()) {
+            self.space();
+        }
+
+        let mut words = text.split_ascii_whitespace().peekable();
+        let has_words = words.peek().is_some();
+
+        while let Some(word) = words.next() {
+            if self.is_markdown() {
+                let mut escaped = escape_markdown(word);
+                if self.at_block_start() {
+                    escaped = escape_markdown_block_start(&escaped);
+                }
+                self.write_str(&escaped);
+            } else {
+                self.write_str(word);
+            }
+
+            if words.peek().is_some() {
+                self.pending_space = true;
+            }
+        }
+
+        if has_words && text.ends_with(|c: char| c.is_ascii_whitespace()) {
+            self.space();
+        }
+    }
+
+    fn write_children(&mut self, html: &Html, node_id: usize) {
+        let mut next_child = html.nodes[node_id].first_child;
+        while let Some(child) = next_child {
+            self.write_node(html, child);
+            next_child = html.nodes[child].next_sibling;
+        }
+    }
+
+    fn write_node(&mut self, html: &Html, node_id: usize) {
+        let node = &html.nodes[node_id];
+
+        let element = match &node.data {
+            NodeData::Text(text) => {
+                self.write_text(text);
+                return;
+            }
+            NodeData::Element(element) => element,
+            NodeData::Document => {
+                self.write_children(html, node_id);
+                return;
+            }
+            NodeData::Other => return,
+        };
+
+        let attr = |name: &str| element.attribute(name);
+
+        match &*element.name.local {
+            "mx-reply" | "script" | "style" | "head" | "title" => {}
+            "br" => self.newline(),
+            "p" | "div" | "details" | "summary" | "caption" => {
+                let newlines = if &*element.name.local == "p" { 2 } else { 1 };
+                self.block_break(newlines);
+                self.write_children(html, node_id);
+                self.block_break(newlines);
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.block_break(2);
+                if self.is_markdown() {
+                    let level = element.name.local[1..].parse().unwrap_or(1);
+                    self.write_str(&"#".repeat(level));
+                    self.pending_space = true;
+                }
+                self.write_children(html, node_id);
+                self.block_break(2);
+            }
+            "hr" => {
+                self.block_break(2);
+                self.write_str("---");
+                self.block_break(2);
+            }
+            "blockquote" => {
+                self.block_break(2);
+                self.prefixes.push("> ".to_owned());
+                self.write_children(html, node_id);
+                self.prefixes.pop();
+                self.block_break(2);
+            }
+            "pre" => {
+                let code = text_content(html, node_id);
+
+                self.block_break(2);
+                if self.is_markdown() {
+                    let language = html.nodes[node_id]
+                        .first_child
+                        .and_then(|child| html.nodes[child].as_element())
+                        .filter(|child| &*child.name.local == "code")
+                        .and_then(|child| {
+                            child.attrs.iter().find(|attr| &*attr.name.local == "class")
+                        })
+                        .and_then(|class| {
+                            class.value.split(' ').find_map(|c| c.strip_prefix("language-"))
+                        })
+                        .unwrap_or_default();
+                    let fence = "`".repeat(longest_backtick_run(&code).max(2) + 1);
+
+                    self.write_str(&format!("{fence}{language}"));
+                    self.newline();
+                    self.write_preformatted(&code);
+                    self.newline();
+                    self.write_str(&fence);
+                } else {
+                    self.write_preformatted(&code);
+                }
+                self.block_break(2);
+            }
+            "code" => {
+                let code = text_content(html, node_id).replace('\n', " ");
+
+                if self.is_markdown() {
+                    let fence = "`".repeat(longest_backtick_run(&code) + 1);
+                    let padding =
+                        if code.starts_with('`') || code.ends_with('`') { " " } else { "" };
+                    self.write_str(&format!("{fence}{padding}{code}{padding}{fence}"));
+                } else {
+                    self.write_str(&code);
+                }
+            }
+            "ul" | "ol" => {
+                let newlines = if self.list_depth == 0 { 2 } else { 1 };
+                let mut number: u64 =
+                    attr("start").and_then(|start| start.parse().ok()).unwrap_or(1);
+                let is_ordered = &*element.name.local == "ol";
+
+                self.block_break(newlines);
+                self.list_depth += 1;
+
+                let mut next_child = node.first_child;
+                while let Some(child) = next_child {
+                    let is_item = html.nodes[child]
+                        .as_element()
+                        .is_some_and(|element| &*element.name.local == "li");
+
+                    if is_item {
+                        let marker = if is_ordered { format!("{number}.") } else { "-".to_owned() };
+                        number += 1;
+
+                        self.start_list_item(&marker);
+                        self.write_children(html, child);
+                        self.prefixes.pop();
+                        self.after_marker = false;
+                        self.block_break(1);
+                    } else {
+                        self.write_node(html, child);
+                    }
+
+                    next_child = html.nodes[child].next_sibling;
+                }
+
+                self.list_depth -= 1;
+                self.block_break(newlines);
+            }
+            "a" => {
+                let Some(href) = attr("href") else {
+                    self.write_children(html, node_id);
+                    return;
+                };
+
+                let text = text_content(html, node_id);
+                let text = text.trim();
+                let is_autolink = text == href || href.strip_prefix("mailto:") == Some(text);
+
+                match self.format {
+                    TextFormat::PlainText if is_autolink => self.write_str(href),
+                    TextFormat::PlainText => {
+                        self.write_children(html, node_id);
+                        self.pending_space = true;
+                        self.write_str(&format!("<{href}>"));
+                    }
+                    TextFormat::Markdown if is_autolink => self.write_str(&format!("<{href}>")),
+                    TextFormat::Markdown => {
+                        self.write_str("[");
+                        self.write_children(html, node_id);
+                        self.write_closing_str(&format!("]({})", escape_link_destination(href)));
+                    }
+                }
+            }
+            "img" => {
+                let alt = attr("alt").or_else(|| attr("title")).unwrap_or_default();
+
+                match (self.format, attr("src")) {
+                    (TextFormat::Markdown, Some(src)) => {
+                        self.write_str(&format!(
+                            "![{}]({})",
+                            escape_markdown(alt),
+                            escape_link_destination(src)
+                        ));
+                    }
+                    _ => self.write_text(alt),
+                }
+            }
+            "strong" | "b" | "em" | "i" | "del" | "s" | "strike" if self.is_markdown() => {
+                let delimiter = match &*element.name.local {
+                    "strong" | "b" => "**",
+                    "em" | "i" => "*",
+                    _ => "~~",
+                };
+
+                self.write_str(delimiter);
+                self.write_children(html, node_id);
+                self.write_closing_str(delimiter);
+            }
+            "table" => {
+                let table_rows = std::mem::take(&mut self.table_rows);
+
+                self.block_break(2);
+                self.write_children(html, node_id);
+                self.block_break(2);
+
+                self.table_rows = table_rows;
+            }
+            "tr" => self.write_table_row(html, node_id),
+            _ => self.write_children(html, node_id),
+        }
+    }
+
+    /// Write a row of a table.
+    ///
+    /// In Markdown, the first row is considered to be the header of the table.
+    fn write_table_row(&mut self, html: &Html, node_id: usize) {
+        let is_first_row = self.table_rows == 0;
+        self.table_rows += 1;
+
+        self.block_break(1);
+
+        let mut columns = 0;
+        let mut next_child = html.nodes[node_id].first_child;
+        while let Some(child) = next_child {
+            let is_cell = html.nodes[child]
+                .as_element()
+                .is_some_and(|element| matches!(&*element.name.local, "td" | "th"));
+
+            if is_cell {
+                if self.is_markdown() || columns > 0 {
+                    self.write_str("|");
+                    self.pending_space = true;
+                }
+                columns += 1;
+            }
+
+            self.write_children(html, child);
+            self.pending_space = true;
+            next_child = html.nodes[child].next_sibling;
+        }
+
+        if self.is_markdown() {
+            self.write_str("|");
+
+            if is_first_row && columns > 0 {
+                self.newline();
+                self.write_str(&"| --- ".repeat(columns));
+                self.write_closing_str("|");
+            }
+        }
+
+        self.block_break(1);
+    }
+}
+
+/// Get the concatenated text of the descendants of the given node.
+pub(crate) fn text_content(html: &Html, node_id: usize) -> String {
+    fn push_text_content(html: &Html, node_id: usize, text: &mut String) {
+        let node = &html.nodes[node_id];
+
+        match &node.data {
+            NodeData::Text(t) => text.push_str(t),
+            NodeData::Element(element) if &*element.name.local == "br" => text.push('\n'),
+            NodeData::Element(element) if &*element.name.local == "mx-reply" => {}
+            _ => {
+                let mut next_child = node.first_child;
+                while let Some(child) = next_child {
+                    push_text_content(html, child, text);
+                    next_child = html.nodes[child].next_sibling;
+                }
+            }
+        }
+    }
+
+    let mut text = String::new();
+    push_text_content(html, node_id, &mut text);
+    text
+}
+
+/// Get the length of the longest run of backticks in the given string.
+fn longest_backtick_run(s: &str) -> usize {
+    s.split(|c| c != '`').map(str::len).max().unwrap_or_default()
+}
+
+/// Escape the characters that have a special meaning in Markdown.
+fn escape_markdown(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '~' | '|' | '&') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Escape the block markers at the start of the given text, which must already be escaped with
+/// [`escape_markdown()`] and be the first text of a line.
+///
+/// This prevents the text from being interpreted as a heading, a list item, a thematic break or a
+/// setext heading underline.
+fn escape_markdown_block_start(s: &str) -> String {
+    if s.starts_with(['#', '-', '+', '=']) {
+        return format!("\\{s}");
+    }
+
+    // Ordered list items start with up to 9 digits followed by `.` or `)`.
+    let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+    if (1..=9).contains(&digits) && s[digits..].starts_with(['.', ')']) {
+        return format!("{}\\{}", &s[..digits], &s[digits..]);
+    }
+
+    s.to_owned()
+}
+
+/// Escape the characters that can't be used in a Markdown link destination.
+fn escape_link_destination(s: &str) -> String {
+    s.replace(' ', "%20").replace('(', "%28").replace(')', "%29")
+}
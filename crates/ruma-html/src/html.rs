@@ -6,7 +6,7 @@ use html5ever::{
     serialize::{serialize, Serialize, SerializeOpts, Serializer, TraversalScope},
     tendril::{StrTendril, TendrilSink},
     tree_builder::{NodeOrText, TreeSink},
-    Attribute, ParseOpts, QualName,
+    Attribute, LocalName, ParseOpts, QualName,
 };
 use tracing::debug;
 
@@ -392,6 +392,29 @@ pub struct ElementData {
     pub attrs: BTreeSet<Attribute>,
 }
 
+impl ElementData {
+    /// Get the value of the attribute with the given name, if any.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|attr| &*attr.name.local == name).map(|attr| &*attr.value)
+    }
+
+    /// Set the value of the attribute with the given name.
+    ///
+    /// Replaces the previous value, if any.
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        self.remove_attribute(name);
+        self.attrs.insert(Attribute {
+            name: QualName::new(None, ns!(), LocalName::from(name)),
+            value: value.into(),
+        });
+    }
+
+    /// Remove the attribute with the given name.
+    pub fn remove_attribute(&mut self, name: &str) {
+        self.attrs.retain(|attr| &*attr.name.local != name);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Html;
@@ -10,12 +10,17 @@
 #![warn(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+mod convert;
 mod helpers;
 mod html;
+#[cfg(feature = "matrix")]
+mod matrix;
 mod sanitizer_config;
 
+#[cfg(feature = "matrix")]
+pub use self::matrix::{MatrixLink, MatrixLinkRewrite};
 pub use self::{
     helpers::*,
     html::{ElementData, Html, Node},
-    sanitizer_config::SanitizerConfig,
+    sanitizer_config::{ElementAction, SanitizerConfig},
 };
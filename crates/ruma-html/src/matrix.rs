@@ -0,0 +1,143 @@
+//! Types and methods to find and rewrite links to Matrix resources.
+
+use html5ever::local_name;
+use ruma_common::{matrix_uri::MatrixId, MatrixToUri, MatrixUri, OwnedServerName};
+
+use crate::{convert::text_content, html::NodeData, Html};
+
+/// A link to a Matrix resource in an HTML fragment.
+///
+/// Links are `a` elements whose `href` is a [`matrix.to` URI] or a [`matrix:` URI]. Links to
+/// users are also known as pills or mentions.
+///
+/// [`matrix.to` URI]: https://spec.matrix.org/latest/appendices/#matrixto-navigation
+/// [`matrix:` URI]: https://spec.matrix.org/latest/appendices/#matrix-uri-scheme
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MatrixLink {
+    /// The value of the `href` attribute of the link.
+    pub href: String,
+
+    /// The identifier the link points to.
+    pub id: MatrixId,
+
+    /// The servers that can be used to route the identifier.
+    pub via: Vec<OwnedServerName>,
+
+    /// The text content of the link.
+    pub text: String,
+}
+
+impl MatrixLink {
+    /// Try to parse the given `a` element as a link to a Matrix resource.
+    fn parse(html: &Html, node_id: usize) -> Option<Self> {
+        let element = html.nodes[node_id].as_element()?;
+        if element.name.local != local_name!("a") {
+            return None;
+        }
+
+        let href = element.attribute("href")?;
+        let (id, via) = if let Ok(uri) = MatrixToUri::parse(href) {
+            (uri.id().clone(), uri.via().to_owned())
+        } else if let Ok(uri) = MatrixUri::parse(href) {
+            (uri.id().clone(), uri.via().to_owned())
+        } else {
+            return None;
+        };
+
+        Some(Self { href: href.to_owned(), id, via, text: text_content(html, node_id) })
+    }
+}
+
+/// How to rewrite a [`MatrixLink`], with [`Html::rewrite_matrix_links()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MatrixLinkRewrite {
+    /// Keep the link as it is.
+    Keep,
+
+    /// Replace the `href` of the link.
+    Href(String),
+
+    /// Replace the content of the link with the given text.
+    Text(String),
+
+    /// Replace the link with the given text.
+    ReplaceWithText(String),
+}
+
+impl Html {
+    /// Get the links to Matrix resources in this HTML, in document order.
+    ///
+    /// Links in the [rich reply fallback] are ignored.
+    ///
+    /// [rich reply fallback]: https://spec.matrix.org/latest/client-server-api/#fallbacks-for-rich-replies
+    pub fn matrix_links(&self) -> Vec<MatrixLink> {
+        self.matrix_link_nodes().into_iter().map(|(_, link)| link).collect()
+    }
+
+    /// Rewrite the links to Matrix resources in this HTML.
+    ///
+    /// `rewrite` is called for every link, in document order, and returns how the link should be
+    /// rewritten. This can be used to update the URIs of pills, or to replace them with the names
+    /// of users on another network when bridging messages.
+    ///
+    /// Links in the [rich reply fallback] are ignored.
+    ///
+    /// [rich reply fallback]: https://spec.matrix.org/latest/client-server-api/#fallbacks-for-rich-replies
+    pub fn rewrite_matrix_links(
+        &mut self,
+        mut rewrite: impl FnMut(&MatrixLink) -> MatrixLinkRewrite,
+    ) {
+        for (node_id, link) in self.matrix_link_nodes() {
+            match rewrite(&link) {
+                MatrixLinkRewrite::Keep => {}
+                MatrixLinkRewrite::Href(href) => {
+                    let element =
+                        self.nodes[node_id].as_element_mut().expect("link should be an element");
+                    element.set_attribute("href", &href);
+                }
+                MatrixLinkRewrite::Text(text) => {
+                    while let Some(child) = self.nodes[node_id].first_child {
+                        self.detach(child);
+                    }
+
+                    let text_node = self.new_node(NodeData::Text(text.into()));
+                    self.append_node(node_id, text_node);
+                }
+                MatrixLinkRewrite::ReplaceWithText(text) => {
+                    let text_node = self.new_node(NodeData::Text(text.into()));
+                    self.insert_before(node_id, text_node);
+                    self.detach(node_id);
+                }
+            }
+        }
+    }
+
+    /// Get the links to Matrix resources in this HTML, with the IDs of their nodes.
+    fn matrix_link_nodes(&self) -> Vec<(usize, MatrixLink)> {
+        let mut links = Vec::new();
+        let mut stack = self.nodes[0].first_child.into_iter().collect::<Vec<_>>();
+
+        while let Some(node_id) = stack.pop() {
+            let node = &self.nodes[node_id];
+
+            if let Some(link) = MatrixLink::parse(self, node_id) {
+                links.push((node_id, link));
+                continue;
+            }
+            if node.as_element().is_some_and(|element| &*element.name.local == "mx-reply") {
+                continue;
+            }
+
+            // Push the children in reverse order to visit them in document order.
+            let mut prev_child = node.last_child;
+            while let Some(child) = prev_child {
+                stack.push(child);
+                prev_child = self.nodes[child].prev_sibling;
+            }
+        }
+
+        links
+    }
+}
@@ -1,3 +1,9 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Arc,
+};
+
 use html5ever::{tendril::StrTendril, Attribute};
 use phf::{phf_map, phf_set, Map, Set};
 use wildmatch::WildMatch;
@@ -5,33 +11,58 @@ use wildmatch::WildMatch;
 use crate::html::{ElementData, Html, NodeData};
 
 /// Configuration to sanitize HTML tags and attributes.
+///
+/// The presets [`SanitizerConfig::strict()`] and [`SanitizerConfig::compat()`] follow the
+/// Matrix specification. They can be customized with the builder methods of this type, which
+/// allow or deny additional tags, attributes, URI schemes and classes, and register hooks to
+/// transform elements:
+///
+/// ```
+/// use ruma_html::{ElementAction, Html, SanitizerConfig};
+///
+/// let config = SanitizerConfig::strict()
+///     .deny_attributes("font", ["color", "data-mx-color"])
+///     .allow_attributes("span", ["data-mx-custom"])
+///     .transform_elements("a", |element| {
+///         element.set_attribute("rel", "noopener");
+///         ElementAction::Keep
+///     });
+///
+/// let mut html =
+///     Html::parse("<font color=\"red\">Hello</font> <a href=\"https://ruma.io\">Ruma</a>");
+/// html.sanitize_with(config);
+///
+/// assert_eq!(
+///     html.to_string(),
+///     "<font>Hello</font> <a href=\"https://ruma.io\" rel=\"noopener\">Ruma</a>"
+/// );
+/// ```
 #[derive(Debug, Default, Clone)]
 pub struct SanitizerConfig {
     /// The allowed HTML tags.
-    ///
-    /// If this is `None`, all tags are allowed.
-    allowed_tags: Option<&'static Set<&'static str>>,
+    allowed_tags: AllowList,
 
     /// The allowed attributes per tag.
-    ///
-    /// If this is `None`, all attributes are allowed.
-    allowed_attrs: Option<&'static Map<&'static str, &'static Set<&'static str>>>,
+    allowed_attrs: AllowMap,
 
-    /// The allowed URI schemes per tag.
+    /// The allowed URI schemes per tag and attribute, with keys in the `tag:attr` format.
     ///
-    /// If this is `None`, all schemes are allowed.
-    allowed_schemes: Option<&'static Map<&'static str, &'static Set<&'static str>>>,
+    /// Only the attributes with an entry are checked.
+    allowed_schemes: AllowMap,
 
     /// The allowed classes per tag.
     ///
-    /// If this is `None`, all classes are allowed.
-    allowed_classes: Option<&'static Map<&'static str, &'static Set<&'static str>>>,
+    /// Only the tags with an entry are checked.
+    allowed_classes: AllowMap,
 
     /// The maximum nesting level of the tags.
     max_depth: Option<u32>,
 
     /// Whether to remove rich reply fallback.
     remove_reply_fallback: bool,
+
+    /// The hooks to call on elements, per tag.
+    element_transforms: Vec<(String, ElementTransform)>,
 }
 
 impl SanitizerConfig {
@@ -48,12 +79,13 @@ impl SanitizerConfig {
     /// [listed in the Matrix specification]: https://spec.matrix.org/latest/client-server-api/#mroommessage-msgtypes
     pub fn strict() -> Self {
         Self {
-            allowed_tags: Some(&ALLOWED_TAGS_WITHOUT_REPLY_STRICT),
-            allowed_attrs: Some(&ALLOWED_ATTRIBUTES_STRICT),
-            allowed_schemes: Some(&ALLOWED_SCHEMES_STRICT),
-            allowed_classes: Some(&ALLOWED_CLASSES_STRICT),
+            allowed_tags: AllowList::with_base(&ALLOWED_TAGS_WITHOUT_REPLY_STRICT),
+            allowed_attrs: AllowMap::with_base(&ALLOWED_ATTRIBUTES_STRICT),
+            allowed_schemes: AllowMap::with_base(&ALLOWED_SCHEMES_STRICT),
+            allowed_classes: AllowMap::with_base(&ALLOWED_CLASSES_STRICT),
             max_depth: Some(MAX_DEPTH_STRICT),
             remove_reply_fallback: false,
+            element_transforms: Vec::new(),
         }
     }
 
@@ -66,7 +98,7 @@ impl SanitizerConfig {
     ///
     /// [listed in the Matrix specification]: https://spec.matrix.org/latest/client-server-api/#mroommessage-msgtypes
     pub fn compat() -> Self {
-        Self { allowed_schemes: Some(&ALLOWED_SCHEMES_COMPAT), ..Self::strict() }
+        Self { allowed_schemes: AllowMap::with_base(&ALLOWED_SCHEMES_COMPAT), ..Self::strict() }
     }
 
     /// Remove the [rich reply fallback].
@@ -77,6 +109,124 @@ impl SanitizerConfig {
         self
     }
 
+    /// Set the maximum nesting level of the tags.
+    ///
+    /// The elements that are nested deeper are removed with their children. If this is `None`,
+    /// the nesting level is not limited.
+    pub fn max_depth(mut self, max_depth: Option<u32>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Allow the given tags.
+    ///
+    /// If no tags were allowed before, like with [`SanitizerConfig::new()`], only the given tags
+    /// are allowed.
+    pub fn allow_tags<T: Into<String>>(mut self, tags: impl IntoIterator<Item = T>) -> Self {
+        self.allowed_tags.allow(tags);
+        self
+    }
+
+    /// Deny the given tags.
+    ///
+    /// Denied elements are removed, but their children are kept.
+    pub fn deny_tags<T: Into<String>>(mut self, tags: impl IntoIterator<Item = T>) -> Self {
+        self.allowed_tags.deny(tags);
+        self
+    }
+
+    /// Allow the given attributes on the given tag.
+    ///
+    /// If no attributes were allowed before, like with [`SanitizerConfig::new()`], only the
+    /// allowed attributes are kept on all tags.
+    pub fn allow_attributes<T: Into<String>>(
+        mut self,
+        tag: &str,
+        attributes: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.allowed_attrs.allow(tag, attributes);
+        self
+    }
+
+    /// Deny the given attributes on the given tag.
+    pub fn deny_attributes<T: Into<String>>(
+        mut self,
+        tag: &str,
+        attributes: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.allowed_attrs.deny(tag, attributes);
+        self
+    }
+
+    /// Allow the given URI schemes in the given attribute of the given tag.
+    ///
+    /// If the value of the attribute doesn't use an allowed scheme, the element is removed, but
+    /// its children are kept. If no schemes were allowed before for this attribute, only the
+    /// given schemes are allowed.
+    pub fn allow_schemes<T: Into<String>>(
+        mut self,
+        tag: &str,
+        attribute: &str,
+        schemes: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.allowed_schemes.allow(&format!("{tag}:{attribute}"), schemes);
+        self
+    }
+
+    /// Deny the given URI schemes in the given attribute of the given tag.
+    ///
+    /// If the value of the attribute uses a denied scheme, the element is removed, but its
+    /// children are kept. Schemes are compared case-insensitively, and leading whitespace in the
+    /// value is ignored.
+    pub fn deny_schemes<T: Into<String>>(
+        mut self,
+        tag: &str,
+        attribute: &str,
+        schemes: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.allowed_schemes.deny(&format!("{tag}:{attribute}"), schemes);
+        self
+    }
+
+    /// Allow the given classes on the given tag.
+    ///
+    /// The classes can contain the wildcards `*` and `?`. If no classes were allowed before for
+    /// this tag, only the given classes are allowed.
+    pub fn allow_classes<T: Into<String>>(
+        mut self,
+        tag: &str,
+        classes: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.allowed_classes.allow(tag, classes);
+        self
+    }
+
+    /// Deny the given classes on the given tag.
+    ///
+    /// The classes can contain the wildcards `*` and `?`.
+    pub fn deny_classes<T: Into<String>>(
+        mut self,
+        tag: &str,
+        classes: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.allowed_classes.deny(tag, classes);
+        self
+    }
+
+    /// Call the given hook on every element with the given tag that is kept by the sanitizer.
+    ///
+    /// The hook is called after the attributes of the element were sanitized, so the attributes
+    /// it adds are kept. It returns whether the element should be kept. The hooks are called in
+    /// the order in which they were added.
+    pub fn transform_elements(
+        mut self,
+        tag: &str,
+        transform: impl Fn(&mut ElementData) -> ElementAction + Send + Sync + 'static,
+    ) -> Self {
+        self.element_transforms.push((tag.to_owned(), ElementTransform(Arc::new(transform))));
+        self
+    }
+
     /// Clean the given HTML with this sanitizer.
     pub(crate) fn clean(self, html: &mut Html) {
         let root = html.nodes[0].first_child.unwrap();
@@ -89,7 +239,7 @@ impl SanitizerConfig {
     }
 
     fn clean_node(&self, html: &mut Html, node_id: usize, depth: u32) {
-        let action = self.node_action(html, node_id, depth);
+        let mut action = self.node_action(html, node_id, depth);
 
         if action != NodeAction::Remove {
             let mut next_child = html.nodes[node_id].first_child;
@@ -104,10 +254,23 @@ impl SanitizerConfig {
             }
         }
 
+        if action == NodeAction::None {
+            if let Some(data) = html.nodes[node_id].as_element_mut() {
+                self.clean_element_attributes(data);
+                action = self.transform_element(data);
+
+                if action == NodeAction::Ignore {
+                    let mut next_child = html.nodes[node_id].first_child;
+                    while let Some(child) = next_child {
+                        next_child = html.nodes[child].next_sibling;
+                        html.insert_before(node_id, child);
+                    }
+                }
+            }
+        }
+
         if matches!(action, NodeAction::Ignore | NodeAction::Remove) {
             html.detach(node_id);
-        } else if let Some(data) = html.nodes[node_id].as_element_mut() {
-            self.clean_element_attributes(data);
         }
     }
 
@@ -120,30 +283,30 @@ impl SanitizerConfig {
                     || self.max_depth.is_some_and(|max| depth >= max)
                 {
                     NodeAction::Remove
-                } else if self
-                    .allowed_tags
-                    .is_some_and(|allowed| tag != RICH_REPLY_TAG && !allowed.contains(tag))
-                {
+                } else if tag != RICH_REPLY_TAG && !self.allowed_tags.allows(tag) {
                     NodeAction::Ignore
-                } else if let Some(allowed_schemes) = self.allowed_schemes {
+                } else {
                     for attr in attrs.iter() {
-                        let value = &attr.value;
+                        let value_scheme = uri_scheme(&attr.value);
                         let attr: &str = &attr.name.local;
+                        let key = format!("{tag}:{attr}");
+                        let has_scheme = |scheme: &str| {
+                            value_scheme.as_deref().is_some_and(|s| s.eq_ignore_ascii_case(scheme))
+                        };
+
+                        // Check if the scheme is denied.
+                        if self.allowed_schemes.denied(&key).any(has_scheme) {
+                            return NodeAction::Ignore;
+                        }
 
-                        // Check if there is a (tag, attr) tuple entry.
-                        if let Some(schemes) = allowed_schemes.get(&*format!("{tag}:{attr}")) {
-                            // Check if the scheme is allowed.
-                            if !schemes
-                                .iter()
-                                .any(|scheme| value.starts_with(&format!("{scheme}:")))
-                            {
+                        // Check if there is a (tag, attr) tuple entry, and the scheme is allowed.
+                        if let Some(mut schemes) = self.allowed_schemes.allowed(&key) {
+                            if !schemes.any(has_scheme) {
                                 return NodeAction::Ignore;
                             }
                         }
                     }
                     NodeAction::None
-                } else {
-                    NodeAction::None
                 }
             }
             NodeData::Text(_) => NodeAction::None,
@@ -161,43 +324,48 @@ impl SanitizerConfig {
                 let value = &attr.value;
                 let name: &str = &attr.name.local;
 
-                if self
-                    .allowed_attrs
-                    .is_some_and(|m| !m.get(tag).is_some_and(|attrs| attrs.contains(name)))
+                if self.allowed_attrs.denied(tag).any(|denied| denied == name)
+                    || (!self.allowed_attrs.is_unrestricted()
+                        && !self
+                            .allowed_attrs
+                            .allowed(tag)
+                            .is_some_and(|mut allowed| allowed.any(|allowed| allowed == name)))
                 {
                     return Some(AttributeAction::Remove(attr.to_owned()));
                 }
 
                 if name == "class" {
-                    if let Some(classes) = self.allowed_classes.and_then(|m| m.get(tag)) {
-                        let mut changed = false;
-                        let attr_classes = value.split_whitespace().filter(|attr_class| {
-                            for class in classes.iter() {
-                                if WildMatch::new(class).matches(attr_class) {
-                                    return true;
-                                }
-                            }
+                    let mut changed = false;
+                    let attr_classes = value.split_whitespace().filter(|attr_class| {
+                        let matches = |class: &str| WildMatch::new(class).matches(attr_class);
+                        let is_allowed = !self.allowed_classes.denied(tag).any(matches)
+                            && self
+                                .allowed_classes
+                                .allowed(tag)
+                                .map_or(true, |mut allowed| allowed.any(matches));
+
+                        if !is_allowed {
                             changed = true;
-                            false
-                        });
-
-                        let folded_classes = attr_classes.fold(String::new(), |mut a, b| {
-                            a.reserve(b.len() + 1);
-                            a.push_str(b);
-                            a.push('\n');
-                            a
-                        });
-                        let final_classes = folded_classes.trim_end();
-
-                        if changed {
-                            if final_classes.is_empty() {
-                                return Some(AttributeAction::Remove(attr.to_owned()));
-                            } else {
-                                return Some(AttributeAction::ReplaceValue(
-                                    attr.to_owned(),
-                                    final_classes.to_owned().into(),
-                                ));
-                            }
+                        }
+                        is_allowed
+                    });
+
+                    let folded_classes = attr_classes.fold(String::new(), |mut a, b| {
+                        a.reserve(b.len() + 1);
+                        a.push_str(b);
+                        a.push('\n');
+                        a
+                    });
+                    let final_classes = folded_classes.trim_end();
+
+                    if changed {
+                        if final_classes.is_empty() {
+                            return Some(AttributeAction::Remove(attr.to_owned()));
+                        } else {
+                            return Some(AttributeAction::ReplaceValue(
+                                attr.to_owned(),
+                                final_classes.to_owned().into(),
+                            ));
                         }
                     }
                 }
@@ -220,6 +388,146 @@ impl SanitizerConfig {
             }
         }
     }
+
+    /// Call the hooks for the given element.
+    fn transform_element(&self, data: &mut ElementData) -> NodeAction {
+        for (tag, transform) in &self.element_transforms {
+            if *tag != *data.name.local {
+                continue;
+            }
+
+            match (transform.0)(data) {
+                ElementAction::Keep => {}
+                ElementAction::Ignore => return NodeAction::Ignore,
+                ElementAction::Remove => return NodeAction::Remove,
+            }
+        }
+
+        NodeAction::None
+    }
+}
+
+/// The action to apply to an element, returned by the hooks of
+/// [`SanitizerConfig::transform_elements()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ElementAction {
+    /// Keep the element.
+    Keep,
+
+    /// Remove the element but keep its children.
+    Ignore,
+
+    /// Remove the element and its children.
+    Remove,
+}
+
+/// A hook to transform an element.
+#[derive(Clone)]
+struct ElementTransform(Arc<dyn Fn(&mut ElementData) -> ElementAction + Send + Sync>);
+
+impl fmt::Debug for ElementTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ElementTransform").finish_non_exhaustive()
+    }
+}
+
+/// A list of allowed values, made of a static list and owned additions and removals.
+#[derive(Clone, Debug, Default)]
+struct AllowList {
+    /// The static list of allowed values, if any.
+    base: Option<&'static Set<&'static str>>,
+
+    /// The values allowed in addition to `base`, if any.
+    added: Option<BTreeSet<String>>,
+
+    /// The denied values.
+    denied: BTreeSet<String>,
+}
+
+impl AllowList {
+    fn with_base(base: &'static Set<&'static str>) -> Self {
+        Self { base: Some(base), ..Default::default() }
+    }
+
+    fn allow<T: Into<String>>(&mut self, values: impl IntoIterator<Item = T>) {
+        let values = values.into_iter().map(Into::into);
+        self.added.get_or_insert_with(Default::default).extend(values);
+    }
+
+    fn deny<T: Into<String>>(&mut self, values: impl IntoIterator<Item = T>) {
+        self.denied.extend(values.into_iter().map(Into::into));
+    }
+
+    /// Whether the given value is allowed.
+    ///
+    /// All the values that are not denied are allowed if no allowed values were set.
+    fn allows(&self, value: &str) -> bool {
+        if self.denied.contains(value) {
+            return false;
+        }
+        if self.base.is_none() && self.added.is_none() {
+            return true;
+        }
+
+        self.base.is_some_and(|base| base.contains(value))
+            || self.added.as_ref().is_some_and(|added| added.contains(value))
+    }
+}
+
+/// Lists of allowed values per key, made of a static map and owned additions and removals.
+#[derive(Clone, Debug, Default)]
+struct AllowMap {
+    /// The static map of allowed values, if any.
+    base: Option<&'static Map<&'static str, &'static Set<&'static str>>>,
+
+    /// The values allowed in addition to `base`, if any.
+    added: Option<BTreeMap<String, BTreeSet<String>>>,
+
+    /// The denied values.
+    denied: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl AllowMap {
+    fn with_base(base: &'static Map<&'static str, &'static Set<&'static str>>) -> Self {
+        Self { base: Some(base), ..Default::default() }
+    }
+
+    fn allow<T: Into<String>>(&mut self, key: &str, values: impl IntoIterator<Item = T>) {
+        let values = values.into_iter().map(Into::into);
+        let added = self.added.get_or_insert_with(Default::default);
+        added.entry(key.to_owned()).or_default().extend(values);
+    }
+
+    fn deny<T: Into<String>>(&mut self, key: &str, values: impl IntoIterator<Item = T>) {
+        self.denied.entry(key.to_owned()).or_default().extend(values.into_iter().map(Into::into));
+    }
+
+    /// Whether no allowed values were set for any key.
+    fn is_unrestricted(&self) -> bool {
+        self.base.is_none() && self.added.is_none()
+    }
+
+    /// The allowed values for the given key, if there is an entry for it.
+    fn allowed<'a>(&'a self, key: &str) -> Option<impl Iterator<Item = &'a str> + 'a> {
+        let base = self.base.and_then(|base| base.get(key));
+        let added = self.added.as_ref().and_then(|added| added.get(key));
+
+        if base.is_none() && added.is_none() {
+            return None;
+        }
+
+        Some(
+            base.into_iter()
+                .flat_map(|base| base.iter().map(|value| -> &'a str { value }))
+                .chain(added.into_iter().flatten().map(String::as_str)),
+        )
+    }
+
+    /// The denied values for the given key.
+    fn denied<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a str> + 'a {
+        self.denied.get(key).into_iter().flatten().map(String::as_str)
+    }
 }
 
 /// The possible actions to apply to an element node.
@@ -275,6 +583,16 @@ static ALLOWED_ATTRIBUTES_IMG_STRICT: Set<&str> =
 static ALLOWED_ATTRIBUTES_OL_STRICT: Set<&str> = phf_set! { "start" };
 static ALLOWED_ATTRIBUTES_CODE_STRICT: Set<&str> = phf_set! { "class" };
 
+/// Get the scheme of the given URI, if any.
+///
+/// Like browsers, leading spaces and C0 control characters, as well as ASCII tabs and newlines, are
+/// ignored. The scheme is not lowercased.
+fn uri_scheme(uri: &str) -> Option<String> {
+    let uri = uri.trim_start_matches(|c: char| c <= ' ');
+    let (scheme, _) = uri.split_once(':')?;
+    Some(scheme.chars().filter(|c| !matches!(c, '\t' | '\n' | '\r')).collect())
+}
+
 /// Allowed schemes of URIs per HTML tag and attribute tuple according to the Matrix specification.
 static ALLOWED_SCHEMES_STRICT: Map<&str, &Set<&str>> = phf_map! {
     "a:href" => &ALLOWED_SCHEMES_A_HREF_STRICT,
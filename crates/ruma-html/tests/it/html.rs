@@ -1 +1,4 @@
+mod convert;
+#[cfg(feature = "matrix")]
+mod matrix;
 mod sanitize;
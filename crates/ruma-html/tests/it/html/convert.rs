@@ -0,0 +1,175 @@
+use ruma_html::Html;
+
+#[test]
+fn paragraphs_and_line_breaks() {
+    let html = Html::parse(
+        "\
+        <p>First   paragraph\n with <em>emphasis</em>.</p>\
+        <p>Second<br>line</p>\
+        ",
+    );
+
+    assert_eq!(html.to_plain_text(), "First paragraph with emphasis.\n\nSecond\nline");
+    assert_eq!(html.to_markdown(), "First paragraph with *emphasis*.\n\nSecond\nline");
+}
+
+#[test]
+fn reply_fallback() {
+    let html = Html::parse(
+        "\
+        <mx-reply>\
+            <blockquote>\
+                <a href=\"https://matrix.to/#/!room:example.org/$event\">In reply to</a> \
+                <a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>\
+                <br>Original message\
+            </blockquote>\
+        </mx-reply>\
+        This is the reply\
+        ",
+    );
+
+    assert_eq!(html.to_plain_text(), "This is the reply");
+    assert_eq!(html.to_markdown(), "This is the reply");
+}
+
+#[test]
+fn lists() {
+    let html = Html::parse(
+        "\
+        <p>Groceries:</p>\
+        <ul>\
+            <li>Apples</li>\
+            <li>Vegetables\
+                <ol start=\"3\">\
+                    <li>Carrots</li>\
+                    <li>Leeks</li>\
+                </ol>\
+            </li>\
+        </ul>\
+        <p>Done</p>\
+        ",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "Groceries:\n\n- Apples\n- Vegetables\n  3. Carrots\n  4. Leeks\n\nDone"
+    );
+}
+
+#[test]
+fn blockquote() {
+    let html = Html::parse(
+        "\
+        <blockquote><p>Quoted</p><p>text</p></blockquote>\
+        <p>Answer</p>\
+        ",
+    );
+
+    assert_eq!(html.to_plain_text(), "> Quoted\n>\n> text\n\nAnswer");
+    assert_eq!(html.to_markdown(), "> Quoted\n>\n> text\n\nAnswer");
+}
+
+#[test]
+fn code() {
+    let html = Html::parse(
+        "\
+        <p>Run <code>cargo test</code>:</p>\
+        <pre><code class=\"language-rust\">fn main() {\n    println!(\"*hello*\");\n}\n</code></pre>\
+        ",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "Run cargo test:\n\nfn main() {\n    println!(\"*hello*\");\n}"
+    );
+    assert_eq!(
+        html.to_markdown(),
+        "Run `cargo test`:\n\n```rust\nfn main() {\n    println!(\"*hello*\");\n}\n```"
+    );
+}
+
+#[test]
+fn links() {
+    let html = Html::parse(
+        "\
+        See <a href=\"https://ruma.io/\">the website</a> \
+        or <a href=\"https://github.com/ruma/ruma\">https://github.com/ruma/ruma</a>.\
+        ",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "See the website <https://ruma.io/> or https://github.com/ruma/ruma."
+    );
+    assert_eq!(
+        html.to_markdown(),
+        "See [the website](https://ruma.io/) or <https://github.com/ruma/ruma>."
+    );
+}
+
+#[test]
+fn markdown_formatting() {
+    let html = Html::parse(
+        "\
+        <h2>Title</h2>\
+        <p><strong>Bold</strong>, <del>deleted</del> and 2*3_4 \
+        <img src=\"mxc://example.org/abc\" alt=\"image\"></p>\
+        <hr>\
+        <table>\
+            <tr><th>Name</th><th>Value</th></tr>\
+            <tr><td>a</td><td>1</td></tr>\
+        </table>\
+        ",
+    );
+
+    assert_eq!(
+        html.to_markdown(),
+        "## Title\n\n\
+        **Bold**, ~~deleted~~ and 2\\*3\\_4 ![image](mxc://example.org/abc)\n\n\
+        ---\n\n\
+        | Name | Value |\n\
+        | --- | --- |\n\
+        | a | 1 |"
+    );
+    assert_eq!(
+        html.to_plain_text(),
+        "Title\n\nBold, deleted and 2*3_4 image\n\n---\n\nName | Value\na | 1"
+    );
+}
+
+/// Convert the given HTML to Markdown, and the Markdown back to HTML.
+fn markdown_round_trip(html: &str) -> String {
+    let markdown = Html::parse(html).to_markdown();
+
+    let mut round_trip = String::new();
+    pulldown_cmark::html::push_html(&mut round_trip, pulldown_cmark::Parser::new(&markdown));
+    round_trip
+}
+
+#[test]
+fn markdown_escape_block_markers() {
+    for text in ["# foo", "- foo", "+ foo", "1. foo", "10) foo", "= foo", "-", "---"] {
+        assert_eq!(markdown_round_trip(&format!("<p>{text}</p>")), format!("<p>{text}</p>\n"));
+    }
+
+    assert_eq!(markdown_round_trip("<p>foo<br>===</p>"), "<p>foo\n===</p>\n");
+    assert_eq!(markdown_round_trip("<p>foo<br>---</p>"), "<p>foo\n---</p>\n");
+    assert_eq!(markdown_round_trip("<p>foo<br># bar</p>"), "<p>foo\n# bar</p>\n");
+    assert_eq!(
+        markdown_round_trip("<ul><li># foo</li><li>1. bar</li></ul>"),
+        "<ul>\n<li># foo</li>\n<li>1. bar</li>\n</ul>\n"
+    );
+    assert_eq!(
+        markdown_round_trip("<blockquote><p>- foo</p></blockquote>"),
+        "<blockquote>\n<p>- foo</p>\n</blockquote>\n"
+    );
+
+    // Markers that are not at the start of a line are not escaped.
+    assert_eq!(Html::parse("<p>a # b - c 1. d</p>").to_markdown(), "a # b - c 1. d");
+}
+
+#[test]
+fn markdown_escape_entities() {
+    assert_eq!(Html::parse("<p>&amp;lt; &amp;#42;</p>").to_markdown(), "\\&lt; \\&#42;");
+    assert_eq!(markdown_round_trip("<p>&amp;lt; &amp;#42;</p>"), "<p>&amp;lt; &amp;#42;</p>\n");
+}
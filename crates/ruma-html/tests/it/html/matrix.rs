@@ -0,0 +1,64 @@
+use ruma_common::{matrix_uri::MatrixId, owned_event_id, owned_room_alias_id, owned_user_id};
+use ruma_html::{Html, MatrixLinkRewrite};
+
+const HTML: &str = "\
+    <mx-reply><blockquote>\
+        <a href=\"https://matrix.to/#/@carl:example.org\">@carl:example.org</a>\
+    </blockquote></mx-reply>\
+    <p>Hello <a href=\"https://matrix.to/#/@alice:example.org\">Alice</a>, \
+    see <a href=\"matrix:r/room:example.org/e/event?via=example.org\">this <em>message</em></a> \
+    on <a href=\"https://ruma.io/\">the website</a>.</p>\
+";
+
+#[test]
+fn matrix_links() {
+    let links = Html::parse(HTML).matrix_links();
+    assert_eq!(links.len(), 2);
+
+    assert_eq!(links[0].href, "https://matrix.to/#/@alice:example.org");
+    assert_eq!(links[0].id, MatrixId::User(owned_user_id!("@alice:example.org")));
+    assert!(links[0].via.is_empty());
+    assert_eq!(links[0].text, "Alice");
+
+    assert_eq!(
+        links[1].id,
+        MatrixId::Event(
+            owned_room_alias_id!("#room:example.org").into(),
+            owned_event_id!("$event")
+        )
+    );
+    assert_eq!(links[1].via, ["example.org"]);
+    assert_eq!(links[1].text, "this message");
+}
+
+#[test]
+fn rewrite_matrix_links() {
+    let mut html = Html::parse(HTML);
+    html.rewrite_matrix_links(|link| match &link.id {
+        MatrixId::User(user_id) => {
+            MatrixLinkRewrite::ReplaceWithText(format!("{}:", user_id.localpart()))
+        }
+        _ => MatrixLinkRewrite::Href("https://example.org/message".to_owned()),
+    });
+
+    assert_eq!(
+        html.to_string(),
+        "\
+        <mx-reply><blockquote>\
+            <a href=\"https://matrix.to/#/@carl:example.org\">@carl:example.org</a>\
+        </blockquote></mx-reply>\
+        <p>Hello alice:, \
+        see <a href=\"https://example.org/message\">this <em>message</em></a> \
+        on <a href=\"https://ruma.io/\">the website</a>.</p>\
+        "
+    );
+
+    let mut html = Html::parse(HTML);
+    html.rewrite_matrix_links(|link| match &link.id {
+        MatrixId::User(_) => MatrixLinkRewrite::Text("@alice".to_owned()),
+        _ => MatrixLinkRewrite::Keep,
+    });
+    assert!(html
+        .to_string()
+        .contains("<a href=\"https://matrix.to/#/@alice:example.org\">@alice</a>"));
+}
@@ -1,4 +1,4 @@
-use ruma_html::{Html, SanitizerConfig};
+use ruma_html::{ElementAction, Html, SanitizerConfig};
 
 #[test]
 fn valid_input() {
@@ -246,3 +246,161 @@ fn depth_remove() {
     assert!(res.contains("I should be fine."));
     assert!(!res.contains("I am in too deep!"));
 }
+
+#[test]
+fn custom_tags() {
+    let config = SanitizerConfig::strict().allow_tags(["section"]).deny_tags(["font"]);
+    let mut html = Html::parse(
+        "\
+        <section><p>Allowed</p></section>\
+        <font color=\"red\">Denied</font>\
+        <article>Not allowed</article>\
+        ",
+    );
+    html.sanitize_with(config);
+
+    assert_eq!(html.to_string(), "<section><p>Allowed</p></section>DeniedNot allowed");
+
+    let config = SanitizerConfig::new().allow_tags(["p", "b"]);
+    let mut html = Html::parse("<p>Only <b>these</b> <i>tags</i></p>");
+    html.sanitize_with(config);
+
+    assert_eq!(html.to_string(), "<p>Only <b>these</b> tags</p>");
+}
+
+#[test]
+fn custom_attrs() {
+    let config = SanitizerConfig::strict()
+        .allow_attributes("span", ["data-mx-custom"])
+        .deny_attributes("font", ["color", "data-mx-color"]);
+    let mut html = Html::parse(
+        "\
+        <span data-mx-custom=\"value\" data-mx-other=\"value\">Span</span>\
+        <font color=\"red\" data-mx-color=\"red\" data-mx-bg-color=\"blue\">Font</font>\
+        ",
+    );
+    html.sanitize_with(config);
+
+    assert_eq!(
+        html.to_string(),
+        "\
+        <span data-mx-custom=\"value\">Span</span>\
+        <font data-mx-bg-color=\"blue\">Font</font>\
+        "
+    );
+}
+
+#[test]
+fn custom_schemes() {
+    let config = SanitizerConfig::compat().deny_schemes("a", "href", ["magnet"]).allow_schemes(
+        "a",
+        "href",
+        ["geo"],
+    );
+    let mut html = Html::parse(
+        "\
+        <a href=\"magnet:?xt=urn:btih:abcdef\">Magnet</a> \
+        <a href=\"geo:48.85,2.35\">Paris</a> \
+        <a href=\"matrix:u/alice:example.org\">Alice</a>\
+        ",
+    );
+    html.sanitize_with(config);
+
+    assert_eq!(
+        html.to_string(),
+        "\
+        Magnet \
+        <a href=\"geo:48.85,2.35\">Paris</a> \
+        <a href=\"matrix:u/alice:example.org\">Alice</a>\
+        "
+    );
+}
+
+#[test]
+fn denied_scheme_case_and_whitespace() {
+    let config = SanitizerConfig::new().deny_schemes("a", "href", ["javascript"]);
+    let mut html = Html::parse(
+        "\
+        <a href=\"JavaScript:alert(1)\">Mixed case</a> \
+        <a href=\" javascript:alert(1)\">Leading space</a> \
+        <a href=\"&#x01;&#x09;java&#x0A;script:alert(1)\">Control characters</a> \
+        <a href=\"https://example.org\">Link</a>\
+        ",
+    );
+    html.sanitize_with(config);
+
+    assert_eq!(
+        html.to_string(),
+        "\
+        Mixed case \
+        Leading space \
+        Control characters \
+        <a href=\"https://example.org\">Link</a>\
+        "
+    );
+}
+
+#[test]
+fn allowed_scheme_case_and_whitespace() {
+    let config = SanitizerConfig::strict();
+    let mut html = Html::parse(
+        "\
+        <a href=\"HTTPS://example.org\">Mixed case</a> \
+        <a href=\" https://example.org\">Leading space</a> \
+        <a href=\" JavaScript:alert(1)\">Not allowed</a>\
+        ",
+    );
+    html.sanitize_with(config);
+
+    assert_eq!(
+        html.to_string(),
+        "\
+        <a href=\"HTTPS://example.org\">Mixed case</a> \
+        <a href=\" https://example.org\">Leading space</a> \
+        Not allowed\
+        "
+    );
+}
+
+#[test]
+fn custom_classes() {
+    let config = SanitizerConfig::strict()
+        .allow_classes("code", ["highlight-*"])
+        .deny_classes("code", ["language-*"]);
+    let mut html = Html::parse("<code class=\"language-rust highlight-line other\">code</code>");
+    html.sanitize_with(config);
+
+    assert_eq!(html.to_string(), "<code class=\"highlight-line\">code</code>");
+}
+
+#[test]
+fn transform_elements() {
+    let config = SanitizerConfig::strict()
+        .transform_elements("a", |element| {
+            element.set_attribute("rel", "noopener");
+            ElementAction::Keep
+        })
+        .transform_elements("img", |element| {
+            if element.attribute("src").is_some_and(|src| src.starts_with("mxc://example.org/")) {
+                ElementAction::Keep
+            } else {
+                ElementAction::Remove
+            }
+        });
+    let mut html = Html::parse(
+        "\
+        <a href=\"https://ruma.io\" rel=\"nofollow\">Ruma</a>\
+        <img src=\"mxc://example.org/abcdef\">\
+        <img src=\"mxc://notareal.hs/abcdef\">\
+        ",
+    );
+    html.sanitize_with(config);
+
+    assert_eq!(
+        html.to_string(),
+        "\
+        <a href=\"https://ruma.io\" rel=\"noopener\">Ruma</a>\
+        <img src=\"mxc://example.org/abcdef\">\
+        "
+    );
+}
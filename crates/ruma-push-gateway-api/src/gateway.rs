@@ -0,0 +1,330 @@
+//! A framework-agnostic dispatcher for push gateways.
+//!
+//! [`PushGateway`] handles the requests to the [`send_event_notification`] endpoint: it validates
+//! the notification, sends it to each device with the [`PushProvider`] registered for the
+//! `app_id` of the device, and collects the pushkeys that were rejected.
+//!
+//! [`send_event_notification`]: crate::send_event_notification
+
+use std::{collections::BTreeMap, error::Error as StdError, fmt, future::Future, pin::Pin};
+
+use crate::send_event_notification::v1::{Device, Notification, Request, Response};
+
+/// The maximum length of the `app_id` of a device, in characters.
+const MAX_APP_ID_LENGTH: usize = 64;
+
+/// The maximum length of the `pushkey` of a device, in bytes.
+const MAX_PUSHKEY_LENGTH: usize = 512;
+
+/// A service that can deliver push notifications to devices, like a vendor-specific push
+/// notification service.
+pub trait PushProvider: Send + Sync {
+    /// Send the given notification to the given device.
+    ///
+    /// The device is one of the devices of the notification.
+    fn send_notification<'a>(
+        &'a self,
+        notification: &'a Notification,
+        device: &'a Device,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PushError>> + Send + 'a>>;
+}
+
+/// An error returned by a [`PushProvider`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PushError {
+    /// The pushkey of the device is not valid.
+    ///
+    /// It could have expired or have never been valid. The pushkey is returned to the homeserver,
+    /// which will remove the associated pusher.
+    RejectedPushkey,
+
+    /// The notification could not be delivered, but it might succeed later.
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RejectedPushkey => f.write_str("the pushkey was rejected"),
+            Self::Other(error) => write!(f, "failed to deliver the notification: {error}"),
+        }
+    }
+}
+
+impl StdError for PushError {}
+
+/// A push gateway that dispatches notifications to [`PushProvider`]s.
+///
+/// Each provider is registered for an `app_id`. The notifications sent to devices with an
+/// `app_id` for which no provider is registered are rejected.
+#[derive(Default)]
+pub struct PushGateway {
+    /// The providers, by `app_id`.
+    providers: BTreeMap<String, Box<dyn PushProvider>>,
+}
+
+impl PushGateway {
+    /// Creates a new `PushGateway` without any provider.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the given provider for the given `app_id`.
+    ///
+    /// Replaces the provider that was previously registered for the same `app_id`, if any.
+    pub fn with_provider(
+        mut self,
+        app_id: impl Into<String>,
+        provider: impl PushProvider + 'static,
+    ) -> Self {
+        self.providers.insert(app_id.into(), Box::new(provider));
+        self
+    }
+
+    /// Whether a provider is registered for the given `app_id`.
+    pub fn has_provider(&self, app_id: &str) -> bool {
+        self.providers.contains_key(app_id)
+    }
+
+    /// Handle the given request to the `send_event_notification` endpoint.
+    ///
+    /// The notification is sent to each device with the provider registered for its `app_id`, in
+    /// order. The response contains the pushkeys of the devices that don't have a provider and of
+    /// the devices for which the provider returned [`PushError::RejectedPushkey`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the notification is invalid, in which case the gateway should respond
+    /// with a `400 Bad Request` status.
+    ///
+    /// Also returns an error if a provider failed to deliver the notification for a reason other
+    /// than a rejected pushkey, in which case the gateway should respond with a `5xx` status so the
+    /// homeserver retries the request later. The notification is still sent to the other devices,
+    /// so providers might receive the same notification again, which can be detected with its
+    /// `event_id`.
+    pub async fn dispatch(&self, request: Request) -> Result<Response, DispatchError> {
+        let notification = request.notification;
+        validate_notification(&notification)?;
+
+        let mut rejected = Vec::new();
+        let mut error = None;
+
+        for device in &notification.devices {
+            let Some(provider) = self.providers.get(&device.app_id) else {
+                rejected.push(device.pushkey.clone());
+                continue;
+            };
+
+            match provider.send_notification(&notification, device).await {
+                Ok(()) => {}
+                Err(PushError::RejectedPushkey) => rejected.push(device.pushkey.clone()),
+                Err(PushError::Other(e)) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match error {
+            Some(error) => Err(DispatchError::Provider(error)),
+            None => Ok(Response::new(rejected)),
+        }
+    }
+}
+
+impl fmt::Debug for PushGateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushGateway").field("app_ids", &self.providers.keys()).finish()
+    }
+}
+
+/// Check that the given notification is valid according to the specification.
+pub fn validate_notification(notification: &Notification) -> Result<(), InvalidNotificationError> {
+    if notification.devices.is_empty() {
+        return Err(InvalidNotificationError::NoDevices);
+    }
+
+    if notification.event_id.is_some() && notification.room_id.is_none() {
+        return Err(InvalidNotificationError::MissingRoomId);
+    }
+
+    for device in &notification.devices {
+        if device.app_id.chars().count() > MAX_APP_ID_LENGTH {
+            return Err(InvalidNotificationError::AppIdTooLong);
+        }
+
+        if device.pushkey.len() > MAX_PUSHKEY_LENGTH {
+            return Err(InvalidNotificationError::PushkeyTooLong);
+        }
+    }
+
+    Ok(())
+}
+
+/// An error encountered when validating a [`Notification`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InvalidNotificationError {
+    /// The notification doesn't have any device.
+    NoDevices,
+
+    /// The notification is about an event, but doesn't have a room ID.
+    MissingRoomId,
+
+    /// The `app_id` of a device is longer than 64 characters.
+    AppIdTooLong,
+
+    /// The `pushkey` of a device is longer than 512 bytes.
+    PushkeyTooLong,
+}
+
+impl fmt::Display for InvalidNotificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::NoDevices => "the notification doesn't have any device",
+            Self::MissingRoomId => "the notification has an event ID but no room ID",
+            Self::AppIdTooLong => "the app_id of a device is longer than 64 characters",
+            Self::PushkeyTooLong => "the pushkey of a device is longer than 512 bytes",
+        };
+
+        f.write_str(msg)
+    }
+}
+
+impl StdError for InvalidNotificationError {}
+
+/// An error returned by [`PushGateway::dispatch()`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DispatchError {
+    /// The notification is invalid.
+    InvalidNotification(InvalidNotificationError),
+
+    /// A provider failed to deliver the notification.
+    Provider(Box<dyn StdError + Send + Sync>),
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidNotification(error) => write!(f, "invalid notification: {error}"),
+            Self::Provider(error) => write!(f, "failed to deliver the notification: {error}"),
+        }
+    }
+}
+
+impl StdError for DispatchError {}
+
+impl From<InvalidNotificationError> for DispatchError {
+    fn from(error: InvalidNotificationError) -> Self {
+        Self::InvalidNotification(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{ready, Future},
+        pin::Pin,
+        sync::{Arc, Mutex},
+    };
+
+    use assert_matches2::assert_matches;
+    use ruma_common::{owned_event_id, owned_room_id};
+
+    use super::{DispatchError, InvalidNotificationError, PushError, PushGateway, PushProvider};
+    use crate::send_event_notification::v1::{Device, Notification, Request};
+
+    /// A provider that rejects the pushkeys starting with `invalid` and fails for the pushkeys
+    /// starting with `fail`.
+    #[derive(Clone, Default)]
+    struct TestProvider {
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl PushProvider for TestProvider {
+        fn send_notification<'a>(
+            &'a self,
+            _notification: &'a Notification,
+            device: &'a Device,
+        ) -> Pin<Box<dyn Future<Output = Result<(), PushError>> + Send + 'a>> {
+            let result = if device.pushkey.starts_with("invalid") {
+                Err(PushError::RejectedPushkey)
+            } else if device.pushkey.starts_with("fail") {
+                Err(PushError::Other("service unavailable".into()))
+            } else {
+                self.sent.lock().unwrap().push(device.pushkey.clone());
+                Ok(())
+            };
+
+            Box::pin(ready(result))
+        }
+    }
+
+    fn request(devices: &[(&str, &str)]) -> Request {
+        let devices = devices
+            .iter()
+            .map(|(app_id, pushkey)| Device::new((*app_id).to_owned(), (*pushkey).to_owned()))
+            .collect();
+        let mut notification = Notification::new(devices);
+        notification.event_id = Some(owned_event_id!("$event:localhost"));
+        notification.room_id = Some(owned_room_id!("!room:localhost"));
+
+        Request::new(notification)
+    }
+
+    #[tokio::test]
+    async fn dispatch_to_providers() {
+        let provider = TestProvider::default();
+        let gateway = PushGateway::new().with_provider("org.example.app", provider.clone());
+
+        let response = gateway
+            .dispatch(request(&[
+                ("org.example.app", "valid1"),
+                ("org.example.app", "invalid"),
+                ("org.example.unknown", "valid2"),
+                ("org.example.app", "valid3"),
+            ]))
+            .await
+            .unwrap();
+
+        assert_eq!(response.rejected, ["invalid", "valid2"]);
+        assert_eq!(*provider.sent.lock().unwrap(), ["valid1", "valid3"]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_provider_error() {
+        let provider = TestProvider::default();
+        let gateway = PushGateway::new().with_provider("org.example.app", provider.clone());
+
+        let result = gateway
+            .dispatch(request(&[("org.example.app", "fail"), ("org.example.app", "valid")]))
+            .await;
+
+        assert_matches!(result, Err(DispatchError::Provider(_)));
+        assert_eq!(*provider.sent.lock().unwrap(), ["valid"]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_invalid_notification() {
+        let gateway = PushGateway::new();
+
+        assert_matches!(
+            gateway.dispatch(request(&[])).await,
+            Err(DispatchError::InvalidNotification(InvalidNotificationError::NoDevices))
+        );
+
+        let long_app_id = "a".repeat(65);
+        assert_matches!(
+            gateway.dispatch(request(&[(&long_app_id, "pushkey")])).await,
+            Err(DispatchError::InvalidNotification(InvalidNotificationError::AppIdTooLong))
+        );
+
+        let mut request = request(&[("org.example.app", "pushkey")]);
+        request.notification.room_id = None;
+        assert_matches!(
+            gateway.dispatch(request).await,
+            Err(DispatchError::InvalidNotification(InvalidNotificationError::MissingRoomId))
+        );
+    }
+}
@@ -9,6 +9,8 @@
 
 use std::fmt;
 
+#[cfg(feature = "server")]
+pub mod gateway;
 pub mod send_event_notification;
 
 // Wrapper around `Box<str>` that cannot be used in a meaningful way outside of
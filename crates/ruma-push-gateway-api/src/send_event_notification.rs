@@ -11,11 +11,11 @@ pub mod v1 {
     use ruma_common::{
         api::{request, response, Metadata},
         metadata,
-        push::{PushFormat, Tweak},
-        serde::StringEnum,
-        OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, SecondsSinceUnixEpoch,
+        push::{Action, PushFormat, Tweak},
+        serde::{Raw, StringEnum},
+        OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, SecondsSinceUnixEpoch, UserId,
     };
-    use ruma_events::TimelineEventType;
+    use ruma_events::{push_rules::UnreadCounts, TimelineEventType};
     use serde::{Deserialize, Serialize};
     use serde_json::value::RawValue as RawJsonValue;
     #[cfg(feature = "unstable-unspecified")]
@@ -139,6 +139,77 @@ pub mod v1 {
         pub fn new(devices: Vec<Device>) -> Self {
             Notification { devices, ..Default::default() }
         }
+
+        /// Create a new notification about the given event for the given device.
+        ///
+        /// This is meant to be used by homeservers to build the notification to send to the push
+        /// gateway of an HTTP pusher. `device.data` can be constructed from the
+        /// [`HttpPusherData`](ruma_common::push::HttpPusherData) of the pusher with `::from()` /
+        /// `.into()`, and the tweaks of the device are set from the given push `actions`.
+        ///
+        /// If the format of the device is [`PushFormat::EventIdOnly`], only the `event_id`,
+        /// `room_id` and `prio` fields are set. Otherwise, the `type`, `sender`, `content` and
+        /// `user_is_target` fields are also set from the event. The fields that are not part of
+        /// the event, like `sender_display_name`, `room_name` or `counts`, must be set
+        /// separately.
+        ///
+        /// The priority of the notification is high if the event is encrypted, or if the actions
+        /// contain a highlight or a sound tweak. Otherwise, it is low.
+        ///
+        /// Returns an error if the event doesn't have the `event_id`, `type` or `sender` fields.
+        pub fn for_event<T>(
+            event: &Raw<T>,
+            room_id: OwnedRoomId,
+            user_id: &UserId,
+            actions: &[Action],
+            mut device: Device,
+        ) -> serde_json::Result<Self> {
+            #[derive(Deserialize)]
+            struct EventFields {
+                event_id: OwnedEventId,
+                #[serde(rename = "type")]
+                event_type: TimelineEventType,
+                sender: OwnedUserId,
+                state_key: Option<String>,
+                content: Option<Box<RawJsonValue>>,
+            }
+
+            let event = event.deserialize_as::<EventFields>()?;
+
+            device.tweaks = actions
+                .iter()
+                .filter_map(|action| match action {
+                    Action::SetTweak(tweak) => Some(tweak.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            let is_high_priority = event.event_type == TimelineEventType::RoomEncrypted
+                || actions.iter().any(|action| action.is_highlight() || action.sound().is_some());
+            let prio = if is_high_priority {
+                NotificationPriority::High
+            } else {
+                NotificationPriority::Low
+            };
+
+            let mut notification = Notification {
+                event_id: Some(event.event_id),
+                room_id: Some(room_id),
+                prio,
+                ..Notification::default()
+            };
+
+            if device.data.format != Some(PushFormat::EventIdOnly) {
+                notification.user_is_target = event.event_type == TimelineEventType::RoomMember
+                    && event.state_key.as_deref() == Some(user_id.as_str());
+                notification.event_type = Some(event.event_type);
+                notification.sender = Some(event.sender);
+                notification.content = event.content;
+            }
+
+            notification.devices = vec![device];
+            Ok(notification)
+        }
     }
 
     /// Type for passing information about notification priority.
@@ -191,6 +262,17 @@ pub mod v1 {
         }
     }
 
+    impl From<UnreadCounts> for NotificationCounts {
+        /// Creates `NotificationCounts` with the number of notifications of the given
+        /// `UnreadCounts` as the number of unread messages.
+        ///
+        /// The counts of all the rooms the user is a member of must be summed before the
+        /// conversion.
+        fn from(counts: UnreadCounts) -> Self {
+            Self::new(counts.notifications, uint!(0))
+        }
+    }
+
     /// Type for passing information about devices.
     #[derive(Clone, Debug, Deserialize, Serialize)]
     #[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
@@ -377,7 +459,9 @@ pub mod v1 {
         use js_int::uint;
         use ruma_common::{
             owned_event_id, owned_room_alias_id, owned_room_id, owned_user_id,
-            SecondsSinceUnixEpoch,
+            push::{Action, HttpPusherData, PushFormat},
+            serde::Raw,
+            user_id, SecondsSinceUnixEpoch,
         };
         use ruma_events::TimelineEventType;
         use serde_json::{
@@ -454,5 +538,78 @@ pub mod v1 {
 
             assert_eq!(expected, to_json_value(notice).unwrap());
         }
+
+        #[test]
+        fn notification_for_event() {
+            let event = Raw::new(&json!({
+                "type": "m.room.member",
+                "event_id": "$3957tyerfgewrf384",
+                "sender": "@alice:example.com",
+                "state_key": "@bob:example.com",
+                "origin_server_ts": 1,
+                "content": { "membership": "invite" },
+            }))
+            .unwrap();
+            let actions = [Action::Notify, Action::SetTweak(Tweak::Sound("default".into()))];
+            let device = Device::new("org.example.app".into(), "pushkey".into());
+
+            let notification = Notification::for_event(
+                &event,
+                owned_room_id!("!slw48wfj34rtnrf:example.com"),
+                user_id!("@bob:example.com"),
+                &actions,
+                device.clone(),
+            )
+            .unwrap();
+
+            assert_eq!(
+                to_json_value(notification).unwrap(),
+                json!({
+                    "event_id": "$3957tyerfgewrf384",
+                    "room_id": "!slw48wfj34rtnrf:example.com",
+                    "type": "m.room.member",
+                    "sender": "@alice:example.com",
+                    "user_is_target": true,
+                    "content": { "membership": "invite" },
+                    "devices": [
+                        {
+                            "app_id": "org.example.app",
+                            "pushkey": "pushkey",
+                            "tweaks": { "sound": "default" },
+                        },
+                    ],
+                })
+            );
+
+            let mut data =
+                HttpPusherData::new("https://push.example.com/_matrix/push/v1/notify".into());
+            data.format = Some(PushFormat::EventIdOnly);
+            let device = Device { data: data.into(), ..device };
+
+            let notification = Notification::for_event(
+                &event,
+                owned_room_id!("!slw48wfj34rtnrf:example.com"),
+                user_id!("@carl:example.com"),
+                &[Action::Notify],
+                device,
+            )
+            .unwrap();
+
+            assert_eq!(
+                to_json_value(notification).unwrap(),
+                json!({
+                    "event_id": "$3957tyerfgewrf384",
+                    "room_id": "!slw48wfj34rtnrf:example.com",
+                    "prio": "low",
+                    "devices": [
+                        {
+                            "app_id": "org.example.app",
+                            "pushkey": "pushkey",
+                            "data": { "format": "event_id_only" },
+                        },
+                    ],
+                })
+            );
+        }
     }
 }
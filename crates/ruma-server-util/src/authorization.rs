@@ -1,7 +1,14 @@
 //! Common types for implementing federation authorization.
 
+use std::collections::BTreeMap;
+
 use headers::{authorization::Credentials, HeaderValue};
-use ruma_common::{OwnedServerName, OwnedServerSigningKeyId};
+use http::header::AUTHORIZATION;
+use ruma_common::{
+    CanonicalJsonObject, CanonicalJsonValue, OwnedServerName, OwnedServerSigningKeyId, ServerName,
+};
+use ruma_signatures::{Ed25519KeyPair, KeyPair, PublicKeyMap};
+use thiserror::Error;
 use tracing::debug;
 use yap::{IntoTokens, TokenLocation, Tokens};
 
@@ -11,6 +18,7 @@ use yap::{IntoTokens, TokenLocation, Tokens};
 /// when using a web framework that supports typed headers.
 ///
 /// [spec]: https://spec.matrix.org/latest/server-server-api/#request-authentication
+#[derive(Clone, Debug)]
 #[non_exhaustive]
 pub struct XMatrix {
     /// The server name of the sending server.
@@ -39,6 +47,166 @@ impl XMatrix {
     }
 }
 
+/// Sign the given federation request and add the resulting `Authorization` header of scheme
+/// `X-Matrix` to it.
+///
+/// The `origin` is the name of the sending server, which owns the `key_pair`, and the
+/// `destination` is the name of the receiving server. The body of the request, if any, must be
+/// JSON.
+pub fn sign_request(
+    request: &mut http::Request<Vec<u8>>,
+    origin: &ServerName,
+    destination: &ServerName,
+    key_pair: &Ed25519KeyPair,
+) -> Result<(), SignRequestError> {
+    let object = request_json(request, origin, destination).map_err(SignRequestError::Body)?;
+    let json = ruma_signatures::canonical_json(&object).map_err(SignRequestError::Signature)?;
+    let signature = key_pair.sign(json.as_bytes());
+
+    let key = signature.id().try_into().map_err(|_| {
+        SignRequestError::Signature(ruma_signatures::Error::InvalidVersion(
+            signature.version().to_owned(),
+        ))
+    })?;
+    let credentials =
+        XMatrix::new(origin.to_owned(), Some(destination.to_owned()), key, signature.base64());
+
+    request.headers_mut().insert(AUTHORIZATION, credentials.encode());
+    Ok(())
+}
+
+/// Verify the `Authorization` header of scheme `X-Matrix` of the given incoming federation
+/// request.
+///
+/// The `destination` is the name of the receiving server, and the `public_key_map` must contain
+/// the public keys of the sending server. Returns the parsed header if the signature is valid.
+pub fn verify_request<T: AsRef<[u8]>>(
+    request: &http::Request<T>,
+    destination: &ServerName,
+    public_key_map: &PublicKeyMap,
+) -> Result<XMatrix, VerifyRequestError> {
+    let credentials = request
+        .headers()
+        .get_all(AUTHORIZATION)
+        .iter()
+        .find_map(XMatrix::decode)
+        .ok_or(VerifyRequestError::MissingAuthorization)?;
+
+    if let Some(header_destination) = &credentials.destination {
+        if header_destination != destination {
+            return Err(VerifyRequestError::DestinationMismatch {
+                expected: destination.to_owned(),
+                found: header_destination.clone(),
+            });
+        }
+    }
+
+    let public_key = public_key_map
+        .get(credentials.origin.as_str())
+        .and_then(|keys| keys.get(credentials.key.as_str()))
+        .ok_or_else(|| VerifyRequestError::UnknownKey {
+            origin: credentials.origin.clone(),
+            key: credentials.key.clone(),
+        })?;
+
+    let mut object = request_json(request, &credentials.origin, destination)
+        .map_err(VerifyRequestError::Body)?;
+    object.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object(BTreeMap::from([(
+            credentials.origin.as_str().to_owned(),
+            CanonicalJsonValue::Object(BTreeMap::from([(
+                credentials.key.as_str().to_owned(),
+                CanonicalJsonValue::String(credentials.sig.clone()),
+            )])),
+        )])),
+    );
+
+    // Only use the key from the header, so the other keys of the origin are not required to have
+    // signed the request.
+    let public_key_map = BTreeMap::from([(
+        credentials.origin.as_str().to_owned(),
+        BTreeMap::from([(credentials.key.as_str().to_owned(), public_key.clone())]),
+    )]);
+    ruma_signatures::verify_json(&public_key_map, &object)
+        .map_err(VerifyRequestError::Signature)?;
+
+    Ok(credentials)
+}
+
+/// Construct the JSON object that is signed to authenticate a federation request.
+fn request_json<T: AsRef<[u8]>>(
+    request: &http::Request<T>,
+    origin: &ServerName,
+    destination: &ServerName,
+) -> Result<CanonicalJsonObject, serde_json::Error> {
+    let uri = request.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+
+    let mut object = BTreeMap::from([
+        ("method".to_owned(), CanonicalJsonValue::String(request.method().as_str().to_owned())),
+        ("uri".to_owned(), CanonicalJsonValue::String(uri.to_owned())),
+        ("origin".to_owned(), CanonicalJsonValue::String(origin.as_str().to_owned())),
+        ("destination".to_owned(), CanonicalJsonValue::String(destination.as_str().to_owned())),
+    ]);
+
+    let body = request.body().as_ref();
+    if !body.is_empty() {
+        object.insert("content".to_owned(), serde_json::from_slice(body)?);
+    }
+
+    Ok(object)
+}
+
+/// An error encountered when signing a federation request.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SignRequestError {
+    /// The body of the request is not valid canonical JSON.
+    #[error("invalid request body: {0}")]
+    Body(serde_json::Error),
+
+    /// The request could not be signed.
+    #[error("failed to sign request: {0}")]
+    Signature(ruma_signatures::Error),
+}
+
+/// An error encountered when verifying the signature of a federation request.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum VerifyRequestError {
+    /// The request doesn't contain a valid `Authorization` header of scheme `X-Matrix`.
+    #[error("missing or invalid X-Matrix Authorization header")]
+    MissingAuthorization,
+
+    /// The destination in the `Authorization` header is not the receiving server.
+    #[error("request is meant for `{found}`, not `{expected}`")]
+    DestinationMismatch {
+        /// The name of the receiving server.
+        expected: OwnedServerName,
+
+        /// The destination in the `Authorization` header.
+        found: OwnedServerName,
+    },
+
+    /// The key used to sign the request is not in the public key map.
+    #[error("unknown key `{key}` of `{origin}`")]
+    UnknownKey {
+        /// The name of the sending server.
+        origin: OwnedServerName,
+
+        /// The ID of the key in the `Authorization` header.
+        key: OwnedServerSigningKeyId,
+    },
+
+    /// The body of the request is not valid canonical JSON.
+    #[error("invalid request body: {0}")]
+    Body(serde_json::Error),
+
+    /// The signature of the request is invalid.
+    #[error("invalid signature: {0}")]
+    Signature(ruma_signatures::Error),
+}
+
 fn parse_token<'a>(tokens: &mut impl Tokens<Item = &'a u8>) -> Option<Vec<u8>> {
     tokens.optional(|t| {
         let token: Vec<u8> = t.tokens_while(|c| is_tchar(**c)).copied().collect();
@@ -239,10 +407,117 @@ impl Credentials for XMatrix {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
+    use assert_matches2::assert_matches;
     use headers::{authorization::Credentials, HeaderValue};
-    use ruma_common::OwnedServerName;
+    use ruma_common::{serde::Base64, server_name, OwnedServerName};
+    use ruma_signatures::{Ed25519KeyPair, PublicKeyMap};
+
+    use super::{sign_request, verify_request, VerifyRequestError, XMatrix};
+
+    fn key_pair() -> Ed25519KeyPair {
+        let document = Ed25519KeyPair::generate().unwrap();
+        Ed25519KeyPair::from_der(&document, "1".to_owned()).unwrap()
+    }
+
+    fn public_key_map(key_pair: &Ed25519KeyPair) -> PublicKeyMap {
+        BTreeMap::from([(
+            "origin.hs.example.com".to_owned(),
+            BTreeMap::from([("ed25519:1".to_owned(), Base64::new(key_pair.public_key().to_vec()))]),
+        )])
+    }
+
+    fn signed_request(key_pair: &Ed25519KeyPair) -> http::Request<Vec<u8>> {
+        let mut request = http::Request::builder()
+            .method("PUT")
+            .uri("https://destination.hs.example.com/_matrix/federation/v1/send/1?foo=bar")
+            .body(br#"{ "pdus": [], "origin": "origin.hs.example.com" }"#.to_vec())
+            .unwrap();
+        sign_request(
+            &mut request,
+            server_name!("origin.hs.example.com"),
+            server_name!("destination.hs.example.com"),
+            key_pair,
+        )
+        .unwrap();
+        request
+    }
 
-    use super::XMatrix;
+    #[test]
+    fn sign_and_verify_request() {
+        let key_pair = key_pair();
+        let request = signed_request(&key_pair);
+
+        let credentials = verify_request(
+            &request,
+            server_name!("destination.hs.example.com"),
+            &public_key_map(&key_pair),
+        )
+        .unwrap();
+        assert_eq!(credentials.origin, "origin.hs.example.com");
+        assert_eq!(credentials.destination.unwrap(), "destination.hs.example.com");
+        assert_eq!(credentials.key, "ed25519:1");
+
+        // A request without body.
+        let mut request = http::Request::builder()
+            .uri("/_matrix/federation/v1/version")
+            .body(Vec::new())
+            .unwrap();
+        sign_request(
+            &mut request,
+            server_name!("origin.hs.example.com"),
+            server_name!("destination.hs.example.com"),
+            &key_pair,
+        )
+        .unwrap();
+        verify_request(
+            &request,
+            server_name!("destination.hs.example.com"),
+            &public_key_map(&key_pair),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn verify_request_errors() {
+        let key_pair = key_pair();
+        let public_key_map = public_key_map(&key_pair);
+        let request = signed_request(&key_pair);
+
+        assert_matches!(
+            verify_request(&request, server_name!("other.hs.example.com"), &public_key_map),
+            Err(VerifyRequestError::DestinationMismatch { expected, found })
+        );
+        assert_eq!(expected, "other.hs.example.com");
+        assert_eq!(found, "destination.hs.example.com");
+
+        let other_key_pair =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "2".to_owned()).unwrap();
+        assert_matches!(
+            verify_request(
+                &signed_request(&other_key_pair),
+                server_name!("destination.hs.example.com"),
+                &public_key_map,
+            ),
+            Err(VerifyRequestError::UnknownKey { origin, key })
+        );
+        assert_eq!(origin, "origin.hs.example.com");
+        assert_eq!(key, "ed25519:2");
+
+        let (parts, _) = request.into_parts();
+        let tampered = http::Request::from_parts(parts, br#"{ "pdus": [] }"#.to_vec());
+        assert_matches!(
+            verify_request(&tampered, server_name!("destination.hs.example.com"), &public_key_map),
+            Err(VerifyRequestError::Signature(_))
+        );
+
+        let unsigned = http::Request::builder().body(Vec::new()).unwrap();
+        assert_matches!(
+            verify_request(&unsigned, server_name!("destination.hs.example.com"), &public_key_map),
+            Err(VerifyRequestError::MissingAuthorization)
+        );
+    }
 
     #[test]
     fn xmatrix_auth_pre_1_3() {
@@ -0,0 +1,386 @@
+//! Helpers for implementing the [server-side key backups] endpoints.
+//!
+//! [server-side key backups]: https://spec.matrix.org/latest/client-server-api/#server-side-key-backups
+
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use js_int::UInt;
+use ruma_client_api::backup::{BackupAlgorithm, KeyBackupData, RoomKeyBackup};
+use ruma_common::{
+    serde::{base64::Standard, Base64, Raw},
+    CanonicalJsonObject, CanonicalJsonValue, OwnedRoomId, UserId,
+};
+use ruma_signatures::{PublicKeyMap, PublicKeySet};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// The length of a Curve25519 public key, in bytes.
+const CURVE25519_PUBLIC_KEY_LENGTH: usize = 32;
+
+/// Whether the given incoming key should replace the key that is already stored for the same
+/// session, according to the rules of the spec.
+///
+/// The keys are compared as follows:
+///
+/// 1. The key that has `is_verified` set to `true` is kept.
+/// 2. Otherwise, the key with the lower `first_message_index` is kept.
+/// 3. Otherwise, the key with the lower `forwarded_count` is kept.
+///
+/// If both keys are equivalent, the stored key is kept.
+pub fn should_replace_backup_key(stored: &KeyBackupData, incoming: &KeyBackupData) -> bool {
+    fn rank(key: &KeyBackupData) -> (bool, Reverse<UInt>, Reverse<UInt>) {
+        (key.is_verified, Reverse(key.first_message_index), Reverse(key.forwarded_count))
+    }
+
+    rank(incoming) > rank(stored)
+}
+
+/// Verify the `auth_data` of a backup version, as received by the [`create_backup_version`] or
+/// [`update_backup_version`] endpoints.
+///
+/// The `public_keys` are the public Ed25519 keys of the user that are trusted to sign the backup,
+/// like the keys of their devices or their master cross-signing key, by key ID.
+///
+/// The signatures of the user by keys that are not in `public_keys` are ignored, since they might
+/// have been made by devices that are not known to the homeserver anymore. Signatures of other
+/// users are ignored too.
+///
+/// Returns the deserialized algorithm on success.
+///
+/// # Errors
+///
+/// Returns an error if the algorithm is not supported, if the `auth_data` is invalid, if it doesn't
+/// have any signature by one of the given keys, or if one of those signatures is invalid.
+///
+/// [`create_backup_version`]: ruma_client_api::backup::create_backup_version
+/// [`update_backup_version`]: ruma_client_api::backup::update_backup_version
+pub fn verify_backup_auth_data(
+    algorithm: &Raw<BackupAlgorithm>,
+    user_id: &UserId,
+    public_keys: &PublicKeySet,
+) -> Result<BackupAlgorithm, VerifyAuthDataError> {
+    #[derive(Deserialize)]
+    struct RawBackupAlgorithm {
+        algorithm: String,
+        auth_data: CanonicalJsonObject,
+    }
+
+    let RawBackupAlgorithm { algorithm: algorithm_name, mut auth_data } =
+        algorithm.deserialize_as().map_err(VerifyAuthDataError::Json)?;
+
+    if algorithm_name != "m.megolm_backup.v1.curve25519-aes-sha2" {
+        return Err(VerifyAuthDataError::UnsupportedAlgorithm(algorithm_name));
+    }
+
+    let algorithm = algorithm.deserialize().map_err(VerifyAuthDataError::Json)?;
+    let BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 { public_key, signatures } = &algorithm
+    else {
+        return Err(VerifyAuthDataError::UnsupportedAlgorithm(algorithm_name));
+    };
+
+    if public_key.as_bytes().len() != CURVE25519_PUBLIC_KEY_LENGTH {
+        return Err(VerifyAuthDataError::InvalidPublicKey);
+    }
+
+    // Only keep the signatures that can be verified.
+    let trusted_signatures: CanonicalJsonObject = signatures
+        .get(user_id)
+        .into_iter()
+        .flatten()
+        .filter(|(key_id, _)| public_keys.contains_key(key_id.as_str()))
+        .map(|(key_id, signature)| {
+            (key_id.to_string(), CanonicalJsonValue::String(signature.clone()))
+        })
+        .collect();
+
+    if trusted_signatures.is_empty() {
+        return Err(VerifyAuthDataError::NoTrustedSignature);
+    }
+
+    auth_data.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object(BTreeMap::from([(
+            user_id.to_string(),
+            CanonicalJsonValue::Object(trusted_signatures),
+        )])),
+    );
+
+    let public_key_map = PublicKeyMap::from([(user_id.to_string(), public_keys.clone())]);
+    ruma_signatures::verify_json(&public_key_map, &auth_data)
+        .map_err(VerifyAuthDataError::Signature)?;
+
+    Ok(algorithm)
+}
+
+/// An error encountered when verifying the `auth_data` of a backup version.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum VerifyAuthDataError {
+    /// The algorithm or its `auth_data` could not be deserialized.
+    #[error("invalid backup algorithm: {0}")]
+    Json(serde_json::Error),
+
+    /// The algorithm of the backup is not supported.
+    #[error("unsupported backup algorithm `{0}`")]
+    UnsupportedAlgorithm(String),
+
+    /// The public key of the backup is not a Curve25519 public key.
+    #[error("invalid backup public key")]
+    InvalidPublicKey,
+
+    /// The `auth_data` isn't signed by any of the trusted keys of the user.
+    #[error("no signature by a trusted key")]
+    NoTrustedSignature,
+
+    /// A signature by a trusted key is invalid.
+    #[error("invalid signature: {0}")]
+    Signature(ruma_signatures::Error),
+}
+
+/// The `etag` and `count` of the keys stored in a backup version, as returned by the endpoints
+/// that get information about a backup version or that modify its keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BackupKeysSummary {
+    /// An opaque string representing the keys stored in the backup.
+    ///
+    /// It changes whenever the stored keys change.
+    pub etag: String,
+
+    /// The number of keys stored in the backup.
+    pub count: UInt,
+}
+
+impl BackupKeysSummary {
+    /// Compute the `BackupKeysSummary` of the given keys, by room ID.
+    ///
+    /// The `etag` is computed by hashing the room IDs, the session IDs and the JSON of the session
+    /// data, so the same keys always produce the same `etag`, as long as they are stored with the
+    /// same serialization.
+    pub fn new(rooms: &BTreeMap<OwnedRoomId, RoomKeyBackup>) -> Self {
+        let mut sha256 = Sha256::new();
+        let mut count = 0_u64;
+
+        for (room_id, room) in rooms {
+            for (session_id, key_data) in &room.sessions {
+                for part in [room_id.as_str(), session_id, key_data.json().get()] {
+                    // Prefix each part with its length so the parts can't be confused.
+                    sha256.update((part.len() as u64).to_be_bytes());
+                    sha256.update(part);
+                }
+
+                count += 1;
+            }
+        }
+
+        Self {
+            etag: Base64::<Standard>::new(sha256.finalize().to_vec()).encode(),
+            count: UInt::new_saturating(count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use assert_matches2::assert_matches;
+    use js_int::{uint, UInt};
+    use ruma_client_api::backup::{
+        BackupAlgorithm, EncryptedSessionDataInit, KeyBackupData, KeyBackupDataInit, RoomKeyBackup,
+    };
+    use ruma_common::{
+        owned_room_id,
+        serde::{base64::Standard, Base64, Raw},
+        user_id,
+    };
+    use ruma_signatures::{Ed25519KeyPair, PublicKeySet};
+    use serde_json::json;
+
+    use super::{
+        should_replace_backup_key, verify_backup_auth_data, BackupKeysSummary, VerifyAuthDataError,
+    };
+
+    fn key_data(
+        is_verified: bool,
+        first_message_index: UInt,
+        forwarded_count: UInt,
+    ) -> KeyBackupData {
+        KeyBackupDataInit {
+            first_message_index,
+            forwarded_count,
+            is_verified,
+            session_data: EncryptedSessionDataInit {
+                ephemeral: Base64::new(b"ephemeral".to_vec()),
+                ciphertext: Base64::new(b"ciphertext".to_vec()),
+                mac: Base64::new(b"mac".to_vec()),
+            }
+            .into(),
+        }
+        .into()
+    }
+
+    fn key_pair(version: &str) -> Ed25519KeyPair {
+        let document = Ed25519KeyPair::generate().unwrap();
+        Ed25519KeyPair::from_der(&document, version.to_owned()).unwrap()
+    }
+
+    fn public_keys(key_pairs: &[&Ed25519KeyPair]) -> PublicKeySet {
+        key_pairs
+            .iter()
+            .map(|key_pair| {
+                (
+                    format!("ed25519:{}", key_pair.version()),
+                    Base64::new(key_pair.public_key().to_vec()),
+                )
+            })
+            .collect()
+    }
+
+    fn signed_algorithm(key_pairs: &[&Ed25519KeyPair]) -> Raw<BackupAlgorithm> {
+        let mut auth_data = serde_json::from_value(json!({
+            "public_key": Base64::<Standard>::new(vec![1; 32]),
+        }))
+        .unwrap();
+        for key_pair in key_pairs {
+            ruma_signatures::sign_json("@alice:localhost", *key_pair, &mut auth_data).unwrap();
+        }
+
+        Raw::new(&json!({
+            "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+            "auth_data": auth_data,
+        }))
+        .unwrap()
+        .cast()
+    }
+
+    #[test]
+    fn replacement_rules() {
+        let verified = key_data(true, uint!(10), uint!(10));
+        let unverified = key_data(false, uint!(0), uint!(0));
+        assert!(should_replace_backup_key(&unverified, &verified));
+        assert!(!should_replace_backup_key(&verified, &unverified));
+
+        let lower_index = key_data(false, uint!(0), uint!(5));
+        let higher_index = key_data(false, uint!(5), uint!(0));
+        assert!(should_replace_backup_key(&higher_index, &lower_index));
+        assert!(!should_replace_backup_key(&lower_index, &higher_index));
+
+        let less_forwarded = key_data(false, uint!(5), uint!(0));
+        let more_forwarded = key_data(false, uint!(5), uint!(1));
+        assert!(should_replace_backup_key(&more_forwarded, &less_forwarded));
+        assert!(!should_replace_backup_key(&less_forwarded, &more_forwarded));
+
+        // Equivalent keys don't replace the stored key.
+        assert!(!should_replace_backup_key(&verified, &verified.clone()));
+    }
+
+    #[test]
+    fn verify_auth_data() {
+        let device_key = key_pair("DEVICE");
+        let unknown_key = key_pair("UNKNOWN");
+        let algorithm = signed_algorithm(&[&device_key, &unknown_key]);
+
+        let algorithm = verify_backup_auth_data(
+            &algorithm,
+            user_id!("@alice:localhost"),
+            &public_keys(&[&device_key]),
+        )
+        .unwrap();
+        assert_matches!(
+            algorithm,
+            BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 { signatures, .. }
+        );
+        assert_eq!(signatures[user_id!("@alice:localhost")].len(), 2);
+    }
+
+    #[test]
+    fn verify_auth_data_without_trusted_signature() {
+        let device_key = key_pair("DEVICE");
+        let algorithm = signed_algorithm(&[&key_pair("UNKNOWN")]);
+
+        assert_matches!(
+            verify_backup_auth_data(
+                &algorithm,
+                user_id!("@alice:localhost"),
+                &public_keys(&[&device_key])
+            ),
+            Err(VerifyAuthDataError::NoTrustedSignature)
+        );
+
+        // Signatures of other users are ignored.
+        assert_matches!(
+            verify_backup_auth_data(
+                &signed_algorithm(&[&device_key]),
+                user_id!("@bob:localhost"),
+                &public_keys(&[&device_key])
+            ),
+            Err(VerifyAuthDataError::NoTrustedSignature)
+        );
+    }
+
+    #[test]
+    fn verify_auth_data_with_invalid_signature() {
+        let device_key = key_pair("DEVICE");
+        let algorithm = signed_algorithm(&[&device_key]);
+
+        // Change the signed data.
+        let mut json: serde_json::Value = algorithm.deserialize_as().unwrap();
+        json["auth_data"]["public_key"] = Base64::<Standard>::new(vec![2; 32]).encode().into();
+        let algorithm = Raw::new(&json).unwrap().cast();
+
+        assert_matches!(
+            verify_backup_auth_data(
+                &algorithm,
+                user_id!("@alice:localhost"),
+                &public_keys(&[&device_key])
+            ),
+            Err(VerifyAuthDataError::Signature(_))
+        );
+    }
+
+    #[test]
+    fn verify_auth_data_with_unsupported_algorithm() {
+        let algorithm = Raw::new(&json!({
+            "algorithm": "org.example.backup",
+            "auth_data": {},
+        }))
+        .unwrap()
+        .cast();
+
+        let error =
+            verify_backup_auth_data(&algorithm, user_id!("@alice:localhost"), &PublicKeySet::new())
+                .unwrap_err();
+        assert_matches!(error, VerifyAuthDataError::UnsupportedAlgorithm(algorithm));
+        assert_eq!(algorithm, "org.example.backup");
+    }
+
+    #[test]
+    fn keys_summary() {
+        let empty = BackupKeysSummary::new(&BTreeMap::new());
+        assert_eq!(empty.count, uint!(0));
+
+        let session = |key_data: KeyBackupData| {
+            RoomKeyBackup::new(BTreeMap::from([(
+                "session".to_owned(),
+                Raw::new(&key_data).unwrap(),
+            )]))
+        };
+        let mut rooms = BTreeMap::from([
+            (owned_room_id!("!a:localhost"), session(key_data(false, uint!(0), uint!(0)))),
+            (owned_room_id!("!b:localhost"), session(key_data(false, uint!(0), uint!(0)))),
+        ]);
+
+        let summary = BackupKeysSummary::new(&rooms);
+        assert_eq!(summary.count, uint!(2));
+        assert_ne!(summary.etag, empty.etag);
+        assert_eq!(BackupKeysSummary::new(&rooms), summary);
+
+        // Replacing a key changes the etag.
+        rooms.insert(owned_room_id!("!b:localhost"), session(key_data(true, uint!(0), uint!(0))));
+        let updated = BackupKeysSummary::new(&rooms);
+        assert_eq!(updated.count, uint!(2));
+        assert_ne!(updated.etag, summary.etag);
+    }
+}
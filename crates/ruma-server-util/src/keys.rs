@@ -0,0 +1,769 @@
+//! Cache of the signing keys of homeservers, to verify the signatures of PDUs.
+//!
+//! The [`KeyRing`] stores the keys fetched with the [`get_server_keys`] and
+//! [`get_remote_server_keys_batch`] endpoints, and assembles the [`PublicKeyMap`] needed to verify
+//! a PDU with [`ruma_signatures::verify_event()`]. Missing keys can be fetched from the origin
+//! server or from notary servers with a [`KeyFetcher`].
+//!
+//! [`get_server_keys`]: ruma_federation_api::discovery::get_server_keys
+//! [`get_remote_server_keys_batch`]: ruma_federation_api::discovery::get_remote_server_keys_batch
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    mem,
+    time::{Duration, SystemTime},
+};
+
+use js_int::UInt;
+use ruma_common::{
+    serde::{Base64, Raw},
+    CanonicalJsonObject, CanonicalJsonValue, MilliSecondsSinceUnixEpoch, OwnedServerName,
+    OwnedServerSigningKeyId, RoomVersionId, ServerName,
+};
+use ruma_federation_api::discovery::{
+    get_remote_server_keys_batch::{self, v2::QueryCriteria},
+    get_server_keys, OldVerifyKey, ServerSigningKeys,
+};
+use ruma_signatures::{PublicKeyMap, PublicKeySet};
+use tracing::{debug, warn};
+
+/// The maximum validity period of keys.
+///
+/// Keys whose `valid_until_ts` is further in the future are considered to expire after 7 days, as
+/// required by the spec.
+const MAX_VALIDITY_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// An in-memory cache of the signing keys of homeservers.
+#[derive(Clone, Debug, Default)]
+pub struct KeyRing {
+    /// The known keys, by server.
+    servers: BTreeMap<OwnedServerName, ServerKeys>,
+
+    /// The notary servers to query for keys that couldn't be fetched from their server.
+    notary_servers: Vec<OwnedServerName>,
+}
+
+impl KeyRing {
+    /// Creates an empty `KeyRing` without notary servers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the notary servers to query when keys can't be fetched from their server.
+    ///
+    /// The notary servers are queried in the given order.
+    pub fn notary_servers(self, notary_servers: Vec<OwnedServerName>) -> Self {
+        Self { notary_servers, ..self }
+    }
+
+    /// Add keys that were fetched directly from their server.
+    ///
+    /// The keys must be signed by the server with at least one of its current keys. Since any
+    /// server can sign keys for any server name, the keys must have been fetched from the server
+    /// they belong to, otherwise [`KeyRing::add_notary_keys()`] should be used.
+    ///
+    /// Returns the name of the server that the keys belong to.
+    pub fn add_server_keys(
+        &mut self,
+        server_keys: &Raw<ServerSigningKeys>,
+    ) -> Result<OwnedServerName, KeyRingError> {
+        let (object, keys) = deserialize_keys(server_keys)?;
+        verify_self_signature(&object, &keys)?;
+
+        let server_name = keys.server_name.clone();
+        self.insert(keys);
+        Ok(server_name)
+    }
+
+    /// Add keys that were returned by a notary server.
+    ///
+    /// The keys must be signed by their server with at least one of its current keys, and by the
+    /// notary server with at least one of its keys in this `KeyRing` that is still valid.
+    ///
+    /// Returns the name of the server that the keys belong to.
+    pub fn add_notary_keys(
+        &mut self,
+        notary: &ServerName,
+        server_keys: &Raw<ServerSigningKeys>,
+    ) -> Result<OwnedServerName, KeyRingError> {
+        let (object, keys) = deserialize_keys(server_keys)?;
+        verify_self_signature(&object, &keys)?;
+
+        if keys.server_name != notary {
+            let now = MilliSecondsSinceUnixEpoch::now();
+            let notary_keys = self
+                .servers
+                .get(notary)
+                .filter(|notary_keys| notary_keys.valid_until_ts >= now)
+                .ok_or_else(|| KeyRingError::UnknownNotaryKeys(notary.to_owned()))?;
+            verify_signature(&object, notary, &notary_keys.verify_keys)?;
+        }
+
+        let server_name = keys.server_name.clone();
+        self.insert(keys);
+        Ok(server_name)
+    }
+
+    /// Get the public key of the given server with the given ID.
+    ///
+    /// If `valid_at` is set, the key is only returned if it was valid at that time, i.e. if it is
+    /// a current key with a `valid_until_ts` after that time, or an old key with an `expired_ts`
+    /// after that time. Otherwise any known key is returned.
+    pub fn public_key(
+        &self,
+        server_name: &ServerName,
+        key_id: &str,
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> Option<&Base64> {
+        let server_keys = self.servers.get(server_name)?;
+
+        if let Some((_, key)) = server_keys.verify_keys.iter().find(|(id, _)| id.as_str() == key_id)
+        {
+            if valid_at.map_or(true, |ts| server_keys.valid_until_ts >= ts) {
+                return Some(key);
+            }
+        }
+
+        server_keys
+            .old_verify_keys
+            .iter()
+            .find(|(id, _)| id.as_str() == key_id)
+            .filter(|(_, old_key)| valid_at.map_or(true, |ts| old_key.expired_ts >= ts))
+            .map(|(_, old_key)| &old_key.key)
+    }
+
+    /// Get the public keys needed to verify the signatures of the given PDU.
+    ///
+    /// The returned map contains exactly the keys needed by [`ruma_signatures::verify_event()`].
+    /// Starting with room version 5, the keys must be valid at the `origin_server_ts` of the PDU.
+    ///
+    /// Returns [`KeyRingError::MissingKeys`] if some keys are unknown or were not valid.
+    pub fn public_key_map_for_pdu(
+        &self,
+        pdu: &CanonicalJsonObject,
+        room_version: &RoomVersionId,
+    ) -> Result<PublicKeyMap, KeyRingError> {
+        let required_keys = ruma_signatures::required_keys(pdu, room_version)?;
+        let valid_at =
+            if enforce_key_validity(room_version) { Some(origin_server_ts(pdu)?) } else { None };
+
+        let mut public_key_map = PublicKeyMap::new();
+        let mut missing_keys = BTreeMap::<String, BTreeSet<String>>::new();
+
+        for (server_name, key_ids) in required_keys {
+            let mut public_key_set = PublicKeySet::new();
+
+            for key_id in key_ids {
+                let key = <&ServerName>::try_from(server_name.as_str())
+                    .ok()
+                    .and_then(|server_name| self.public_key(server_name, &key_id, valid_at));
+
+                match key {
+                    Some(key) => {
+                        public_key_set.insert(key_id, key.clone());
+                    }
+                    None => {
+                        missing_keys.entry(server_name.clone()).or_default().insert(key_id);
+                    }
+                }
+            }
+
+            public_key_map.insert(server_name, public_key_set);
+        }
+
+        if missing_keys.is_empty() {
+            Ok(public_key_map)
+        } else {
+            Err(KeyRingError::MissingKeys(missing_keys))
+        }
+    }
+
+    /// Get the public keys needed to verify the signatures of the given PDU, fetching the missing
+    /// keys with the given fetcher.
+    ///
+    /// Missing keys are first fetched from their server. If some keys are still missing, they are
+    /// requested from the notary servers, in order. Errors while fetching keys are logged and the
+    /// next source is tried.
+    ///
+    /// Returns [`KeyRingError::MissingKeys`] if some keys couldn't be fetched.
+    pub async fn fetch_public_key_map_for_pdu<F: KeyFetcher>(
+        &mut self,
+        fetcher: &F,
+        pdu: &CanonicalJsonObject,
+        room_version: &RoomVersionId,
+    ) -> Result<PublicKeyMap, KeyRingError> {
+        let mut missing_keys = match self.public_key_map_for_pdu(pdu, room_version) {
+            Err(KeyRingError::MissingKeys(missing_keys)) => missing_keys,
+            result => return result,
+        };
+        let origin_server_ts = origin_server_ts(pdu)?;
+
+        for server_name in missing_keys.keys() {
+            let Ok(server_name) = <&ServerName>::try_from(server_name.as_str()) else {
+                continue;
+            };
+            self.fetch_server_keys(fetcher, server_name).await;
+        }
+
+        for notary in self.notary_servers.clone() {
+            missing_keys = match self.public_key_map_for_pdu(pdu, room_version) {
+                Err(KeyRingError::MissingKeys(missing_keys)) => missing_keys,
+                result => return result,
+            };
+
+            let now = MilliSecondsSinceUnixEpoch::now();
+            if !self.servers.get(&notary).is_some_and(|keys| keys.valid_until_ts >= now) {
+                self.fetch_server_keys(fetcher, &notary).await;
+            }
+
+            let query = missing_keys
+                .iter()
+                .filter_map(|(server_name, key_ids)| {
+                    let server_name = OwnedServerName::try_from(server_name.as_str()).ok()?;
+                    let key_ids = key_ids
+                        .iter()
+                        .filter_map(|key_id| {
+                            let key_id = OwnedServerSigningKeyId::try_from(key_id.as_str()).ok()?;
+                            let mut criteria = QueryCriteria::new();
+                            criteria.minimum_valid_until_ts = Some(origin_server_ts);
+                            Some((key_id, criteria))
+                        })
+                        .collect();
+                    Some((server_name, key_ids))
+                })
+                .collect();
+            let request = get_remote_server_keys_batch::v2::Request::new(query);
+
+            let response = match fetcher.fetch_keys_from_notary(&notary, request).await {
+                Ok(response) => response,
+                Err(error) => {
+                    debug!(%notary, %error, "Failed to fetch keys from notary server");
+                    continue;
+                }
+            };
+
+            for server_keys in &response.server_keys {
+                if let Err(error) = self.add_notary_keys(&notary, server_keys) {
+                    warn!(%notary, %error, "Received invalid keys from notary server");
+                }
+            }
+        }
+
+        self.public_key_map_for_pdu(pdu, room_version)
+    }
+
+    /// Fetch the keys of the given server from the server itself and add them to this `KeyRing`.
+    ///
+    /// Errors are logged.
+    async fn fetch_server_keys<F: KeyFetcher>(&mut self, fetcher: &F, server_name: &ServerName) {
+        let response = match fetcher.fetch_server_keys(server_name).await {
+            Ok(response) => response,
+            Err(error) => {
+                debug!(%server_name, %error, "Failed to fetch keys from server");
+                return;
+            }
+        };
+
+        let (object, keys) = match deserialize_keys(&response.server_key) {
+            Ok(keys) => keys,
+            Err(error) => {
+                warn!(%server_name, %error, "Received invalid keys from server");
+                return;
+            }
+        };
+
+        if keys.server_name != server_name {
+            warn!(
+                %server_name,
+                found = %keys.server_name,
+                "Received keys of another server"
+            );
+            return;
+        }
+
+        match verify_self_signature(&object, &keys) {
+            Ok(()) => self.insert(keys),
+            Err(error) => warn!(%server_name, %error, "Received invalid keys from server"),
+        }
+    }
+
+    /// Insert the given keys, whose signatures were verified.
+    ///
+    /// The new keys replace the known keys if they are valid for longer. Keys that are not
+    /// current anymore are kept as old keys.
+    fn insert(&mut self, keys: ServerSigningKeys) {
+        let valid_until_ts = match MilliSecondsSinceUnixEpoch::from_system_time(
+            SystemTime::now() + MAX_VALIDITY_PERIOD,
+        ) {
+            Some(max_valid_until_ts) => keys.valid_until_ts.min(max_valid_until_ts),
+            None => keys.valid_until_ts,
+        };
+        let verify_keys = keys.verify_keys.into_iter().map(|(id, key)| (id, key.key)).collect();
+
+        let Some(server_keys) = self.servers.get_mut(&keys.server_name) else {
+            self.servers.insert(
+                keys.server_name,
+                ServerKeys { verify_keys, old_verify_keys: keys.old_verify_keys, valid_until_ts },
+            );
+            return;
+        };
+
+        server_keys.old_verify_keys.extend(keys.old_verify_keys);
+
+        // Keys that are not current anymore are kept as old keys, that were valid until the time
+        // the keys that included them were valid.
+        let (stale_keys, expired_ts) = if valid_until_ts >= server_keys.valid_until_ts {
+            let previous_keys = mem::replace(&mut server_keys.verify_keys, verify_keys);
+            let previous_valid_until_ts =
+                mem::replace(&mut server_keys.valid_until_ts, valid_until_ts);
+            (previous_keys, previous_valid_until_ts)
+        } else {
+            (verify_keys, valid_until_ts)
+        };
+
+        for (key_id, key) in stale_keys {
+            if !server_keys.verify_keys.contains_key(&key_id) {
+                server_keys
+                    .old_verify_keys
+                    .entry(key_id)
+                    .or_insert_with(|| OldVerifyKey::new(expired_ts, key));
+            }
+        }
+    }
+}
+
+/// The keys of a server in a [`KeyRing`].
+#[derive(Clone, Debug)]
+struct ServerKeys {
+    /// The current keys of the server.
+    verify_keys: BTreeMap<OwnedServerSigningKeyId, Base64>,
+
+    /// The keys the server used to use.
+    old_verify_keys: BTreeMap<OwnedServerSigningKeyId, OldVerifyKey>,
+
+    /// The time until which the current keys are valid.
+    valid_until_ts: MilliSecondsSinceUnixEpoch,
+}
+
+/// A source of signing keys of homeservers.
+///
+/// This is usually implemented by sending requests to the [`get_server_keys`] and
+/// [`get_remote_server_keys_batch`] endpoints with an HTTP client.
+///
+/// [`get_server_keys`]: ruma_federation_api::discovery::get_server_keys
+/// [`get_remote_server_keys_batch`]: ruma_federation_api::discovery::get_remote_server_keys_batch
+pub trait KeyFetcher: Send + Sync {
+    /// The error returned when a request fails.
+    type Error: std::error::Error;
+
+    /// Fetch the keys of the given server from the server itself.
+    fn fetch_server_keys(
+        &self,
+        server_name: &ServerName,
+    ) -> impl Future<Output = Result<get_server_keys::v2::Response, Self::Error>> + Send;
+
+    /// Fetch the keys of other servers from the given notary server.
+    fn fetch_keys_from_notary(
+        &self,
+        notary: &ServerName,
+        request: get_remote_server_keys_batch::v2::Request,
+    ) -> impl Future<Output = Result<get_remote_server_keys_batch::v2::Response, Self::Error>> + Send;
+}
+
+/// An error encountered when adding keys to a [`KeyRing`] or when getting the keys for a PDU.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum KeyRingError {
+    /// The server keys are not valid JSON or don't match the expected format.
+    #[error("invalid server keys: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The PDU doesn't have a valid `origin_server_ts`.
+    #[error("missing or invalid `origin_server_ts` in PDU")]
+    InvalidOriginServerTs,
+
+    /// The server keys or the PDU are not signed by a server that needs to sign them.
+    #[error("missing signature of {0}")]
+    MissingSignature(OwnedServerName),
+
+    /// The keys of the notary server that signed the server keys are unknown or expired.
+    #[error("unknown keys for notary server {0}")]
+    UnknownNotaryKeys(OwnedServerName),
+
+    /// A signature is invalid, or the PDU is malformed.
+    #[error(transparent)]
+    Signature(#[from] ruma_signatures::Error),
+
+    /// Some of the keys needed to verify the PDU are unknown or were not valid at the time the
+    /// PDU was sent.
+    ///
+    /// Contains the missing key IDs by server name.
+    #[error("missing public keys: {0:?}")]
+    MissingKeys(BTreeMap<String, BTreeSet<String>>),
+}
+
+/// Whether the validity period of keys must be enforced for the given room version.
+fn enforce_key_validity(room_version: &RoomVersionId) -> bool {
+    !matches!(
+        room_version,
+        RoomVersionId::V1 | RoomVersionId::V2 | RoomVersionId::V3 | RoomVersionId::V4
+    )
+}
+
+/// Get the `origin_server_ts` of the given PDU.
+fn origin_server_ts(pdu: &CanonicalJsonObject) -> Result<MilliSecondsSinceUnixEpoch, KeyRingError> {
+    match pdu.get("origin_server_ts") {
+        Some(CanonicalJsonValue::Integer(ts)) => UInt::try_from(i64::from(*ts))
+            .map(MilliSecondsSinceUnixEpoch)
+            .map_err(|_| KeyRingError::InvalidOriginServerTs),
+        _ => Err(KeyRingError::InvalidOriginServerTs),
+    }
+}
+
+/// Deserialize the given server keys as canonical JSON, to verify their signatures, and as
+/// `ServerSigningKeys`.
+fn deserialize_keys(
+    server_keys: &Raw<ServerSigningKeys>,
+) -> Result<(CanonicalJsonObject, ServerSigningKeys), KeyRingError> {
+    let object = serde_json::from_str(server_keys.json().get())?;
+    let keys = server_keys.deserialize()?;
+    Ok((object, keys))
+}
+
+/// Verify that the given server keys are signed by their server with its current keys.
+fn verify_self_signature(
+    object: &CanonicalJsonObject,
+    keys: &ServerSigningKeys,
+) -> Result<(), KeyRingError> {
+    let verify_keys =
+        keys.verify_keys.iter().map(|(id, key)| (id.clone(), key.key.clone())).collect();
+    verify_signature(object, &keys.server_name, &verify_keys)
+}
+
+/// Verify the signatures of the given entity on the given object, with the given keys.
+///
+/// At least one of the signatures must be made with one of the keys. Signatures made with unknown
+/// keys are ignored.
+fn verify_signature(
+    object: &CanonicalJsonObject,
+    entity: &ServerName,
+    keys: &BTreeMap<OwnedServerSigningKeyId, Base64>,
+) -> Result<(), KeyRingError> {
+    let signature_set = match object.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => match signatures.get(entity.as_str()) {
+            Some(CanonicalJsonValue::Object(signature_set)) => signature_set
+                .iter()
+                .filter(|(key_id, _)| keys.keys().any(|id| id.as_str() == key_id.as_str()))
+                .map(|(key_id, signature)| (key_id.clone(), signature.clone()))
+                .collect::<CanonicalJsonObject>(),
+            _ => CanonicalJsonObject::new(),
+        },
+        _ => CanonicalJsonObject::new(),
+    };
+
+    if signature_set.is_empty() {
+        return Err(KeyRingError::MissingSignature(entity.to_owned()));
+    }
+
+    let public_key_set =
+        keys.iter().map(|(key_id, key)| (key_id.to_string(), key.clone())).collect();
+    let public_key_map = BTreeMap::from([(entity.as_str().to_owned(), public_key_set)]);
+
+    let mut object = object.clone();
+    object.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object(BTreeMap::from([(
+            entity.as_str().to_owned(),
+            CanonicalJsonValue::Object(signature_set),
+        )])),
+    );
+
+    ruma_signatures::verify_json(&public_key_map, &object)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        io,
+        sync::Mutex,
+        time::{Duration, SystemTime},
+    };
+
+    use assert_matches2::assert_matches;
+    use ruma_common::{
+        serde::{Base64, Raw},
+        server_name, CanonicalJsonObject, MilliSecondsSinceUnixEpoch, OwnedServerName,
+        RoomVersionId, ServerName, ServerSigningKeyId, SigningKeyAlgorithm,
+    };
+    use ruma_federation_api::discovery::{
+        get_remote_server_keys_batch, get_server_keys, ServerSigningKeys, VerifyKey,
+    };
+    use ruma_signatures::{hash_and_sign_event, sign_json, verify_event, Ed25519KeyPair, Verified};
+    use serde_json::json;
+
+    use super::{KeyFetcher, KeyRing, KeyRingError};
+
+    fn generate_key_pair(version: &str) -> Ed25519KeyPair {
+        let document = Ed25519KeyPair::generate().unwrap();
+        Ed25519KeyPair::from_der(&document, version.to_owned()).unwrap()
+    }
+
+    fn ts_from_now(offset: Duration) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch::from_system_time(SystemTime::now() + offset).unwrap()
+    }
+
+    fn server_keys(
+        server_name: &ServerName,
+        key_pairs: &[&Ed25519KeyPair],
+        valid_until_ts: MilliSecondsSinceUnixEpoch,
+        signers: &[(&ServerName, &Ed25519KeyPair)],
+    ) -> Raw<ServerSigningKeys> {
+        let mut keys = ServerSigningKeys::new(server_name.to_owned(), valid_until_ts);
+        for key_pair in key_pairs {
+            keys.verify_keys.insert(
+                ServerSigningKeyId::from_parts(
+                    SigningKeyAlgorithm::Ed25519,
+                    key_pair.version().into(),
+                ),
+                VerifyKey::new(Base64::new(key_pair.public_key().to_vec())),
+            );
+        }
+
+        let mut object: CanonicalJsonObject =
+            serde_json::from_value(serde_json::to_value(keys).unwrap()).unwrap();
+        for (signer, key_pair) in signers {
+            sign_json(signer.as_str(), *key_pair, &mut object).unwrap();
+        }
+
+        Raw::from_json(serde_json::value::to_raw_value(&object).unwrap())
+    }
+
+    fn pdu(
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        key_pair: &Ed25519KeyPair,
+    ) -> CanonicalJsonObject {
+        let mut pdu = serde_json::from_value(json!({
+            "auth_events": [],
+            "content": {},
+            "depth": 3,
+            "origin_server_ts": origin_server_ts,
+            "prev_events": [],
+            "room_id": "!x:origin.hs.example.com",
+            "sender": "@alice:origin.hs.example.com",
+            "type": "X",
+        }))
+        .unwrap();
+        hash_and_sign_event("origin.hs.example.com", key_pair, &mut pdu, &RoomVersionId::V10)
+            .unwrap();
+        pdu
+    }
+
+    #[test]
+    fn add_server_keys_checks_self_signature() {
+        let origin = server_name!("origin.hs.example.com");
+        let key_pair = generate_key_pair("1");
+        let valid_until_ts = ts_from_now(Duration::from_secs(3600));
+        let mut key_ring = KeyRing::new();
+
+        let unsigned = server_keys(origin, &[&key_pair], valid_until_ts, &[]);
+        assert_matches!(
+            key_ring.add_server_keys(&unsigned),
+            Err(KeyRingError::MissingSignature(server_name))
+        );
+        assert_eq!(server_name, origin);
+
+        // Signed with a key that is not one of the server's keys.
+        let unknown_key = generate_key_pair("2");
+        let keys = server_keys(origin, &[&key_pair], valid_until_ts, &[(origin, &unknown_key)]);
+        assert_matches!(key_ring.add_server_keys(&keys), Err(KeyRingError::MissingSignature(_)));
+
+        // Signed with another key with the same ID.
+        let forged_key = generate_key_pair("1");
+        let keys = server_keys(origin, &[&key_pair], valid_until_ts, &[(origin, &forged_key)]);
+        assert_matches!(key_ring.add_server_keys(&keys), Err(KeyRingError::Signature(_)));
+        assert_eq!(key_ring.public_key(origin, "ed25519:1", None), None);
+
+        let keys = server_keys(origin, &[&key_pair], valid_until_ts, &[(origin, &key_pair)]);
+        assert_eq!(key_ring.add_server_keys(&keys).unwrap(), origin);
+        assert_eq!(
+            key_ring.public_key(origin, "ed25519:1", None).unwrap().as_bytes(),
+            key_pair.public_key()
+        );
+    }
+
+    #[test]
+    fn public_key_map_for_pdu_respects_validity() {
+        let origin = server_name!("origin.hs.example.com");
+        let old_key_pair = generate_key_pair("1");
+        let key_pair = generate_key_pair("2");
+        let mut key_ring = KeyRing::new();
+
+        let now = MilliSecondsSinceUnixEpoch::now();
+        let later = ts_from_now(Duration::from_secs(7200));
+        let old_pdu = pdu(now, &old_key_pair);
+
+        assert_matches!(
+            key_ring.public_key_map_for_pdu(&old_pdu, &RoomVersionId::V10),
+            Err(KeyRingError::MissingKeys(missing_keys))
+        );
+        assert_eq!(missing_keys.len(), 1);
+        assert!(missing_keys[origin.as_str()].contains("ed25519:1"));
+
+        let keys = server_keys(
+            origin,
+            &[&old_key_pair],
+            ts_from_now(Duration::from_secs(3600)),
+            &[(origin, &old_key_pair)],
+        );
+        key_ring.add_server_keys(&keys).unwrap();
+
+        let public_key_map =
+            key_ring.public_key_map_for_pdu(&old_pdu, &RoomVersionId::V10).unwrap();
+        assert_eq!(
+            verify_event(&public_key_map, &old_pdu, &RoomVersionId::V10).unwrap(),
+            Verified::All
+        );
+
+        // The key is not valid anymore when the PDU is sent.
+        let new_pdu = pdu(later, &old_key_pair);
+        assert_matches!(
+            key_ring.public_key_map_for_pdu(&new_pdu, &RoomVersionId::V10),
+            Err(KeyRingError::MissingKeys(_))
+        );
+        // Key validity is not enforced in room versions 1 to 4.
+        key_ring.public_key_map_for_pdu(&new_pdu, &RoomVersionId::V4).unwrap();
+
+        // The server rotates its key, the previous key becomes an old key.
+        let keys = server_keys(
+            origin,
+            &[&key_pair],
+            ts_from_now(Duration::from_secs(86_400)),
+            &[(origin, &key_pair)],
+        );
+        key_ring.add_server_keys(&keys).unwrap();
+
+        key_ring.public_key_map_for_pdu(&old_pdu, &RoomVersionId::V10).unwrap();
+        assert_matches!(
+            key_ring.public_key_map_for_pdu(&new_pdu, &RoomVersionId::V10),
+            Err(KeyRingError::MissingKeys(_))
+        );
+        key_ring.public_key_map_for_pdu(&pdu(later, &key_pair), &RoomVersionId::V10).unwrap();
+    }
+
+    #[derive(Default)]
+    struct Fetcher {
+        server_keys: BTreeMap<OwnedServerName, Raw<ServerSigningKeys>>,
+        notary_keys: Vec<Raw<ServerSigningKeys>>,
+        notary_requests: Mutex<Vec<get_remote_server_keys_batch::v2::Request>>,
+    }
+
+    impl KeyFetcher for Fetcher {
+        type Error = io::Error;
+
+        async fn fetch_server_keys(
+            &self,
+            server_name: &ServerName,
+        ) -> Result<get_server_keys::v2::Response, Self::Error> {
+            let server_key = self
+                .server_keys
+                .get(server_name)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "server is down"))?;
+            Ok(get_server_keys::v2::Response::new(server_key.clone()))
+        }
+
+        async fn fetch_keys_from_notary(
+            &self,
+            _notary: &ServerName,
+            request: get_remote_server_keys_batch::v2::Request,
+        ) -> Result<get_remote_server_keys_batch::v2::Response, Self::Error> {
+            self.notary_requests.lock().unwrap().push(request);
+            Ok(get_remote_server_keys_batch::v2::Response::new(self.notary_keys.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_keys_from_notary() {
+        let origin = server_name!("origin.hs.example.com");
+        let notary = server_name!("notary.hs.example.com");
+        let origin_key_pair = generate_key_pair("1");
+        let notary_key_pair = generate_key_pair("a");
+        let valid_until_ts = ts_from_now(Duration::from_secs(3600));
+        let origin_server_ts = MilliSecondsSinceUnixEpoch::now();
+        let pdu = pdu(origin_server_ts, &origin_key_pair);
+
+        // The origin server is down, so its keys are fetched from the notary server.
+        let mut fetcher = Fetcher::default();
+        fetcher.server_keys.insert(
+            notary.to_owned(),
+            server_keys(notary, &[&notary_key_pair], valid_until_ts, &[(notary, &notary_key_pair)]),
+        );
+        fetcher.notary_keys.push(server_keys(
+            origin,
+            &[&origin_key_pair],
+            valid_until_ts,
+            &[(origin, &origin_key_pair), (notary, &notary_key_pair)],
+        ));
+
+        let mut key_ring = KeyRing::new();
+        assert_matches!(
+            key_ring.fetch_public_key_map_for_pdu(&fetcher, &pdu, &RoomVersionId::V10).await,
+            Err(KeyRingError::MissingKeys(_))
+        );
+        assert!(fetcher.notary_requests.lock().unwrap().is_empty());
+
+        let mut key_ring = KeyRing::new().notary_servers(vec![notary.to_owned()]);
+        let public_key_map = key_ring
+            .fetch_public_key_map_for_pdu(&fetcher, &pdu, &RoomVersionId::V10)
+            .await
+            .unwrap();
+        assert_eq!(
+            verify_event(&public_key_map, &pdu, &RoomVersionId::V10).unwrap(),
+            Verified::All
+        );
+
+        let requests = fetcher.notary_requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let query = &requests[0].server_keys[origin];
+        assert_eq!(query.len(), 1);
+        assert_eq!(query.keys().next().unwrap(), "ed25519:1");
+        assert_eq!(query.values().next().unwrap().minimum_valid_until_ts, Some(origin_server_ts));
+    }
+
+    #[test]
+    fn notary_keys_must_be_signed_by_notary() {
+        let origin = server_name!("origin.hs.example.com");
+        let notary = server_name!("notary.hs.example.com");
+        let origin_key_pair = generate_key_pair("1");
+        let notary_key_pair = generate_key_pair("a");
+        let valid_until_ts = ts_from_now(Duration::from_secs(3600));
+        let mut key_ring = KeyRing::new();
+
+        let keys = server_keys(
+            origin,
+            &[&origin_key_pair],
+            valid_until_ts,
+            &[(origin, &origin_key_pair), (notary, &notary_key_pair)],
+        );
+        assert_matches!(
+            key_ring.add_notary_keys(notary, &keys),
+            Err(KeyRingError::UnknownNotaryKeys(_))
+        );
+
+        let notary_keys =
+            server_keys(notary, &[&notary_key_pair], valid_until_ts, &[(notary, &notary_key_pair)]);
+        key_ring.add_server_keys(&notary_keys).unwrap();
+
+        let unsigned_keys =
+            server_keys(origin, &[&origin_key_pair], valid_until_ts, &[(origin, &origin_key_pair)]);
+        assert_matches!(
+            key_ring.add_notary_keys(notary, &unsigned_keys),
+            Err(KeyRingError::MissingSignature(server_name))
+        );
+        assert_eq!(server_name, notary);
+
+        assert_eq!(key_ring.add_notary_keys(notary, &keys).unwrap(), origin);
+    }
+}
@@ -2,5 +2,12 @@
 #![doc(html_logo_url = "https://ruma.io/images/logo.png")]
 //! Collection of helpers for implementing Matrix homeservers using Ruma.
 
+#![cfg_attr(docsrs, feature(doc_auto_cfg))]
 #![warn(missing_docs)]
 pub mod authorization;
+#[cfg(feature = "backup")]
+pub mod backup;
+#[cfg(feature = "keys")]
+pub mod keys;
+#[cfg(feature = "resolver")]
+pub mod resolver;
@@ -0,0 +1,622 @@
+//! Resolution of server names to the address of their federation API.
+//!
+//! The [`Resolver`] implements the [server discovery] algorithm, using a pluggable
+//! [`DnsResolver`] to look up SRV records and a pluggable [`HttpClient`] to fetch
+//! `/.well-known/matrix/server`.
+//!
+//! [server discovery]: https://spec.matrix.org/latest/server-server-api/#resolving-server-names
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use http::header::CACHE_CONTROL;
+use ruma_common::{
+    api::{IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken},
+    OwnedServerName, ServerName,
+};
+use ruma_federation_api::discovery::discover_homeserver;
+use tracing::debug;
+
+/// The default port of the federation API.
+const DEFAULT_PORT: u16 = 8448;
+
+/// How long a `.well-known` response is cached if it doesn't specify a cache duration.
+const DEFAULT_WELL_KNOWN_CACHE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The maximum duration a `.well-known` response is cached.
+const MAX_WELL_KNOWN_CACHE_DURATION: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// How long a failure to fetch a `.well-known` response is cached.
+const WELL_KNOWN_ERROR_CACHE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// The SRV services to look up, in order.
+const SRV_SERVICES: [&str; 2] = ["_matrix-fed._tcp", "_matrix._tcp"];
+
+/// A resolver of server names to the address of their federation API.
+///
+/// The responses to `/.well-known/matrix/server` are cached according to their `Cache-Control`
+/// header, for 24 hours by default and at most 48 hours. Failures are cached for an hour.
+#[derive(Debug)]
+pub struct Resolver<D, H> {
+    /// The DNS backend.
+    dns: D,
+
+    /// The HTTP backend.
+    http: H,
+
+    /// The cached `.well-known` delegations, by server name.
+    well_known_cache: Mutex<BTreeMap<OwnedServerName, CachedWellKnown>>,
+}
+
+impl<D, H> Resolver<D, H>
+where
+    D: DnsResolver,
+    H: HttpClient,
+{
+    /// Creates a new `Resolver` with the given DNS and HTTP backends.
+    pub fn new(dns: D, http: H) -> Self {
+        Self { dns, http, well_known_cache: Default::default() }
+    }
+
+    /// Resolve the given server name.
+    ///
+    /// This never fails: if the lookups don't give any result, the server is expected to listen
+    /// on the default port of its hostname.
+    pub async fn resolve(&self, server_name: &ServerName) -> ResolvedServer {
+        if let Some(resolved) = resolve_without_lookup(server_name) {
+            return resolved;
+        }
+
+        match self.well_known(server_name).await {
+            Some(delegated) => match resolve_without_lookup(&delegated) {
+                Some(resolved) => resolved,
+                None => self.resolve_srv(delegated.host()).await,
+            },
+            None => self.resolve_srv(server_name.host()).await,
+        }
+    }
+
+    /// Remove the cached `.well-known` delegation of the given server name, if any.
+    pub fn forget_well_known(&self, server_name: &ServerName) {
+        self.well_known_cache
+            .lock()
+            .expect("well-known cache mutex was poisoned")
+            .remove(server_name);
+    }
+
+    /// Get the server name that the given server delegates to with `/.well-known/matrix/server`,
+    /// using the cached result if it is still valid.
+    async fn well_known(&self, server_name: &ServerName) -> Option<OwnedServerName> {
+        let now = SystemTime::now();
+
+        if let Some(cached) = self
+            .well_known_cache
+            .lock()
+            .expect("well-known cache mutex was poisoned")
+            .get(server_name)
+        {
+            if cached.expires_at > now {
+                return cached.delegated.clone();
+            }
+        }
+
+        let (delegated, cache_duration) = match self.fetch_well_known(server_name).await {
+            Ok((delegated, cache_duration)) => (
+                Some(delegated),
+                cache_duration
+                    .unwrap_or(DEFAULT_WELL_KNOWN_CACHE_DURATION)
+                    .min(MAX_WELL_KNOWN_CACHE_DURATION),
+            ),
+            Err(error) => {
+                debug!(%server_name, %error, "Failed to fetch .well-known/matrix/server");
+                (None, WELL_KNOWN_ERROR_CACHE_DURATION)
+            }
+        };
+
+        self.well_known_cache.lock().expect("well-known cache mutex was poisoned").insert(
+            server_name.to_owned(),
+            CachedWellKnown { delegated: delegated.clone(), expires_at: now + cache_duration },
+        );
+
+        delegated
+    }
+
+    /// Fetch `/.well-known/matrix/server` for the given server name.
+    ///
+    /// Returns the delegated server name and the cache duration of the response, if any.
+    async fn fetch_well_known(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<(OwnedServerName, Option<Duration>), Box<dyn std::error::Error + Send + Sync>> {
+        let request = discover_homeserver::Request::new().try_into_http_request::<Vec<u8>>(
+            &format!("https://{}", server_name.host()),
+            SendAccessToken::None,
+            &[MatrixVersion::V1_0],
+        )?;
+
+        let response = self.http.send(request).await?;
+        let cache_duration = cache_duration(response.headers());
+        let response = discover_homeserver::Response::try_from_http_response(response)?;
+
+        Ok((response.server, cache_duration))
+    }
+
+    /// Resolve the given hostname with SRV records, falling back to the default port.
+    async fn resolve_srv(&self, hostname: &str) -> ResolvedServer {
+        for service in SRV_SERVICES {
+            let name = format!("{service}.{hostname}");
+
+            match self.dns.lookup_srv(&name).await {
+                Ok(records) => {
+                    let destinations = srv_destinations(records);
+
+                    if !destinations.is_empty() {
+                        return ResolvedServer::new(destinations, hostname, hostname);
+                    }
+                }
+                Err(error) => {
+                    debug!(%name, %error, "Failed to look up SRV records");
+                }
+            }
+        }
+
+        ResolvedServer::new(
+            vec![Destination::new(Host::Name(hostname.to_owned()), DEFAULT_PORT)],
+            hostname,
+            hostname,
+        )
+    }
+}
+
+/// The result of the resolution of a server name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ResolvedServer {
+    /// The addresses to connect to, in order of preference.
+    ///
+    /// The hostnames must be resolved with AAAA or A records.
+    pub destinations: Vec<Destination>,
+
+    /// The value of the `Host` header of the requests.
+    pub host_header: String,
+
+    /// The name that the TLS certificate of the server must be valid for.
+    ///
+    /// It should also be used for SNI, unless it is an IP address.
+    pub tls_name: String,
+}
+
+impl ResolvedServer {
+    /// Creates a new `ResolvedServer` with the given destinations, `Host` header and TLS name.
+    pub fn new(destinations: Vec<Destination>, host_header: &str, tls_name: &str) -> Self {
+        Self { destinations, host_header: host_header.to_owned(), tls_name: tls_name.to_owned() }
+    }
+}
+
+/// An address to connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Destination {
+    /// The host to connect to.
+    pub host: Host,
+
+    /// The port to connect to.
+    pub port: u16,
+}
+
+impl Destination {
+    /// Creates a new `Destination` with the given host and port.
+    pub fn new(host: Host, port: u16) -> Self {
+        Self { host, port }
+    }
+}
+
+/// A host to connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_enums)]
+pub enum Host {
+    /// An IP address.
+    Ip(IpAddr),
+
+    /// A hostname.
+    Name(String),
+}
+
+/// An SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SrvRecord {
+    /// The priority of the target, lower values are preferred.
+    pub priority: u16,
+
+    /// The relative weight of targets with the same priority, higher values are preferred.
+    pub weight: u16,
+
+    /// The port of the target.
+    pub port: u16,
+
+    /// The hostname of the target.
+    pub target: String,
+}
+
+impl SrvRecord {
+    /// Creates a new `SrvRecord` with the given priority, weight, port and target.
+    pub fn new(priority: u16, weight: u16, port: u16, target: String) -> Self {
+        Self { priority, weight, port, target }
+    }
+}
+
+/// A DNS backend for a [`Resolver`].
+pub trait DnsResolver: Send + Sync {
+    /// The error returned when a lookup fails.
+    type Error: std::error::Error;
+
+    /// Look up the SRV records with the given name.
+    ///
+    /// Returns an empty list if there are no records.
+    fn lookup_srv(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<Vec<SrvRecord>, Self::Error>> + Send;
+}
+
+/// An HTTP backend for a [`Resolver`].
+pub trait HttpClient: Send + Sync {
+    /// The error returned when a request fails.
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Send the given request and return the response.
+    ///
+    /// Redirects must be followed.
+    fn send(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> impl Future<Output = Result<http::Response<Vec<u8>>, Self::Error>> + Send;
+}
+
+/// A cached `.well-known` delegation.
+#[derive(Clone, Debug)]
+struct CachedWellKnown {
+    /// The delegated server name, or `None` if fetching it failed.
+    delegated: Option<OwnedServerName>,
+
+    /// When the cached result expires.
+    expires_at: SystemTime,
+}
+
+/// Resolve the given server name if it is an IP literal or has an explicit port.
+fn resolve_without_lookup(server_name: &ServerName) -> Option<ResolvedServer> {
+    let host = server_name.host();
+
+    if server_name.is_ip_literal() {
+        let ip = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .map_or_else(|| host.parse(), |host| host.parse::<Ipv6Addr>().map(IpAddr::V6))
+            .ok()?;
+        let destination =
+            Destination::new(Host::Ip(ip), server_name.port().unwrap_or(DEFAULT_PORT));
+
+        return Some(ResolvedServer::new(vec![destination], server_name.as_str(), &ip.to_string()));
+    }
+
+    let destination = Destination::new(Host::Name(host.to_owned()), server_name.port()?);
+    Some(ResolvedServer::new(vec![destination], server_name.as_str(), host))
+}
+
+/// Get the destinations of the given SRV records, in order of preference.
+///
+/// The records are sorted by ascending priority, then by descending weight. Records with a target
+/// of `.` are ignored, since they mean that the service is not available.
+fn srv_destinations(mut records: Vec<SrvRecord>) -> Vec<Destination> {
+    records.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+
+    records
+        .into_iter()
+        .filter_map(|record| {
+            let target = record.target.strip_suffix('.').unwrap_or(&record.target);
+            (!target.is_empty())
+                .then(|| Destination::new(Host::Name(target.to_owned()), record.port))
+        })
+        .collect()
+}
+
+/// Get the cache duration from the `Cache-Control` header in the given headers.
+fn cache_duration(headers: &http::HeaderMap) -> Option<Duration> {
+    let cache_control = headers.get(CACHE_CONTROL)?.to_str().ok()?;
+
+    let mut max_age = None;
+    for directive in cache_control.split(',') {
+        let directive = directive.trim().to_ascii_lowercase();
+
+        if directive == "no-store" || directive == "no-cache" {
+            return Some(Duration::ZERO);
+        }
+        if let Some(seconds) = directive.strip_prefix("max-age=") {
+            max_age = seconds.parse().ok().map(Duration::from_secs);
+        }
+    }
+
+    max_age
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        io,
+        net::IpAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use http::{header::CACHE_CONTROL, StatusCode};
+    use ruma_common::server_name;
+
+    use super::{DnsResolver, Host, HttpClient, ResolvedServer, Resolver, SrvRecord};
+
+    #[derive(Default)]
+    struct Dns {
+        records: BTreeMap<&'static str, Vec<SrvRecord>>,
+    }
+
+    impl DnsResolver for Dns {
+        type Error = io::Error;
+
+        async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, Self::Error> {
+            Ok(self.records.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    #[derive(Default)]
+    struct Http {
+        well_known: BTreeMap<&'static str, (&'static str, Option<&'static str>)>,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl HttpClient for Http {
+        type Error = io::Error;
+
+        async fn send(
+            &self,
+            request: http::Request<Vec<u8>>,
+        ) -> Result<http::Response<Vec<u8>>, Self::Error> {
+            let uri = request.uri().to_string();
+            self.requests.lock().unwrap().push(uri.clone());
+
+            let host = request.uri().host().unwrap();
+            let Some((server, cache_control)) = self.well_known.get(host) else {
+                return Ok(http::Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(b"{}".to_vec())
+                    .unwrap());
+            };
+
+            let mut response = http::Response::builder();
+            if let Some(cache_control) = cache_control {
+                response = response.header(CACHE_CONTROL, *cache_control);
+            }
+            Ok(response.body(format!(r#"{{ "m.server": "{server}" }}"#).into_bytes()).unwrap())
+        }
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> SrvRecord {
+        SrvRecord::new(priority, weight, port, target.to_owned())
+    }
+
+    fn name(name: &str) -> Host {
+        Host::Name(name.to_owned())
+    }
+
+    fn ip(ip: &str) -> Host {
+        Host::Ip(ip.parse::<IpAddr>().unwrap())
+    }
+
+    fn assert_resolved(
+        resolved: ResolvedServer,
+        destinations: &[(Host, u16)],
+        host_header: &str,
+        tls_name: &str,
+    ) {
+        let found: Vec<_> = resolved.destinations.into_iter().map(|d| (d.host, d.port)).collect();
+        assert_eq!(found, destinations);
+        assert_eq!(resolved.host_header, host_header);
+        assert_eq!(resolved.tls_name, tls_name);
+    }
+
+    fn resolver() -> Resolver<Dns, Http> {
+        let mut dns = Dns::default();
+        dns.records.insert(
+            "_matrix-fed._tcp.srv.example.org",
+            vec![srv(10, 0, 8000, "backup.example.org."), srv(0, 5, 8001, "main.example.org.")],
+        );
+        dns.records.insert("_matrix._tcp.legacy-srv.example.org", vec![srv(0, 0, 8002, "legacy.")]);
+        dns.records.insert("_matrix-fed._tcp.no-service.example.org", vec![srv(0, 0, 0, ".")]);
+        dns.records.insert(
+            "_matrix-fed._tcp.delegated-srv.example.net",
+            vec![srv(0, 0, 8003, "target.example.net")],
+        );
+        dns.records.insert(
+            "_matrix._tcp.delegated-legacy.example.net",
+            vec![srv(0, 0, 8004, "legacy.example.net")],
+        );
+
+        let mut http = Http::default();
+        for (server, delegated) in [
+            ("ip.example.org", "1.2.3.4"),
+            ("ipv6.example.org", "[1234:5678::abcd]:5678"),
+            ("port.example.org", "delegated.example.net:8443"),
+            ("delegated-srv.example.org", "delegated-srv.example.net"),
+            ("delegated-legacy.example.org", "delegated-legacy.example.net"),
+            ("delegated.example.org", "delegated.example.net"),
+        ] {
+            http.well_known.insert(server, (delegated, None));
+        }
+
+        Resolver::new(dns, http)
+    }
+
+    #[tokio::test]
+    async fn resolution_without_lookup() {
+        let resolver = resolver();
+
+        // Step 1: IP literal.
+        assert_resolved(
+            resolver.resolve(server_name!("1.2.3.4")).await,
+            &[(ip("1.2.3.4"), 8448)],
+            "1.2.3.4",
+            "1.2.3.4",
+        );
+        assert_resolved(
+            resolver.resolve(server_name!("1.2.3.4:1234")).await,
+            &[(ip("1.2.3.4"), 1234)],
+            "1.2.3.4:1234",
+            "1.2.3.4",
+        );
+        assert_resolved(
+            resolver.resolve(server_name!("[1234:5678::abcd]")).await,
+            &[(ip("1234:5678::abcd"), 8448)],
+            "[1234:5678::abcd]",
+            "1234:5678::abcd",
+        );
+
+        // Step 2: explicit port.
+        assert_resolved(
+            resolver.resolve(server_name!("example.org:1234")).await,
+            &[(name("example.org"), 1234)],
+            "example.org:1234",
+            "example.org",
+        );
+
+        assert!(resolver.http.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolution_with_well_known() {
+        let resolver = resolver();
+
+        // Step 3.1: delegated IP literal.
+        assert_resolved(
+            resolver.resolve(server_name!("ip.example.org")).await,
+            &[(ip("1.2.3.4"), 8448)],
+            "1.2.3.4",
+            "1.2.3.4",
+        );
+        assert_resolved(
+            resolver.resolve(server_name!("ipv6.example.org")).await,
+            &[(ip("1234:5678::abcd"), 5678)],
+            "[1234:5678::abcd]:5678",
+            "1234:5678::abcd",
+        );
+
+        // Step 3.2: delegated hostname with explicit port.
+        assert_resolved(
+            resolver.resolve(server_name!("port.example.org")).await,
+            &[(name("delegated.example.net"), 8443)],
+            "delegated.example.net:8443",
+            "delegated.example.net",
+        );
+
+        // Step 3.3: SRV record of the delegated hostname.
+        assert_resolved(
+            resolver.resolve(server_name!("delegated-srv.example.org")).await,
+            &[(name("target.example.net"), 8003)],
+            "delegated-srv.example.net",
+            "delegated-srv.example.net",
+        );
+
+        // Step 3.4: deprecated SRV record of the delegated hostname.
+        assert_resolved(
+            resolver.resolve(server_name!("delegated-legacy.example.org")).await,
+            &[(name("legacy.example.net"), 8004)],
+            "delegated-legacy.example.net",
+            "delegated-legacy.example.net",
+        );
+
+        // Step 3.5: delegated hostname with default port.
+        assert_resolved(
+            resolver.resolve(server_name!("delegated.example.org")).await,
+            &[(name("delegated.example.net"), 8448)],
+            "delegated.example.net",
+            "delegated.example.net",
+        );
+
+        assert_eq!(
+            resolver.http.requests.lock().unwrap()[0],
+            "https://ip.example.org/.well-known/matrix/server"
+        );
+    }
+
+    #[tokio::test]
+    async fn resolution_without_well_known() {
+        let resolver = resolver();
+
+        // Step 4: SRV record, ordered by priority.
+        assert_resolved(
+            resolver.resolve(server_name!("srv.example.org")).await,
+            &[(name("main.example.org"), 8001), (name("backup.example.org"), 8000)],
+            "srv.example.org",
+            "srv.example.org",
+        );
+
+        // Step 5: deprecated SRV record.
+        assert_resolved(
+            resolver.resolve(server_name!("legacy-srv.example.org")).await,
+            &[(name("legacy"), 8002)],
+            "legacy-srv.example.org",
+            "legacy-srv.example.org",
+        );
+
+        // Step 6: hostname with default port.
+        assert_resolved(
+            resolver.resolve(server_name!("example.org")).await,
+            &[(name("example.org"), 8448)],
+            "example.org",
+            "example.org",
+        );
+        assert_resolved(
+            resolver.resolve(server_name!("no-service.example.org")).await,
+            &[(name("no-service.example.org"), 8448)],
+            "no-service.example.org",
+            "no-service.example.org",
+        );
+    }
+
+    #[tokio::test]
+    async fn well_known_cache() {
+        let mut http = Http::default();
+        http.well_known.insert("cached.example.org", ("cached.example.net", None));
+        http.well_known.insert("uncached.example.org", ("uncached.example.net", Some("no-store")));
+        http.well_known
+            .insert("short.example.org", ("short.example.net", Some("public, max-age=0")));
+        let requests = http.requests.clone();
+        let resolver = Resolver::new(Dns::default(), http);
+
+        for _ in 0..2 {
+            for server_name in [
+                server_name!("cached.example.org"),
+                server_name!("uncached.example.org"),
+                server_name!("short.example.org"),
+                server_name!("missing.example.org"),
+            ] {
+                resolver.resolve(server_name).await;
+            }
+        }
+
+        let request_count =
+            |host: &str| requests.lock().unwrap().iter().filter(|uri| uri.contains(host)).count();
+        assert_eq!(request_count("//cached.example.org/"), 1);
+        assert_eq!(request_count("//uncached.example.org/"), 2);
+        assert_eq!(request_count("//short.example.org/"), 2);
+        // Errors are cached too.
+        assert_eq!(request_count("//missing.example.org/"), 1);
+
+        resolver.forget_well_known(server_name!("cached.example.org"));
+        resolver.resolve(server_name!("cached.example.org")).await;
+        assert_eq!(request_count("//cached.example.org/"), 2);
+    }
+}
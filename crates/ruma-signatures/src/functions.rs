@@ -628,6 +628,52 @@ pub fn verify_event(
     Ok(Verified::Signatures)
 }
 
+/// Extracts the public keys that are needed to verify the signatures of the given event.
+///
+/// Returns a map from the name of the servers whose signatures [`verify_event`] checks to the IDs
+/// of the keys they signed the event with. A [`PublicKeyMap`] containing all these keys is
+/// sufficient to verify the event.
+///
+/// Signatures with an unsupported algorithm are ignored, like in [`verify_event`].
+///
+/// # Parameters
+///
+/// * object: The JSON object of the event that was signed.
+/// * version: Room version of the given event
+///
+/// # Errors
+///
+/// Returns an error if the event is malformed, or if it is missing the signatures of a server
+/// that needs to sign it.
+pub fn required_keys(
+    object: &CanonicalJsonObject,
+    version: &RoomVersionId,
+) -> Result<BTreeMap<String, BTreeSet<String>>, Error> {
+    let signature_map = match object.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => signatures,
+        Some(_) => return Err(JsonError::not_of_type("signatures", JsonType::Object)),
+        None => return Err(JsonError::field_missing_from_object("signatures")),
+    };
+
+    let mut required_keys = BTreeMap::new();
+
+    for entity_id in servers_to_check_signatures(object, version)? {
+        let signature_set = match signature_map.get(entity_id.as_str()) {
+            Some(CanonicalJsonValue::Object(set)) => set,
+            Some(_) => {
+                return Err(JsonError::not_multiples_of_type("signature sets", JsonType::Object))
+            }
+            None => return Err(VerificationError::signature_not_found(entity_id)),
+        };
+
+        let key_ids =
+            signature_set.keys().filter(|key_id| split_id(key_id).is_ok()).cloned().collect();
+        required_keys.insert(entity_id.into(), key_ids);
+    }
+
+    Ok(required_keys)
+}
+
 /// Internal implementation detail of the canonical JSON algorithm.
 ///
 /// Allows customization of the fields that will be removed before serializing.
@@ -733,7 +779,7 @@ mod tests {
 
     use super::canonical_json;
     use crate::{
-        sign_json, verify_event, Ed25519KeyPair, Error, PublicKeyMap, PublicKeySet,
+        required_keys, sign_json, verify_event, Ed25519KeyPair, Error, PublicKeyMap, PublicKeySet,
         VerificationError, Verified,
     };
 
@@ -1151,6 +1197,63 @@ mod tests {
         );
     }
 
+    #[test]
+    fn required_keys_for_sender_and_authorized_user() {
+        let key_pair_sender = generate_key_pair("1");
+        let secondary_key_pair_sender = generate_key_pair("2");
+        let key_pair_authorized = generate_key_pair("3");
+        let mut signed_event = serde_json::from_str(
+            r#"{
+                "auth_events": [],
+                "content": {"join_authorised_via_users_server": "@authorized:domain-authorized"},
+                "depth": 3,
+                "hashes": {
+                    "sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"
+                },
+                "origin": "domain",
+                "origin_server_ts": 1000000,
+                "prev_events": [],
+                "room_id": "!x:domain",
+                "sender": "@name:domain-sender",
+                "type": "m.room.member",
+                "signatures": {
+                    "domain-sender": {
+                        "an-unknown-algorithm:1": "pE5UT/4JiY7YZDtZDOsEaxc0wblurdoYqNQx4bCXORA3vLFOGOK10Q/xXVLPWWgIKo15LNvWwWd/2YjmdPvYCg"
+                    },
+                    "domain-other": {
+                        "ed25519:1": "pE5UT/4JiY7YZDtZDOsEaxc0wblurdoYqNQx4bCXORA3vLFOGOK10Q/xXVLPWWgIKo15LNvWwWd/2YjmdPvYCg"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        sign_json("domain-sender", &key_pair_sender, &mut signed_event).unwrap();
+        sign_json("domain-sender", &secondary_key_pair_sender, &mut signed_event).unwrap();
+
+        // The authorizing server didn't sign the event.
+        assert_matches!(
+            required_keys(&signed_event, &RoomVersionId::V9),
+            Err(Error::Verification(VerificationError::SignatureNotFound(server_name)))
+        );
+        assert_eq!(server_name, "domain-authorized");
+
+        sign_json("domain-authorized", &key_pair_authorized, &mut signed_event).unwrap();
+        let required = required_keys(&signed_event, &RoomVersionId::V9).unwrap();
+        assert_eq!(required.len(), 2);
+        assert_eq!(
+            required["domain-sender"].iter().map(String::as_str).collect::<Vec<_>>(),
+            ["ed25519:1", "ed25519:2"]
+        );
+        assert_eq!(
+            required["domain-authorized"].iter().map(String::as_str).collect::<Vec<_>>(),
+            ["ed25519:3"]
+        );
+
+        // Before room version 8, the authorizing server is not checked.
+        let required = required_keys(&signed_event, &RoomVersionId::V6).unwrap();
+        assert_eq!(required.keys().collect::<Vec<_>>(), ["domain-sender"]);
+    }
+
     fn generate_key_pair(name: &str) -> Ed25519KeyPair {
         let key_content = Ed25519KeyPair::generate().unwrap();
         Ed25519KeyPair::from_der(&key_content, name.to_owned())
@@ -529,6 +529,7 @@ impl EventTypeExt for &TimelineEventType {
 }
 
 mod event {
+    use js_int::UInt;
     use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, UserId};
     use ruma_events::{pdu::Pdu, TimelineEventType};
     use ruma_state_res::Event;
@@ -596,6 +597,15 @@ mod event {
             }
         }
 
+        fn depth(&self) -> UInt {
+            match &self.rest {
+                Pdu::RoomV1Pdu(ev) => ev.depth,
+                Pdu::RoomV3Pdu(ev) => ev.depth,
+                #[allow(unreachable_patterns)]
+                _ => unreachable!("new PDU version"),
+            }
+        }
+
         fn prev_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_> {
             match &self.rest {
                 Pdu::RoomV1Pdu(ev) => Box::new(ev.prev_events.iter().map(|(id, _)| id)),
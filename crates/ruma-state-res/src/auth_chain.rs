@@ -0,0 +1,675 @@
+//! Computation of auth chains and of the auth chain difference of state sets.
+//!
+//! The auth chain of an event is the set of its `auth_events`, and of their auth chains
+//! recursively. [`AuthChains`] computes them from an event source and remembers them, so they can
+//! be reused between calls to [`resolve_with_auth_chains`](crate::resolve_with_auth_chains).
+//!
+//! For large rooms, remembering the full auth chain of every event becomes expensive. An
+//! [`AuthChains`] created with [`AuthChains::with_chain_cover_index`] builds a
+//! [`ChainCoverIndex`] instead, from which the auth chain difference can be computed without
+//! building the auth chains.
+
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    hash::Hash,
+    sync::Arc,
+};
+
+use ruma_common::EventId;
+use ruma_events::TimelineEventType;
+
+use crate::{Error, Event, Result, StateMap};
+
+/// A memoizing calculator of auth chains.
+#[derive(Debug)]
+pub struct AuthChains<Id> {
+    /// The auth chains that were already computed.
+    chains: HashMap<Id, Arc<HashSet<Id>>>,
+
+    /// The chain cover index, if it is enabled.
+    chain_cover_index: Option<ChainCoverIndex<Id>>,
+}
+
+impl<Id> AuthChains<Id>
+where
+    Id: Clone + Eq + Hash + Borrow<EventId>,
+{
+    /// Creates an empty `AuthChains` that remembers the auth chain of every event.
+    pub fn new() -> Self {
+        Self { chains: HashMap::new(), chain_cover_index: None }
+    }
+
+    /// Creates an empty `AuthChains` that builds a [`ChainCoverIndex`] of the events.
+    ///
+    /// The auth chain difference is computed with the index, so computing it doesn't remember the
+    /// auth chains. The auth chains computed with [`auth_chain()`](Self::auth_chain) or
+    /// [`auth_chain_sets()`](Self::auth_chain_sets) are still remembered.
+    pub fn with_chain_cover_index() -> Self {
+        Self { chains: HashMap::new(), chain_cover_index: Some(ChainCoverIndex::new()) }
+    }
+
+    /// The chain cover index, if it is enabled.
+    pub fn chain_cover_index(&self) -> Option<&ChainCoverIndex<Id>> {
+        self.chain_cover_index.as_ref()
+    }
+
+    /// Get the auth chain of the given event.
+    ///
+    /// The auth chain doesn't include the event itself.
+    ///
+    /// Returns an [`Error::NotFound`] if an event in the auth chain cannot be fetched.
+    pub fn auth_chain<E>(
+        &mut self,
+        event_id: &EventId,
+        fetch_event: impl Fn(&EventId) -> Option<E>,
+    ) -> Result<HashSet<Id>>
+    where
+        E: Event<Id = Id>,
+    {
+        let event = fetch(event_id, &fetch_event)?;
+
+        let mut auth_chain = HashSet::new();
+        for auth_event_id in event.auth_events() {
+            auth_chain.insert(auth_event_id.clone());
+            auth_chain
+                .extend(self.inclusive_auth_chain(auth_event_id, &fetch_event)?.iter().cloned());
+        }
+
+        Ok(auth_chain)
+    }
+
+    /// Get the full auth chain of each of the given state sets.
+    ///
+    /// The full auth chain of a state set is the union of the state events and of their auth
+    /// chains. This is the `auth_chain_sets` argument expected by [`resolve`](crate::resolve).
+    ///
+    /// Returns an [`Error::NotFound`] if an event in the auth chains cannot be fetched.
+    pub fn auth_chain_sets<'a, E>(
+        &mut self,
+        state_sets: impl IntoIterator<Item = &'a StateMap<Id>>,
+        fetch_event: impl Fn(&EventId) -> Option<E>,
+    ) -> Result<Vec<HashSet<Id>>>
+    where
+        Id: 'a,
+        E: Event<Id = Id>,
+    {
+        state_sets
+            .into_iter()
+            .map(|state_set| {
+                let mut auth_chain = HashSet::new();
+                for event_id in state_set.values() {
+                    if auth_chain.contains(event_id) {
+                        continue;
+                    }
+
+                    let chain = self.inclusive_auth_chain(event_id, &fetch_event)?;
+                    auth_chain.extend(chain.iter().cloned());
+                }
+
+                Ok(auth_chain)
+            })
+            .collect()
+    }
+
+    /// Get the auth chain difference of the given state sets.
+    ///
+    /// The auth chain difference is the set of events that are in the full auth chain of some
+    /// state sets, but not of all of them.
+    ///
+    /// Returns an [`Error::NotFound`] if an event in the auth chains cannot be fetched.
+    pub fn auth_chain_difference<'a, E>(
+        &mut self,
+        state_sets: impl IntoIterator<Item = &'a StateMap<Id>>,
+        fetch_event: impl Fn(&EventId) -> Option<E>,
+    ) -> Result<HashSet<Id>>
+    where
+        Id: 'a,
+        E: Event<Id = Id>,
+    {
+        if let Some(index) = &mut self.chain_cover_index {
+            let state_sets = state_sets.into_iter().collect::<Vec<_>>();
+
+            for event_id in state_sets.iter().flat_map(|state_set| state_set.values()) {
+                index.add_event_and_auth_chain(event_id.borrow(), &fetch_event)?;
+            }
+
+            return index
+                .auth_chain_difference(state_sets.into_iter().map(|state_set| state_set.values()));
+        }
+
+        let auth_chain_sets = self.auth_chain_sets(state_sets, fetch_event)?;
+        Ok(crate::get_auth_chain_diff(auth_chain_sets).collect())
+    }
+
+    /// Get the auth chain of the given event, including the event itself.
+    ///
+    /// Walks the auth events iteratively, so long auth chains don't overflow the stack.
+    fn inclusive_auth_chain<E>(
+        &mut self,
+        event_id: &Id,
+        fetch_event: impl Fn(&EventId) -> Option<E>,
+    ) -> Result<Arc<HashSet<Id>>>
+    where
+        E: Event<Id = Id>,
+    {
+        // The events are visited a first time to add their auth events to the stack, and a
+        // second time once the auth chains of their auth events are known.
+        let mut stack = vec![(event_id.clone(), false)];
+        let mut visiting = HashSet::new();
+
+        while let Some((event_id, auth_events_visited)) = stack.pop() {
+            if self.chains.contains_key(event_id.borrow())
+                || (!auth_events_visited && !visiting.insert(event_id.clone()))
+            {
+                continue;
+            }
+
+            let event = fetch(event_id.borrow(), &fetch_event)?;
+
+            if auth_events_visited {
+                let mut auth_chain = HashSet::from([event_id.clone()]);
+                for auth_event_id in event.auth_events() {
+                    // The auth chain is missing if there is a cycle in the auth events.
+                    let chain = self.chains.get(auth_event_id.borrow()).ok_or_else(|| {
+                        let event_id: &EventId = event_id.borrow();
+                        Error::InvalidPdu(format!("cycle in the auth events of {event_id}"))
+                    })?;
+                    auth_chain.extend(chain.iter().cloned());
+                }
+
+                self.chains.insert(event_id, Arc::new(auth_chain));
+            } else {
+                stack.push((event_id.clone(), true));
+                stack.extend(
+                    event
+                        .auth_events()
+                        .filter(|id| !self.chains.contains_key((*id).borrow()))
+                        .map(|id| (id.clone(), false)),
+                );
+            }
+        }
+
+        Ok(self.chains[event_id.borrow()].clone())
+    }
+}
+
+impl<Id> Default for AuthChains<Id>
+where
+    Id: Clone + Eq + Hash + Borrow<EventId>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The position of an event in a [`ChainCoverIndex`].
+///
+/// The sequence numbers in a chain start at 1.
+type Position = (usize, u64);
+
+/// An index of the auth events of a room, that allows to compute auth chains and their difference
+/// efficiently.
+///
+/// Every event is assigned to a chain, in which it is preceded by events in its auth chain. An
+/// event extends the chain of one of its auth events if it replaces the same state and that auth
+/// event is the last one of its chain, otherwise it starts a new chain. The index also stores links
+/// from each event to the last events of other chains that are reachable through its auth events.
+///
+/// With this structure, the part of the auth chain of an event that belongs to a given chain is
+/// always the start of that chain, until a certain sequence number.
+///
+/// See the [chain cover index of Synapse](https://github.com/matrix-org/synapse/blob/develop/docs/auth_chain_difference_algorithm.md).
+#[derive(Debug)]
+pub struct ChainCoverIndex<Id> {
+    /// The positions of the indexed events.
+    positions: HashMap<Id, Position>,
+
+    /// The chains, in the order of their IDs.
+    chains: Vec<Chain<Id>>,
+}
+
+#[derive(Debug)]
+struct Chain<Id> {
+    /// The type and state key of the events in this chain.
+    key: (TimelineEventType, Option<String>),
+
+    /// The events in this chain, in the order of their sequence numbers.
+    events: Vec<Id>,
+
+    /// The links of the events in this chain, as `(sequence number, target)`, in ascending order
+    /// of sequence number.
+    links: Vec<(u64, Position)>,
+}
+
+impl<Id> ChainCoverIndex<Id>
+where
+    Id: Clone + Eq + Hash + Borrow<EventId>,
+{
+    /// Creates an empty `ChainCoverIndex`.
+    pub fn new() -> Self {
+        Self { positions: HashMap::new(), chains: Vec::new() }
+    }
+
+    /// The number of events in this index.
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Whether this index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Whether the given event is in this index.
+    pub fn contains(&self, event_id: &EventId) -> bool {
+        self.positions.contains_key(event_id)
+    }
+
+    /// Add the given event to this index.
+    ///
+    /// Does nothing if the event is already in the index.
+    ///
+    /// Returns an [`Error::NotFound`] if one of the auth events of the event is not in the index.
+    pub fn add_event<E>(&mut self, event: &E) -> Result<()>
+    where
+        E: Event<Id = Id>,
+    {
+        let event_id: &EventId = event.event_id().borrow();
+        if self.positions.contains_key(event_id) {
+            return Ok(());
+        }
+
+        let key = (event.event_type().clone(), event.state_key().map(ToOwned::to_owned));
+
+        let mut auth_positions = Vec::new();
+        for auth_event_id in event.auth_events() {
+            let auth_event_id: &EventId = auth_event_id.borrow();
+            let position = *self.positions.get(auth_event_id).ok_or_else(|| {
+                Error::NotFound(format!("auth event {auth_event_id} of {event_id} is not indexed"))
+            })?;
+            auth_positions.push(position);
+        }
+
+        // Extend the chain of an auth event that is replaced by this event, if it is the last one.
+        let extended_chain = auth_positions.iter().copied().find(|&(chain_id, seq)| {
+            let chain = &self.chains[chain_id];
+            chain.key == key && chain.events.len() as u64 == seq
+        });
+
+        let position = match extended_chain {
+            Some((chain_id, seq)) => {
+                self.chains[chain_id].events.push(event.event_id().clone());
+                (chain_id, seq + 1)
+            }
+            None => {
+                self.chains.push(Chain {
+                    key,
+                    events: vec![event.event_id().clone()],
+                    links: Vec::new(),
+                });
+                (self.chains.len() - 1, 1)
+            }
+        };
+
+        // Link to the last reachable auth event of each other chain.
+        let mut links = HashMap::<usize, u64>::new();
+        for (chain_id, seq) in auth_positions {
+            if chain_id != position.0 {
+                let target_seq = links.entry(chain_id).or_default();
+                *target_seq = (*target_seq).max(seq);
+            }
+        }
+        self.chains[position.0]
+            .links
+            .extend(links.into_iter().map(|(chain_id, seq)| (position.1, (chain_id, seq))));
+
+        self.positions.insert(event.event_id().clone(), position);
+
+        Ok(())
+    }
+
+    /// Add the given event and its auth chain to this index.
+    ///
+    /// The events that are not in the index yet are fetched, and added after their auth events.
+    ///
+    /// Returns an [`Error::NotFound`] if an event in the auth chain cannot be fetched.
+    pub fn add_event_and_auth_chain<E>(
+        &mut self,
+        event_id: &EventId,
+        fetch_event: impl Fn(&EventId) -> Option<E>,
+    ) -> Result<()>
+    where
+        E: Event<Id = Id>,
+    {
+        let mut stack = vec![(fetch(event_id, &fetch_event)?, false)];
+        let mut visiting = HashSet::new();
+
+        while let Some((event, auth_events_visited)) = stack.pop() {
+            if self.positions.contains_key(event.event_id().borrow()) {
+                continue;
+            }
+
+            if auth_events_visited {
+                self.add_event(&event)?;
+                continue;
+            }
+
+            // The event was already visited through another path, or there is a cycle in the
+            // auth events, in which case adding the event fails.
+            if !visiting.insert(event.event_id().clone()) {
+                continue;
+            }
+
+            let mut auth_events = Vec::new();
+            for auth_event_id in event.auth_events() {
+                if !self.positions.contains_key(auth_event_id.borrow()) {
+                    auth_events.push((fetch(auth_event_id.borrow(), &fetch_event)?, false));
+                }
+            }
+
+            stack.push((event, true));
+            stack.extend(auth_events);
+        }
+
+        Ok(())
+    }
+
+    /// Get the auth chain of the given indexed event.
+    ///
+    /// The auth chain doesn't include the event itself.
+    ///
+    /// Returns an [`Error::NotFound`] if the event is not in the index.
+    pub fn auth_chain(&self, event_id: &EventId) -> Result<HashSet<Id>> {
+        let (chain_id, seq) = self.position(event_id)?;
+
+        let starts = self.chains[chain_id]
+            .links
+            .iter()
+            .filter(|(s, _)| *s == seq)
+            .map(|&(_, target)| target)
+            .chain((seq > 1).then_some((chain_id, seq - 1)));
+
+        Ok(self.events_until(self.reachable(starts)).collect())
+    }
+
+    /// Get the auth chain difference of the given sets of indexed events.
+    ///
+    /// The events in a set are included in its auth chain.
+    ///
+    /// Returns an [`Error::NotFound`] if an event is not in the index.
+    pub fn auth_chain_difference<'a>(
+        &self,
+        event_sets: impl IntoIterator<Item = impl IntoIterator<Item = &'a Id>>,
+    ) -> Result<HashSet<Id>>
+    where
+        Id: 'a,
+    {
+        let reachable_sets = event_sets
+            .into_iter()
+            .map(|event_ids| {
+                let positions = event_ids
+                    .into_iter()
+                    .map(|id| self.position(id.borrow()))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.reachable(positions))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // For each chain, the events between the smallest and the largest reachable sequence
+        // numbers are in the difference.
+        let mut ranges = HashMap::<usize, (u64, u64)>::new();
+        for reachable in &reachable_sets {
+            for (&chain_id, &seq) in reachable {
+                let (min, max) = ranges.entry(chain_id).or_insert((seq, seq));
+                *min = (*min).min(seq);
+                *max = (*max).max(seq);
+            }
+        }
+
+        let mut difference = HashSet::new();
+        for (chain_id, (min, max)) in ranges {
+            let in_all_sets =
+                reachable_sets.iter().all(|reachable| reachable.contains_key(&chain_id));
+            let start = if in_all_sets { min } else { 0 };
+
+            difference
+                .extend(self.chains[chain_id].events[start as usize..max as usize].iter().cloned());
+        }
+
+        Ok(difference)
+    }
+
+    fn position(&self, event_id: &EventId) -> Result<Position> {
+        self.positions
+            .get(event_id)
+            .copied()
+            .ok_or_else(|| Error::NotFound(format!("{event_id} is not indexed")))
+    }
+
+    /// Get the largest sequence number of each chain that is reachable from the given positions,
+    /// including the positions themselves.
+    fn reachable(&self, starts: impl IntoIterator<Item = Position>) -> HashMap<usize, u64> {
+        let mut reachable = HashMap::<usize, u64>::new();
+        let mut stack = starts.into_iter().collect::<Vec<_>>();
+
+        while let Some((chain_id, seq)) = stack.pop() {
+            // The links of the events until the previously reachable one were already followed.
+            let previous = match reachable.entry(chain_id) {
+                Entry::Occupied(mut entry) => {
+                    let previous = *entry.get();
+                    if previous >= seq {
+                        continue;
+                    }
+                    entry.insert(seq);
+                    previous
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(seq);
+                    0
+                }
+            };
+
+            stack.extend(
+                self.chains[chain_id]
+                    .links
+                    .iter()
+                    .filter(|(s, _)| *s > previous && *s <= seq)
+                    .map(|&(_, target)| target),
+            );
+        }
+
+        reachable
+    }
+
+    /// Get the events of each chain until the given sequence number.
+    fn events_until(&self, reachable: HashMap<usize, u64>) -> impl Iterator<Item = Id> + '_ {
+        reachable.into_iter().flat_map(move |(chain_id, seq)| {
+            self.chains[chain_id].events[..seq as usize].iter().cloned()
+        })
+    }
+}
+
+impl<Id> Default for ChainCoverIndex<Id>
+where
+    Id: Clone + Eq + Hash + Borrow<EventId>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn fetch<E: Event>(event_id: &EventId, fetch_event: impl Fn(&EventId) -> Option<E>) -> Result<E> {
+    fetch_event(event_id).ok_or_else(|| Error::NotFound(format!("{event_id} not found")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use ruma_common::{EventId, OwnedEventId};
+    use ruma_events::TimelineEventType;
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::{AuthChains, ChainCoverIndex};
+    use crate::{
+        test_utils::{
+            alice, bob, ella, event::PduEvent, event_id, member_content_ban, member_content_join,
+            room_id, to_pdu_event, TestStore, INITIAL_EVENTS,
+        },
+        Event, EventTypeExt, StateMap,
+    };
+
+    fn store() -> TestStore<PduEvent> {
+        let mut events = INITIAL_EVENTS();
+        events.extend(
+            [
+                to_pdu_event(
+                    "PA",
+                    alice(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                    &["CREATE", "IMA", "IPOWER"],
+                    &["IMC"],
+                ),
+                to_pdu_event(
+                    "PB",
+                    alice(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(&json!({ "users": { alice(): 100 } })).unwrap(),
+                    &["CREATE", "IMA", "PA"],
+                    &["PA"],
+                ),
+                to_pdu_event(
+                    "IME",
+                    ella(),
+                    TimelineEventType::RoomMember,
+                    Some(ella().as_str()),
+                    member_content_join(),
+                    &["CREATE", "IJR", "PA"],
+                    &["PA"],
+                ),
+                to_pdu_event(
+                    "MB",
+                    alice(),
+                    TimelineEventType::RoomMember,
+                    Some(ella().as_str()),
+                    member_content_ban(),
+                    &["CREATE", "IMA", "PB", "IME"],
+                    &["PB", "IME"],
+                ),
+            ]
+            .into_iter()
+            .map(|ev| (ev.event_id().clone(), ev)),
+        );
+
+        TestStore(events)
+    }
+
+    fn state_set(store: &TestStore<PduEvent>, ids: &[&str]) -> StateMap<OwnedEventId> {
+        ids.iter()
+            .map(|id| {
+                let ev = &store.0[&event_id(id)];
+                (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id().clone())
+            })
+            .collect()
+    }
+
+    fn fetch(store: &TestStore<PduEvent>) -> impl Fn(&EventId) -> Option<Arc<PduEvent>> + '_ {
+        |id| store.0.get(id).map(Arc::clone)
+    }
+
+    #[test]
+    fn auth_chains() {
+        let store = store();
+        let mut auth_chains = AuthChains::new();
+
+        let mut index = ChainCoverIndex::new();
+        for id in store.0.keys() {
+            index.add_event_and_auth_chain(id, fetch(&store)).unwrap();
+        }
+        assert_eq!(index.len(), store.0.len());
+
+        for id in store.0.keys() {
+            let mut expected = store.auth_event_ids(room_id(), vec![id.clone()]).unwrap();
+            expected.remove(id);
+
+            assert_eq!(auth_chains.auth_chain(id, fetch(&store)).unwrap(), expected, "{id}");
+            assert_eq!(index.auth_chain(id).unwrap(), expected, "{id}");
+        }
+
+        let chain = auth_chains.auth_chain(&event_id("MB"), fetch(&store)).unwrap();
+        let expected = ["CREATE", "IMA", "IPOWER", "IJR", "PA", "PB", "IME"]
+            .into_iter()
+            .map(event_id)
+            .collect::<HashSet<_>>();
+        assert_eq!(chain, expected);
+    }
+
+    #[test]
+    fn auth_chain_difference() {
+        let store = store();
+        let state_sets = [
+            state_set(&store, &["CREATE", "IMA", "IJR", "IMB", "MB"]),
+            state_set(&store, &["CREATE", "IMA", "IJR", "IMC", "IME", "PA"]),
+            state_set(&store, &["CREATE", "IMA", "IJR", "IPOWER"]),
+        ];
+
+        let mut auth_chains = AuthChains::new();
+        let mut indexed_auth_chains = AuthChains::with_chain_cover_index();
+
+        for len in 1..=state_sets.len() {
+            let state_sets = &state_sets[..len];
+
+            let auth_chain_sets = auth_chains.auth_chain_sets(state_sets, fetch(&store)).unwrap();
+            for (state_set, auth_chain) in state_sets.iter().zip(&auth_chain_sets) {
+                let expected =
+                    store.auth_event_ids(room_id(), state_set.values().cloned().collect()).unwrap();
+                assert_eq!(*auth_chain, expected);
+            }
+
+            let mut id_counts = HashMap::<_, usize>::new();
+            for id in auth_chain_sets.into_iter().flatten() {
+                *id_counts.entry(id).or_default() += 1;
+            }
+            let expected = id_counts
+                .into_iter()
+                .filter_map(|(id, count)| (count < len).then_some(id))
+                .collect::<HashSet<_>>();
+
+            assert_eq!(
+                auth_chains.auth_chain_difference(state_sets, fetch(&store)).unwrap(),
+                expected
+            );
+            assert_eq!(
+                indexed_auth_chains.auth_chain_difference(state_sets, fetch(&store)).unwrap(),
+                expected
+            );
+        }
+
+        let difference =
+            auth_chains.auth_chain_difference(&state_sets[..2], fetch(&store)).unwrap();
+        let expected = ["IMB", "IMC", "PB", "MB"].into_iter().map(event_id).collect::<HashSet<_>>();
+        assert_eq!(difference, expected);
+    }
+
+    #[test]
+    fn missing_auth_event() {
+        let mut store = store();
+        store.0.remove(&event_id("IPOWER"));
+
+        let mut auth_chains = AuthChains::new();
+        auth_chains.auth_chain(&event_id("IMA"), fetch(&store)).unwrap();
+        auth_chains.auth_chain(&event_id("PA"), fetch(&store)).unwrap_err();
+
+        let mut index = ChainCoverIndex::new();
+        index.add_event_and_auth_chain(&event_id("PA"), fetch(&store)).unwrap_err();
+        index.add_event(&store.0[&event_id("PB")]).unwrap_err();
+        assert!(!index.contains(&event_id("PB")));
+    }
+}
@@ -17,6 +17,7 @@ use serde::{
     Deserialize,
 };
 use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
+use thiserror::Error;
 use tracing::{debug, error, info, warn};
 
 use crate::{
@@ -40,6 +41,138 @@ struct RoomMemberContentFields {
     join_authorised_via_users_server: Option<Raw<OwnedUserId>>,
 }
 
+/// The reason why an event was rejected by the authorization rules.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum AuthRejection {
+    /// The `m.room.create` event has previous events.
+    #[error("the m.room.create event has previous events")]
+    CreateEventHasPrevEvents,
+
+    /// The server name of the room ID doesn't match the server name of the sender of the
+    /// `m.room.create` event.
+    #[error("the server name of the room ID does not match the server name of the sender")]
+    RoomIdServerNameMismatch,
+
+    /// The room version in the `m.room.create` event is invalid.
+    #[error("invalid room version in the m.room.create event")]
+    InvalidRoomVersion,
+
+    /// The `m.room.create` event has no `creator` field.
+    #[error("no creator field in the m.room.create event")]
+    MissingCreator,
+
+    /// There is no `m.room.create` event in the state.
+    #[error("no m.room.create event in the state")]
+    MissingCreateEvent,
+
+    /// The `m.room.create` event is not in the `auth_events` of the event.
+    #[error("the m.room.create event is not in the auth events")]
+    CreateEventNotInAuthEvents,
+
+    /// The room is not federated and the sender's server is not the server of the room creator.
+    #[error("the room is not federated and the sender is on another server")]
+    RoomNotFederated,
+
+    /// The state key of the `m.room.aliases` event doesn't match the server name of the sender.
+    #[error("the state key of the m.room.aliases event does not match the sender's server name")]
+    AliasesStateKeyMismatch,
+
+    /// The `m.room.member` event has no state key.
+    #[error("no state key in the m.room.member event")]
+    MissingStateKey,
+
+    /// The `membership` field of the `m.room.member` event is missing or invalid.
+    #[error("invalid membership in the m.room.member event")]
+    InvalidMembership,
+
+    /// The sender is not joined to the room.
+    #[error("the sender is not joined to the room")]
+    SenderNotJoined,
+
+    /// The state key is a user ID that doesn't match the sender.
+    #[error("the state key does not match the sender")]
+    StateKeyNotSender,
+
+    /// The sender's power level is too low to send an event of this type.
+    #[error(
+        "the power level required to send {event_type} is {required_level}, \
+         the sender's power level is {sender_level}"
+    )]
+    InsufficientPowerLevel {
+        /// The type of the event.
+        event_type: TimelineEventType,
+
+        /// The power level required to send the event.
+        required_level: Int,
+
+        /// The power level of the sender.
+        sender_level: Int,
+    },
+
+    /// The `m.room.power_levels` event has invalid content or a non-empty state key.
+    #[error("invalid m.room.power_levels event")]
+    InvalidPowerLevels,
+
+    /// The `m.room.power_levels` event changes power levels that are higher than or equal to the
+    /// sender's power level.
+    #[error("the sender cannot change power levels higher than or equal to their own")]
+    ForbiddenPowerLevelsChange,
+
+    /// The sender's power level is too low to redact the event, and the redacted event comes from
+    /// another server.
+    #[error("the sender cannot redact this event")]
+    InsufficientRedactPowerLevel,
+
+    /// The target user is banned from the room.
+    #[error("the target user is banned")]
+    TargetBanned,
+
+    /// The join rule of the room doesn't allow this membership.
+    #[error("the join rule {join_rule:?} does not allow the {membership} membership")]
+    JoinRuleForbidsMembership {
+        /// The join rule of the room.
+        join_rule: JoinRule,
+
+        /// The membership in the event.
+        membership: MembershipState,
+    },
+
+    /// The room has a restricted join rule and the event doesn't have a user authorizing the join.
+    #[error("no user authorizing the restricted join")]
+    MissingJoinAuthoriser,
+
+    /// The user authorizing the restricted join is not joined to the room or cannot invite users.
+    #[error("the user authorizing the restricted join cannot invite users")]
+    InvalidJoinAuthoriser,
+
+    /// The `third_party_invite` of the `m.room.member` event doesn't match an
+    /// `m.room.third_party_invite` event in the room.
+    #[error("invalid third-party invite")]
+    InvalidThirdPartyInvite,
+
+    /// The sender's power level is too low to change the membership of another user to this one.
+    #[error("the sender's power level is too low to change the membership to {membership}")]
+    InsufficientMembershipPowerLevel {
+        /// The membership in the event.
+        membership: MembershipState,
+    },
+
+    /// The target user's power level is not lower than the sender's power level.
+    #[error("the target user's power level is not lower than the sender's")]
+    TargetPowerLevelNotLower,
+
+    /// The membership of the target user cannot change from one state to another.
+    #[error("invalid membership transition from {from} to {to}")]
+    InvalidMembershipTransition {
+        /// The current membership of the target user.
+        from: MembershipState,
+
+        /// The membership in the event.
+        to: MembershipState,
+    },
+}
+
 /// For the given event `kind` what are the relevant auth events that are needed to authenticate
 /// this `content`.
 ///
@@ -121,12 +254,33 @@ pub fn auth_types_for_event(
 ///
 /// The `fetch_state` closure should gather state from a state snapshot. We need to know if the
 /// event passes auth against some state not a recursive collection of auth_events fields.
+///
+/// Use [`auth_check_with_rejection`] to know why an event was rejected.
 pub fn auth_check<E: Event>(
     room_version: &RoomVersion,
     incoming_event: impl Event,
     current_third_party_invite: Option<impl Event>,
     fetch_state: impl Fn(&StateEventType, &str) -> Option<E>,
 ) -> Result<bool> {
+    Ok(auth_check_with_rejection(
+        room_version,
+        incoming_event,
+        current_third_party_invite,
+        fetch_state,
+    )?
+    .is_ok())
+}
+
+/// Authenticate the incoming `event`, and return the reason why it was rejected.
+///
+/// This is the same as [`auth_check`], except that it returns `Ok(Err(rejection))` instead of
+/// `Ok(false)` when the event is rejected by the authorization rules.
+pub fn auth_check_with_rejection<E: Event>(
+    room_version: &RoomVersion,
+    incoming_event: impl Event,
+    current_third_party_invite: Option<impl Event>,
+    fetch_state: impl Fn(&StateEventType, &str) -> Option<E>,
+) -> Result<std::result::Result<(), AuthRejection>> {
     info!(
         "auth_check beginning for {} ({})",
         incoming_event.event_id(),
@@ -158,37 +312,37 @@ pub fn auth_check<E: Event>(
         // If it has any previous events, reject
         if incoming_event.prev_events().next().is_some() {
             warn!("the room creation event had previous events");
-            return Ok(false);
+            return Ok(Err(AuthRejection::CreateEventHasPrevEvents));
         }
 
         // If the domain of the room_id does not match the domain of the sender, reject
         let Some(room_id_server_name) = incoming_event.room_id().server_name() else {
             warn!("room ID has no servername");
-            return Ok(false);
+            return Ok(Err(AuthRejection::RoomIdServerNameMismatch));
         };
 
         if room_id_server_name != sender.server_name() {
             warn!("servername of room ID does not match servername of sender");
-            return Ok(false);
+            return Ok(Err(AuthRejection::RoomIdServerNameMismatch));
         }
 
         // If content.room_version is present and is not a recognized version, reject
         let content: RoomCreateContentFields = from_json_str(incoming_event.content().get())?;
         if content.room_version.map(|v| v.deserialize().is_err()).unwrap_or(false) {
             warn!("invalid room version found in m.room.create event");
-            return Ok(false);
+            return Ok(Err(AuthRejection::InvalidRoomVersion));
         }
 
         if !room_version.use_room_create_sender {
             // If content has no creator field, reject
             if content.creator.is_none() {
                 warn!("no creator field found in m.room.create content");
-                return Ok(false);
+                return Ok(Err(AuthRejection::MissingCreator));
             }
         }
 
         info!("m.room.create event was allowed");
-        return Ok(true);
+        return Ok(Ok(()));
     }
 
     /*
@@ -219,7 +373,7 @@ pub fn auth_check<E: Event>(
     let room_create_event = match fetch_state(&StateEventType::RoomCreate, "") {
         None => {
             warn!("no m.room.create event in auth chain");
-            return Ok(false);
+            return Ok(Err(AuthRejection::MissingCreateEvent));
         }
         Some(e) => e,
     };
@@ -228,7 +382,7 @@ pub fn auth_check<E: Event>(
     if !incoming_event.auth_events().any(|id| id.borrow() == room_create_event.event_id().borrow())
     {
         warn!("no m.room.create event in auth events");
-        return Ok(false);
+        return Ok(Err(AuthRejection::CreateEventNotInAuthEvents));
     }
 
     // If the create event content has the field m.federate set to false and the sender domain of
@@ -244,7 +398,7 @@ pub fn auth_check<E: Event>(
         && room_create_event.sender().server_name() != incoming_event.sender().server_name()
     {
         warn!("room is not federated and event's sender domain does not match create event's sender domain");
-        return Ok(false);
+        return Ok(Err(AuthRejection::RoomNotFederated));
     }
 
     // Only in some room versions 6 and below
@@ -256,11 +410,11 @@ pub fn auth_check<E: Event>(
             // If sender's domain doesn't matches state_key, reject
             if incoming_event.state_key() != Some(sender.server_name().as_str()) {
                 warn!("state_key does not match sender");
-                return Ok(false);
+                return Ok(Err(AuthRejection::AliasesStateKeyMismatch));
             }
 
             info!("m.room.aliases event was allowed");
-            return Ok(true);
+            return Ok(Ok(()));
         }
     }
 
@@ -273,7 +427,7 @@ pub fn auth_check<E: Event>(
         let state_key = match incoming_event.state_key() {
             None => {
                 warn!("no statekey in member event");
-                return Ok(false);
+                return Ok(Err(AuthRejection::MissingStateKey));
             }
             Some(s) => s,
         };
@@ -281,7 +435,7 @@ pub fn auth_check<E: Event>(
         let content: RoomMemberContentFields = from_json_str(incoming_event.content().get())?;
         if content.membership.as_ref().and_then(|m| m.deserialize().ok()).is_none() {
             warn!("no valid membership field found for m.room.member event content");
-            return Ok(false);
+            return Ok(Err(AuthRejection::InvalidMembership));
         }
 
         let target_user =
@@ -297,7 +451,7 @@ pub fn auth_check<E: Event>(
             .map(|mem| mem.membership)
             .unwrap_or(MembershipState::Leave);
 
-        if !valid_membership_change(
+        if let Err(rejection) = valid_membership_change(
             room_version,
             target_user,
             fetch_state(&StateEventType::RoomMember, target_user.as_str()).as_ref(),
@@ -311,11 +465,11 @@ pub fn auth_check<E: Event>(
             &user_for_join_auth_membership,
             room_create_event,
         )? {
-            return Ok(false);
+            return Ok(Err(rejection));
         }
 
         info!("m.room.member event was allowed");
-        return Ok(true);
+        return Ok(Ok(()));
     }
 
     // If the sender's current membership state is not join, reject
@@ -323,7 +477,7 @@ pub fn auth_check<E: Event>(
         Some(mem) => mem,
         None => {
             warn!("sender not found in room");
-            return Ok(false);
+            return Ok(Err(AuthRejection::SenderNotJoined));
         }
     };
 
@@ -336,7 +490,7 @@ pub fn auth_check<E: Event>(
 
     if !matches!(membership_state, MembershipState::Join) {
         warn!("sender's membership is not join");
-        return Ok(false);
+        return Ok(Err(AuthRejection::SenderNotJoined));
     }
 
     // If type is m.room.third_party_invite
@@ -377,18 +531,24 @@ pub fn auth_check<E: Event>(
 
         if sender_power_level < invite_level {
             warn!("sender's cannot send invites in this room");
-            return Ok(false);
+            return Ok(Err(AuthRejection::InsufficientPowerLevel {
+                event_type: TimelineEventType::RoomThirdPartyInvite,
+                required_level: invite_level,
+                sender_level: sender_power_level,
+            }));
         }
 
         info!("m.room.third_party_invite event was allowed");
-        return Ok(true);
+        return Ok(Ok(()));
     }
 
     // If the event type's required power level is greater than the sender's power level, reject
     // If the event has a state_key that starts with an @ and does not match the sender, reject.
-    if !can_send_event(&incoming_event, power_levels_event.as_ref(), sender_power_level) {
+    if let Err(rejection) =
+        can_send_event(&incoming_event, power_levels_event.as_ref(), sender_power_level)
+    {
         warn!("user cannot send event");
-        return Ok(false);
+        return Ok(Err(rejection));
     }
 
     // If type is m.room.power_levels
@@ -403,11 +563,11 @@ pub fn auth_check<E: Event>(
         ) {
             if !required_pwr_lvl {
                 warn!("power level was not allowed");
-                return Ok(false);
+                return Ok(Err(AuthRejection::ForbiddenPowerLevelsChange));
             }
         } else {
             warn!("power level was not allowed");
-            return Ok(false);
+            return Ok(Err(AuthRejection::InvalidPowerLevels));
         }
         info!("power levels event allowed");
     }
@@ -430,12 +590,12 @@ pub fn auth_check<E: Event>(
         };
 
         if !check_redaction(room_version, incoming_event, sender_power_level, redact_level)? {
-            return Ok(false);
+            return Ok(Err(AuthRejection::InsufficientRedactPowerLevel));
         }
     }
 
     info!("allowing event passed all checks");
-    Ok(true)
+    Ok(Ok(()))
 }
 
 // TODO deserializing the member, power, join_rules event contents is done in conduit
@@ -461,7 +621,7 @@ fn valid_membership_change(
     user_for_join_auth: Option<&UserId>,
     user_for_join_auth_membership: &MembershipState,
     create_room: impl Event,
-) -> Result<bool> {
+) -> Result<std::result::Result<(), AuthRejection>> {
     #[derive(Deserialize)]
     struct GetThirdPartyInvite {
         third_party_invite: Option<Raw<ThirdPartyInvite>>,
@@ -532,7 +692,7 @@ fn valid_membership_change(
         false
     };
 
-    Ok(match target_membership {
+    Ok(match &target_membership {
         MembershipState::Join => {
             // 1. If the only previous event is an m.room.create and the state_key is the creator,
             // allow
@@ -560,25 +720,25 @@ fn valid_membership_change(
                 };
 
                 if is_creator {
-                    return Ok(true);
+                    return Ok(Ok(()));
                 }
             }
 
             if sender != target_user {
                 // If the sender does not match state_key, reject.
                 warn!("Can't make other user join");
-                false
+                Err(AuthRejection::StateKeyNotSender)
             } else if let MembershipState::Ban = target_user_current_membership {
                 // If the sender is banned, reject.
                 warn!(?target_user_membership_event_id, "Banned user can't join");
-                false
+                Err(AuthRejection::TargetBanned)
             } else if (join_rules == JoinRule::Invite
                     || room_version.allow_knocking && join_rules == JoinRule::Knock)
                 // If the join_rule is invite then allow if membership state is invite or join
                     && (target_user_current_membership == MembershipState::Join
                         || target_user_current_membership == MembershipState::Invite)
             {
-                true
+                Ok(())
             } else if room_version.restricted_join_rules
                 && matches!(join_rules, JoinRule::Restricted(_))
                 || room_version.knock_restricted_join_rule
@@ -590,17 +750,26 @@ fn valid_membership_change(
                     MembershipState::Invite | MembershipState::Join
                 ) {
                     // If membership state is join or invite, allow.
-                    true
-                } else {
+                    Ok(())
+                } else if user_for_join_auth.is_none() {
+                    Err(AuthRejection::MissingJoinAuthoriser)
+                } else if !user_for_join_auth_is_valid {
                     // If the join_authorised_via_users_server key in content is not a user with
                     // sufficient permission to invite other users, reject.
+                    Err(AuthRejection::InvalidJoinAuthoriser)
+                } else {
                     // Otherwise, allow.
-                    user_for_join_auth_is_valid
+                    Ok(())
                 }
-            } else {
+            } else if join_rules == JoinRule::Public {
                 // If the join_rule is public, allow.
+                Ok(())
+            } else {
                 // Otherwise, reject.
-                join_rules == JoinRule::Public
+                Err(AuthRejection::JoinRuleForbidsMembership {
+                    join_rule: join_rules,
+                    membership: MembershipState::Join,
+                })
             }
         }
         MembershipState::Invite => {
@@ -608,18 +777,17 @@ fn valid_membership_change(
             if let Some(tp_id) = third_party_invite.and_then(|i| i.deserialize().ok()) {
                 if target_user_current_membership == MembershipState::Ban {
                     warn!(?target_user_membership_event_id, "Can't invite banned user");
-                    false
+                    Err(AuthRejection::TargetBanned)
+                } else if verify_third_party_invite(
+                    Some(target_user),
+                    sender,
+                    &tp_id,
+                    current_third_party_invite,
+                ) {
+                    Ok(())
                 } else {
-                    let allow = verify_third_party_invite(
-                        Some(target_user),
-                        sender,
-                        &tp_id,
-                        current_third_party_invite,
-                    );
-                    if !allow {
-                        warn!("Third party invite invalid");
-                    }
-                    allow
+                    warn!("Third party invite invalid");
+                    Err(AuthRejection::InvalidThirdPartyInvite)
                 }
             } else if !sender_is_joined
                 || target_user_current_membership == MembershipState::Join
@@ -631,27 +799,42 @@ fn valid_membership_change(
                     "Can't invite user if sender not joined or the user is currently joined or \
                      banned",
                 );
-                false
-            } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.invite).is_some();
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to invite",
-                    );
+                if !sender_is_joined {
+                    Err(AuthRejection::SenderNotJoined)
+                } else if target_user_current_membership == MembershipState::Ban {
+                    Err(AuthRejection::TargetBanned)
+                } else {
+                    Err(AuthRejection::InvalidMembershipTransition {
+                        from: target_user_current_membership,
+                        to: MembershipState::Invite,
+                    })
                 }
-                allow
+            } else if sender_power.filter(|&p| p >= &power_levels.invite).is_some() {
+                Ok(())
+            } else {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to invite",
+                );
+                Err(AuthRejection::InsufficientMembershipPowerLevel {
+                    membership: MembershipState::Invite,
+                })
             }
         }
         MembershipState::Leave => {
             if sender == target_user {
-                let allow = target_user_current_membership == MembershipState::Join
-                    || target_user_current_membership == MembershipState::Invite;
-                if !allow {
+                if target_user_current_membership == MembershipState::Join
+                    || target_user_current_membership == MembershipState::Invite
+                {
+                    Ok(())
+                } else {
                     warn!(?target_user_membership_event_id, "Can't leave if not invited or joined");
+                    Err(AuthRejection::InvalidMembershipTransition {
+                        from: target_user_current_membership,
+                        to: MembershipState::Leave,
+                    })
                 }
-                allow
             } else if !sender_is_joined
                 || target_user_current_membership == MembershipState::Ban
                     && sender_power.filter(|&p| p < &power_levels.ban).is_some()
@@ -661,35 +844,55 @@ fn valid_membership_change(
                     ?sender_membership_event_id,
                     "Can't kick if sender not joined or user is already banned",
                 );
-                false
-            } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.kick).is_some()
-                    && target_power < sender_power;
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to kick",
-                    );
+                if sender_is_joined {
+                    Err(AuthRejection::InsufficientMembershipPowerLevel {
+                        membership: MembershipState::Leave,
+                    })
+                } else {
+                    Err(AuthRejection::SenderNotJoined)
                 }
-                allow
+            } else if sender_power.filter(|&p| p >= &power_levels.kick).is_none() {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to kick",
+                );
+                Err(AuthRejection::InsufficientMembershipPowerLevel {
+                    membership: MembershipState::Leave,
+                })
+            } else if target_power >= sender_power {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to kick",
+                );
+                Err(AuthRejection::TargetPowerLevelNotLower)
+            } else {
+                Ok(())
             }
         }
         MembershipState::Ban => {
             if !sender_is_joined {
                 warn!(?sender_membership_event_id, "Can't ban user if sender is not joined");
-                false
+                Err(AuthRejection::SenderNotJoined)
+            } else if sender_power.filter(|&p| p >= &power_levels.ban).is_none() {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to ban",
+                );
+                Err(AuthRejection::InsufficientMembershipPowerLevel {
+                    membership: MembershipState::Ban,
+                })
+            } else if target_power >= sender_power {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to ban",
+                );
+                Err(AuthRejection::TargetPowerLevelNotLower)
             } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.ban).is_some()
-                    && target_power < sender_power;
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to ban",
-                    );
-                }
-                allow
+                Ok(())
             }
         }
         MembershipState::Knock if room_version.allow_knocking => {
@@ -699,7 +902,10 @@ fn valid_membership_change(
                     && matches!(join_rules, JoinRule::KnockRestricted(_))
             {
                 warn!("Join rule is not set to knock or knock_restricted, knocking is not allowed");
-                false
+                Err(AuthRejection::JoinRuleForbidsMembership {
+                    join_rule: join_rules,
+                    membership: MembershipState::Knock,
+                })
             } else {
                 // 2. If `sender` does not match `state_key`, reject.
                 // 3. If the `sender`'s current membership is not `ban` or `join`, allow.
@@ -710,22 +916,28 @@ fn valid_membership_change(
                         ?target_user,
                         "Can't make another user join, sender did not match target"
                     );
-                    false
+                    Err(AuthRejection::StateKeyNotSender)
                 } else if matches!(sender_membership, MembershipState::Ban | MembershipState::Join)
                 {
                     warn!(
                         ?target_user_membership_event_id,
                         "Membership state of ban or join are invalid",
                     );
-                    false
+                    Err(AuthRejection::InvalidMembershipTransition {
+                        from: sender_membership,
+                        to: MembershipState::Knock,
+                    })
                 } else {
-                    true
+                    Ok(())
                 }
             }
         }
         _ => {
             warn!("Unknown membership transition");
-            false
+            Err(AuthRejection::InvalidMembershipTransition {
+                from: target_user_current_membership,
+                to: target_membership.clone(),
+            })
         }
     })
 }
@@ -733,22 +945,30 @@ fn valid_membership_change(
 /// Is the user allowed to send a specific event based on the rooms power levels.
 ///
 /// Does the event have the correct userId as its state_key if it's not the "" state_key.
-fn can_send_event(event: impl Event, ple: Option<impl Event>, user_level: Int) -> bool {
+fn can_send_event(
+    event: impl Event,
+    ple: Option<impl Event>,
+    user_level: Int,
+) -> std::result::Result<(), AuthRejection> {
     let event_type_power_level = get_send_level(event.event_type(), event.state_key(), ple);
 
     debug!("{} ev_type {event_type_power_level} usr {user_level}", event.event_id());
 
     if user_level < event_type_power_level {
-        return false;
+        return Err(AuthRejection::InsufficientPowerLevel {
+            event_type: event.event_type().clone(),
+            required_level: event_type_power_level,
+            sender_level: user_level,
+        });
     }
 
     if event.state_key().is_some_and(|k| k.starts_with('@'))
         && event.state_key() != Some(event.sender().as_str())
     {
-        return false; // permission required to post in this room
+        return Err(AuthRejection::StateKeyNotSender); // permission required to post in this room
     }
 
-    true
+    Ok(())
 }
 
 /// Confirm that the event sender has the required power levels.
@@ -1010,6 +1230,8 @@ fn verify_third_party_invite(
 mod tests {
     use std::sync::Arc;
 
+    use js_int::int;
+    use ruma_common::{user_id, UserId};
     use ruma_events::{
         room::{
             join_rules::{
@@ -1019,17 +1241,72 @@ mod tests {
         },
         StateEventType, TimelineEventType,
     };
-    use serde_json::value::to_raw_value as to_raw_json_value;
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
 
     use crate::{
-        event_auth::valid_membership_change,
+        event_auth::{auth_check_with_rejection, valid_membership_change, AuthRejection},
         test_utils::{
-            alice, charlie, ella, event_id, member_content_ban, member_content_join, room_id,
-            to_pdu_event, PduEvent, INITIAL_EVENTS, INITIAL_EVENTS_CREATE_ROOM,
+            alice, bob, charlie, ella, event_id, member_content_ban, member_content_join, room_id,
+            to_pdu_event, zara, PduEvent, INITIAL_EVENTS, INITIAL_EVENTS_CREATE_ROOM,
         },
         Event, EventTypeExt, RoomVersion, StateMap,
     };
 
+    /// The state of the room after the initial events.
+    fn initial_state() -> StateMap<Arc<PduEvent>> {
+        INITIAL_EVENTS()
+            .into_values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev))
+            .collect()
+    }
+
+    /// Add the given event to the state.
+    fn set_state(state: &mut StateMap<Arc<PduEvent>>, event: Arc<PduEvent>) {
+        state.insert(event.event_type().with_state_key(event.state_key().unwrap()), event);
+    }
+
+    /// Check the given event against the given state, and return the rejection if any.
+    fn check(
+        room_version: &RoomVersion,
+        event: &Arc<PduEvent>,
+        current_third_party_invite: Option<&Arc<PduEvent>>,
+        state: &StateMap<Arc<PduEvent>>,
+    ) -> Result<(), AuthRejection> {
+        auth_check_with_rejection(room_version, event, current_third_party_invite, |ty, key| {
+            state.get(&ty.with_state_key(key)).cloned()
+        })
+        .unwrap()
+    }
+
+    fn member_event(
+        id: &str,
+        sender: &UserId,
+        target: &UserId,
+        content: serde_json::Value,
+    ) -> Arc<PduEvent> {
+        to_pdu_event(
+            id,
+            sender,
+            TimelineEventType::RoomMember,
+            Some(target.as_str()),
+            to_raw_json_value(&content).unwrap(),
+            &["CREATE", "IMA", "IPOWER", "IJR"],
+            &["IMC"],
+        )
+    }
+
+    fn power_levels_event(id: &str, sender: &UserId, content: serde_json::Value) -> Arc<PduEvent> {
+        to_pdu_event(
+            id,
+            sender,
+            TimelineEventType::RoomPowerLevels,
+            Some(""),
+            to_raw_json_value(&content).unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IMC"],
+        )
+    }
+
     #[test]
     fn test_ban_pass() {
         let _ =
@@ -1055,7 +1332,7 @@ mod tests {
         let target_user = charlie();
         let sender = alice();
 
-        assert!(valid_membership_change(
+        valid_membership_change(
             &RoomVersion::V6,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1069,7 +1346,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .unwrap();
     }
 
     #[test]
@@ -1097,21 +1375,27 @@ mod tests {
         let target_user = charlie();
         let sender = charlie();
 
-        assert!(!valid_membership_change(
-            &RoomVersion::V6,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            None,
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        assert_eq!(
+            valid_membership_change(
+                &RoomVersion::V6,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            )
+            .unwrap(),
+            Err(AuthRejection::JoinRuleForbidsMembership {
+                join_rule: JoinRule::Invite,
+                membership: MembershipState::Join,
+            })
+        );
     }
 
     #[test]
@@ -1139,7 +1423,7 @@ mod tests {
         let target_user = alice();
         let sender = alice();
 
-        assert!(valid_membership_change(
+        valid_membership_change(
             &RoomVersion::V6,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1153,7 +1437,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .unwrap();
     }
 
     #[test]
@@ -1181,21 +1466,26 @@ mod tests {
         let target_user = alice();
         let sender = charlie();
 
-        assert!(!valid_membership_change(
-            &RoomVersion::V6,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            None,
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        assert_eq!(
+            valid_membership_change(
+                &RoomVersion::V6,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            )
+            .unwrap(),
+            Err(AuthRejection::InsufficientMembershipPowerLevel {
+                membership: MembershipState::Ban
+            })
+        );
     }
 
     #[test]
@@ -1240,7 +1530,7 @@ mod tests {
         let target_user = ella();
         let sender = ella();
 
-        assert!(valid_membership_change(
+        valid_membership_change(
             &RoomVersion::V9,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1254,23 +1544,27 @@ mod tests {
             &MembershipState::Join,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
-
-        assert!(!valid_membership_change(
-            &RoomVersion::V9,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            Some(ella()),
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            valid_membership_change(
+                &RoomVersion::V9,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                Some(ella()),
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            )
+            .unwrap(),
+            Err(AuthRejection::InvalidJoinAuthoriser)
+        );
     }
 
     #[test]
@@ -1307,7 +1601,7 @@ mod tests {
         let target_user = ella();
         let sender = ella();
 
-        assert!(valid_membership_change(
+        valid_membership_change(
             &RoomVersion::V7,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1321,6 +1615,366 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .unwrap();
+    }
+
+    #[test]
+    fn create_rejections() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let state = initial_state();
+        let create_event = |sender, content: serde_json::Value, prev_events: &[&str]| {
+            to_pdu_event(
+                "CREATE2",
+                sender,
+                TimelineEventType::RoomCreate,
+                Some(""),
+                to_raw_json_value(&content).unwrap(),
+                &[],
+                prev_events,
+            )
+        };
+
+        let event = create_event(alice(), json!({ "creator": alice() }), &["IMC"]);
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::CreateEventHasPrevEvents)
+        );
+
+        let sender = user_id!("@mallory:example.org");
+        let event = create_event(sender, json!({ "creator": sender }), &[]);
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::RoomIdServerNameMismatch)
+        );
+
+        let event = create_event(alice(), json!({ "creator": alice(), "room_version": 6 }), &[]);
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::InvalidRoomVersion)
+        );
+
+        let event = create_event(alice(), json!({}), &[]);
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::MissingCreator)
+        );
+        assert_eq!(check(&RoomVersion::V11, &event, None, &state), Ok(()));
+    }
+
+    #[test]
+    fn create_event_in_auth_events_rejections() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut state = initial_state();
+        let topic_event = |auth_events: &[&str]| {
+            to_pdu_event(
+                "TOPIC",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "Test" })).unwrap(),
+                auth_events,
+                &["IMC"],
+            )
+        };
+
+        let event = topic_event(&["CREATE", "IMA", "IPOWER"]);
+        assert_eq!(check(&RoomVersion::V6, &event, None, &state), Ok(()));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &StateMap::new()),
+            Err(AuthRejection::MissingCreateEvent)
+        );
+
+        let event = topic_event(&["IMA", "IPOWER"]);
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::CreateEventNotInAuthEvents)
+        );
+
+        set_state(
+            &mut state,
+            to_pdu_event::<&str>(
+                "CREATE",
+                alice(),
+                TimelineEventType::RoomCreate,
+                Some(""),
+                to_raw_json_value(&json!({ "creator": alice(), "m.federate": false })).unwrap(),
+                &[],
+                &[],
+            ),
+        );
+        let event = to_pdu_event(
+            "TOPIC",
+            user_id!("@mallory:example.org"),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({ "topic": "Test" })).unwrap(),
+            &["CREATE"],
+            &["IMC"],
+        );
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::RoomNotFederated)
+        );
+    }
+
+    #[test]
+    fn join_rejections() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut state = initial_state();
+
+        let event = member_event("JOIN", alice(), ella(), json!({ "membership": "join" }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::StateKeyNotSender)
+        );
+
+        let event = member_event("JOIN", ella(), ella(), json!({}));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::InvalidMembership)
+        );
+
+        let event = to_pdu_event(
+            "JOIN",
+            ella(),
+            TimelineEventType::RoomMember,
+            None,
+            member_content_join(),
+            &["CREATE", "IJR", "IPOWER"],
+            &["IMC"],
+        );
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::MissingStateKey)
+        );
+
+        set_state(&mut state, member_event("BAN", alice(), ella(), json!({ "membership": "ban" })));
+        let event = member_event("JOIN", ella(), ella(), json!({ "membership": "join" }));
+        assert_eq!(check(&RoomVersion::V6, &event, None, &state), Err(AuthRejection::TargetBanned));
+
+        set_state(
+            &mut state,
+            to_pdu_event(
+                "IJR",
+                alice(),
+                TimelineEventType::RoomJoinRules,
+                Some(""),
+                to_raw_json_value(&RoomJoinRulesEventContent::new(JoinRule::Restricted(
+                    Restricted::new(vec![AllowRule::RoomMembership(RoomMembership::new(
+                        room_id().to_owned(),
+                    ))]),
+                )))
+                .unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IPOWER"],
+            ),
+        );
+        let event = member_event("JOIN", zara(), zara(), json!({ "membership": "join" }));
+        assert_eq!(
+            check(&RoomVersion::V9, &event, None, &state),
+            Err(AuthRejection::MissingJoinAuthoriser)
+        );
+    }
+
+    #[test]
+    fn invite_rejections() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut state = initial_state();
+        set_state(
+            &mut state,
+            power_levels_event(
+                "IPOWER",
+                alice(),
+                json!({ "users": { alice(): 100 }, "invite": 50 }),
+            ),
+        );
+
+        let event = member_event("INVITE", ella(), zara(), json!({ "membership": "invite" }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::SenderNotJoined)
+        );
+
+        let event = member_event("INVITE", alice(), bob(), json!({ "membership": "invite" }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::InvalidMembershipTransition {
+                from: MembershipState::Join,
+                to: MembershipState::Invite,
+            })
+        );
+
+        let event = member_event("INVITE", bob(), ella(), json!({ "membership": "invite" }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::InsufficientMembershipPowerLevel {
+                membership: MembershipState::Invite
+            })
+        );
+
+        set_state(&mut state, member_event("BAN", alice(), ella(), json!({ "membership": "ban" })));
+        let event = member_event("INVITE", alice(), ella(), json!({ "membership": "invite" }));
+        assert_eq!(check(&RoomVersion::V6, &event, None, &state), Err(AuthRejection::TargetBanned));
+    }
+
+    #[test]
+    fn ban_rejections() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut state = initial_state();
+        set_state(
+            &mut state,
+            power_levels_event("IPOWER", alice(), json!({ "users": { alice(): 100, bob(): 100 } })),
+        );
+
+        let event = member_event("BAN", ella(), bob(), json!({ "membership": "ban" }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::SenderNotJoined)
+        );
+
+        let event = member_event("BAN", charlie(), ella(), json!({ "membership": "ban" }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::InsufficientMembershipPowerLevel {
+                membership: MembershipState::Ban
+            })
+        );
+
+        let event = member_event("BAN", alice(), bob(), json!({ "membership": "ban" }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::TargetPowerLevelNotLower)
+        );
+
+        let event = member_event("BAN", alice(), charlie(), json!({ "membership": "ban" }));
+        assert_eq!(check(&RoomVersion::V6, &event, None, &state), Ok(()));
+    }
+
+    #[test]
+    fn power_levels_rejections() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut state = initial_state();
+        set_state(
+            &mut state,
+            power_levels_event("IPOWER", alice(), json!({ "users": { alice(): 100, bob(): 50 } })),
+        );
+
+        let event =
+            power_levels_event("PL", ella(), json!({ "users": { alice(): 100, ella(): 100 } }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::SenderNotJoined)
+        );
+
+        let event = power_levels_event(
+            "PL",
+            charlie(),
+            json!({ "users": { alice(): 100, charlie(): 50 } }),
+        );
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::InsufficientPowerLevel {
+                event_type: TimelineEventType::RoomPowerLevels,
+                required_level: int!(50),
+                sender_level: int!(0),
+            })
+        );
+
+        let event =
+            power_levels_event("PL", bob(), json!({ "users": { alice(): 100, bob(): 100 } }));
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::ForbiddenPowerLevelsChange)
+        );
+
+        let event = to_pdu_event(
+            "PL",
+            alice(),
+            TimelineEventType::RoomPowerLevels,
+            Some("state_key"),
+            to_raw_json_value(&json!({ "users": { alice(): 100 } })).unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IMC"],
+        );
+        assert_eq!(
+            check(&RoomVersion::V6, &event, None, &state),
+            Err(AuthRejection::InvalidPowerLevels)
+        );
+    }
+
+    #[test]
+    fn third_party_invite_rejections() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut state = initial_state();
+        set_state(
+            &mut state,
+            power_levels_event(
+                "IPOWER",
+                alice(),
+                json!({ "users": { alice(): 100 }, "invite": 50 }),
+            ),
+        );
+
+        let third_party_invite = |sender| {
+            to_pdu_event(
+                "THIRD_PARTY_INVITE",
+                sender,
+                TimelineEventType::RoomThirdPartyInvite,
+                Some("token"),
+                to_raw_json_value(&json!({
+                    "display_name": "ella",
+                    "key_validity_url": "https://localhost/validity",
+                    "public_key": "public_key",
+                }))
+                .unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            )
+        };
+        assert_eq!(
+            check(&RoomVersion::V6, &third_party_invite(charlie()), None, &state),
+            Err(AuthRejection::InsufficientPowerLevel {
+                event_type: TimelineEventType::RoomThirdPartyInvite,
+                required_level: int!(50),
+                sender_level: int!(0),
+            })
+        );
+
+        let invite = member_event(
+            "INVITE",
+            alice(),
+            ella(),
+            json!({
+                "membership": "invite",
+                "third_party_invite": {
+                    "display_name": "ella",
+                    "signed": { "mxid": ella(), "token": "token", "signatures": {} },
+                },
+            }),
+        );
+        // There is no matching `m.room.third_party_invite` event.
+        assert_eq!(
+            check(&RoomVersion::V6, &invite, None, &state),
+            Err(AuthRejection::InvalidThirdPartyInvite)
+        );
+        // The `m.room.third_party_invite` event was not sent by the sender of the invite.
+        assert_eq!(
+            check(&RoomVersion::V6, &invite, Some(&third_party_invite(bob())), &state),
+            Err(AuthRejection::InvalidThirdPartyInvite)
+        );
+
+        set_state(&mut state, member_event("BAN", alice(), ella(), json!({ "membership": "ban" })));
+        assert_eq!(
+            check(&RoomVersion::V6, &invite, Some(&third_party_invite(alice())), &state),
+            Err(AuthRejection::TargetBanned)
+        );
     }
 }
@@ -0,0 +1,90 @@
+use std::{
+    borrow::Borrow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    future::Future,
+};
+
+use ruma_common::{EventId, OwnedEventId};
+
+use crate::{Event, Result};
+
+/// An asynchronous source of events, used by [`resolve_async`](crate::resolve_async).
+pub trait EventFetcher<E: Event> {
+    /// Fetch the events with the given IDs.
+    ///
+    /// The events that cannot be found are omitted from the returned list, and are ignored like
+    /// the events that are not returned by the `fetch_event` argument of
+    /// [`resolve`](crate::resolve).
+    fn fetch_events(&self, event_ids: &[&EventId]) -> impl Future<Output = Result<Vec<E>>> + Send;
+}
+
+/// The events fetched with an [`EventFetcher`].
+///
+/// Keeps track of the events that were requested during state resolution but were not fetched
+/// yet.
+pub(crate) struct FetchedEvents<E> {
+    /// The fetched events.
+    events: HashMap<OwnedEventId, E>,
+
+    /// The events that the fetcher could not find.
+    not_found: HashSet<OwnedEventId>,
+
+    /// The events that were requested but were not fetched yet.
+    misses: RefCell<HashSet<OwnedEventId>>,
+}
+
+impl<E: Event + Clone> FetchedEvents<E> {
+    pub(crate) fn new() -> Self {
+        Self { events: HashMap::new(), not_found: HashSet::new(), misses: RefCell::default() }
+    }
+
+    /// Fetch the given events that were not fetched yet in a single batch.
+    ///
+    /// Returns the events that were fetched by this call.
+    pub(crate) async fn fetch<'a>(
+        &mut self,
+        fetcher: &impl EventFetcher<E>,
+        event_ids: impl IntoIterator<Item = &'a EventId>,
+    ) -> Result<Vec<E>> {
+        let mut event_ids = event_ids
+            .into_iter()
+            .filter(|id| !self.events.contains_key(*id) && !self.not_found.contains(*id))
+            .collect::<Vec<_>>();
+        event_ids.sort_unstable();
+        event_ids.dedup();
+
+        if event_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let events = fetcher.fetch_events(&event_ids).await?;
+
+        self.not_found.extend(event_ids.into_iter().map(ToOwned::to_owned));
+        for event in &events {
+            let event_id: &EventId = event.event_id().borrow();
+            self.not_found.remove(event_id);
+            self.events.insert(event_id.to_owned(), event.clone());
+        }
+
+        Ok(events)
+    }
+
+    /// Get the fetched event with the given ID.
+    ///
+    /// If the event was not fetched yet, it is recorded as a miss.
+    pub(crate) fn get(&self, event_id: &EventId) -> Option<E> {
+        let event = self.events.get(event_id).cloned();
+
+        if event.is_none() && !self.not_found.contains(event_id) {
+            self.misses.borrow_mut().insert(event_id.to_owned());
+        }
+
+        event
+    }
+
+    /// Take the events that were requested but were not fetched yet.
+    pub(crate) fn take_misses(&mut self) -> HashSet<OwnedEventId> {
+        self.misses.take()
+    }
+}
@@ -7,7 +7,7 @@ use std::{
 
 use itertools::Itertools;
 use js_int::{int, Int};
-use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, RoomVersionId};
+use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
 use ruma_events::{
     room::member::{MembershipState, RoomMemberEventContent},
     StateEventType, TimelineEventType,
@@ -15,27 +15,75 @@ use ruma_events::{
 use serde_json::from_str as from_json_str;
 use tracing::{debug, info, trace, warn};
 
+pub mod auth_chain;
 mod error;
 pub mod event_auth;
+mod event_fetcher;
 mod power_levels;
 pub mod room_version;
 mod state_event;
+mod state_res_v1;
 #[cfg(test)]
 mod test_utils;
 
+pub use auth_chain::AuthChains;
 pub use error::{Error, Result};
-pub use event_auth::{auth_check, auth_types_for_event};
+pub use event_auth::{auth_check, auth_check_with_rejection, auth_types_for_event, AuthRejection};
+pub use event_fetcher::EventFetcher;
+use event_fetcher::FetchedEvents;
 use power_levels::PowerLevelsContentFields;
-pub use room_version::RoomVersion;
+pub use room_version::{RoomVersion, StateResolutionVersion};
 pub use state_event::Event;
 
 /// A mapping of event type and state_key to some value `T`, usually an `EventId`.
 pub type StateMap<T> = HashMap<(StateEventType, String), T>;
 
+/// A report of the authorization checks performed during state resolution.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct ResolutionReport<Id> {
+    /// The events that were checked against the partially resolved state, in the order they were
+    /// checked, with the reason why they were rejected if they were.
+    pub auth_checks: Vec<(Id, std::result::Result<(), AuthRejection>)>,
+}
+
+impl<Id> ResolutionReport<Id> {
+    /// Creates an empty `ResolutionReport`.
+    pub fn new() -> Self {
+        Self { auth_checks: Vec::new() }
+    }
+
+    /// The events that were rejected, with the reason why they were rejected.
+    pub fn rejected(&self) -> impl Iterator<Item = (&Id, &AuthRejection)> {
+        self.auth_checks
+            .iter()
+            .filter_map(|(id, result)| result.as_ref().err().map(|rejection| (id, rejection)))
+    }
+
+    /// The reason why the given event was rejected, if it was.
+    pub fn rejection(&self, event_id: &EventId) -> Option<&AuthRejection>
+    where
+        Id: Borrow<EventId>,
+    {
+        self.rejected().find_map(|(id, rejection)| (id.borrow() == event_id).then_some(rejection))
+    }
+
+    fn push(&mut self, event_id: Id, result: std::result::Result<(), AuthRejection>) {
+        self.auth_checks.push((event_id, result));
+    }
+}
+
+impl<Id> Default for ResolutionReport<Id> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Resolve sets of state events as they come in.
 ///
-/// Internally `StateResolution` builds a graph and an auth chain to allow for state conflict
-/// resolution.
+/// The state resolution algorithm is selected with the [`RoomVersion::state_res`] of the given
+/// room version. Internally, the algorithm for room versions 2 and later builds a graph and an auth
+/// chain to allow for state conflict resolution.
 ///
 /// ## Arguments
 ///
@@ -43,7 +91,7 @@ pub type StateMap<T> = HashMap<(StateEventType, String), T>;
 ///   the state of a room.
 ///
 /// * `auth_chain_sets` - The full recursive set of `auth_events` for each event in the
-///   `state_sets`.
+///   `state_sets`. It is not used by the algorithm for room version 1.
 ///
 /// * `fetch_event` - Any event not found in the `event_map` will defer to this closure to find the
 ///   event.
@@ -63,10 +111,196 @@ where
     E::Id: 'a,
     SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
 {
+    resolve_inner(
+        room_version,
+        state_sets,
+        |_| Ok(get_auth_chain_diff(auth_chain_sets).collect()),
+        fetch_event,
+        &mut ResolutionReport::new(),
+    )
+}
+
+/// Resolve sets of state events, and report the authorization checks that were performed.
+///
+/// This is the same as [`resolve`], except that it also returns a [`ResolutionReport`] with the
+/// reason why each conflicted event was rejected, if it was.
+///
+/// ## Arguments
+///
+/// * `state_sets` - The incoming state to resolve. Each `StateMap` represents a possible fork in
+///   the state of a room.
+///
+/// * `auth_chain_sets` - The full recursive set of `auth_events` for each event in the
+///   `state_sets`. It is not used by the algorithm for room version 1.
+///
+/// * `fetch_event` - Any event not found in the `event_map` will defer to this closure to find the
+///   event.
+///
+/// ## Invariants
+///
+/// The caller of `resolve_with_report` must ensure that all the events are from the same room.
+#[allow(clippy::type_complexity)]
+pub fn resolve_with_report<'a, E, SetIter>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: impl Fn(&EventId) -> Option<E>,
+) -> Result<(StateMap<E::Id>, ResolutionReport<E::Id>)>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+{
+    let mut report = ResolutionReport::new();
+    let resolved = resolve_inner(
+        room_version,
+        state_sets,
+        |_| Ok(get_auth_chain_diff(auth_chain_sets).collect()),
+        fetch_event,
+        &mut report,
+    )?;
+
+    Ok((resolved, report))
+}
+
+/// Resolve sets of state events, computing the auth chains with the given [`AuthChains`].
+///
+/// This is the same as [`resolve`], except that the auth chains of the state sets don't need to
+/// be computed by the caller. Since `auth_chains` remembers what it computed, it should be reused
+/// between calls for the same room.
+///
+/// ## Arguments
+///
+/// * `state_sets` - The incoming state to resolve. Each `StateMap` represents a possible fork in
+///   the state of a room.
+///
+/// * `auth_chains` - The calculator of the auth chains of the events.
+///
+/// * `fetch_event` - Used to find the events in the state sets and their auth chains.
+///
+/// ## Invariants
+///
+/// The caller of `resolve_with_auth_chains` must ensure that all the events are from the same room.
+pub fn resolve_with_auth_chains<'a, E, SetIter>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chains: &mut AuthChains<E::Id>,
+    fetch_event: impl Fn(&EventId) -> Option<E>,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+{
+    resolve_inner(
+        room_version,
+        state_sets,
+        |(state_sets, fetch_event)| auth_chains.auth_chain_difference(state_sets, fetch_event),
+        fetch_event,
+        &mut ResolutionReport::new(),
+    )
+}
+
+/// Resolve sets of state events, fetching the events asynchronously.
+///
+/// This is the same as [`resolve`], except that the events are fetched in batches with the given
+/// [`EventFetcher`] instead of being looked up one by one.
+///
+/// The events in the state sets and in the auth chain difference, and their auth events, are
+/// fetched before resolving the state. When the algorithm needs events that were not fetched yet,
+/// they are fetched and the algorithm is run again, until all the events it needs were fetched.
+///
+/// ## Arguments
+///
+/// * `state_sets` - The incoming state to resolve. Each `StateMap` represents a possible fork in
+///   the state of a room.
+///
+/// * `auth_chain_sets` - The full recursive set of `auth_events` for each event in the
+///   `state_sets`. It is not used by the algorithm for room version 1.
+///
+/// * `fetcher` - Used to fetch the events needed to resolve the state.
+///
+/// ## Invariants
+///
+/// The caller of `resolve_async` must ensure that all the events are from the same room.
+pub async fn resolve_async<'a, E, SetIter>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetcher: &impl EventFetcher<E>,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+{
+    let state_sets = state_sets.into_iter();
+    let auth_chain_diff = get_auth_chain_diff(auth_chain_sets).collect::<HashSet<_>>();
+
+    let mut events = FetchedEvents::new();
+    let fetched = events
+        .fetch(
+            fetcher,
+            state_sets
+                .clone()
+                .flat_map(|state_set| state_set.values())
+                .chain(&auth_chain_diff)
+                .map(|id| id.borrow()),
+        )
+        .await?;
+    let auth_event_ids = fetched
+        .iter()
+        .flat_map(|event| event.auth_events().map(|id| id.borrow().to_owned()))
+        .collect::<Vec<OwnedEventId>>();
+    events.fetch(fetcher, auth_event_ids.iter().map(|id| &**id)).await?;
+
+    loop {
+        let resolved = resolve_inner(
+            room_version,
+            state_sets.clone(),
+            |_| Ok(auth_chain_diff.clone()),
+            |id| events.get(id),
+            &mut ResolutionReport::new(),
+        );
+
+        let misses = events.take_misses();
+        if misses.is_empty() {
+            return resolved;
+        }
+
+        debug!("fetching {} missing events", misses.len());
+        events.fetch(fetcher, misses.iter().map(|id| &**id)).await?;
+    }
+}
+
+/// Resolve sets of state events with the given function to compute the auth chain difference.
+fn resolve_inner<'a, E, SetIter, F>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_diff: impl FnOnce((SetIter, &F)) -> Result<HashSet<E::Id>>,
+    fetch_event: F,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    F: Fn(&EventId) -> Option<E>,
+{
+    let room_version = RoomVersion::new(room_version)?;
+    let state_sets = state_sets.into_iter();
+
+    match room_version.state_res {
+        StateResolutionVersion::V1 => {
+            return state_res_v1::resolve(&room_version, state_sets, fetch_event, report);
+        }
+        StateResolutionVersion::V2 => {}
+    }
+
     info!("State resolution starting");
 
     // Split non-conflicting and conflicting state
-    let (clean, conflicting) = separate(state_sets.into_iter());
+    let (clean, conflicting) = separate(state_sets.clone());
 
     info!("non conflicting events: {}", clean.len());
     trace!("{clean:?}");
@@ -81,7 +315,8 @@ where
 
     // `all_conflicted` contains unique items
     // synapse says `full_set = {eid for eid in full_conflicted_set if eid in event_map}`
-    let all_conflicted: HashSet<_> = get_auth_chain_diff(auth_chain_sets)
+    let all_conflicted: HashSet<_> = auth_chain_diff((state_sets, &fetch_event))?
+        .into_iter()
         .chain(conflicting.into_values().flatten())
         // Don't honor events we cannot "verify"
         .filter(|id| fetch_event(id.borrow()).is_some())
@@ -107,10 +342,14 @@ where
     debug!("sorted control events: {}", sorted_control_levels.len());
     trace!("{sorted_control_levels:?}");
 
-    let room_version = RoomVersion::new(room_version)?;
     // Sequentially auth check each control event.
-    let resolved_control =
-        iterative_auth_check(&room_version, &sorted_control_levels, clean.clone(), &fetch_event)?;
+    let resolved_control = iterative_auth_check(
+        &room_version,
+        &sorted_control_levels,
+        clean.clone(),
+        &fetch_event,
+        report,
+    )?;
 
     debug!("resolved control events: {}", resolved_control.len());
     trace!("{resolved_control:?}");
@@ -144,6 +383,7 @@ where
         &sorted_left_events,
         resolved_control, // The control events are added to the final resolved state
         &fetch_event,
+        report,
     )?;
 
     // Add unconflicted state to the resolved state
@@ -386,6 +626,7 @@ fn iterative_auth_check<E: Event + Clone>(
     events_to_check: &[E::Id],
     unconflicted_state: StateMap<E::Id>,
     fetch_event: impl Fn(&EventId) -> Option<E>,
+    report: &mut ResolutionReport<E::Id>,
 ) -> Result<StateMap<E::Id>> {
     info!("starting iterative auth check");
 
@@ -438,9 +679,12 @@ fn iterative_auth_check<E: Event + Clone>(
             (*pdu.event_type() == TimelineEventType::RoomThirdPartyInvite).then_some(pdu)
         });
 
-        if auth_check(room_version, &event, current_third_party, |ty, key| {
-            auth_events.get(&ty.with_state_key(key))
-        })? {
+        let result =
+            auth_check_with_rejection(room_version, &event, current_third_party, |ty, key| {
+                auth_events.get(&ty.with_state_key(key))
+            })?;
+
+        if result.is_ok() {
             // add event to resolved state map
             resolved_state.insert(event.event_type().with_state_key(state_key), event_id.clone());
         } else {
@@ -448,6 +692,8 @@ fn iterative_auth_check<E: Event + Clone>(
             warn!("event {event_id} failed the authentication check");
         }
 
+        report.push(event_id.clone(), result);
+
         // TODO: if these functions are ever made async here
         // is a good place to yield every once in a while so other
         // tasks can make progress
@@ -638,13 +884,13 @@ where
 mod tests {
     use std::{
         collections::{HashMap, HashSet},
-        sync::Arc,
+        sync::{Arc, Mutex},
     };
 
     use js_int::{int, uint};
     use maplit::{hashmap, hashset};
     use rand::seq::SliceRandom;
-    use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
+    use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
     use ruma_events::{
         room::join_rules::{JoinRule, RoomJoinRulesEventContent},
         StateEventType, TimelineEventType,
@@ -659,7 +905,8 @@ mod tests {
             alice, bob, charlie, do_check, ella, event_id, member_content_ban, member_content_join,
             room_id, to_init_pdu_event, to_pdu_event, zara, PduEvent, TestStore, INITIAL_EVENTS,
         },
-        Event, EventTypeExt, StateMap,
+        AuthChains, AuthRejection, Event, EventFetcher, EventTypeExt, ResolutionReport, Result,
+        StateMap,
     };
 
     fn test_event_sort() {
@@ -691,6 +938,7 @@ mod tests {
             &sorted_power_events,
             HashMap::new(), // unconflicted events
             |id| events.get(id).map(Arc::clone),
+            &mut ResolutionReport::new(),
         )
         .expect("iterative auth check failed on resolved events");
 
@@ -1180,6 +1428,190 @@ mod tests {
         assert_eq!(expected.len(), resolved.len());
     }
 
+    #[test]
+    fn ban_with_computed_auth_chains() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut inner = INITIAL_EVENTS();
+        inner.extend(BAN_STATE_SET());
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = inner.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
+        ];
+
+        let expected = crate::resolve(
+            &RoomVersionId::V6,
+            &state_sets,
+            AuthChains::new()
+                .auth_chain_sets(&state_sets, |id| inner.get(id).map(Arc::clone))
+                .unwrap(),
+            |id| inner.get(id).map(Arc::clone),
+        )
+        .unwrap();
+        assert_eq!(expected[&(StateEventType::RoomMember, ella().to_string())], event_id("MB"));
+
+        for mut auth_chains in [AuthChains::new(), AuthChains::with_chain_cover_index()] {
+            let resolved = crate::resolve_with_auth_chains(
+                &RoomVersionId::V6,
+                &state_sets,
+                &mut auth_chains,
+                |id| inner.get(id).map(Arc::clone),
+            )
+            .unwrap();
+            assert_eq!(resolved, expected);
+        }
+    }
+
+    struct BatchFetcher {
+        events: HashMap<OwnedEventId, Arc<PduEvent>>,
+        batches: Mutex<Vec<Vec<OwnedEventId>>>,
+    }
+
+    impl EventFetcher<Arc<PduEvent>> for BatchFetcher {
+        async fn fetch_events(&self, event_ids: &[&EventId]) -> Result<Vec<Arc<PduEvent>>> {
+            self.batches.lock().unwrap().push(event_ids.iter().map(|&id| id.to_owned()).collect());
+            Ok(event_ids.iter().filter_map(|&id| self.events.get(id).map(Arc::clone)).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn ban_with_async_fetcher() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut inner = INITIAL_EVENTS();
+        inner.extend(BAN_STATE_SET());
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = inner.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
+        ];
+        let auth_chain_sets = AuthChains::new()
+            .auth_chain_sets(&state_sets, |id| inner.get(id).map(Arc::clone))
+            .unwrap();
+
+        let expected =
+            crate::resolve(&RoomVersionId::V6, &state_sets, auth_chain_sets.clone(), |id| {
+                inner.get(id).map(Arc::clone)
+            })
+            .unwrap();
+
+        let fetcher = BatchFetcher { events: inner.clone(), batches: Mutex::default() };
+        let resolved =
+            crate::resolve_async(&RoomVersionId::V6, &state_sets, auth_chain_sets, &fetcher)
+                .await
+                .unwrap();
+        assert_eq!(resolved, expected);
+
+        // Every event is only fetched once, and the events that are not needed are not fetched.
+        let fetched =
+            fetcher.batches.into_inner().unwrap().into_iter().flatten().collect::<Vec<_>>();
+        let fetched_set = fetched.iter().collect::<HashSet<_>>();
+        assert_eq!(fetched.len(), fetched_set.len());
+        assert!(!fetched_set.contains(&event_id("START")));
+        assert!(!fetched_set.contains(&event_id("END")));
+    }
+
+    #[test]
+    fn topic_rejection_report() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut inner = INITIAL_EVENTS();
+        inner.extend(
+            [
+                to_pdu_event(
+                    "PA",
+                    alice(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 0 } })).unwrap(),
+                    &["CREATE", "IMA", "IPOWER"],
+                    &["IMC"],
+                ),
+                to_pdu_event(
+                    "TB",
+                    bob(),
+                    TimelineEventType::RoomTopic,
+                    Some(""),
+                    to_raw_json_value(&json!({})).unwrap(),
+                    &["CREATE", "IMB", "IPOWER"],
+                    &["IMC"],
+                ),
+            ]
+            .into_iter()
+            .map(|ev| (ev.event_id.clone(), ev)),
+        );
+        let rejection = AuthRejection::InsufficientPowerLevel {
+            event_type: TimelineEventType::RoomTopic,
+            required_level: int!(50),
+            sender_level: int!(0),
+        };
+        assert_eq!(
+            crate::auth_check_with_rejection(
+                &RoomVersion::V6,
+                &inner[&event_id("TB")],
+                None::<PduEvent>,
+                |ty, key| {
+                    let id = match ty {
+                        StateEventType::RoomPowerLevels => "IPOWER",
+                        StateEventType::RoomMember if key == bob().as_str() => "IMB",
+                        StateEventType::RoomCreate => "CREATE",
+                        _ => return None,
+                    };
+                    inner.get(&event_id(id)).cloned()
+                },
+            )
+            .unwrap(),
+            Err(rejection.clone())
+        );
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = inner.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IPOWER", "TB"]),
+        ];
+        let auth_chain_sets = AuthChains::new()
+            .auth_chain_sets(&state_sets, |id| inner.get(id).map(Arc::clone))
+            .unwrap();
+
+        let (resolved, report) =
+            crate::resolve_with_report(&RoomVersionId::V6, &state_sets, auth_chain_sets, |id| {
+                inner.get(id).map(Arc::clone)
+            })
+            .unwrap();
+
+        assert_eq!(resolved[&(StateEventType::RoomPowerLevels, "".to_owned())], event_id("PA"));
+        assert!(!resolved.contains_key(&(StateEventType::RoomTopic, "".to_owned())));
+
+        assert_eq!(report.rejected().count(), 1);
+        assert_eq!(*report.rejection(&event_id("TB")).unwrap(), rejection);
+        assert!(report.rejection(&event_id("PA")).is_none());
+        assert!(report.auth_checks.iter().any(|(id, _)| *id == event_id("PA")));
+    }
+
     #[test]
     fn join_rule_with_auth_chain() {
         let join_rule = JOIN_RULE();
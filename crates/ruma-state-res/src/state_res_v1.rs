@@ -0,0 +1,687 @@
+//! The original state resolution algorithm, used by room version 1.
+//!
+//! See the [room version 1 specification](https://spec.matrix.org/latest/rooms/v1/#state-resolution).
+
+use std::{borrow::Borrow, cmp::Reverse, hash::Hash};
+
+use ruma_common::EventId;
+use ruma_events::{StateEventType, TimelineEventType};
+use sha1::{Digest, Sha1};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    auth_check_with_rejection, auth_types_for_event, Error, Event, EventTypeExt, ResolutionReport,
+    Result, RoomVersion, StateMap,
+};
+
+/// Resolve sets of state events with the state resolution algorithm of room version 1.
+///
+/// The conflicted `m.room.power_levels`, `m.room.join_rules` and `m.room.member` events are
+/// resolved in that order, by authorizing them in order of ascending depth against the resolved
+/// state. The other conflicts are resolved by picking the event with the highest depth that is
+/// authorized.
+pub(crate) fn resolve<'a, E, SetIter>(
+    room_version: &RoomVersion,
+    state_sets: SetIter,
+    fetch_event: impl Fn(&EventId) -> Option<E>,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>>,
+{
+    info!("State resolution v1 starting");
+
+    let (mut resolved_state, conflicted) = separate(state_sets);
+
+    // Events that we cannot find are ignored. If only one event of a conflict can be found, it is
+    // part of the resolved state but it is not used to authorize the other conflicted events.
+    let mut conflicted_events = StateMap::new();
+    let mut found_events = Vec::new();
+    for (key, event_ids) in conflicted {
+        let mut events =
+            event_ids.iter().filter_map(|id| fetch_event(id.borrow())).collect::<Vec<_>>();
+
+        match events.len() {
+            0 => warn!("no conflicted events found for {key:?}"),
+            1 => {
+                let event = events.pop().unwrap();
+                found_events.push((key, event.event_id().clone()));
+            }
+            _ => {
+                conflicted_events.insert(key, events);
+            }
+        }
+    }
+
+    info!("non conflicting events: {}", resolved_state.len() + found_events.len());
+    trace!("{resolved_state:?}");
+
+    if conflicted_events.is_empty() {
+        info!("no conflicting state found");
+        resolved_state.extend(found_events);
+        return Ok(resolved_state);
+    }
+
+    info!("conflicting events: {}", conflicted_events.len());
+    debug!("{:?}", conflicted_events.keys().collect::<Vec<_>>());
+
+    // The conflicted events are authorized against the unconflicted state, updated with the
+    // resolved auth events, like Synapse does. Only the auth types of the conflicted events are
+    // kept, since those are the only keys that the authorization rules look up.
+    let mut auth_state = StateMap::new();
+    for event in conflicted_events.values().flatten() {
+        for key in auth_types_for_event(
+            event.event_type(),
+            event.sender(),
+            event.state_key(),
+            event.content(),
+        )? {
+            if auth_state.contains_key(&key) {
+                continue;
+            }
+
+            if let Some(auth_event) =
+                resolved_state.get(&key).and_then(|id| fetch_event(id.borrow()))
+            {
+                auth_state.insert(key, auth_event);
+            }
+        }
+    }
+
+    resolved_state.extend(found_events);
+
+    // First resolve the events that affect authorization, in order. The resolved events of a
+    // step are only used to authorize the events of the next steps.
+    for event_type in
+        [StateEventType::RoomPowerLevels, StateEventType::RoomJoinRules, StateEventType::RoomMember]
+    {
+        let keys = conflicted_events
+            .keys()
+            .filter(|(ty, _)| *ty == event_type)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut resolved_events = Vec::with_capacity(keys.len());
+        for key in keys {
+            let events = conflicted_events.remove(&key).expect("key comes from the map");
+            resolved_events
+                .push((key, resolve_auth_events(room_version, events, &auth_state, report)?));
+        }
+
+        for (key, event) in resolved_events {
+            debug!("resolved {key:?} to {}", event.event_id());
+            resolved_state.insert(key.clone(), event.event_id().clone());
+            auth_state.insert(key, event);
+        }
+    }
+
+    // Then resolve the other events.
+    for (key, events) in conflicted_events {
+        let event = resolve_normal_events(room_version, events, &auth_state, report)?;
+        debug!("resolved {key:?} to {}", event.event_id());
+        resolved_state.insert(key, event.event_id().clone());
+    }
+
+    Ok(resolved_state)
+}
+
+/// Split the events that have no conflicts from those that are conflicting.
+///
+/// The return tuple looks like `(unconflicted, conflicted)`.
+///
+/// Contrary to state resolution v2, state is only conflicting if there are different event IDs for
+/// the same key (StateEventType, StateKey). If a key is missing from some state sets, it is not
+/// conflicting.
+fn separate<'a, Id>(
+    state_sets: impl Iterator<Item = &'a StateMap<Id>>,
+) -> (StateMap<Id>, StateMap<Vec<Id>>)
+where
+    Id: Clone + Eq + Hash + 'a,
+{
+    let mut event_ids_by_key = StateMap::<Vec<Id>>::new();
+
+    for state_set in state_sets {
+        for (key, id) in state_set {
+            let event_ids = event_ids_by_key.entry(key.clone()).or_default();
+            if !event_ids.contains(id) {
+                event_ids.push(id.clone());
+            }
+        }
+    }
+
+    let mut unconflicted_state = StateMap::new();
+    let mut conflicted_state = StateMap::new();
+
+    for (key, mut event_ids) in event_ids_by_key {
+        if event_ids.len() == 1 {
+            unconflicted_state.insert(key, event_ids.pop().unwrap());
+        } else {
+            conflicted_state.insert(key, event_ids);
+        }
+    }
+
+    (unconflicted_state, conflicted_state)
+}
+
+/// Resolve conflicting events that affect authorization.
+///
+/// The events are sorted by ascending depth then descending SHA-1 hash of their event ID. The first
+/// event is accepted, then each following event is accepted if it is authorized by the state with
+/// the previous event. Returns the last accepted event.
+fn resolve_auth_events<E: Event + Clone>(
+    room_version: &RoomVersion,
+    events: Vec<E>,
+    auth_state: &StateMap<E>,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<E> {
+    let mut auth_events = StateMap::new();
+    for event in &events {
+        for key in auth_types_for_event(
+            event.event_type(),
+            event.sender(),
+            event.state_key(),
+            event.content(),
+        )? {
+            if let Some(auth_event) = auth_state.get(&key) {
+                auth_events.insert(key, auth_event.clone());
+            }
+        }
+    }
+
+    let mut events = sort_events(events).into_iter().rev();
+    let mut accepted =
+        events.next().ok_or_else(|| Error::NotFound("no events to resolve".into()))?;
+
+    for event in events {
+        auth_events.insert(event_key(&accepted)?, accepted.clone());
+
+        if !is_authorized(room_version, &event, &auth_events, report)? {
+            break;
+        }
+
+        accepted = event;
+    }
+
+    Ok(accepted)
+}
+
+/// Resolve conflicting events that don't affect authorization.
+///
+/// Returns the first authorized event, sorted by descending depth then ascending SHA-1 hash of
+/// their event ID, or the last one if none is authorized.
+fn resolve_normal_events<E: Event + Clone>(
+    room_version: &RoomVersion,
+    events: Vec<E>,
+    auth_state: &StateMap<E>,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<E> {
+    let mut last_event = None;
+
+    for event in sort_events(events) {
+        if is_authorized(room_version, &event, auth_state, report)? {
+            return Ok(event);
+        }
+
+        last_event = Some(event);
+    }
+
+    last_event.ok_or_else(|| Error::NotFound("no events to resolve".into()))
+}
+
+/// Sort the given events by descending depth then ascending SHA-1 hash of their event ID.
+fn sort_events<E: Event>(events: Vec<E>) -> Vec<E> {
+    let mut events = events
+        .into_iter()
+        .map(|event| {
+            let event_id: &EventId = event.event_id().borrow();
+            let hash = Sha1::digest(event_id.as_bytes());
+            ((Reverse(event.depth()), hash), event)
+        })
+        .collect::<Vec<_>>();
+
+    events.sort_by(|(a, _), (b, _)| a.cmp(b));
+    events.into_iter().map(|(_, event)| event).collect()
+}
+
+/// Whether the given event is authorized by the given state.
+///
+/// The result of the check is added to the report.
+fn is_authorized<E: Event>(
+    room_version: &RoomVersion,
+    event: &E,
+    auth_events: &StateMap<E>,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<bool> {
+    let current_third_party = auth_events
+        .values()
+        .find(|event| *event.event_type() == TimelineEventType::RoomThirdPartyInvite);
+
+    let result = auth_check_with_rejection(room_version, event, current_third_party, |ty, key| {
+        auth_events.get(&ty.with_state_key(key))
+    })?;
+
+    let authorized = result.is_ok();
+    if !authorized {
+        warn!("event {} failed the authentication check", event.event_id());
+    }
+
+    report.push(event.event_id().clone(), result);
+
+    Ok(authorized)
+}
+
+/// Get the state map key of the given state event.
+fn event_key<E: Event>(event: &E) -> Result<(StateEventType, String)> {
+    let state_key = event
+        .state_key()
+        .ok_or_else(|| Error::InvalidPdu("State event had no state key".to_owned()))?;
+    Ok(event.event_type().with_state_key(state_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma_common::RoomVersionId;
+    use ruma_events::{
+        room::join_rules::{JoinRule, RoomJoinRulesEventContent},
+        TimelineEventType,
+    };
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use crate::{
+        test_utils::{
+            alice, bob, charlie, do_check_with_room_version, ella, event_id, member_content_ban,
+            member_content_join, to_init_pdu_event, to_pdu_event, zara, INITIAL_EVENTS,
+        },
+        AuthRejection, Event, EventTypeExt, StateMap,
+    };
+
+    #[test]
+    fn ban_vs_power_level() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "PA",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "MA",
+                alice(),
+                TimelineEventType::RoomMember,
+                Some(alice().to_string().as_str()),
+                member_content_join(),
+            ),
+            to_init_pdu_event(
+                "MB",
+                alice(),
+                TimelineEventType::RoomMember,
+                Some(bob().to_string().as_str()),
+                member_content_ban(),
+            ),
+            to_init_pdu_event(
+                "PB",
+                bob(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+        ];
+
+        let edges = vec![vec!["END", "MB", "MA", "PA", "START"], vec!["END", "PA", "PB"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        // Alice's membership is conflicted too, so it is not in the state used to authorize the ban
+        // and Bob's membership is reset.
+        let expected_state_ids =
+            vec!["PA", "MA", "IMB"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check_with_room_version(&RoomVersionId::V1, events, edges, expected_state_ids);
+    }
+
+    #[test]
+    fn topic_basic() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "T1",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA1",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T2",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA2",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 0 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PB",
+                bob(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T3",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+        ];
+
+        let edges =
+            vec![vec!["END", "PA2", "T2", "PA1", "T1", "START"], vec!["END", "T3", "PB", "PA1"]]
+                .into_iter()
+                .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+
+        let expected_state_ids = vec!["PA2", "T2"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check_with_room_version(&RoomVersionId::V1, events, edges, expected_state_ids);
+    }
+
+    #[test]
+    fn topic_reset() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "T1",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T2",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "MB",
+                alice(),
+                TimelineEventType::RoomMember,
+                Some(bob().to_string().as_str()),
+                member_content_ban(),
+            ),
+        ];
+
+        let edges = vec![vec!["END", "MB", "T2", "PA", "T1", "START"], vec!["END", "T1"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let expected_state_ids =
+            vec!["T1", "MB", "PA"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check_with_room_version(&RoomVersionId::V1, events, edges, expected_state_ids);
+    }
+
+    #[test]
+    fn join_rule_evasion() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "JR",
+                alice(),
+                TimelineEventType::RoomJoinRules,
+                Some(""),
+                to_raw_json_value(&RoomJoinRulesEventContent::new(JoinRule::Private)).unwrap(),
+            ),
+            to_init_pdu_event(
+                "ME",
+                ella(),
+                TimelineEventType::RoomMember,
+                Some(ella().to_string().as_str()),
+                member_content_join(),
+            ),
+        ];
+
+        let edges = vec![vec!["END", "JR", "START"], vec!["END", "ME", "START"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        // Ella's membership is not in the state at `JR`, so it is not conflicting.
+        let expected_state_ids = vec![event_id("JR"), event_id("ME")];
+
+        do_check_with_room_version(&RoomVersionId::V1, events, edges, expected_state_ids);
+    }
+
+    #[test]
+    fn offtopic_power_level() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "PA",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PB",
+                bob(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50, charlie(): 50 } }))
+                    .unwrap(),
+            ),
+            to_init_pdu_event(
+                "PC",
+                charlie(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50, charlie(): 0 } }))
+                    .unwrap(),
+            ),
+        ];
+
+        let edges = vec![vec!["END", "PC", "PB", "PA", "START"], vec!["END", "PA"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        // `PC` is not authorized by `PA`, which has the lowest depth.
+        let expected_state_ids = vec!["PA"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check_with_room_version(&RoomVersionId::V1, events, edges, expected_state_ids);
+    }
+
+    #[test]
+    fn topic_setting() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "T1",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA1",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T2",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA2",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 0 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PB",
+                bob(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T3",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "MZ1",
+                zara(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T4",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+        ];
+
+        let edges = vec![
+            vec!["END", "T4", "MZ1", "PA2", "T2", "PA1", "T1", "START"],
+            vec!["END", "MZ1", "T3", "PB", "PA1"],
+        ]
+        .into_iter()
+        .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+        let expected_state_ids = vec!["T4", "PA2"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check_with_room_version(&RoomVersionId::V1, events, edges, expected_state_ids);
+    }
+
+    #[test]
+    fn single_found_conflicted_event_not_in_auth_state() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut events = INITIAL_EVENTS();
+        for event in [
+            to_init_pdu_event(
+                "PB",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_pdu_event(
+                "T1",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+                &["CREATE", "IMB"],
+                &["IMB"],
+            ),
+            to_pdu_event(
+                "T2",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+                &["CREATE", "IMB"],
+                &["IMB"],
+            ),
+        ] {
+            events.insert(event.event_id().to_owned(), event);
+        }
+
+        let base_state = ["CREATE", "IMA", "IJR", "IMB"]
+            .into_iter()
+            .map(|id| {
+                let event = &events[&event_id(id)];
+                let key = event.event_type().with_state_key(event.state_key().unwrap());
+                (key, event.event_id().to_owned())
+            })
+            .collect::<StateMap<_>>();
+
+        let power_levels_key = TimelineEventType::RoomPowerLevels.with_state_key("");
+        let topic_key = TimelineEventType::RoomTopic.with_state_key("");
+
+        let mut state_set_1 = base_state.clone();
+        state_set_1.insert(power_levels_key.clone(), event_id("PB"));
+        state_set_1.insert(topic_key.clone(), event_id("T1"));
+
+        // The power levels of this state set cannot be found.
+        let mut state_set_2 = base_state;
+        state_set_2.insert(power_levels_key.clone(), event_id("PMISSING"));
+        state_set_2.insert(topic_key.clone(), event_id("T2"));
+
+        let (resolved, report) = crate::resolve_with_report(
+            &RoomVersionId::V1,
+            [&state_set_1, &state_set_2],
+            Vec::new(),
+            |id| events.get(id).cloned(),
+        )
+        .unwrap();
+
+        // `PB` is the only power levels event that was found, so it is in the resolved state.
+        assert_eq!(resolved[&power_levels_key], event_id("PB"));
+        assert!(resolved[&topic_key] == event_id("T1") || resolved[&topic_key] == event_id("T2"));
+
+        // Like in Synapse, it is not used to authorize the topics, so Bob doesn't have the power
+        // level to send them.
+        for topic in ["T1", "T2"] {
+            assert!(matches!(
+                report.rejection(&event_id(topic)),
+                Some(AuthRejection::InsufficientPowerLevel { .. })
+            ));
+        }
+    }
+}
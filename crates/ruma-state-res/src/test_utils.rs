@@ -35,6 +35,15 @@ pub(crate) fn do_check(
     events: &[Arc<PduEvent>],
     edges: Vec<Vec<OwnedEventId>>,
     expected_state_ids: Vec<OwnedEventId>,
+) {
+    do_check_with_room_version(&RoomVersionId::V6, events, edges, expected_state_ids);
+}
+
+pub(crate) fn do_check_with_room_version(
+    room_version: &RoomVersionId,
+    events: &[Arc<PduEvent>],
+    edges: Vec<Vec<OwnedEventId>>,
+    expected_state_ids: Vec<OwnedEventId>,
 ) {
     // To activate logging use `RUST_LOG=debug cargo t`
 
@@ -117,7 +126,7 @@ pub(crate) fn do_check(
                 })
                 .collect();
 
-            let resolved = crate::resolve(&RoomVersionId::V6, state_sets, auth_chain_sets, |id| {
+            let resolved = crate::resolve(room_version, state_sets, auth_chain_sets, |id| {
                 event_map.get(id).map(Arc::clone)
             });
             match resolved {
@@ -151,7 +160,7 @@ pub(crate) fn do_check(
         // the `to_pdu_event` was split into `init` and the fn below, could be better
         let e = fake_event;
         let ev_id = e.event_id();
-        let event = to_pdu_event(
+        let mut event = to_pdu_event(
             e.event_id().as_str(),
             e.sender(),
             e.event_type().clone(),
@@ -161,6 +170,17 @@ pub(crate) fn do_check(
             &prev_events.iter().cloned().collect::<Vec<_>>(),
         );
 
+        // The depth is only used by state resolution v1.
+        let depth = prev_events
+            .iter()
+            .filter_map(|id| event_map.get(id))
+            .map(|prev_event| prev_event.depth() + uint!(1))
+            .max()
+            .unwrap_or_default();
+        if let Pdu::RoomV3Pdu(pdu) = &mut Arc::make_mut(&mut event).rest {
+            pdu.depth = depth;
+        }
+
         // We have to update our store, an actual user of this lib would
         // be giving us state from a DB.
         store.0.insert(ev_id.to_owned(), event.clone());
@@ -546,6 +566,7 @@ pub(crate) fn INITIAL_EDGES() -> Vec<OwnedEventId> {
 }
 
 pub(crate) mod event {
+    use js_int::UInt;
     use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, UserId};
     use ruma_events::{pdu::Pdu, TimelineEventType};
     use serde::{Deserialize, Serialize};
@@ -614,6 +635,15 @@ pub(crate) mod event {
             }
         }
 
+        fn depth(&self) -> UInt {
+            match &self.rest {
+                Pdu::RoomV1Pdu(ev) => ev.depth,
+                Pdu::RoomV3Pdu(ev) => ev.depth,
+                #[allow(unreachable_patterns)]
+                _ => unreachable!("new PDU version"),
+            }
+        }
+
         fn prev_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_> {
             match &self.rest {
                 Pdu::RoomV1Pdu(ev) => Box::new(ev.prev_events.iter().map(|(id, _)| id)),